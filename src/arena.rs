@@ -0,0 +1,430 @@
+//! Parsing into buffers owned by the caller, for targets without an allocator.
+//!
+//! An [`Arena`] holds fixed size tables for the nodes and keys of one descriptor. Parsing,
+//! type checking and script building all run on these tables and never allocate; when
+//! a descriptor needs more room than the arena has, parsing fails with
+//! [`ParseError::CapacityExceeded`](crate::parser::ParseError::CapacityExceeded).
+//!
+//! Only what fits in fixed size values can be parsed: hex keys, but no extended keys,
+//! `musig()`, `addr()` or `raw()`, which fail with
+//! [`ParseError::NeedsAllocator`](crate::parser::ParseError::NeedsAllocator).
+//!
+//! ```rust
+//! use tinyminiscript::arena::Arena;
+//!
+//! let mut arena = Arena::<16, 2>::new();
+//! let mut ctx = arena
+//!     .parse("wsh(or_d(pk(022f8bde4d1a07209355b4a7250a5c5128e88b84bddc619ab7cba8d569b240efe4),and_v(v:pk(025cbdf0646e5db4eaa398f365f2ea7a0e3d419b7e0330e39ce92bddedcac4f9bc),older(144))))")
+//!     .unwrap();
+//!
+//! let mut buf = [0u8; 128];
+//! let script = ctx.build_script_into(&mut buf).unwrap();
+//! assert_eq!(script.len(), 77);
+//! ```
+
+use core::marker::PhantomData;
+
+use crate::context::{self, ContextError, Tree};
+use crate::descriptor::Descriptor;
+use crate::parser::keys::KeyToken;
+use crate::parser::{
+    self, AST, Call, Fragment, KeyIndex, List, NodeIndex, ParserContext, Stack, Storage,
+};
+use crate::script::{Link, ScriptBuilder, ScriptBuilderError, ScriptWriter};
+use crate::type_checker::{CorrectnessPropertiesVisitor, TypeInfo};
+
+/// Storage for the nodes and keys of one descriptor.
+///
+/// `NODES` bounds the fragments, the sub-expressions of `thresh()` and the keys of
+/// `multi()` and `multi_a()`, which share a table of the same size. `KEYS` bounds the
+/// distinct keys.
+///
+/// The arena is large, as it stores every table inline; keep it in a `static` cell or
+/// on a stack with room for it.
+pub struct Arena<const NODES: usize, const KEYS: usize> {
+    nodes: [AST; NODES],
+    keys: [KeyToken; KEYS],
+    lists: [u32; NODES],
+    types: [Option<TypeInfo>; NODES],
+    /// The fragments whose arguments are being parsed
+    calls: [Call; NODES],
+    /// The arguments parsed so far of the fragments in `calls`
+    args: [u32; NODES],
+    /// The walk of the script builder
+    links: [Link; NODES],
+}
+
+/// The value of the unused slots of the node table.
+const EMPTY_NODE: AST = AST {
+    position: 0,
+    end: 0,
+    fragment: Fragment::False,
+};
+
+impl<const NODES: usize, const KEYS: usize> Arena<NODES, KEYS> {
+    pub fn new() -> Self {
+        Self {
+            nodes: [const { EMPTY_NODE }; NODES],
+            keys: core::array::from_fn(|_| KeyToken::nums()),
+            lists: [0; NODES],
+            types: [None; NODES],
+            calls: [Call::default(); NODES],
+            args: [0; NODES],
+            links: [Link::EMPTY; NODES],
+        }
+    }
+
+    /// Parse and validate a descriptor like [`Context::try_from`](crate::Context), storing
+    /// it in the arena. The previous descriptor of the arena is overwritten.
+    pub fn parse<'a>(&mut self, input: &'a str) -> Result<ArenaContext<'_>, ContextError<'a>> {
+        parser::check_ascii(input).map_err(ContextError::ParserError)?;
+
+        let mut ctx = ParserContext::<Fixed>::with_storage(
+            input,
+            Slots::new(&mut self.nodes),
+            Slots::new(&mut self.keys),
+            Slots::new(&mut self.lists),
+            Slots::new(&mut self.calls),
+            Slots::new(&mut self.args),
+        );
+        let (root, top_level_descriptor) =
+            parser::parse_context(&mut ctx, input).map_err(ContextError::ParserError)?;
+        let (nodes, keys, lists) = ctx.lens();
+        let inner_descriptor = ctx.inner_descriptor().clone();
+
+        let mut tree = ArenaContext {
+            nodes: &self.nodes[..nodes],
+            keys: &self.keys[..keys],
+            lists: &self.lists[..lists],
+            types: &[],
+            links: &mut self.links[..nodes],
+            root,
+            root_type: None,
+            top_level_descriptor,
+            inner_descriptor,
+        };
+        let mut visitor = CorrectnessPropertiesVisitor::with_types(&mut self.types[..]);
+        let root_type = context::check_tree(&tree, &mut visitor)?;
+        tree.types = &visitor.into_types()[..nodes];
+        tree.root_type = Some(root_type);
+        Ok(tree)
+    }
+}
+
+impl<const NODES: usize, const KEYS: usize> Default for Arena<NODES, KEYS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A descriptor parsed into an [`Arena`], with the accessors of a
+/// [`Context`](crate::Context).
+pub struct ArenaContext<'arena> {
+    nodes: &'arena [AST],
+    keys: &'arena [KeyToken],
+    lists: &'arena [u32],
+    types: &'arena [Option<TypeInfo>],
+    links: &'arena mut [Link],
+    root: AST,
+    root_type: Option<TypeInfo>,
+    top_level_descriptor: Descriptor,
+    inner_descriptor: Descriptor,
+}
+
+impl ArenaContext<'_> {
+    /// Get all the nodes in the AST.
+    pub fn get_nodes(&self) -> &[AST] {
+        self.nodes
+    }
+
+    /// Get the root node of the AST.
+    pub fn get_root(&self) -> &AST {
+        &self.root
+    }
+
+    /// Get the top level descriptor, e.g. `sh` for `sh(wsh(...))`.
+    pub fn top_level_descriptor(&self) -> Descriptor {
+        self.top_level_descriptor.clone()
+    }
+
+    /// Get the innermost descriptor, e.g. `wsh` for `sh(wsh(...))`.
+    pub fn descriptor(&self) -> Descriptor {
+        self.inner_descriptor.clone()
+    }
+
+    /// Get a node by its index.
+    pub fn get_node(&self, index: NodeIndex) -> &AST {
+        &self.nodes[index as usize]
+    }
+
+    /// Get the type of a node.
+    pub fn node_type(&self, index: NodeIndex) -> Option<TypeInfo> {
+        self.types.get(index as usize).copied().flatten()
+    }
+
+    /// Get the type of the root.
+    pub fn root_type(&self) -> Option<TypeInfo> {
+        self.root_type
+    }
+
+    /// Get the distinct keys, in order of first appearance.
+    pub fn get_keys(&self) -> &[KeyToken] {
+        self.keys
+    }
+
+    /// Get a key by its index.
+    pub fn get_key(&self, index: KeyIndex) -> &KeyToken {
+        &self.keys[index as usize]
+    }
+
+    /// Get the node indices of the sub-expressions of a `thresh()`, or the key indices of
+    /// a `multi()` or `multi_a()`.
+    pub fn list(&self, list: List) -> &[u32] {
+        &self.lists[list.range()]
+    }
+
+    /// Build the script like [`Context::build_script`](crate::Context::build_script),
+    /// writing it to the start of `buf`. Fails with
+    /// [`ScriptBuilderError::CapacityExceeded`] if the script does not fit.
+    pub fn build_script_into<'buf, 'a>(
+        &mut self,
+        buf: &'buf mut [u8],
+    ) -> Result<&'buf [u8], ScriptBuilderError<'a>> {
+        let mut writer = ScriptWriter::new(buf);
+        // the links are taken out while the tree is borrowed by the builder
+        let links = core::mem::take(&mut self.links);
+        let built = ScriptBuilder::new().build_fragment(&*self, &self.root, links, &mut writer);
+        self.links = links;
+        built?;
+        writer.finish()
+    }
+}
+
+impl Tree for ArenaContext<'_> {
+    fn get_nodes(&self) -> &[AST] {
+        self.nodes
+    }
+
+    fn get_key(&self, index: KeyIndex) -> &KeyToken {
+        &self.keys[index as usize]
+    }
+
+    fn lists(&self) -> &[u32] {
+        self.lists
+    }
+
+    fn get_root(&self) -> &AST {
+        &self.root
+    }
+
+    fn descriptor(&self) -> Descriptor {
+        self.inner_descriptor.clone()
+    }
+}
+
+/// A table in a fixed buffer, filled from the start.
+pub(crate) struct Slots<'buf, T> {
+    slots: &'buf mut [T],
+    len: usize,
+}
+
+impl<'buf, T> Slots<'buf, T> {
+    fn new(slots: &'buf mut [T]) -> Self {
+        Self { slots, len: 0 }
+    }
+}
+
+impl<T> Stack<T> for Slots<'_, T> {
+    fn as_slice(&self) -> &[T] {
+        &self.slots[..self.len]
+    }
+
+    fn push(&mut self, value: T) -> Result<(), usize> {
+        let Some(slot) = self.slots.get_mut(self.len) else {
+            return Err(self.slots.len());
+        };
+        *slot = value;
+        self.len += 1;
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: usize) {
+        // the slots past `len` keep their values until they are overwritten
+        self.len = core::cmp::min(self.len, len);
+    }
+}
+
+/// Storage in the buffers of an [`Arena`].
+pub(crate) struct Fixed<'buf>(PhantomData<&'buf ()>);
+
+impl<'buf> Storage for Fixed<'buf> {
+    type Nodes = Slots<'buf, AST>;
+    type Keys = Slots<'buf, KeyToken>;
+    type Indices = Slots<'buf, u32>;
+    type Calls = Slots<'buf, Call>;
+
+    const ALLOCATES: bool = false;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Context;
+    use crate::parser::{ParseError, Table};
+
+    use core::alloc::{GlobalAlloc, Layout};
+    use core::cell::Cell;
+    use std::alloc::System;
+
+    /// Counts the allocations of the current thread.
+    struct CountingAllocator;
+
+    std::thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.with(|count| count.set(count.get() + 1));
+            unsafe { System.alloc(layout) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            unsafe { System.dealloc(ptr, layout) }
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    fn allocations() -> usize {
+        ALLOCATIONS.with(Cell::get)
+    }
+
+    const KEY_A: &str = "022f8bde4d1a07209355b4a7250a5c5128e88b84bddc619ab7cba8d569b240efe4";
+    const KEY_B: &str = "025cbdf0646e5db4eaa398f365f2ea7a0e3d419b7e0330e39ce92bddedcac4f9bc";
+    const X_KEY_A: &str = "2f8bde4d1a07209355b4a7250a5c5128e88b84bddc619ab7cba8d569b240efe4";
+    const X_KEY_B: &str = "5cbdf0646e5db4eaa398f365f2ea7a0e3d419b7e0330e39ce92bddedcac4f9bc";
+
+    fn descriptors() -> [String; 6] {
+        [
+            format!("wsh(multi(1,{KEY_A},{KEY_B}))"),
+            format!("sh(wsh(or_d(pk({KEY_A}),and_v(v:pk({KEY_B}),older(144)))))"),
+            format!("wsh(thresh(2,pk({KEY_A}),s:pk({KEY_B}),sln:after(100)))"),
+            format!(
+                "tr({KEY_A},and_v(v:multi_a(1,{X_KEY_A},{X_KEY_B}),sha256(0000000000000000000000000000000000000000000000000000000000000000)))"
+            ),
+            format!("wpkh({KEY_A})"),
+            format!("wsh(andor(pk({KEY_A}),after(10),and_n(pk({KEY_B}),older(5))))"),
+        ]
+    }
+
+    #[test]
+    fn test_script_matches_context() {
+        for input in descriptors() {
+            let expected = Context::try_from(input.as_str()).unwrap();
+            let expected = expected.build_script().unwrap();
+
+            let mut arena = Arena::<32, 4>::new();
+            let mut ctx = arena.parse(&input).unwrap();
+            let mut buf = [0u8; 256];
+            let script = ctx.build_script_into(&mut buf).unwrap();
+            assert_eq!(script, expected.as_bytes(), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_no_allocation() {
+        let inputs = descriptors();
+        let mut arena = Arena::<32, 4>::new();
+        let mut buf = [0u8; 256];
+
+        // the counter sees the allocations of a context
+        let before = allocations();
+        Context::try_from(inputs[0].as_str()).unwrap();
+        assert!(allocations() > before);
+
+        for input in &inputs {
+            let before = allocations();
+            let mut ctx = arena.parse(input).unwrap();
+            let script = ctx.build_script_into(&mut buf).unwrap();
+            assert!(!script.is_empty());
+            assert_eq!(allocations(), before, "{input}");
+        }
+    }
+
+    #[test]
+    fn test_node_capacity() {
+        let input = format!("wsh(and_v(v:pk({KEY_A}),pk({KEY_B})))");
+        let mut arena = Arena::<4, 2>::new();
+        assert!(matches!(
+            arena.parse(&input),
+            Err(ContextError::ParserError(ParseError::CapacityExceeded {
+                table: Table::Nodes,
+                capacity: 4,
+                ..
+            }))
+        ));
+        assert!(Arena::<6, 2>::new().parse(&input).is_ok());
+    }
+
+    #[test]
+    fn test_key_capacity() {
+        let input = format!("wsh(multi(1,{KEY_A},{KEY_B}))");
+        let mut arena = Arena::<4, 1>::new();
+        assert!(matches!(
+            arena.parse(&input),
+            Err(ContextError::ParserError(ParseError::CapacityExceeded {
+                table: Table::Keys,
+                capacity: 1,
+                position: 80,
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_list_capacity() {
+        // the keys of `multi()` share the capacity of the nodes
+        let input = format!("wsh(multi(1,{KEY_A},{KEY_B}))");
+        let mut arena = Arena::<1, 2>::new();
+        assert!(matches!(
+            arena.parse(&input),
+            Err(ContextError::ParserError(ParseError::CapacityExceeded {
+                table: Table::Lists,
+                capacity: 1,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_script_capacity() {
+        let input = format!("wsh(multi(1,{KEY_A},{KEY_B}))");
+        let mut arena = Arena::<4, 2>::new();
+        let mut ctx = arena.parse(&input).unwrap();
+        let mut buf = [0u8; 16];
+        assert!(matches!(
+            ctx.build_script_into(&mut buf),
+            Err(ScriptBuilderError::CapacityExceeded {
+                length: 71,
+                capacity: 16,
+            })
+        ));
+    }
+
+    #[test]
+    fn test_needs_allocator() {
+        let mut arena = Arena::<8, 2>::new();
+        let input = "wpkh(xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8)";
+        assert!(matches!(
+            arena.parse(input),
+            Err(ContextError::ParserError(ParseError::NeedsAllocator {
+                found: (_, 6)
+            }))
+        ));
+        assert!(matches!(
+            arena.parse("raw(51)"),
+            Err(ContextError::ParserError(ParseError::NeedsAllocator { .. }))
+        ));
+    }
+}
@@ -1,36 +1,121 @@
 use core::marker::PhantomData;
 
 use crate::descriptor::{self, Descriptor, DescriptorValidator};
-use crate::parser::keys::KeyToken;
-use crate::parser::{Fragment, NodeIndex};
-use crate::script::{AddressBuilderError, ScriptBuilderError};
-use crate::type_checker::CorrectnessPropertiesVisitor;
+use crate::error::{Diagnostic, Help, Span};
+use crate::parser::keys::{DeriveError, KeyToken};
+use crate::parser::{Fragment, KeyIndex, List, NodeIndex};
+use crate::script::{AddressBuilderError, AddressIter, ScriptBuilderError};
+use crate::type_checker::{CorrectnessPropertiesVisitor, TypeInfo, TypeTable};
 use crate::{Vec, parser::AST};
 use crate::{limits, parser, type_checker};
 
 use alloc::string::String;
-use bitcoin::{Address, Network, ScriptBuf};
+use bitcoin::bip32::{Fingerprint, KeySource};
+use bitcoin::{Address, Network, Script, ScriptBuf};
+use core::ops::Range;
+
+/// The parsed nodes, keys and lists, wherever they are stored: in a [`Context`] or in
+/// the buffers of an [`Arena`](crate::arena::Arena).
+pub(crate) trait Tree {
+    fn get_nodes(&self) -> &[AST];
+
+    fn get_key(&self, index: KeyIndex) -> &KeyToken;
+
+    /// The list table, see [`Context::list`].
+    fn lists(&self) -> &[u32];
+
+    fn get_root(&self) -> &AST;
+
+    fn descriptor(&self) -> Descriptor;
+
+    #[inline]
+    fn get_node(&self, index: NodeIndex) -> &AST {
+        &self.get_nodes()[index as usize]
+    }
+
+    #[inline]
+    fn list(&self, list: List) -> &[u32] {
+        &self.lists()[list.range()]
+    }
+}
+
+impl Tree for Context {
+    fn get_nodes(&self) -> &[AST] {
+        &self.nodes
+    }
+
+    fn get_key(&self, index: KeyIndex) -> &KeyToken {
+        &self.keys[index as usize]
+    }
+
+    fn lists(&self) -> &[u32] {
+        &self.lists
+    }
+
+    fn get_root(&self) -> &AST {
+        &self.root
+    }
+
+    fn descriptor(&self) -> Descriptor {
+        self.inner_descriptor.clone()
+    }
+}
 
 // AST Visitor trait for visiting the AST.
 pub(crate) trait ASTVisitor<T> {
     type Error;
 
-    fn visit_ast(&mut self, ctx: &Context, node: &AST) -> Result<T, Self::Error>;
+    fn visit_ast<C: Tree>(&mut self, ctx: &C, node: &AST) -> Result<T, Self::Error>;
 
     #[inline]
-    fn visit_ast_by_index(&mut self, ctx: &Context, index: NodeIndex) -> Result<T, Self::Error> {
-        self.visit_ast(ctx, &ctx.nodes[index as usize])
+    fn visit_ast_by_index<C: Tree>(&mut self, ctx: &C, index: NodeIndex) -> Result<T, Self::Error> {
+        self.visit_ast(ctx, ctx.get_node(index))
     }
 
     #[inline]
-    fn visit(&mut self, ctx: &Context) -> Result<T, Self::Error> {
-        self.visit_ast(ctx, &ctx.get_root())
+    fn visit<C: Tree>(&mut self, ctx: &C) -> Result<T, Self::Error> {
+        self.visit_ast(ctx, ctx.get_root())
+    }
+}
+
+/// Iterator over the nodes below a fragment in the order a recursive traversal finishes
+/// them: the sub-expressions of every node from left to right, then the node itself.
+///
+/// Processing the nodes in this order computes the result of every sub-expression before
+/// the node using it, without recursing.
+pub(crate) struct PostOrder<'a> {
+    ctx: &'a Context,
+    /// Nodes left to visit, with whether their sub-expressions were already pushed
+    stack: Vec<(NodeIndex, bool)>,
+}
+
+impl Iterator for PostOrder<'_> {
+    type Item = NodeIndex;
+
+    fn next(&mut self) -> Option<NodeIndex> {
+        while let Some((index, expanded)) = self.stack.pop() {
+            if expanded {
+                return Some(index);
+            }
+            self.stack.push((index, true));
+            let children = self.ctx.get_node(index).fragment.children(&self.ctx.lists);
+            self.stack
+                .extend(children.rev().map(|child| (child, false)));
+        }
+        None
     }
 }
 
 /// Context for miniscript expressions.
+#[derive(Clone)]
 pub struct Context {
     nodes: Vec<AST>,
+    keys: Vec<KeyToken>,
+    /// The sub-expressions of `thresh()` and the keys of `multi()` and `multi_a()`
+    lists: Vec<u32>,
+    /// The type of every node, filled in once the context type checked
+    types: Vec<Option<TypeInfo>>,
+    root_type: Option<TypeInfo>,
     root: AST,
     top_level_descriptor: Descriptor,
     inner_descriptor: Descriptor,
@@ -39,12 +124,18 @@ pub struct Context {
 impl Context {
     pub(crate) fn new(
         nodes: Vec<AST>,
+        keys: Vec<KeyToken>,
+        lists: Vec<u32>,
         root: AST,
         top_level_descriptor: Descriptor,
         inner_descriptor: Descriptor,
     ) -> Self {
         Self {
             nodes,
+            keys,
+            lists,
+            types: Vec::new(),
+            root_type: None,
             root,
             top_level_descriptor,
             inner_descriptor,
@@ -81,94 +172,233 @@ impl Context {
         &self.nodes[index as usize]
     }
 
-    /// Satisfy the context with a satisfier.
+    /// Get the type of a node: its base type and its `zondu` correctness and `esfm`
+    /// malleability properties, as computed by the type checker.
+    pub fn node_type(&self, index: NodeIndex) -> Option<TypeInfo> {
+        self.types.get(index as usize).copied().flatten()
+    }
+
+    /// Get the type of the miniscript at the root, e.g. the expression inside `wsh()`.
+    pub fn root_type(&self) -> Option<TypeInfo> {
+        self.root_type
+    }
+
+    /// Iterate over the nodes below `ast`, every node after its sub-expressions.
+    pub(crate) fn post_order(&self, ast: &AST) -> PostOrder<'_> {
+        PostOrder {
+            ctx: self,
+            stack: ast
+                .fragment
+                .children(&self.lists)
+                .rev()
+                .map(|child| (child, false))
+                .collect(),
+        }
+    }
+
+    /// Get all the distinct keys, in the order they first appear in the descriptor.
+    pub fn get_keys(&self) -> &[KeyToken] {
+        &self.keys[..]
+    }
+
+    /// Get a key by index.
+    pub fn get_key(&self, index: KeyIndex) -> &KeyToken {
+        &self.keys[index as usize]
+    }
+
+    /// Get the node indices of the sub-expressions of a `thresh()`, or the key indices of
+    /// a `multi()` or `multi_a()`.
+    pub fn list(&self, list: List) -> &[u32] {
+        &self.lists[list.range()]
+    }
+
+    /// Check if some key appears more than once in the descriptor.
+    pub fn has_repeated_keys(&self) -> bool {
+        let mut uses = 0;
+        self.nodes.iter().for_each(|node| match &node.fragment {
+            Fragment::PkK { .. }
+            | Fragment::PkH { .. }
+            | Fragment::RawPkH { .. }
+            | Fragment::RawTr { .. }
+            | Fragment::RawPk { .. } => uses += 1,
+            Fragment::Multi { keys, .. } | Fragment::MultiA { keys, .. } => uses += keys.len(),
+            _ => (),
+        });
+        uses != self.keys.len()
+    }
+
+    /// Check if the descriptor can be spent with a single signature for its taproot output
+    /// key. A `tr()` whose internal key is the NUMS point, see [`KeyToken::is_nums`], can
+    /// only be spent by script, and non-taproot descriptors have no key path at all.
+    pub fn has_key_path(&self) -> bool {
+        self.nodes.iter().any(|node| match node.fragment {
+            Fragment::RawTr { key, .. } => !self.get_key(key).is_nums(),
+            _ => false,
+        })
+    }
+
+    /// Satisfy the context with a satisfier, picking between spending paths according to
+    /// `strategy`. Use [`Strategy::DEFAULT`](crate::satisfy::Strategy::DEFAULT) for the
+    /// smallest non-malleable witness.
     #[cfg(feature = "satisfy")]
     pub fn satisfy(
         &self,
         satisfier: &dyn crate::satisfy::Satisfier,
+        strategy: &crate::satisfy::Strategy,
     ) -> Result<crate::satisfy::Satisfactions, crate::satisfy::SatisfyError> {
-        crate::satisfy::satisfy(self, satisfier, &self.get_root())
-    }
-
-    /// Iterate over all the keys mutably.
-    /// Not using a Visitor pattern because it's not needed for the current use case.
-    pub fn iterate_keys_mut(&mut self, mut callback: impl FnMut(&mut KeyToken)) {
-        self.nodes
-            .iter_mut()
-            .for_each(|node| match &mut node.fragment {
-                Fragment::PkK { key } => callback(key),
-                Fragment::PkH { key } => callback(key),
-                Fragment::Multi { keys, .. } => {
-                    for key in keys.iter_mut() {
-                        callback(key);
-                    }
-                }
-                Fragment::MultiA { keys, .. } => {
-                    for key in keys.iter_mut() {
-                        callback(key);
-                    }
-                }
-                Fragment::RawPkH { key } => callback(key),
-                Fragment::RawTr { key, .. } => {
-                    callback(key);
-                }
-                Fragment::RawPk { key } => {
-                    callback(key);
-                }
-                _ => (),
-            });
+        crate::satisfy::satisfy(self, satisfier, strategy, self.get_root())
+            .map(crate::satisfy::Candidates::build)
     }
 
-    /// Iterate over all the keys.
-    pub fn iterate_keys(&self, mut callback: impl FnMut(&KeyToken)) {
-        self.nodes.iter().for_each(|node| match &node.fragment {
-            Fragment::PkK { key } => callback(key),
-            Fragment::PkH { key } => callback(key),
-            Fragment::Multi { keys, .. } => {
-                for key in keys.iter() {
-                    callback(key);
-                }
-            }
-            Fragment::MultiA { keys, .. } => {
-                for key in keys.iter() {
-                    callback(key);
-                }
-            }
-            Fragment::RawPkH { key } => callback(key),
-            Fragment::RawTr { key, .. } => {
-                callback(key);
-            }
-            Fragment::RawPk { key } => {
-                callback(key);
-            }
-            _ => (),
+    /// Plan how to spend the descriptor with the given assets, before any signature exists.
+    ///
+    /// The plan lists the keys to request signatures from, the preimages and locktimes
+    /// needed, and the size of the witness and script sig. Once the signatures are
+    /// available, [`Plan::satisfy`](crate::plan::Plan::satisfy) yields the planned witness.
+    #[cfg(feature = "satisfy")]
+    pub fn plan(
+        &self,
+        assets: &crate::plan::Assets,
+    ) -> Result<crate::plan::Plan, crate::satisfy::SatisfyError> {
+        crate::plan::Plan::new(self, assets)
+    }
+
+    /// List every minimal way the descriptor can be spent, e.g. to audit a vault.
+    ///
+    /// Each path gives the keys that must sign, the preimages and locktimes needed, the
+    /// witness size and whether the witness is malleable. Fails with
+    /// [`SatisfyError::TooManyPaths`](crate::satisfy::SatisfyError::TooManyPaths) if a
+    /// fragment has more than `max_paths` paths, e.g. a large threshold.
+    #[cfg(feature = "satisfy")]
+    pub fn spending_paths(
+        &self,
+        max_paths: usize,
+    ) -> Result<Vec<crate::plan::Plan>, crate::satisfy::SatisfyError> {
+        crate::plan::spending_paths(self, max_paths)
+    }
+
+    /// Execute the script of the descriptor against a witness, e.g. to test a wallet's
+    /// satisfier end-to-end. Errors point to the fragment whose script failed.
+    pub fn verify_witness(
+        &self,
+        witness: &bitcoin::Witness,
+        verifier: &dyn crate::interpreter::Verifier,
+    ) -> Result<(), crate::interpreter::InterpreterError> {
+        let stack = witness.iter().map(|e| e.to_vec()).collect();
+        crate::interpreter::inspect(self, stack, verifier).map(|_| ())
+    }
+
+    /// Find out how a spent input used the descriptor: the branch taken by each
+    /// disjunction, the keys that signed, the preimages revealed and the timelocks
+    /// relied on. `script_sig` is only read for legacy descriptors.
+    pub fn inspect_spend(
+        &self,
+        witness: &bitcoin::Witness,
+        script_sig: &Script,
+        verifier: &dyn crate::interpreter::Verifier,
+    ) -> Result<crate::interpreter::Inspection, crate::interpreter::InterpreterError> {
+        let stack = crate::interpreter::spend_stack(self, witness, script_sig)?;
+        crate::interpreter::inspect(self, stack, verifier)
+    }
+
+    /// Iterate over all the distinct keys mutably.
+    pub fn iterate_keys_mut(&mut self, callback: impl FnMut(&mut KeyToken)) {
+        self.keys.iter_mut().for_each(callback);
+    }
+
+    /// Iterate over all the distinct keys.
+    pub fn iterate_keys(&self, callback: impl FnMut(&KeyToken)) {
+        self.keys.iter().for_each(callback);
+    }
+
+    /// Check if any key in the AST has a wildcard.
+    pub fn is_ranged(&self) -> bool {
+        let mut ranged = false;
+        self.iterate_keys(|key| ranged |= key.is_ranged());
+        ranged
+    }
+
+    // iterate over the distinct keys and the participants of the `musig()` ones
+    fn iterate_signing_keys(&self, mut callback: impl FnMut(&KeyToken)) {
+        self.iterate_keys(|key| {
+            callback(key);
+            key.participants().iter().for_each(&mut callback);
         });
     }
 
-    /// Derive all the keys in the AST.
-    pub fn derive(&mut self, index: u32) -> Result<(), String> {
-        for node in &mut self.nodes {
-            match &mut node.fragment {
-                Fragment::PkK { key } | Fragment::PkH { key } | Fragment::RawPkH { key } => {
-                    let derived = key.derive(index)?;
-                    *key = derived;
-                }
-                Fragment::Multi { keys, k } => {
-                    for key in keys.iter_mut() {
-                        let derived = key.derive(index)?;
-                        *key = derived;
-                    }
-                }
-                Fragment::MultiA { keys, k } => {
-                    for key in keys.iter_mut() {
-                        let derived = key.derive(index)?;
-                        *key = derived;
-                    }
-                }
-                _ => (),
+    /// The master fingerprints of all the extended keys in the AST, including the
+    /// participants of `musig()` keys, without duplicates.
+    pub fn fingerprints(&self) -> Vec<Fingerprint> {
+        let mut fingerprints = Vec::new();
+        self.iterate_signing_keys(|key| {
+            if let Some(fingerprint) = key.master_fingerprint()
+                && !fingerprints.contains(&fingerprint)
+            {
+                fingerprints.push(fingerprint);
             }
+        });
+        fingerprints
+    }
+
+    /// The master fingerprint and full derivation path of every extended key in the AST
+    /// at the given index, in the order the keys appear. The participants of a `musig()`
+    /// key follow it.
+    ///
+    /// Signing devices need these to find which of their keys the descriptor uses.
+    pub fn derivation_paths(&self, index: u32) -> Result<Vec<KeySource>, DeriveError> {
+        let mut paths = Vec::new();
+        let mut error = None;
+        self.iterate_signing_keys(|key| match key.full_derivation_path(index) {
+            Ok(Some(path)) => paths.extend(key.master_fingerprint().map(|f| (f, path))),
+            Ok(None) => (),
+            Err(e) => error = Some(e),
+        });
+        match error {
+            Some(e) => Err(e),
+            None => Ok(paths),
         }
-        Ok(())
+    }
+
+    /// Derive all the keys in the AST at the given index, returning the derived context.
+    ///
+    /// Every key-bearing fragment is derived, including the `tr()` internal key and
+    /// the `pk()`/`pkh()`/`wpkh()` top level keys. Non-extended keys are left untouched.
+    pub fn derive(&self, index: u32) -> Result<Context, DeriveError> {
+        self.derive_with(&bitcoin::secp256k1::Secp256k1::verification_only(), index)
+    }
+
+    /// Derive all the keys at `index` with a caller-provided context, so that deriving
+    /// many indexes does not create a context every time.
+    pub fn derive_with<C: bitcoin::secp256k1::Verification>(
+        &self,
+        secp: &bitcoin::secp256k1::Secp256k1<C>,
+        index: u32,
+    ) -> Result<Context, DeriveError> {
+        self.try_map_keys(|key| key.derive_with(secp, index))
+    }
+
+    /// Derive the context at every index of `range`, deriving every key as
+    /// [`KeyToken::derive_range`] does.
+    pub fn derive_range<C: bitcoin::secp256k1::Verification>(
+        &self,
+        secp: &bitcoin::secp256k1::Secp256k1<C>,
+        range: Range<u32>,
+    ) -> Result<Vec<Context>, DeriveError> {
+        let parent = self.try_map_keys(|key| key.cache_parent(secp))?;
+        range.map(|index| parent.derive_with(secp, index)).collect()
+    }
+
+    /// Clone the context, replacing every key with the result of `f`.
+    ///
+    /// `f` is called once per distinct key, the fragments keep referring to the same index.
+    pub(crate) fn try_map_keys<E>(
+        &self,
+        f: impl FnMut(&KeyToken) -> Result<KeyToken, E>,
+    ) -> Result<Context, E> {
+        Ok(Context {
+            keys: self.keys.iter().map(f).collect::<Result<_, E>>()?,
+            ..self.clone()
+        })
     }
 
     /// Serialize the AST to a string.
@@ -186,13 +416,140 @@ impl Context {
     pub fn build_address<'a>(&self, network: Network) -> Result<Address, AddressBuilderError<'a>> {
         crate::script::build_address(self, network)
     }
+
+    /// Build the script pubkey from the AST.
+    pub fn build_script_pubkey<'a>(&self) -> Result<ScriptBuf, AddressBuilderError<'a>> {
+        crate::script::build_script_pubkey(self)
+    }
+
+    /// Iterate over the addresses of a ranged descriptor for every index in `range`.
+    /// `combo()` yields the address of each output it stands for at every index. Keys are
+    /// derived as by [`KeyToken::derive_range`].
+    pub fn addresses(
+        &self,
+        network: Network,
+        range: Range<u32>,
+    ) -> Result<AddressIter, AddressBuilderError<'static>> {
+        AddressIter::new(self, network, range)
+    }
+
+    /// Find the index in `range` whose derived script pubkey is `script_pubkey`.
+    ///
+    /// This is the gap-limit scanning helper: scan `0..gap_limit` past the last used index.
+    /// Unlike [`Context::addresses`], it also finds the p2pk outputs of `pk()` and `combo()`.
+    pub fn find_script_pubkey(
+        &self,
+        script_pubkey: &Script,
+        range: Range<u32>,
+    ) -> Result<Option<u32>, AddressBuilderError<'static>> {
+        if !self.is_ranged() {
+            return Err(AddressBuilderError::NotRanged {
+                span: self.root.span(),
+            });
+        }
+        let secp = bitcoin::secp256k1::Secp256k1::verification_only();
+        let ctx = self
+            .try_map_keys(|key| key.cache_parent(&secp))
+            .map_err(AddressBuilderError::Derive)?;
+        for index in range {
+            let derived = ctx
+                .derive_with(&secp, index)
+                .map_err(AddressBuilderError::Derive)?;
+            if derived
+                .script_pubkeys()?
+                .iter()
+                .any(|spk| spk.as_script() == script_pubkey)
+            {
+                return Ok(Some(index));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Expand a `combo(KEY)` descriptor into the descriptors it stands for: `pk(KEY)`,
+    /// `pkh(KEY)` and, if the key is compressed, `wpkh(KEY)` and `sh(wpkh(KEY))`.
+    ///
+    /// Any other descriptor expands to itself.
+    pub fn expand_combo(&self) -> Vec<Context> {
+        let Fragment::Descriptor {
+            descriptor: Descriptor::Combo,
+            inner,
+        } = &self.root.fragment
+        else {
+            return alloc::vec![self.clone()];
+        };
+        let node = self.get_node(*inner);
+        let Fragment::RawPk { key } = node.fragment else {
+            return alloc::vec![self.clone()];
+        };
+
+        let (position, end) = (node.position, node.end);
+        let ast = |fragment| AST {
+            position,
+            end,
+            fragment,
+        };
+        let root = |descriptor, inner| AST {
+            position: self.root.position,
+            end: self.root.end,
+            fragment: Fragment::Descriptor { descriptor, inner },
+        };
+        let single = |descriptor: Descriptor, fragment| {
+            let nodes = alloc::vec![ast(fragment)];
+            let root = root(descriptor.clone(), 0);
+            let (keys, lists) = (self.keys.clone(), Vec::new());
+            Context::new(nodes, keys, lists, root, descriptor.clone(), descriptor)
+        };
+
+        let mut components = alloc::vec![
+            single(Descriptor::Pk, Fragment::RawPk { key }),
+            single(Descriptor::Pkh, Fragment::RawPkH { key }),
+        ];
+        if self.get_key(key).is_compressed() {
+            components.push(single(Descriptor::Wpkh, Fragment::RawPkH { key }));
+            let nodes = alloc::vec![ast(Fragment::RawPkH { key }), root(Descriptor::Wpkh, 0),];
+            let root = root(Descriptor::Sh, 1);
+            components.push(Context::new(
+                nodes,
+                self.keys.clone(),
+                Vec::new(),
+                root,
+                Descriptor::Sh,
+                Descriptor::Wpkh,
+            ));
+        }
+        components
+            .into_iter()
+            .map(|ctx| Context::check(ctx).expect("single key descriptors are valid"))
+            .collect()
+    }
+
+    /// Build the script pubkeys of the descriptor, one per descriptor `combo()` stands for.
+    pub fn script_pubkeys<'a>(&self) -> Result<Vec<ScriptBuf>, AddressBuilderError<'a>> {
+        self.expand_combo()
+            .iter()
+            .map(crate::script::build_script_pubkey)
+            .collect()
+    }
+
+    /// Build the addresses of the descriptor, one per descriptor `combo()` stands for
+    /// except `pk()` which has no address of its own.
+    pub fn build_addresses<'a>(
+        &self,
+        network: Network,
+    ) -> Result<Vec<Address>, AddressBuilderError<'a>> {
+        self.expand_combo()
+            .iter()
+            .filter(|ctx| {
+                self.descriptor() != Descriptor::Combo || ctx.descriptor() != Descriptor::Pk
+            })
+            .map(|ctx| crate::script::build_address(ctx, network))
+            .collect()
+    }
 }
 
 /// Errors that can occur during miniscript parsing, validation, or script building.
-///
-/// When the `debug` feature is enabled, this enum implements [`Debug`] for easier
-/// debugging and error reporting in development environments.
-#[cfg_attr(feature = "debug", derive(Debug))]
+#[derive(Debug)]
 pub enum ContextError<'a> {
     /// Error occurred during parsing of the miniscript string
     ParserError(parser::ParseError<'a>),
@@ -204,6 +561,39 @@ pub enum ContextError<'a> {
     LimitsError(limits::LimitsError),
 }
 
+impl ContextError<'_> {
+    fn as_diagnostic(&self) -> &dyn Diagnostic {
+        match self {
+            ContextError::ParserError(e) => e,
+            ContextError::TypeCheckerError(e) => e,
+            ContextError::DescriptorVisitorError(e) => e,
+            ContextError::LimitsError(e) => e,
+        }
+    }
+}
+
+impl core::fmt::Display for ContextError<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Display::fmt(self.as_diagnostic(), f)
+    }
+}
+
+impl core::error::Error for ContextError<'_> {}
+
+impl Diagnostic for ContextError<'_> {
+    fn code(&self) -> &'static str {
+        self.as_diagnostic().code()
+    }
+
+    fn span(&self) -> Option<Span> {
+        self.as_diagnostic().span()
+    }
+
+    fn help(&self) -> Option<Help> {
+        self.as_diagnostic().help()
+    }
+}
+
 /// Parse and validate a miniscript string, returning the parsed context and generated Bitcoin script.
 ///
 /// This function performs a complete validation pipeline:
@@ -242,24 +632,680 @@ impl<'a> TryFrom<&'a str> for Context {
     type Error = ContextError<'a>;
     fn try_from(value: &'a str) -> Result<Self, Self::Error> {
         let ctx = parser::parse(value).map_err(ContextError::ParserError)?;
+        Context::check(ctx)
+    }
+}
+
+impl Context {
+    /// Parse and validate a miniscript string like [`Context::try_from`], within the
+    /// bounds of `options`.
+    ///
+    /// Use it to bound the stack used by the parser, e.g. on devices with a few kilobytes
+    /// of stack.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use tinyminiscript::{Context, parser::ParseOptions};
+    ///
+    /// let input = "wsh(and_v(v:pk(022f8bde4d1a07209355b4a7250a5c5128e88b84bddc619ab7cba8d569b240efe4),older(10)))";
+    /// assert!(Context::try_from_with_options(input, &ParseOptions::new().max_depth(3)).is_ok());
+    /// assert!(Context::try_from_with_options(input, &ParseOptions::new().max_depth(1)).is_err());
+    /// ```
+    pub fn try_from_with_options<'a>(
+        value: &'a str,
+        options: &parser::ParseOptions,
+    ) -> Result<Context, ContextError<'a>> {
+        let ctx = parser::parse_with_options(value, options).map_err(ContextError::ParserError)?;
+        Context::check(ctx)
+    }
+
+    /// Parse and validate a miniscript expression that is not wrapped in a descriptor,
+    /// e.g. `and_v(v:pk(A),older(10))`, with the same checks as inside the descriptor of
+    /// `context`: `sh()`, `wsh()` or `tr()`.
+    ///
+    /// The expression must be of type B. [`Context::descriptor`] returns the descriptor
+    /// of `context` and [`Context::top_level_descriptor`] returns [`Descriptor::Bare`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use tinyminiscript::{Context, descriptor::ScriptContext};
+    ///
+    /// let input = "and_v(v:pk(022f8bde4d1a07209355b4a7250a5c5128e88b84bddc619ab7cba8d569b240efe4),older(10))";
+    /// let ctx = Context::try_from_miniscript(input, ScriptContext::Segwitv0).unwrap();
+    /// assert_eq!(ctx.root_type().unwrap().to_string(), "Bonsfm");
+    ///
+    /// // multi() is not valid in tapscript
+    /// let input = "multi(1,022f8bde4d1a07209355b4a7250a5c5128e88b84bddc619ab7cba8d569b240efe4)";
+    /// assert!(Context::try_from_miniscript(input, ScriptContext::Tapscript).is_err());
+    /// ```
+    pub fn try_from_miniscript(
+        value: &str,
+        context: descriptor::ScriptContext,
+    ) -> Result<Context, ContextError<'_>> {
+        let ctx = parser::parse_miniscript(value, context).map_err(ContextError::ParserError)?;
+        Context::check(ctx)
+    }
+
+    /// Type check and validate a parsed context.
+    fn check<'a>(mut ctx: Context) -> Result<Context, ContextError<'a>> {
+        let mut visitor = CorrectnessPropertiesVisitor::new();
+        let type_info = check_tree(&ctx, &mut visitor)?;
+        ctx.types = visitor.into_types();
+        ctx.root_type = Some(type_info);
+        Ok(ctx)
+    }
+}
+
+/// Type check and validate a parsed tree, recording the types of its nodes in the
+/// visitor. Returns the type of the root.
+pub(crate) fn check_tree<'a, C: Tree, T: TypeTable>(
+    ctx: &C,
+    visitor: &mut CorrectnessPropertiesVisitor<T>,
+) -> Result<TypeInfo, ContextError<'a>> {
+    // Type check the AST for correctness properties
+    let type_info = visitor.visit(ctx).map_err(ContextError::TypeCheckerError)?;
+
+    // The descriptors check their inner expression, a bare expression is checked here
+    if type_info.base_type() != type_checker::MINISCRIPT_TYPE_B {
+        return Err(ContextError::TypeCheckerError(
+            type_checker::CorrectnessPropertiesVisitorError::NonTopLevel {
+                span: ctx.get_root().span(),
+            },
+        ));
+    }
+
+    // Validate the descriptor structure
+    let _: () = DescriptorValidator::new()
+        .validate_tree(ctx)
+        .map_err(ContextError::DescriptorVisitorError)?;
+
+    // Check the recursion depth
+    let span = ctx.get_root().span();
+    limits::check_recursion_depth(type_info.tree_height, span)
+        .map_err(ContextError::LimitsError)?;
+
+    // Check the script size
+    limits::check_script_size(&ctx.descriptor(), type_info.pk_cost, span)
+        .map_err(ContextError::LimitsError)?;
 
-        // Type check the AST for correctness properties
-        let type_info = CorrectnessPropertiesVisitor::new()
-            .visit(&ctx)
-            .map_err(ContextError::TypeCheckerError)?;
+    Ok(type_info)
+}
+
+impl Context {
+    /// Parse and validate a miniscript string like [`Context::try_from`], but keep going
+    /// after errors and return every diagnostic found in one pass.
+    ///
+    /// Sub-expressions that fail to parse are skipped up to the next `,` or `)` and the
+    /// remaining subtrees are still type checked. An error is reported once, at the
+    /// innermost failing fragment, without cascading to the fragments containing it.
+    /// Errors are sorted by their position in the input.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use tinyminiscript::Context;
+    ///
+    /// let errors = Context::try_from_recovering("wsh(or_b(pk(02),and_v(1,1)))").err().unwrap();
+    /// assert_eq!(errors.len(), 2);
+    /// ```
+    pub fn try_from_recovering(value: &str) -> Result<Context, Vec<ContextError<'_>>> {
+        let parsed = parser::parse_recovering(value);
+        let mut errors: Vec<ContextError> = parsed
+            .errors
+            .into_iter()
+            .map(ContextError::ParserError)
+            .collect();
+        let Some(mut ctx) = parsed.ctx else {
+            return Err(errors);
+        };
+
+        // Type check every subtree that parsed
+        let mut visitor = CorrectnessPropertiesVisitor::new();
+        let (type_info, type_errors) = visitor.check_recovering(&ctx, &parsed.placeholders);
+        errors.extend(type_errors.into_iter().map(ContextError::TypeCheckerError));
 
         // Validate the descriptor structure
-        let _: () = DescriptorValidator::new()
-            .validate(&ctx)
-            .map_err(ContextError::DescriptorVisitorError)?;
+        errors.extend(
+            DescriptorValidator::new()
+                .validate_all(&ctx)
+                .into_iter()
+                .map(ContextError::DescriptorVisitorError),
+        );
 
-        // Check the recursion depth
-        limits::check_recursion_depth(type_info.tree_height).map_err(ContextError::LimitsError)?;
+        // The limits are only known if the whole tree type checked
+        if let Some(type_info) = type_info {
+            let span = ctx.get_root().span();
+            if let Err(e) = limits::check_recursion_depth(type_info.tree_height, span) {
+                errors.push(ContextError::LimitsError(e));
+            }
+            if let Err(e) = limits::check_script_size(&ctx.descriptor(), type_info.pk_cost, span) {
+                errors.push(ContextError::LimitsError(e));
+            }
+        }
 
-        // Check the script size
-        limits::check_script_size(&ctx.descriptor(), type_info.pk_cost)
-            .map_err(ContextError::LimitsError)?;
+        if errors.is_empty() {
+            ctx.types = visitor.into_types();
+            ctx.root_type = type_info;
+            Ok(ctx)
+        } else {
+            errors.sort_by_key(|e| e.span().map(|span| span.start));
+            Err(errors)
+        }
+    }
+}
 
-        Ok(ctx)
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::fixtures::{A, B, C, XPUB};
+
+    #[test]
+    fn test_derive_taproot_internal_key() {
+        let descriptor = alloc::format!("tr({XPUB}/0/*,pk({XPUB}/1/*))");
+        let ctx = Context::try_from(descriptor.as_str()).unwrap();
+
+        let derived = ctx.derive(7).unwrap();
+        derived.iterate_keys(|key| assert!(key.as_definite_key().is_some()));
+        assert!(derived.build_script().is_ok());
+
+        // the original context is left untouched
+        ctx.iterate_keys(|key| assert!(key.as_definite_key().is_none()));
+    }
+
+    #[test]
+    fn test_derive_range() {
+        let descriptor = alloc::format!("wsh(or_d(pk({XPUB}/0/*),pk({XPUB}/1/*)))");
+        let ctx = Context::try_from(descriptor.as_str()).unwrap();
+
+        let secp = bitcoin::secp256k1::Secp256k1::verification_only();
+        let derived = ctx.derive_range(&secp, 10..15).unwrap();
+        assert_eq!(derived.len(), 5);
+        for (index, derived) in (10..).zip(&derived) {
+            assert_eq!(
+                derived.build_script().unwrap(),
+                ctx.derive(index).unwrap().build_script().unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_key_table() {
+        let descriptor =
+            alloc::format!("wsh(or_d(multi(1,{A},{B}),and_v(vc:pk_k({A}),older(10))))");
+        let ctx = Context::try_from(descriptor.as_str()).unwrap();
+
+        assert_eq!(ctx.get_keys().len(), 2);
+        assert!(ctx.has_repeated_keys());
+        let pk = ctx
+            .get_nodes()
+            .iter()
+            .find_map(|node| match &node.fragment {
+                Fragment::PkK { key } => Some(*key),
+                _ => None,
+            });
+        assert_eq!(ctx.get_key(pk.unwrap()).identifier(), A);
+        assert_eq!(ctx.serialize(), descriptor);
+
+        let ctx = Context::try_from(alloc::format!("wsh(multi(1,{A},{B}))").as_str()).unwrap();
+        assert!(!ctx.has_repeated_keys());
+    }
+
+    #[test]
+    fn test_node_types() {
+        let descriptor = alloc::format!("wsh(or_d(pk({A}),and_v(v:pk({B}),older(10))))");
+
+        for ctx in [
+            Context::try_from(descriptor.as_str()).unwrap(),
+            Context::try_from_recovering(descriptor.as_str()).unwrap(),
+        ] {
+            let type_of = |matches: fn(&Fragment) -> bool| {
+                let index = ctx
+                    .get_nodes()
+                    .iter()
+                    .position(|node| matches(&node.fragment));
+                ctx.node_type(index.unwrap() as NodeIndex)
+                    .unwrap()
+                    .to_string()
+            };
+            assert_eq!(type_of(|f| matches!(f, Fragment::PkK { .. })), "Konduesm");
+            assert_eq!(type_of(|f| matches!(f, Fragment::Older { .. })), "Bzfm");
+            assert_eq!(type_of(|f| matches!(f, Fragment::AndV { .. })), "Bonsfm");
+            assert_eq!(type_of(|f| matches!(f, Fragment::OrD { .. })), "Bsfm");
+            assert!(ctx.node_type(ctx.get_nodes().len() as NodeIndex).is_none());
+        }
+    }
+
+    #[test]
+    fn test_key_origins() {
+        let descriptor = alloc::format!(
+            "wsh(or_d(pk([aabbccdd/48'/1'/0'/2']{XPUB}/0/*),pk([aabbccdd/48'/1'/1'/2']{XPUB}/0/*)))"
+        );
+        let ctx = Context::try_from(descriptor.as_str()).unwrap();
+        assert!(ctx.is_ranged());
+
+        let fingerprint = "aabbccdd".parse::<Fingerprint>().unwrap();
+        assert_eq!(ctx.fingerprints(), alloc::vec![fingerprint]);
+
+        let paths = ctx.derivation_paths(5).unwrap();
+        assert_eq!(paths.len(), 2);
+        assert!(paths.iter().all(|(f, _)| *f == fingerprint));
+        assert_eq!(paths[0].1.to_string(), "48'/1'/0'/2'/0/5");
+        assert_eq!(paths[1].1.to_string(), "48'/1'/1'/2'/0/5");
+    }
+
+    #[test]
+    fn test_addresses_match_derivation() {
+        for descriptor in [
+            alloc::format!("wpkh([aabbccdd/84'/1'/0']{XPUB}/0/*)"),
+            alloc::format!("tr({XPUB}/0/*,pk({XPUB}/1/*))"),
+        ] {
+            let ctx = Context::try_from(descriptor.as_str()).unwrap();
+            let addresses = ctx.addresses(Network::Testnet, 0..5).unwrap();
+
+            for item in addresses {
+                let (index, address, script_pubkey) = item.unwrap();
+                let derived = ctx.derive(index).unwrap();
+                assert_eq!(address, derived.build_address(Network::Testnet).unwrap());
+                assert_eq!(script_pubkey, address.script_pubkey());
+            }
+
+            let target = ctx.derive(3).unwrap().build_script_pubkey().unwrap();
+            assert_eq!(ctx.find_script_pubkey(&target, 0..20).unwrap(), Some(3));
+            assert_eq!(ctx.find_script_pubkey(&target, 4..20).unwrap(), None);
+        }
+
+        // p2pk outputs have no address, but can still be found
+        let pk = Context::try_from(alloc::format!("pk({XPUB}/0/*)").as_str()).unwrap();
+        assert!(matches!(
+            pk.addresses(Network::Bitcoin, 0..10),
+            Err(AddressBuilderError::NoAddressForm { .. })
+        ));
+        let derived = pk.derive(4).unwrap();
+        assert_eq!(
+            derived
+                .build_address(Network::Bitcoin)
+                .err()
+                .unwrap()
+                .code(),
+            "A001"
+        );
+        let target = derived.build_script_pubkey().unwrap();
+        assert_eq!(pk.find_script_pubkey(&target, 0..10).unwrap(), Some(4));
+
+        let non_ranged = Context::try_from(
+            "wpkh(022f8bde4d1a07209355b4a7250a5c5128e88b84bddc619ab7cba8d569b240efe4)",
+        )
+        .unwrap();
+        assert!(matches!(
+            non_ranged.addresses(Network::Bitcoin, 0..10),
+            Err(AddressBuilderError::NotRanged { .. })
+        ));
+    }
+
+    #[test]
+    fn test_error_spans() {
+        // a type error points at the sub-expression of the wrong type
+        let input = "wsh(and_v(older(1),1))";
+        let err = Context::try_from(input).err().unwrap();
+        assert_eq!(err.code(), "T001");
+        let span = err.span().unwrap();
+        assert_eq!(&input[span.start..span.end], "older(1)");
+
+        let input = "wsh(j:older(1))";
+        let err = Context::try_from(input).err().unwrap();
+        assert_eq!(err.code(), "T004");
+        let span = err.span().unwrap();
+        assert_eq!(&input[span.start..span.end], "older(1)");
+
+        let input = "wsh(or_d(pk(02),1))";
+        let err = Context::try_from(input).err().unwrap();
+        assert_eq!(err.code(), "P003");
+        let span = err.span().unwrap();
+        assert_eq!(&input[span.start..span.end], "02");
+
+        // the message names the fragment, not the parser internals
+        let err = Context::try_from("wsh(multi(2,").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "unexpected end of input while parsing multi()"
+        );
+        let err = Context::try_from("wsh(").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "unexpected end of input while parsing the descriptor"
+        );
+
+        // derivation errors point to the key
+        let input = alloc::format!("wsh(and_v(v:pk({XPUB}/0/*),older(1)))");
+        let ctx = Context::try_from(input.as_str()).unwrap();
+        let err = ctx.derive(1 << 31).err().unwrap();
+        assert_eq!(err.code(), "K001");
+        let span = err.span().unwrap();
+        assert_eq!(input[span.start..span.end], alloc::format!("{XPUB}/0/*"));
+    }
+
+    #[test]
+    fn test_recovering_collects_all_errors() {
+        let input = "wsh(andor(pk(02),and_v(older(1),1),older(0)))";
+        let errors = Context::try_from_recovering(input).err().unwrap();
+        let codes: Vec<_> = errors.iter().map(|e| e.code()).collect();
+        assert_eq!(codes, ["P003", "T001", "P002"]);
+
+        let span = errors[1].span().unwrap();
+        assert_eq!(&input[span.start..span.end], "older(1)");
+
+        // a subtree containing a parse error is not reported again
+        let input = "wsh(and_v(v:pk(02),pk(03)))";
+        let errors = Context::try_from_recovering(input).err().unwrap();
+        let codes: Vec<_> = errors.iter().map(|e| e.code()).collect();
+        assert_eq!(codes, ["P003", "P003"]);
+
+        // placeholders are the nodes inserted for the failed sub-expressions, and only those
+        let input = alloc::format!("wsh(and_v(v:pk(02),or_b(pk({A}),s:pk(03))))");
+        let parsed = parser::parse_recovering(&input);
+        let ctx = parsed.ctx.unwrap();
+        let spans: Vec<_> = parsed
+            .placeholders
+            .iter()
+            .map(|&index| ctx.get_node(index).span())
+            .map(|span| &input[span.start..span.end])
+            .collect();
+        assert_eq!(spans, ["v:pk(02)", "s:pk(03)"]);
+        let nodes = ctx.get_nodes().iter();
+        assert_eq!(
+            nodes
+                .filter(|node| matches!(node.fragment, Fragment::False))
+                .count(),
+            2
+        );
+
+        let input = "wsh(pk(022f8bde4d1a07209355b4a7250a5c5128e88b84bddc619ab7cba8d569b240efe4))";
+        assert!(Context::try_from_recovering(input).is_ok());
+    }
+
+    #[test]
+    fn test_nesting_depth() {
+        // as deep as consensus allows, without recursing once per level
+        let input = alloc::format!("wsh({}:0)", "u".repeat(400));
+        let ctx = Context::try_from(input.as_str()).unwrap();
+        assert!(ctx.build_script().is_ok());
+        assert!(!ctx.serialize().is_empty());
+        let assets = crate::plan::Assets::new();
+        assert!(
+            ctx.satisfy(&assets, &crate::satisfy::Strategy::DEFAULT)
+                .is_ok()
+        );
+        assert!(ctx.spending_paths(10).is_ok());
+
+        // the depth is bounded while parsing, before the tree is built
+        let input = alloc::format!("wsh({}1{})", "and_v(v:1,".repeat(5000), ")".repeat(5000));
+        let err = Context::try_from(input.as_str()).err().unwrap();
+        assert_eq!(err.code(), "P015");
+
+        let options = parser::ParseOptions::new().max_depth(3);
+        let input = alloc::format!("wsh(or_d(pk({A}),l:older(1)))");
+        assert!(Context::try_from_with_options(input.as_str(), &options).is_ok());
+        let input = alloc::format!("wsh(or_d(pk({A}),lv:older(1)))");
+        let err = Context::try_from_with_options(input.as_str(), &options)
+            .err()
+            .unwrap();
+        assert_eq!(err.code(), "P015");
+    }
+
+    #[test]
+    fn test_long_input() {
+        // columns and node indices past 65,535
+        let x = "2f8bde4d1a07209355b4a7250a5c5128e88b84bddc619ab7cba8d569b240efe4";
+        let keys = alloc::format!(",{x}").repeat(1100);
+        let input = alloc::format!("tr(02{x},multi_a(1{keys}))");
+        assert!(input.len() > u16::MAX as usize);
+        let ctx = Context::try_from(input.as_str()).unwrap();
+        assert_eq!(ctx.get_root().span().end, input.len());
+
+        let input = alloc::format!("tr(02{x},multi_a(1{keys},zz))");
+        let err = Context::try_from(input.as_str()).err().unwrap();
+        assert!(err.span().unwrap().start > u16::MAX as usize);
+
+        let args = ",a:0".repeat(40000);
+        let input = alloc::format!("tr(02{x},thresh(1,pk(02{x}){args}))");
+        let ctx = Context::try_from(input.as_str()).unwrap();
+        assert_eq!(ctx.get_root().span().end, input.len());
+        assert!(ctx.serialize().ends_with(&alloc::format!("{args}))")));
+    }
+
+    #[test]
+    fn test_miniscript_context() {
+        use crate::descriptor::ScriptContext;
+
+        let input = alloc::format!("and_v(v:pk({A}),older(10))");
+        let ctx = Context::try_from_miniscript(&input, ScriptContext::Segwitv0).unwrap();
+        let wsh = Context::try_from(alloc::format!("wsh({input})").as_str()).unwrap();
+        assert_eq!(ctx.build_script().unwrap(), wsh.build_script().unwrap());
+        assert_eq!(ctx.root_type(), wsh.root_type());
+        assert_eq!(ctx.top_level_descriptor(), Descriptor::Bare);
+        assert_eq!(ctx.descriptor(), Descriptor::Wsh);
+        assert_eq!(wsh.serialize(), alloc::format!("wsh({})", ctx.serialize()));
+
+        // the checks of the descriptor of the context apply
+        let multi = alloc::format!("multi(1,{A})");
+        assert!(Context::try_from_miniscript(&multi, ScriptContext::Segwitv0).is_ok());
+        let err = Context::try_from_miniscript(&multi, ScriptContext::Legacy)
+            .err()
+            .unwrap();
+        assert_eq!(err.code(), "D001");
+        let x = &A[2..];
+        let input = alloc::format!("multi_a(1,{x})");
+        assert!(Context::try_from_miniscript(&input, ScriptContext::Tapscript).is_ok());
+
+        // the expression must be of type B, and is not a descriptor
+        let input = alloc::format!("v:pk({A})");
+        let err = Context::try_from_miniscript(&input, ScriptContext::Legacy)
+            .err()
+            .unwrap();
+        assert_eq!(err.code(), "T006");
+        let input = alloc::format!("wsh(pk({A}))");
+        assert!(Context::try_from_miniscript(&input, ScriptContext::Segwitv0).is_err());
+        let input = alloc::format!("pk({A})#8dk2cml2");
+        let err = Context::try_from_miniscript(&input, ScriptContext::Legacy)
+            .err()
+            .unwrap();
+        assert_eq!(err.code(), "P006");
+    }
+
+    #[test]
+    fn test_addr_and_raw() {
+        let input = "addr(bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4)#uyjndxcw";
+        let ctx = Context::try_from(input).unwrap();
+        assert_eq!(ctx.descriptor(), Descriptor::Addr);
+        assert_eq!(ctx.serialize(), input[..input.len() - 9]);
+        let address = ctx.build_address(Network::Bitcoin).unwrap();
+        assert_eq!(
+            address.to_string(),
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"
+        );
+        let script_pubkey = ctx.build_script_pubkey().unwrap();
+        assert_eq!(script_pubkey, address.script_pubkey());
+        let err = ctx.build_address(Network::Testnet).err().unwrap();
+        assert_eq!(err.code(), "A004");
+
+        let input = "raw(0014751e76e8199196d454941c45d1b3a323f1433bd6)#t687veyk";
+        let raw = Context::try_from(input).unwrap();
+        assert_eq!(raw.descriptor(), Descriptor::Raw);
+        assert_eq!(raw.serialize(), input[..input.len() - 9]);
+        assert_eq!(raw.build_script_pubkey().unwrap(), script_pubkey);
+        assert_eq!(raw.build_address(Network::Bitcoin).unwrap(), address);
+
+        // neither can be spent without knowing the script behind them
+        let assets = crate::plan::Assets::new();
+        let err = raw
+            .satisfy(&assets, &crate::satisfy::Strategy::DEFAULT)
+            .err()
+            .unwrap();
+        assert_eq!(err.code(), "F011");
+        assert_eq!(ctx.plan(&assets).err().unwrap().code(), "F011");
+
+        let err = Context::try_from("addr(bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t5)");
+        assert_eq!(err.err().unwrap().code(), "P017");
+        assert_eq!(
+            Context::try_from("raw(00147)").err().unwrap().code(),
+            "P012"
+        );
+        let input = "raw(0014751e76e8199196d454941c45d1b3a323f1433bd6)#uyjndxcw";
+        assert_eq!(Context::try_from(input).err().unwrap().code(), "P009");
+        assert!(Context::try_from("sh(raw(51))").is_err());
+    }
+
+    #[test]
+    fn test_combo() {
+        let input = alloc::format!("combo({A})");
+        let ctx = Context::try_from(input.as_str()).unwrap();
+        assert_eq!(ctx.serialize(), input);
+        assert_eq!(
+            ctx.build_address(Network::Bitcoin).err().unwrap().code(),
+            "A005"
+        );
+        assert_eq!(ctx.build_script_pubkey().err().unwrap().code(), "A005");
+
+        let expected = ["pk({A})", "pkh({A})", "wpkh({A})", "sh(wpkh({A}))"].map(|d| {
+            let d = d.replace("{A}", A);
+            Context::try_from(d.as_str())
+                .unwrap()
+                .build_script_pubkey()
+                .unwrap()
+        });
+        assert_eq!(ctx.script_pubkeys().unwrap(), expected);
+        let addresses = ctx.build_addresses(Network::Bitcoin).unwrap();
+        assert_eq!(addresses.len(), 3);
+        assert!(
+            addresses
+                .iter()
+                .zip(&expected[1..])
+                .all(|(a, spk)| a.script_pubkey() == *spk)
+        );
+
+        // uncompressed keys have no segwit outputs
+        let u = "04a34b99f22c790c4e36b2b3c2c35a36db06226e41c692fc82b8b56ac1c540c5bd5b8dec5235a0fa8722476c7709c02559e3aa73aa03918ba2d492eea75abea235";
+        let ctx = Context::try_from(alloc::format!("combo({u})").as_str()).unwrap();
+        assert_eq!(ctx.script_pubkeys().unwrap().len(), 2);
+
+        let ctx = Context::try_from(alloc::format!("combo({XPUB}/0/*)").as_str()).unwrap();
+        let wpkh = Context::try_from(alloc::format!("sh(wpkh({XPUB}/0/*))").as_str()).unwrap();
+        let spk = wpkh.derive(7).unwrap().build_script_pubkey().unwrap();
+        assert_eq!(ctx.find_script_pubkey(&spk, 0..10).unwrap(), Some(7));
+
+        // every address of every index, for rescanning
+        let addresses = ctx.addresses(Network::Testnet, 0..4).unwrap();
+        assert_eq!(addresses.size_hint(), (4, Some(12)));
+        let addresses = addresses.collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(addresses.len(), 12);
+        for (index, address, script_pubkey) in addresses {
+            let derived = ctx.derive(index).unwrap();
+            assert_eq!(address.script_pubkey(), script_pubkey);
+            assert!(derived.script_pubkeys().unwrap().contains(&script_pubkey));
+        }
+    }
+
+    #[test]
+    fn test_rawtr() {
+        let x = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+        let input = alloc::format!("rawtr({x})");
+        let ctx = Context::try_from(input.as_str()).unwrap();
+        assert_eq!(ctx.descriptor(), Descriptor::RawTr);
+        assert_eq!(ctx.serialize(), input);
+
+        // the key is the output key, it is not tweaked
+        let script_pubkey = ctx.build_script_pubkey().unwrap();
+        assert_eq!(script_pubkey.to_hex_string(), alloc::format!("5120{x}"));
+        let address = ctx.build_address(Network::Bitcoin).unwrap();
+        assert_eq!(address.script_pubkey(), script_pubkey);
+        let tr = Context::try_from(alloc::format!("tr(02{x})").as_str()).unwrap();
+        assert_ne!(tr.build_script_pubkey().unwrap(), script_pubkey);
+        let compressed = Context::try_from(alloc::format!("rawtr(02{x})").as_str()).unwrap();
+        assert_eq!(compressed.build_script_pubkey().unwrap(), script_pubkey);
+
+        let assets = crate::plan::Assets::new().add_key(ctx.get_key(0).clone());
+        let plan = ctx.plan(&assets).unwrap();
+        assert_eq!(plan.keys.len(), 1);
+        assert_eq!(plan.witness_size, 1 + 1 + 65);
+        assert!(Context::try_from(alloc::format!("rawtr({x},pk({x}))").as_str()).is_err());
+
+        // the x-only form of an uncompressed key would be a different descriptor
+        let uncompressed = "04a34b99f22c790c4e36b2b3c2c35a36db06226e41c692fc82b8b56ac1c540c5bd5b8dec5235a0fa8722476c7709c02559e3aa73aa03918ba2d492eea75abea235";
+        let input = alloc::format!("rawtr({uncompressed})");
+        assert!(matches!(
+            Context::try_from(input.as_str()).err().unwrap(),
+            ContextError::ParserError(parser::ParseError::InvalidKey {
+                inner: crate::parser::keys::InvalidKeyReason::UncompressedOutputKey,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_musig() {
+        let aggregate = "6d9e5c2127d3ffd073ac66e4e203f108af657cc5da2ea27603e7767317f7f216";
+
+        let input = alloc::format!("tr(musig({A},{B}))");
+        let ctx = Context::try_from(input.as_str()).unwrap();
+        assert_eq!(ctx.serialize(), input);
+        assert_eq!(
+            ctx.build_script_pubkey().unwrap().to_hex_string(),
+            "51203071ccd92ba0bc0686007a37089aad00004e637b9fab6e7b66d16a3b07d01649"
+        );
+        let swapped = Context::try_from(alloc::format!("tr(musig({B},{A}))").as_str()).unwrap();
+        assert_eq!(
+            swapped.build_address(Network::Bitcoin).unwrap(),
+            ctx.build_address(Network::Bitcoin).unwrap()
+        );
+
+        // BIP-328 derivation of the aggregate key
+        let input = alloc::format!("tr(musig({A},{B})/1/*)");
+        let ctx = Context::try_from(input.as_str()).unwrap();
+        assert_eq!(ctx.serialize(), input);
+        assert!(ctx.is_ranged());
+        assert_eq!(
+            ctx.derive(3)
+                .unwrap()
+                .build_script_pubkey()
+                .unwrap()
+                .to_hex_string(),
+            "51208f01e26ec94692d473646fa6735d328f1623f8425706d8f3286ec3a185b69239"
+        );
+
+        // the aggregate key in a tapscript leaf
+        let ctx = Context::try_from(alloc::format!("tr({C},pk(musig({A},{B})))").as_str()).unwrap();
+        assert_eq!(
+            ctx.build_script().unwrap().to_hex_string(),
+            alloc::format!("20{aggregate}ac")
+        );
+
+        // signing devices find their keys among the participants
+        let input = alloc::format!(
+            "tr(musig([aabbccdd/86'/1'/0']{XPUB}/0/*,[11223344/86'/1'/1']{XPUB}/1/*))"
+        );
+        let ctx = Context::try_from(input.as_str()).unwrap();
+        let fingerprints = ["aabbccdd", "11223344"].map(|f| f.parse::<Fingerprint>().unwrap());
+        assert_eq!(ctx.fingerprints(), fingerprints);
+        let paths = ctx.derivation_paths(7).unwrap();
+        assert_eq!(paths.len(), 2);
+        assert_eq!(
+            paths[0],
+            (fingerprints[0], "86'/1'/0'/0/7".parse().unwrap())
+        );
+        assert_eq!(
+            paths[1],
+            (fingerprints[1], "86'/1'/1'/1/7".parse().unwrap())
+        );
+
+        for input in [
+            alloc::format!("wsh(pk(musig({A},{B})))"),
+            alloc::format!("tr(musig({A},{B})/0h/*)"),
+            alloc::format!("tr(musig({A},musig({A},{B})))"),
+            alloc::format!("tr(musig({A} {B}))"),
+        ] {
+            assert!(Context::try_from(input.as_str()).is_err(), "{input}");
+        }
     }
 }
@@ -1,5 +1,7 @@
-use crate::context::Context;
-use crate::parser::{Fragment, Position};
+use crate::Vec;
+use crate::context::{Context, Tree};
+use crate::error::{Diagnostic, Span};
+use crate::parser::{AST, Fragment};
 
 /// Script descriptor
 #[derive(Clone, PartialEq)]
@@ -20,9 +22,20 @@ pub enum Descriptor {
 
     /// Pay-to-Taproot
     Tr,
+    /// Pay-to-Taproot with the output key itself, the tweak is already applied
+    RawTr,
 
     /// Raw public key
     Pk,
+
+    /// Every standard output of a single key: P2PK, P2PKH and, if the key is
+    /// compressed, P2WPKH and P2SH-P2WPKH
+    Combo,
+
+    /// Address, for watch-only wallets
+    Addr,
+    /// Raw script pubkey, for watch-only wallets
+    Raw,
 }
 
 impl Default for Descriptor {
@@ -36,6 +49,38 @@ impl Descriptor {
     pub fn is_witness(&self) -> bool {
         matches!(self, Descriptor::Wsh | Descriptor::Wpkh)
     }
+
+    /// Whether a spend provides its stack in the witness rather than the script sig, this
+    /// holds for the inner descriptor of `sh(wpkh())` and `sh(wsh())` as well.
+    pub fn spends_from_witness(&self) -> bool {
+        matches!(
+            self,
+            Descriptor::Wsh | Descriptor::Wpkh | Descriptor::Tr | Descriptor::RawTr
+        )
+    }
+}
+
+/// Script context a miniscript expression is checked against when it is parsed on its
+/// own, see [`Context::try_from_miniscript`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScriptContext {
+    /// Legacy script, checked like inside `sh()`
+    Legacy,
+    /// Segwit v0 witness script, checked like inside `wsh()`
+    Segwitv0,
+    /// Tapscript leaf, checked like inside `tr()`
+    Tapscript,
+}
+
+impl ScriptContext {
+    /// The descriptor whose rules apply to expressions in this context.
+    pub const fn descriptor(&self) -> Descriptor {
+        match self {
+            ScriptContext::Legacy => Descriptor::Sh,
+            ScriptContext::Segwitv0 => Descriptor::Wsh,
+            ScriptContext::Tapscript => Descriptor::Tr,
+        }
+    }
 }
 
 pub struct InvalidDescriptor;
@@ -49,14 +94,23 @@ impl<'a> TryFrom<&'a str> for Descriptor {
             "wpkh" => Ok(Descriptor::Wpkh),
             "wsh" => Ok(Descriptor::Wsh),
             "tr" => Ok(Descriptor::Tr),
+            "rawtr" => Ok(Descriptor::RawTr),
             "pk" => Ok(Descriptor::Pk),
+            "combo" => Ok(Descriptor::Combo),
+            "addr" => Ok(Descriptor::Addr),
+            "raw" => Ok(Descriptor::Raw),
             _ => Err(InvalidDescriptor),
         }
     }
 }
 
-#[cfg(feature = "debug")]
 impl core::fmt::Debug for Descriptor {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Display::fmt(self, f)
+    }
+}
+
+impl core::fmt::Display for Descriptor {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             Descriptor::Bare => write!(f, "bare"),
@@ -65,7 +119,11 @@ impl core::fmt::Debug for Descriptor {
             Descriptor::Wpkh => write!(f, "wpkh"),
             Descriptor::Wsh => write!(f, "wsh"),
             Descriptor::Tr => write!(f, "tr"),
+            Descriptor::RawTr => write!(f, "rawtr"),
             Descriptor::Pk => write!(f, "pk"),
+            Descriptor::Combo => write!(f, "combo"),
+            Descriptor::Addr => write!(f, "addr"),
+            Descriptor::Raw => write!(f, "raw"),
         }
     }
 }
@@ -81,60 +139,109 @@ impl DescriptorValidator {
     /// Validate the descriptor structure
     /// Not using a Visitor pattern because it's not needed for the current use case.
     pub fn validate(&self, ctx: &Context) -> Result<(), DescriptorVisitorError> {
+        self.validate_tree(ctx)
+    }
+
+    pub(crate) fn validate_tree<C: Tree>(&self, ctx: &C) -> Result<(), DescriptorVisitorError> {
         let descriptor = ctx.descriptor();
         for ele in ctx.get_nodes() {
-            match &ele.fragment {
-                Fragment::PkK { key } | Fragment::PkH { key } => {
-                    if descriptor.is_witness() && !key.is_compressed() {
-                        return Err(DescriptorVisitorError::PublicKeyNotCompressed {
-                            position: ele.position,
-                        });
-                    }
-                }
-                Fragment::Multi { keys, .. } => {
-                    // (P2WSH only)
-                    if descriptor != Descriptor::Wsh {
-                        return Err(DescriptorVisitorError::InvalidFragmentForDescriptor {
-                            position: ele.position,
-                            expected: Descriptor::Wsh,
-                            found: descriptor,
-                        });
-                    }
-                }
-                Fragment::MultiA { keys, .. } => {
-                    // Tapscript only
-                    if descriptor != Descriptor::Tr {
-                        return Err(DescriptorVisitorError::InvalidFragmentForDescriptor {
-                            position: ele.position,
-                            expected: Descriptor::Tr,
-                            found: descriptor,
-                        });
-                    }
-                }
-                Fragment::RawPkH { key } => {
-                    if descriptor == Descriptor::Wpkh && !key.is_compressed() {
-                        return Err(DescriptorVisitorError::PublicKeyNotCompressed {
-                            position: ele.position,
-                        });
-                    }
-                }
-                _ => {
-                    continue;
-                }
-            }
+            Self::validate_node(ctx, &descriptor, ele)?;
         }
         Ok(())
     }
+
+    /// Validate the descriptor structure, collecting all the errors instead of
+    /// stopping at the first one.
+    pub fn validate_all(&self, ctx: &Context) -> Vec<DescriptorVisitorError> {
+        let descriptor = ctx.descriptor();
+        ctx.get_nodes()
+            .iter()
+            .filter_map(|ele| Self::validate_node(ctx, &descriptor, ele).err())
+            .collect()
+    }
+
+    fn validate_node<C: Tree>(
+        ctx: &C,
+        descriptor: &Descriptor,
+        ele: &AST,
+    ) -> Result<(), DescriptorVisitorError> {
+        match &ele.fragment {
+            Fragment::PkK { key } | Fragment::PkH { key }
+                if descriptor.is_witness() && !ctx.get_key(*key).is_compressed() =>
+            {
+                Err(DescriptorVisitorError::PublicKeyNotCompressed { span: ele.span() })
+            }
+            // (P2WSH only)
+            Fragment::Multi { .. } if *descriptor != Descriptor::Wsh => {
+                Err(DescriptorVisitorError::InvalidFragmentForDescriptor {
+                    span: ele.span(),
+                    expected: Descriptor::Wsh,
+                    found: descriptor.clone(),
+                })
+            }
+            // Tapscript only
+            Fragment::MultiA { .. } if *descriptor != Descriptor::Tr => {
+                Err(DescriptorVisitorError::InvalidFragmentForDescriptor {
+                    span: ele.span(),
+                    expected: Descriptor::Tr,
+                    found: descriptor.clone(),
+                })
+            }
+            Fragment::RawPkH { key }
+                if *descriptor == Descriptor::Wpkh && !ctx.get_key(*key).is_compressed() =>
+            {
+                Err(DescriptorVisitorError::PublicKeyNotCompressed { span: ele.span() })
+            }
+            _ => Ok(()),
+        }
+    }
 }
 
-#[cfg_attr(feature = "debug", derive(Debug))]
+#[derive(Debug)]
 pub enum DescriptorVisitorError {
     InvalidFragmentForDescriptor {
-        position: Position,
+        span: Span,
         expected: Descriptor,
         found: Descriptor,
     },
     PublicKeyNotCompressed {
-        position: Position,
+        span: Span,
     },
 }
+
+impl core::fmt::Display for DescriptorVisitorError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            DescriptorVisitorError::InvalidFragmentForDescriptor {
+                expected, found, ..
+            } => write!(
+                f,
+                "fragment is only valid inside {expected}(), found {found}()"
+            ),
+            DescriptorVisitorError::PublicKeyNotCompressed { .. } => {
+                write!(
+                    f,
+                    "uncompressed public keys are not allowed in segwit descriptors"
+                )
+            }
+        }
+    }
+}
+
+impl core::error::Error for DescriptorVisitorError {}
+
+impl Diagnostic for DescriptorVisitorError {
+    fn code(&self) -> &'static str {
+        match self {
+            DescriptorVisitorError::InvalidFragmentForDescriptor { .. } => "D001",
+            DescriptorVisitorError::PublicKeyNotCompressed { .. } => "D002",
+        }
+    }
+
+    fn span(&self) -> Option<Span> {
+        match self {
+            DescriptorVisitorError::InvalidFragmentForDescriptor { span, .. }
+            | DescriptorVisitorError::PublicKeyNotCompressed { span } => Some(*span),
+        }
+    }
+}
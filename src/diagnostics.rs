@@ -0,0 +1,119 @@
+use core::fmt;
+
+use crate::error::Diagnostic;
+
+/// Number of characters of context shown around the failing fragment of long descriptors.
+const CONTEXT_WIDTH: usize = 40;
+
+/// Human readable rendering of a [`Diagnostic`] against the input it was produced from.
+///
+/// The descriptor is printed with the failing fragment underlined, followed by an
+/// explanation of what the fragment expected when one is available:
+///
+/// ```text
+/// error[T001]: and_v(X,Y): X must be type V (Verify) (found B)
+///   |
+///   | wsh(and_v(1,1))
+///   |           ^
+///   = help: expected type V, found type B
+/// ```
+///
+/// # Examples
+///
+/// ```rust
+/// use tinyminiscript::{Context, diagnostics::Report};
+///
+/// let input = "wsh(and_v(1,1))";
+/// if let Err(e) = Context::try_from(input) {
+///     println!("{}", Report::new(input, &e));
+/// }
+/// ```
+pub struct Report<'a> {
+    input: &'a str,
+    error: &'a dyn Diagnostic,
+}
+
+impl<'a> Report<'a> {
+    #[inline]
+    pub fn new(input: &'a str, error: &'a dyn Diagnostic) -> Self {
+        Self { input, error }
+    }
+}
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "error[{}]: {}", self.error.code(), self.error)?;
+
+        if let Some(span) = self.error.span() {
+            // Clamp the span to the input, an end of input error points right after it.
+            let start = span.start.min(self.input.len());
+            let end = span.end.clamp(start + 1, self.input.len() + 1);
+
+            // Window long descriptors around the start of the failing fragment.
+            let window_start = floor_char_boundary(self.input, start.saturating_sub(CONTEXT_WIDTH));
+            let window_end = floor_char_boundary(
+                self.input,
+                end.max(start + CONTEXT_WIDTH).min(self.input.len()),
+            );
+            let prefix = if window_start > 0 { "..." } else { "" };
+            let suffix = if window_end < self.input.len() {
+                "..."
+            } else {
+                ""
+            };
+
+            writeln!(f, "  |")?;
+            writeln!(
+                f,
+                "  | {prefix}{}{suffix}",
+                &self.input[window_start..window_end]
+            )?;
+
+            let offset = prefix.len() + self.input[window_start..start].chars().count();
+            let width = (end.min(window_end.max(start + 1)) - start).max(1);
+            write!(f, "  | {:offset$}", "")?;
+            for _ in 0..width {
+                f.write_str("^")?;
+            }
+            writeln!(f)?;
+        }
+
+        if let Some(help) = self.error.help() {
+            writeln!(f, "  = help: {help}")?;
+        }
+        Ok(())
+    }
+}
+
+fn floor_char_boundary(s: &str, mut index: usize) -> usize {
+    while !s.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Context;
+    use alloc::string::ToString;
+
+    #[test]
+    fn test_render_type_error() {
+        let input = "wsh(and_v(1,1))";
+        let err = Context::try_from(input).err().unwrap();
+        let rendered = Report::new(input, &err).to_string();
+        assert_eq!(
+            rendered,
+            "error[T001]: and_v(X,Y): X must be type V (Verify) (found B)\n  |\n  | wsh(and_v(1,1))\n  |           ^\n  = help: expected type V, found type B\n"
+        );
+
+        let input = "wsh(s:older(1))";
+        let err = Context::try_from(input).err().unwrap();
+        let rendered = Report::new(input, &err).to_string();
+        assert_eq!(
+            rendered,
+            "error[T005]: s:X: X must have property o (one-arg)\n  |\n  | wsh(s:older(1))\n  |       ^^^^^^^^\n  = help: expected properties 'o', found 'z' (missing 'o')\n"
+        );
+    }
+}
@@ -0,0 +1,92 @@
+use crate::parser::Position;
+use crate::type_checker::{DisplayProperties, DisplayType};
+
+/// A range of byte offsets into the original input, `start` inclusive and `end` exclusive.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    #[inline]
+    pub const fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+
+    /// Build a span from the 1-based, end-exclusive columns used by the parser.
+    #[inline]
+    pub const fn from_columns(start: Position, end: Position) -> Self {
+        let start = (start as usize).saturating_sub(1);
+        let end = (end as usize).saturating_sub(1);
+        Self {
+            start,
+            end: if end > start { end } else { start + 1 },
+        }
+    }
+
+    /// Build the span of a token starting at the given column.
+    #[inline]
+    pub const fn from_token(token: &str, column: Position) -> Self {
+        let start = (column as usize).saturating_sub(1);
+        Self::new(
+            start,
+            start + if token.is_empty() { 1 } else { token.len() },
+        )
+    }
+
+    #[inline]
+    pub const fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    #[inline]
+    pub const fn is_empty(&self) -> bool {
+        self.end == self.start
+    }
+}
+
+/// Common interface of the crate's error types.
+///
+/// Codes are stable across releases and can be used as translation keys.
+pub trait Diagnostic: core::error::Error {
+    /// Stable identifier of the error.
+    fn code(&self) -> &'static str;
+
+    /// Byte range of the input the error refers to, if the error is tied to the input.
+    fn span(&self) -> Option<Span>;
+
+    /// Explanation of what the failing fragment expected, if there is one.
+    fn help(&self) -> Option<Help> {
+        None
+    }
+}
+
+/// Explanation of what the failing fragment expected.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Help {
+    /// The fragment should have had one of the `expected` base types.
+    Type { expected: u8, found: u8 },
+    /// The fragment should have had all the `expected` correctness properties.
+    Properties { expected: u8, found: u8 },
+}
+
+impl core::fmt::Display for Help {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match *self {
+            Help::Type { expected, found } => write!(
+                f,
+                "expected type {}, found type {}",
+                DisplayType(expected),
+                DisplayType(found)
+            ),
+            Help::Properties { expected, found } => write!(
+                f,
+                "expected properties '{}', found '{}' (missing '{}')",
+                DisplayProperties(expected),
+                DisplayProperties(found),
+                DisplayProperties(expected & !found)
+            ),
+        }
+    }
+}
@@ -0,0 +1,1161 @@
+use bitcoin::hashes::{Hash, hash160, ripemd160, sha256, sha256d};
+use bitcoin::key::TapTweak;
+use bitcoin::opcodes::all::*;
+use bitcoin::script::Instruction;
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::taproot::{ControlBlock, LeafVersion, TapNodeHash};
+use bitcoin::{Script, ScriptBuf, Witness, XOnlyPublicKey, opcodes::Opcode};
+
+use crate::{
+    Vec,
+    context::Context,
+    descriptor::Descriptor,
+    error::{Diagnostic, Span},
+    parser::{
+        Fragment, IdentityType, NodeIndex,
+        keys::{DefiniteKeyToken, KeyToken},
+    },
+    script::{FragmentMap, ScriptBuilderError},
+};
+
+/// Signature and locktime checks used by the interpreter.
+pub trait Verifier {
+    /// Check `sig` against the serialized public key, 33 or 65 bytes for ECDSA and
+    /// 32 bytes for Schnorr signatures in tapscript. The signature includes the sighash byte.
+    fn check_sig(&self, sig: &[u8], pubkey: &[u8]) -> bool;
+
+    /// Check if the OP_CHECKSEQUENCEVERIFY call is satisfied in the context of a transaction.
+    fn check_older(&self, locktime: u32) -> bool;
+
+    /// Check if the OP_CHECKLOCKTIMEVERIFY call is satisfied in the context of a transaction.
+    fn check_after(&self, locktime: u32) -> bool;
+}
+
+#[derive(Debug)]
+pub enum InterpreterError {
+    /// The script could not be built from the descriptor
+    ScriptBuilder(ScriptBuilderError<'static>),
+    /// The script contains a non-minimal push or is truncated
+    InvalidScript {
+        span: Span,
+    },
+    UnsupportedOpcode {
+        opcode: Opcode,
+        span: Span,
+    },
+    StackUnderflow {
+        span: Span,
+    },
+    /// The argument of `OP_IF`/`OP_NOTIF` is neither empty nor `1`
+    NonMinimalIf {
+        span: Span,
+    },
+    UnbalancedConditional {
+        span: Span,
+    },
+    InvalidNumber {
+        span: Span,
+    },
+    VerifyFailed {
+        span: Span,
+    },
+    /// A non-empty signature failed to verify
+    InvalidSignature {
+        span: Span,
+    },
+    /// The dummy element of `OP_CHECKMULTISIG` is not empty
+    NonEmptyDummy {
+        span: Span,
+    },
+    UnsatisfiedLockTime {
+        locktime: u32,
+        span: Span,
+    },
+    /// The script did not end with a single true element on the stack
+    FalseResult {
+        stack_size: usize,
+        span: Span,
+    },
+    /// A taproot script path spend does not reveal the leaf script of the descriptor
+    MissingLeafScript {
+        span: Span,
+    },
+    /// The control block is malformed or does not commit to the output key
+    InvalidControlBlock {
+        span: Span,
+    },
+}
+
+impl core::fmt::Display for InterpreterError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            InterpreterError::ScriptBuilder(e) => core::fmt::Display::fmt(e, f),
+            InterpreterError::InvalidScript { .. } => write!(f, "script is not minimally encoded"),
+            InterpreterError::UnsupportedOpcode { opcode, .. } => {
+                write!(f, "opcode {opcode} is not supported")
+            }
+            InterpreterError::StackUnderflow { .. } => {
+                write!(f, "not enough elements on the stack")
+            }
+            InterpreterError::NonMinimalIf { .. } => {
+                write!(f, "argument of OP_IF/OP_NOTIF must be empty or 1")
+            }
+            InterpreterError::UnbalancedConditional { .. } => {
+                write!(f, "unbalanced OP_IF/OP_ELSE/OP_ENDIF")
+            }
+            InterpreterError::InvalidNumber { .. } => write!(f, "invalid script number"),
+            InterpreterError::VerifyFailed { .. } => write!(f, "verification failed"),
+            InterpreterError::InvalidSignature { .. } => write!(f, "invalid signature"),
+            InterpreterError::NonEmptyDummy { .. } => {
+                write!(f, "OP_CHECKMULTISIG dummy element must be empty")
+            }
+            InterpreterError::UnsatisfiedLockTime { locktime, .. } => {
+                write!(f, "locktime {locktime} is not satisfied")
+            }
+            InterpreterError::FalseResult { stack_size, .. } => write!(
+                f,
+                "script must end with a single true element, found {stack_size} elements"
+            ),
+            InterpreterError::MissingLeafScript { .. } => {
+                write!(f, "witness does not reveal the leaf script")
+            }
+            InterpreterError::InvalidControlBlock { .. } => {
+                write!(f, "control block does not commit to the output key")
+            }
+        }
+    }
+}
+
+impl core::error::Error for InterpreterError {}
+
+impl Diagnostic for InterpreterError {
+    fn code(&self) -> &'static str {
+        match self {
+            InterpreterError::ScriptBuilder(e) => e.code(),
+            InterpreterError::InvalidScript { .. } => "I001",
+            InterpreterError::UnsupportedOpcode { .. } => "I002",
+            InterpreterError::StackUnderflow { .. } => "I003",
+            InterpreterError::NonMinimalIf { .. } => "I004",
+            InterpreterError::UnbalancedConditional { .. } => "I005",
+            InterpreterError::InvalidNumber { .. } => "I006",
+            InterpreterError::VerifyFailed { .. } => "I007",
+            InterpreterError::InvalidSignature { .. } => "I008",
+            InterpreterError::NonEmptyDummy { .. } => "I009",
+            InterpreterError::UnsatisfiedLockTime { .. } => "I010",
+            InterpreterError::FalseResult { .. } => "I011",
+            InterpreterError::MissingLeafScript { .. } => "I012",
+            InterpreterError::InvalidControlBlock { .. } => "I013",
+        }
+    }
+
+    fn span(&self) -> Option<Span> {
+        match self {
+            InterpreterError::ScriptBuilder(e) => e.span(),
+            InterpreterError::InvalidScript { span }
+            | InterpreterError::UnsupportedOpcode { span, .. }
+            | InterpreterError::StackUnderflow { span }
+            | InterpreterError::NonMinimalIf { span }
+            | InterpreterError::UnbalancedConditional { span }
+            | InterpreterError::InvalidNumber { span }
+            | InterpreterError::VerifyFailed { span }
+            | InterpreterError::InvalidSignature { span }
+            | InterpreterError::NonEmptyDummy { span }
+            | InterpreterError::UnsatisfiedLockTime { span, .. }
+            | InterpreterError::FalseResult { span, .. }
+            | InterpreterError::MissingLeafScript { span }
+            | InterpreterError::InvalidControlBlock { span } => Some(*span),
+        }
+    }
+}
+
+/// The sub-expression a disjunction was satisfied with.
+#[cfg_attr(feature = "debug", derive(Debug))]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Branch {
+    /// `X` of `or_b`, `or_c`, `or_d` and `or_i`, or `X` and `Y` of `andor(X,Y,Z)`
+    Left,
+    /// `Z` of `or_b`, `or_c`, `or_d`, `or_i` and `andor`
+    Right,
+}
+
+/// What a spend of the descriptor revealed, mapped back to the fragments of the [`Context`].
+#[cfg_attr(feature = "debug", derive(Debug))]
+#[derive(Clone)]
+pub struct Inspection {
+    /// The branch taken by every satisfied `or_*` and `andor` fragment
+    pub branches: Vec<(NodeIndex, Branch)>,
+    /// The keys that provided a valid signature
+    pub keys: Vec<KeyToken>,
+    /// The preimages revealed, with the hash fragment they satisfy
+    pub preimages: Vec<(NodeIndex, Vec<u8>)>,
+    /// The largest `older()` the spend relied on
+    pub relative_timelock: Option<u32>,
+    /// The largest `after()` the spend relied on
+    pub absolute_timelock: Option<u32>,
+    satisfied: Vec<bool>,
+}
+
+impl Inspection {
+    /// Whether the fragment at `index` was satisfied by the spend, e.g. to detect that
+    /// the recovery branch of a vault was used.
+    pub fn is_satisfied(&self, index: NodeIndex) -> bool {
+        self.satisfied.get(index as usize).copied().unwrap_or(false)
+    }
+
+    /// The branch taken by the `or_*` or `andor` fragment at `index`, if it was satisfied.
+    pub fn branch(&self, index: NodeIndex) -> Option<Branch> {
+        self.branches
+            .iter()
+            .find(|(i, _)| *i == index)
+            .map(|(_, branch)| *branch)
+    }
+}
+
+/// Execute the script of the descriptor with the witness as initial stack.
+///
+/// Only the opcodes emitted by miniscript are supported. Segwit policy rules relied on
+/// by miniscript (minimal pushes and `OP_IF` arguments, empty `OP_CHECKMULTISIG` dummy,
+/// failing signatures must be empty, clean stack) are enforced. Errors point to the
+/// innermost fragment that emitted the failing opcode.
+///
+/// The witness script is skipped when present so that witnesses can be taken from the
+/// chain as is. Taproot script path spends must end with the leaf script and a control
+/// block committing to the output key of the descriptor.
+pub(crate) fn inspect(
+    ctx: &Context,
+    mut stack: Vec<Vec<u8>>,
+    verifier: &dyn Verifier,
+) -> Result<Inspection, InterpreterError> {
+    let root = ctx.get_root().span();
+    let Fragment::Descriptor { inner, .. } = ctx.get_root().fragment else {
+        return Err(InterpreterError::InvalidScript { span: root });
+    };
+    // the script of addr() and raw() is not miniscript
+    if matches!(ctx.descriptor(), Descriptor::Addr | Descriptor::Raw) {
+        return Err(InterpreterError::InvalidScript { span: root });
+    }
+
+    let (script, fragments) = match ctx.descriptor() {
+        // the witness program of wpkh() is executed as a p2pkh script
+        Descriptor::Wpkh => {
+            let mut hash = None;
+            ctx.iterate_keys(|key| hash = key.as_definite_key().map(|k| k.pubkey_hash()));
+            let hash = hash.ok_or(InterpreterError::ScriptBuilder(
+                ScriptBuilderError::NonDefiniteKey {
+                    key: alloc::string::String::new(),
+                    span: root,
+                },
+            ))?;
+            let script = ScriptBuf::new_p2pkh(&hash);
+            let len = script.len();
+            (script, alloc::vec![(0..len, inner)])
+        }
+        Descriptor::Tr | Descriptor::RawTr => {
+            // the annex is not part of the script execution
+            if stack.len() >= 2 && stack.last().is_some_and(|e| e.first() == Some(&0x50)) {
+                stack.pop();
+            }
+            // a single element is a key path spend, which nobody can sign for a NUMS
+            // internal key: script path spends always end with the leaf and control block
+            if stack.len() == 1 {
+                if !ctx.has_key_path() {
+                    let span = ctx.get_node(inner).span();
+                    return Err(InterpreterError::MissingLeafScript { span });
+                }
+                return inspect_key_spend(ctx, inner, &stack[0], verifier);
+            }
+            crate::script::build_script_with_map(ctx).map_err(InterpreterError::ScriptBuilder)?
+        }
+        _ => crate::script::build_script_with_map(ctx).map_err(InterpreterError::ScriptBuilder)?,
+    };
+
+    if ctx.descriptor() == Descriptor::Tr {
+        check_tap_leaf(ctx, inner, &mut stack, &script)?;
+    } else if !matches!(ctx.descriptor(), Descriptor::Wpkh | Descriptor::Pkh)
+        && stack.last().is_some_and(|e| e == script.as_bytes())
+    {
+        stack.pop();
+    }
+
+    let mut machine = Machine {
+        ctx,
+        stack,
+        altstack: Vec::new(),
+        exec: Vec::new(),
+        verifier,
+        tapscript: ctx.descriptor() == Descriptor::Tr,
+        fragments: &fragments,
+        root,
+        trace: Trace::default(),
+    };
+    machine.execute(&script)?;
+    Ok(machine.trace.into_inspection(ctx))
+}
+
+/// The initial stack of a spent input, read from `script_sig` for legacy descriptors.
+pub(crate) fn spend_stack(
+    ctx: &Context,
+    witness: &Witness,
+    script_sig: &Script,
+) -> Result<Vec<Vec<u8>>, InterpreterError> {
+    if ctx.descriptor().spends_from_witness() {
+        return Ok(witness.iter().map(|e| e.to_vec()).collect());
+    }
+    script_sig
+        .instructions_minimal()
+        .map(|instruction| match instruction {
+            Ok(Instruction::PushBytes(bytes)) => Ok(bytes.as_bytes().to_vec()),
+            // minimal pushes of -1 and 1 to 16
+            Ok(Instruction::Op(op)) if op == OP_PUSHNUM_NEG1 => Ok(write_number(-1)),
+            Ok(Instruction::Op(op))
+                if (OP_PUSHNUM_1.to_u8()..=OP_PUSHNUM_16.to_u8()).contains(&op.to_u8()) =>
+            {
+                Ok(write_number((op.to_u8() - OP_PUSHNUM_1.to_u8() + 1) as i64))
+            }
+            _ => Err(InterpreterError::InvalidScript {
+                span: ctx.get_root().span(),
+            }),
+        })
+        .collect()
+}
+
+/// A taproot key path spend, the witness is a single signature for the output key. The
+/// output key of `tr()` is the internal key tweaked with the leaf, `rawtr()` takes it as is.
+fn inspect_key_spend(
+    ctx: &Context,
+    index: NodeIndex,
+    sig: &[u8],
+    verifier: &dyn Verifier,
+) -> Result<Inspection, InterpreterError> {
+    let node = ctx.get_node(index);
+    let (key, leaf) = match &node.fragment {
+        Fragment::RawTr { key, inner } => (ctx.get_key(*key), inner),
+        _ => return Err(InterpreterError::InvalidScript { span: node.span() }),
+    };
+    let internal_key = internal_key(ctx, index)?;
+    let output_key = if ctx.descriptor() == Descriptor::Tr {
+        let merkle_root = match leaf {
+            Some(_) => {
+                let script =
+                    crate::script::build_script(ctx).map_err(InterpreterError::ScriptBuilder)?;
+                Some(TapNodeHash::from_script(&script, LeafVersion::TapScript))
+            }
+            None => None,
+        };
+        let secp = Secp256k1::verification_only();
+        internal_key
+            .tap_tweak(&secp, merkle_root)
+            .0
+            .to_x_only_public_key()
+    } else {
+        internal_key
+    };
+    if !verifier.check_sig(sig, &output_key.serialize()) {
+        return Err(InterpreterError::InvalidSignature { span: node.span() });
+    }
+
+    let mut trace = Trace::default();
+    trace.keys.push((index, key.clone()));
+    Ok(trace.into_inspection(ctx))
+}
+
+/// Remove the leaf script and the control block ending a taproot script path spend, after
+/// checking that the control block commits the internal key and the leaf of the `tr()` at
+/// `index` to its output key.
+fn check_tap_leaf(
+    ctx: &Context,
+    index: NodeIndex,
+    stack: &mut Vec<Vec<u8>>,
+    script: &Script,
+) -> Result<(), InterpreterError> {
+    let span = ctx.get_node(index).span();
+    let has_leaf = matches!(
+        ctx.get_node(index).fragment,
+        Fragment::RawTr { inner: Some(_), .. }
+    );
+    if !has_leaf || stack.len() < 2 || stack[stack.len() - 2] != script.as_bytes() {
+        return Err(InterpreterError::MissingLeafScript { span });
+    }
+    let control_block = ControlBlock::decode(&stack[stack.len() - 1])
+        .map_err(|_| InterpreterError::InvalidControlBlock { span })?;
+
+    let secp = Secp256k1::verification_only();
+    let merkle_root = TapNodeHash::from_script(script, LeafVersion::TapScript);
+    let (output_key, _) = internal_key(ctx, index)?.tap_tweak(&secp, Some(merkle_root));
+    if control_block.leaf_version != LeafVersion::TapScript
+        || !control_block.verify_taproot_commitment(
+            &secp,
+            output_key.to_x_only_public_key(),
+            script,
+        )
+    {
+        return Err(InterpreterError::InvalidControlBlock { span });
+    }
+
+    stack.truncate(stack.len() - 2);
+    Ok(())
+}
+
+/// The internal key of the `tr()` at `index`, the output key of `rawtr()`.
+fn internal_key(ctx: &Context, index: NodeIndex) -> Result<XOnlyPublicKey, InterpreterError> {
+    let node = ctx.get_node(index);
+    let Fragment::RawTr { key, .. } = &node.fragment else {
+        return Err(InterpreterError::InvalidScript { span: node.span() });
+    };
+    let key = ctx.get_key(*key);
+    match key.as_definite_key() {
+        Some(DefiniteKeyToken::XOnlyPublicKey(pk)) => Ok(pk),
+        Some(DefiniteKeyToken::PublicKey(pk)) => Ok(pk.into()),
+        None => Err(InterpreterError::ScriptBuilder(
+            ScriptBuilderError::NonDefiniteKey {
+                key: key.identifier(),
+                span: node.span(),
+            },
+        )),
+    }
+}
+
+/// The signatures, preimages and locktimes checked by each fragment during execution.
+#[derive(Default)]
+struct Trace {
+    keys: Vec<(NodeIndex, KeyToken)>,
+    preimages: Vec<(NodeIndex, Vec<u8>)>,
+    older: Vec<(NodeIndex, u32)>,
+    after: Vec<(NodeIndex, u32)>,
+}
+
+impl Trace {
+    /// Evaluate every fragment from the leaves checked during execution. Children are
+    /// always stored before their parent, so a single pass is enough.
+    fn into_inspection(self, ctx: &Context) -> Inspection {
+        let nodes = ctx.get_nodes();
+        let mut satisfied: Vec<bool> = Vec::with_capacity(nodes.len());
+        let mut branches = Vec::new();
+
+        for (index, node) in nodes.iter().enumerate() {
+            let index = index as NodeIndex;
+            let signatures = self.keys.iter().filter(|(i, _)| *i == index).count();
+            let sat = |i: &NodeIndex| satisfied[*i as usize];
+
+            let value = match &node.fragment {
+                Fragment::False => false,
+                Fragment::True => true,
+                Fragment::PkK { .. }
+                | Fragment::PkH { .. }
+                | Fragment::RawPk { .. }
+                | Fragment::RawPkH { .. } => signatures > 0,
+                Fragment::Multi { k, .. } | Fragment::MultiA { k, .. } => signatures >= *k as usize,
+                Fragment::Older { .. } => self.older.iter().any(|(i, _)| *i == index),
+                Fragment::After { .. } => self.after.iter().any(|(i, _)| *i == index),
+                Fragment::Sha256 { .. }
+                | Fragment::Hash256 { .. }
+                | Fragment::Ripemd160 { .. }
+                | Fragment::Hash160 { .. } => self.preimages.iter().any(|(i, _)| *i == index),
+                Fragment::AndOr { x, y, z } => {
+                    let (branch, value) = if sat(x) {
+                        (Branch::Left, sat(y))
+                    } else {
+                        (Branch::Right, sat(z))
+                    };
+                    if value {
+                        branches.push((index, branch));
+                    }
+                    value
+                }
+                Fragment::AndV { x, y } | Fragment::AndB { x, y } => sat(x) && sat(y),
+                Fragment::OrB { x, z }
+                | Fragment::OrC { x, z }
+                | Fragment::OrD { x, z }
+                | Fragment::OrI { x, z } => {
+                    if sat(x) {
+                        branches.push((index, Branch::Left));
+                    } else if sat(z) {
+                        branches.push((index, Branch::Right));
+                    }
+                    sat(x) || sat(z)
+                }
+                Fragment::Thresh { k, xs } => {
+                    let xs = ctx.list(*xs);
+                    xs.iter().filter(|x| sat(x)).count() >= *k as usize
+                }
+                Fragment::Identity { x, .. } => sat(x),
+                Fragment::Descriptor { inner, .. } => sat(inner),
+                Fragment::RawTr { inner, .. } => signatures > 0 || inner.is_some_and(|x| sat(&x)),
+                Fragment::RawAddr { .. } | Fragment::RawScript { .. } => false,
+            };
+            satisfied.push(value);
+        }
+
+        Inspection {
+            branches,
+            keys: self.keys.into_iter().map(|(_, key)| key).collect(),
+            preimages: self.preimages,
+            relative_timelock: self.older.iter().map(|(_, n)| *n).max(),
+            absolute_timelock: self.after.iter().map(|(_, n)| *n).max(),
+            satisfied,
+        }
+    }
+}
+
+struct Machine<'a> {
+    ctx: &'a Context,
+    stack: Vec<Vec<u8>>,
+    altstack: Vec<Vec<u8>>,
+    /// Whether each enclosing `OP_IF` branch is executed
+    exec: Vec<bool>,
+    verifier: &'a dyn Verifier,
+    tapscript: bool,
+    fragments: &'a FragmentMap,
+    root: Span,
+    trace: Trace,
+}
+
+impl Machine<'_> {
+    fn execute(&mut self, script: &Script) -> Result<(), InterpreterError> {
+        for instruction in script.instruction_indices_minimal() {
+            let (position, instruction) =
+                instruction.map_err(|_| InterpreterError::InvalidScript { span: self.root })?;
+            let node = self.fragment_at(position);
+            let executing = self.exec.iter().all(|b| *b);
+
+            match instruction {
+                Instruction::PushBytes(bytes) => {
+                    if executing {
+                        self.stack.push(bytes.as_bytes().to_vec());
+                    }
+                }
+                Instruction::Op(op) => self.step(op, executing, node)?,
+            }
+        }
+
+        if !self.exec.is_empty() {
+            return Err(InterpreterError::UnbalancedConditional { span: self.root });
+        }
+        if self.stack.len() != 1 || !cast_to_bool(&self.stack[0]) {
+            return Err(InterpreterError::FalseResult {
+                stack_size: self.stack.len(),
+                span: self.root,
+            });
+        }
+        Ok(())
+    }
+
+    /// The innermost fragment that emitted the byte at `position`.
+    fn fragment_at(&self, position: usize) -> Option<NodeIndex> {
+        self.fragments
+            .iter()
+            .filter(|(range, _)| range.contains(&position))
+            .min_by_key(|(range, _)| range.len())
+            .map(|(_, index)| *index)
+    }
+
+    /// The key fragment checked by a signature opcode emitted by `node`, looking through
+    /// the `c:` wrapper of `pk_k` and `pk_h`.
+    fn key_fragment(&self, node: NodeIndex) -> NodeIndex {
+        match &self.ctx.get_node(node).fragment {
+            Fragment::Identity {
+                identity_type: IdentityType::C,
+                x,
+            } => *x,
+            _ => node,
+        }
+    }
+
+    fn record_signature(&mut self, node: Option<NodeIndex>, pubkey: &[u8]) {
+        let Some(node) = node.map(|node| self.key_fragment(node)) else {
+            return;
+        };
+        let keys = match &self.ctx.get_node(node).fragment {
+            Fragment::PkK { key }
+            | Fragment::PkH { key }
+            | Fragment::RawPk { key }
+            | Fragment::RawPkH { key } => core::slice::from_ref(key),
+            Fragment::Multi { keys, .. } | Fragment::MultiA { keys, .. } => self.ctx.list(*keys),
+            _ => return,
+        };
+        let key = keys.iter().map(|key| self.ctx.get_key(*key)).find(|key| {
+            key.as_definite_key()
+                .is_some_and(|k| k.to_bytes() == pubkey || k.to_bytes().get(1..) == Some(pubkey))
+        });
+        if let Some(key) = key {
+            self.trace.keys.push((node, key.clone()));
+        }
+    }
+
+    fn record_preimage(&mut self, node: Option<NodeIndex>, preimage: &[u8], hash: &[u8]) {
+        let Some(node) = node else {
+            return;
+        };
+        let expected: &[u8] = match &self.ctx.get_node(node).fragment {
+            Fragment::Sha256 { h } | Fragment::Hash256 { h } => h,
+            Fragment::Ripemd160 { h } | Fragment::Hash160 { h } => h,
+            _ => return,
+        };
+        if expected == hash {
+            self.trace.preimages.push((node, preimage.to_vec()));
+        }
+    }
+
+    fn step(
+        &mut self,
+        op: Opcode,
+        executing: bool,
+        node: Option<NodeIndex>,
+    ) -> Result<(), InterpreterError> {
+        let span = node.map_or(self.root, |node| self.ctx.get_node(node).span());
+
+        // conditionals are tracked even in non executed branches
+        match op {
+            OP_IF | OP_NOTIF => {
+                let mut value = false;
+                if executing {
+                    let top = self.pop(span)?;
+                    if top.len() > 1 || (top.len() == 1 && top[0] != 1) {
+                        return Err(InterpreterError::NonMinimalIf { span });
+                    }
+                    value = top.is_empty() != (op == OP_IF);
+                }
+                self.exec.push(value);
+                return Ok(());
+            }
+            OP_ELSE => {
+                let last = self
+                    .exec
+                    .last_mut()
+                    .ok_or(InterpreterError::UnbalancedConditional { span })?;
+                *last = !*last;
+                return Ok(());
+            }
+            OP_ENDIF => {
+                self.exec
+                    .pop()
+                    .ok_or(InterpreterError::UnbalancedConditional { span })?;
+                return Ok(());
+            }
+            _ if !executing => return Ok(()),
+            _ => (),
+        }
+
+        match op {
+            OP_PUSHNUM_NEG1 => self.push_number(-1),
+            op if (OP_PUSHNUM_1.to_u8()..=OP_PUSHNUM_16.to_u8()).contains(&op.to_u8()) => {
+                self.push_number((op.to_u8() - OP_PUSHNUM_1.to_u8() + 1) as i64)
+            }
+            OP_VERIFY => {
+                let top = self.pop(span)?;
+                self.verify(cast_to_bool(&top), span)?;
+            }
+            OP_TOALTSTACK => {
+                let top = self.pop(span)?;
+                self.altstack.push(top);
+            }
+            OP_FROMALTSTACK => {
+                let top = self
+                    .altstack
+                    .pop()
+                    .ok_or(InterpreterError::StackUnderflow { span })?;
+                self.stack.push(top);
+            }
+            OP_IFDUP => {
+                let top = self.top(span)?.clone();
+                if cast_to_bool(&top) {
+                    self.stack.push(top);
+                }
+            }
+            OP_DUP => {
+                let top = self.top(span)?.clone();
+                self.stack.push(top);
+            }
+            OP_SWAP => {
+                let a = self.pop(span)?;
+                let b = self.pop(span)?;
+                self.stack.push(a);
+                self.stack.push(b);
+            }
+            OP_SIZE => {
+                let size = self.top(span)?.len();
+                self.push_number(size as i64);
+            }
+            OP_EQUAL | OP_EQUALVERIFY => {
+                let a = self.pop(span)?;
+                let b = self.pop(span)?;
+                self.push_result(a == b, op == OP_EQUALVERIFY, span)?;
+            }
+            OP_0NOTEQUAL => {
+                let n = self.pop_number(4, span)?;
+                self.push_number((n != 0) as i64);
+            }
+            OP_ADD | OP_BOOLAND | OP_BOOLOR | OP_NUMEQUAL | OP_NUMEQUALVERIFY => {
+                let b = self.pop_number(4, span)?;
+                let a = self.pop_number(4, span)?;
+                match op {
+                    OP_ADD => self.push_number(a + b),
+                    OP_BOOLAND => self.push_number((a != 0 && b != 0) as i64),
+                    OP_BOOLOR => self.push_number((a != 0 || b != 0) as i64),
+                    _ => self.push_result(a == b, op == OP_NUMEQUALVERIFY, span)?,
+                }
+            }
+            OP_RIPEMD160 | OP_SHA256 | OP_HASH160 | OP_HASH256 => {
+                let data = self.pop(span)?;
+                let hash = match op {
+                    OP_RIPEMD160 => ripemd160::Hash::hash(&data).to_byte_array().to_vec(),
+                    OP_SHA256 => sha256::Hash::hash(&data).to_byte_array().to_vec(),
+                    OP_HASH160 => hash160::Hash::hash(&data).to_byte_array().to_vec(),
+                    _ => sha256d::Hash::hash(&data).to_byte_array().to_vec(),
+                };
+                self.record_preimage(node, &data, &hash);
+                self.stack.push(hash);
+            }
+            OP_CHECKSIG | OP_CHECKSIGVERIFY => {
+                let pubkey = self.pop(span)?;
+                let sig = self.pop(span)?;
+                let valid = self.check_sig(&sig, &pubkey, span)?;
+                if valid {
+                    self.record_signature(node, &pubkey);
+                }
+                self.push_result(valid, op == OP_CHECKSIGVERIFY, span)?;
+            }
+            OP_CHECKSIGADD if self.tapscript => {
+                let pubkey = self.pop(span)?;
+                let n = self.pop_number(4, span)?;
+                let sig = self.pop(span)?;
+                let valid = self.check_sig(&sig, &pubkey, span)?;
+                if valid {
+                    self.record_signature(node, &pubkey);
+                }
+                self.push_number(n + valid as i64);
+            }
+            OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY if !self.tapscript => {
+                let valid = self.check_multisig(node, span)?;
+                self.push_result(valid, op == OP_CHECKMULTISIGVERIFY, span)?;
+            }
+            OP_CSV | OP_CLTV => {
+                let n = self.top(span)?.clone();
+                let n = read_number(&n, 5).ok_or(InterpreterError::InvalidNumber { span })?;
+                let locktime =
+                    u32::try_from(n).map_err(|_| InterpreterError::InvalidNumber { span })?;
+                let satisfied = if op == OP_CSV {
+                    self.verifier.check_older(locktime)
+                } else {
+                    self.verifier.check_after(locktime)
+                };
+                if !satisfied {
+                    return Err(InterpreterError::UnsatisfiedLockTime { locktime, span });
+                }
+                if let Some(node) = node {
+                    let locktimes = if op == OP_CSV {
+                        &mut self.trace.older
+                    } else {
+                        &mut self.trace.after
+                    };
+                    locktimes.push((node, locktime));
+                }
+            }
+            opcode => return Err(InterpreterError::UnsupportedOpcode { opcode, span }),
+        }
+        Ok(())
+    }
+
+    fn check_multisig(
+        &mut self,
+        node: Option<NodeIndex>,
+        span: Span,
+    ) -> Result<bool, InterpreterError> {
+        let n = self.pop_number(4, span)?;
+        let mut pubkeys = Vec::new();
+        for _ in 0..n {
+            pubkeys.push(self.pop(span)?);
+        }
+        let k = self.pop_number(4, span)?;
+        let mut sigs = Vec::new();
+        for _ in 0..k {
+            sigs.push(self.pop(span)?);
+        }
+        if !self.pop(span)?.is_empty() {
+            return Err(InterpreterError::NonEmptyDummy { span });
+        }
+
+        // signatures and keys were popped in reverse order, match them from the first one
+        let mut pubkeys = pubkeys.iter().rev();
+        let mut signers = Vec::new();
+        for sig in sigs.iter().rev() {
+            let signer = pubkeys
+                .by_ref()
+                .find(|pubkey| !sig.is_empty() && self.verifier.check_sig(sig, pubkey));
+            match signer {
+                Some(pubkey) => signers.push(pubkey.clone()),
+                None => break,
+            }
+        }
+        let valid = signers.len() == sigs.len();
+        if !valid && sigs.iter().any(|sig| !sig.is_empty()) {
+            return Err(InterpreterError::InvalidSignature { span });
+        }
+        if valid {
+            for pubkey in signers {
+                self.record_signature(node, &pubkey);
+            }
+        }
+        Ok(valid)
+    }
+
+    fn check_sig(&self, sig: &[u8], pubkey: &[u8], span: Span) -> Result<bool, InterpreterError> {
+        if sig.is_empty() {
+            return Ok(false);
+        }
+        if !self.verifier.check_sig(sig, pubkey) {
+            return Err(InterpreterError::InvalidSignature { span });
+        }
+        Ok(true)
+    }
+
+    fn verify(&self, value: bool, span: Span) -> Result<(), InterpreterError> {
+        if value {
+            Ok(())
+        } else {
+            Err(InterpreterError::VerifyFailed { span })
+        }
+    }
+
+    fn push_result(
+        &mut self,
+        value: bool,
+        verify: bool,
+        span: Span,
+    ) -> Result<(), InterpreterError> {
+        if verify {
+            self.verify(value, span)
+        } else {
+            self.push_number(value as i64);
+            Ok(())
+        }
+    }
+
+    fn top(&self, span: Span) -> Result<&Vec<u8>, InterpreterError> {
+        self.stack
+            .last()
+            .ok_or(InterpreterError::StackUnderflow { span })
+    }
+
+    fn pop(&mut self, span: Span) -> Result<Vec<u8>, InterpreterError> {
+        self.stack
+            .pop()
+            .ok_or(InterpreterError::StackUnderflow { span })
+    }
+
+    fn pop_number(&mut self, max_len: usize, span: Span) -> Result<i64, InterpreterError> {
+        let top = self.pop(span)?;
+        read_number(&top, max_len).ok_or(InterpreterError::InvalidNumber { span })
+    }
+
+    fn push_number(&mut self, n: i64) {
+        self.stack.push(write_number(n));
+    }
+}
+
+fn cast_to_bool(bytes: &[u8]) -> bool {
+    match bytes.split_last() {
+        Some((last, rest)) => rest.iter().any(|b| *b != 0) || (*last != 0 && *last != 0x80),
+        None => false,
+    }
+}
+
+/// Decode a minimally encoded script number of at most `max_len` bytes.
+fn read_number(bytes: &[u8], max_len: usize) -> Option<i64> {
+    let Some((last, rest)) = bytes.split_last() else {
+        return Some(0);
+    };
+    if bytes.len() > max_len {
+        return None;
+    }
+    // the most significant byte can only be zero (or the sign bit) if the next one needs its sign bit
+    if last & 0x7f == 0 && rest.last().is_none_or(|b| b & 0x80 == 0) {
+        return None;
+    }
+
+    let mut n: i64 = 0;
+    for (i, b) in bytes.iter().enumerate() {
+        n |= (*b as i64) << (8 * i);
+    }
+    if last & 0x80 != 0 {
+        n &= !(0x80 << (8 * rest.len()));
+        n = -n;
+    }
+    Some(n)
+}
+
+fn write_number(n: i64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let negative = n < 0;
+    let mut abs = n.unsigned_abs();
+    while abs > 0 {
+        bytes.push((abs & 0xff) as u8);
+        abs >>= 8;
+    }
+    match bytes.last_mut() {
+        Some(last) if *last & 0x80 != 0 => bytes.push(if negative { 0x80 } else { 0 }),
+        Some(last) if negative => *last |= 0x80,
+        _ => (),
+    }
+    bytes
+}
+
+#[cfg(all(test, feature = "satisfy"))]
+mod test {
+    use super::*;
+    use crate::parser::keys::KeyToken;
+    use crate::satisfy::Strategy;
+    use crate::utils::fixtures::{A, B, C, Offline, Signer};
+
+    #[test]
+    fn test_verify_satisfactions() {
+        let h = sha256::Hash::hash(&[0x42; 32]);
+        let r = ripemd160::Hash::hash(&[0x42; 32]);
+
+        for descriptor in [
+            alloc::format!("wsh(pk({A}))"),
+            alloc::format!("wpkh({A})"),
+            alloc::format!("wsh(multi(2,{A},{B},{C}))"),
+            alloc::format!("wsh(or_d(pk({A}),and_v(v:pkh({B}),older(144))))"),
+            alloc::format!("wsh(andor(pk({A}),sha256({h}),and_v(v:pk({B}),after(800000))))"),
+            alloc::format!("wsh(thresh(2,pk({A}),s:pk({B}),a:ripemd160({r})))"),
+            alloc::format!("sh(wsh(or_i(and_b(pk({A}),s:pk({B})),j:and_v(v:pk({C}),n:after(5)))))"),
+        ] {
+            let ctx = Context::try_from(descriptor.as_str()).unwrap();
+            let sat = ctx.satisfy(&Signer, &Strategy::DEFAULT).unwrap().sat;
+            assert!(sat.available);
+            ctx.verify_witness(&sat.witness, &Signer)
+                .unwrap_or_else(|e| panic!("{descriptor}: {e}"));
+        }
+    }
+
+    #[test]
+    fn test_verify_reports_failing_fragment() {
+        let input = alloc::format!("wsh(and_v(v:pk({A}),after(900000)))");
+        let ctx = Context::try_from(input.as_str()).unwrap();
+        let witness = ctx
+            .satisfy(&Signer, &Strategy::DEFAULT)
+            .unwrap()
+            .sat
+            .witness;
+
+        let err = ctx.verify_witness(&witness, &Signer).unwrap_err();
+        assert!(matches!(
+            err,
+            InterpreterError::UnsatisfiedLockTime {
+                locktime: 900000,
+                ..
+            }
+        ));
+        let span = err.span().unwrap();
+        assert_eq!(&input[span.start..span.end], "after(900000)");
+
+        // a signature from the wrong key
+        let mut sig = alloc::vec![0x30];
+        sig.extend(
+            bitcoin::hex::FromHex::from_hex(B)
+                .map(|v: Vec<u8>| v)
+                .unwrap(),
+        );
+        let err = ctx
+            .verify_witness(&Witness::from_slice(&[sig]), &Signer)
+            .unwrap_err();
+        assert!(matches!(err, InterpreterError::InvalidSignature { .. }));
+        let span = err.span().unwrap();
+        assert_eq!(&input[span.start..span.end], alloc::format!("pk({A})"));
+    }
+
+    #[test]
+    fn test_inspect_recovery_path() {
+        let input = alloc::format!("wsh(or_d(pk({A}),and_v(v:pk({B}),older(144))))");
+        let ctx = Context::try_from(input.as_str()).unwrap();
+        let Fragment::Descriptor { inner: or_d, .. } = ctx.get_root().fragment else {
+            unreachable!()
+        };
+
+        // the owner key is lost, the recovery key spends after the timelock
+        let mut witness = ctx
+            .satisfy(&Offline(A), &Strategy::DEFAULT)
+            .unwrap()
+            .sat
+            .witness;
+        witness.push(ctx.build_script().unwrap().as_bytes());
+
+        let spend = ctx.inspect_spend(&witness, Script::new(), &Signer).unwrap();
+        assert_eq!(spend.branch(or_d), Some(Branch::Right));
+        assert_eq!(spend.keys.len(), 1);
+        assert_eq!(spend.keys[0].identifier(), B);
+        assert_eq!(spend.relative_timelock, Some(144));
+        assert_eq!(spend.absolute_timelock, None);
+
+        let witness = ctx
+            .satisfy(&Signer, &Strategy::DEFAULT)
+            .unwrap()
+            .sat
+            .witness;
+        let spend = ctx.inspect_spend(&witness, Script::new(), &Signer).unwrap();
+        assert_eq!(spend.branch(or_d), Some(Branch::Left));
+        assert_eq!(spend.keys[0].identifier(), A);
+        assert_eq!(spend.relative_timelock, None);
+    }
+
+    #[test]
+    fn test_inspect_script_sig() {
+        let input = alloc::format!("sh(or_i(pk({A}),pk({B})))");
+        let ctx = Context::try_from(input.as_str()).unwrap();
+        let Fragment::Descriptor { inner: or_i, .. } = ctx.get_root().fragment else {
+            unreachable!()
+        };
+
+        // the branch of or_i is selected with OP_1, the minimal push of 1
+        let mut sig = alloc::vec![0x30];
+        sig.extend(ctx.get_key(0).as_definite_key().unwrap().to_bytes());
+        let script = ctx.build_script().unwrap();
+        let script_sig = bitcoin::script::Builder::new()
+            .push_slice(<&bitcoin::script::PushBytes>::try_from(sig.as_slice()).unwrap())
+            .push_opcode(OP_PUSHNUM_1)
+            .push_slice(<&bitcoin::script::PushBytes>::try_from(script.as_bytes()).unwrap())
+            .into_script();
+        let spend = ctx
+            .inspect_spend(&Witness::new(), &script_sig, &Signer)
+            .unwrap();
+        assert_eq!(spend.branch(or_i), Some(Branch::Left));
+        assert_eq!(spend.keys[0].identifier(), A);
+    }
+
+    #[test]
+    fn test_inspect_preimages_and_multisig() {
+        let h = sha256::Hash::hash(&[0x42; 32]);
+        let input = alloc::format!("wsh(andor(multi(2,{A},{B},{C}),sha256({h}),pk({B})))");
+        let ctx = Context::try_from(input.as_str()).unwrap();
+
+        let witness = ctx
+            .satisfy(&Offline(B), &Strategy::DEFAULT)
+            .unwrap()
+            .sat
+            .witness;
+        let spend = ctx.inspect_spend(&witness, Script::new(), &Signer).unwrap();
+        let keys: Vec<_> = spend.keys.iter().map(|k| k.identifier()).collect();
+        assert_eq!(keys, [A, C]);
+        assert_eq!(spend.preimages.len(), 1);
+        assert_eq!(spend.preimages[0].1, [0x42; 32]);
+        assert!(matches!(
+            ctx.get_node(spend.preimages[0].0).fragment,
+            Fragment::Sha256 { .. }
+        ));
+        assert!(
+            spend
+                .branches
+                .iter()
+                .all(|(_, branch)| *branch == Branch::Left)
+        );
+    }
+
+    #[test]
+    fn test_inspect_taproot_key_spend() {
+        for descriptor in [
+            alloc::format!("tr({A})"),
+            alloc::format!("tr({A},pk({B}))"),
+            alloc::format!("rawtr({A})"),
+        ] {
+            let ctx = Context::try_from(descriptor.as_str()).unwrap();
+            // the witness program is the output key
+            let mut sig = alloc::vec![0x30];
+            sig.extend_from_slice(&ctx.build_script_pubkey().unwrap().as_bytes()[2..]);
+            let spend = ctx
+                .inspect_spend(&Witness::from_slice(&[sig]), Script::new(), &Signer)
+                .unwrap_or_else(|e| panic!("{descriptor}: {e}"));
+            assert_eq!(spend.keys.len(), 1);
+        }
+
+        // a signature for the untweaked internal key does not spend tr()
+        let ctx = Context::try_from(alloc::format!("tr({A})").as_str()).unwrap();
+        let mut sig = alloc::vec![0x30];
+        sig.extend_from_slice(&ctx.get_keys()[0].as_definite_key().unwrap().to_bytes());
+        let err = ctx
+            .inspect_spend(&Witness::from_slice(&[sig]), Script::new(), &Signer)
+            .unwrap_err();
+        assert!(matches!(err, InterpreterError::InvalidSignature { .. }));
+    }
+
+    #[test]
+    fn test_verify_taproot_script_path() {
+        let input = alloc::format!("tr({A},pk({B}))");
+        let ctx = Context::try_from(input.as_str()).unwrap();
+
+        // the internal key is offline, the leaf script and control block follow the signature
+        let witness = ctx
+            .satisfy(&Offline(&A[2..]), &Strategy::DEFAULT)
+            .unwrap()
+            .sat
+            .witness;
+        assert_eq!(witness.len(), 3);
+        assert_eq!(
+            witness.nth(1).unwrap(),
+            ctx.build_script().unwrap().as_bytes()
+        );
+        assert_eq!(witness.nth(2).unwrap().len(), 33);
+
+        let spend = ctx.inspect_spend(&witness, Script::new(), &Signer).unwrap();
+        assert_eq!(spend.keys.len(), 1);
+        assert_eq!(spend.keys[0].identifier(), B[2..]);
+
+        // without a key path, the witness still ends with the leaf and control block
+        let nums = KeyToken::nums();
+        let input = alloc::format!("tr({nums},pk({B}))");
+        let ctx = Context::try_from(input.as_str()).unwrap();
+        let witness = ctx
+            .satisfy(&Signer, &Strategy::DEFAULT)
+            .unwrap()
+            .sat
+            .witness;
+        assert_eq!(witness.len(), 3);
+        ctx.verify_witness(&witness, &Signer).unwrap();
+
+        // a lone element is a key path spend, which cannot be made for the NUMS key
+        let sig = Witness::from_slice(&[witness.nth(0).unwrap()]);
+        let err = ctx.verify_witness(&sig, &Signer).unwrap_err();
+        assert!(matches!(err, InterpreterError::MissingLeafScript { .. }));
+    }
+
+    #[test]
+    fn test_verify_taproot_control_block() {
+        let input = alloc::format!("tr({A},pk({B}))");
+        let ctx = Context::try_from(input.as_str()).unwrap();
+        let witness = ctx
+            .satisfy(&Offline(&A[2..]), &Strategy::DEFAULT)
+            .unwrap()
+            .sat
+            .witness;
+        let elements: Vec<Vec<u8>> = witness.iter().map(|e| e.to_vec()).collect();
+        let verify =
+            |elements: &[Vec<u8>]| ctx.verify_witness(&Witness::from_slice(elements), &Signer);
+
+        // the leaf script is missing or is another one
+        let err = verify(&[elements[0].clone(), elements[2].clone()]).unwrap_err();
+        assert_eq!(err.code(), "I012");
+        let other = Context::try_from(alloc::format!("tr({A},pk({C}))").as_str()).unwrap();
+        let mut tampered = elements.clone();
+        tampered[1] = other.build_script().unwrap().to_bytes();
+        assert_eq!(verify(&tampered).unwrap_err().code(), "I012");
+
+        // the output key parity, the leaf version and the internal key are committed to
+        for (offset, flip) in [(0, 0x01), (0, 0x02), (1, 0x01)] {
+            let mut tampered = elements.clone();
+            tampered[2][offset] ^= flip;
+            assert_eq!(verify(&tampered).unwrap_err().code(), "I013");
+        }
+
+        // a control block for another internal key
+        let other = Context::try_from(alloc::format!("tr({C},pk({B}))").as_str()).unwrap();
+        let other = other
+            .satisfy(&Offline(&C[2..]), &Strategy::DEFAULT)
+            .unwrap()
+            .sat
+            .witness;
+        let mut tampered = elements.clone();
+        tampered[2] = other.nth(2).unwrap().to_vec();
+        assert_eq!(verify(&tampered).unwrap_err().code(), "I013");
+        assert!(verify(&elements).is_ok());
+    }
+}
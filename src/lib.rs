@@ -6,8 +6,9 @@
 //!
 //! # Features
 //!
-//! - **`debug`**: Enables `Debug` trait implementations for error types, useful for
-//!   development and debugging.
+//! - **`debug`**: Enables `Debug` trait implementations for the AST types, useful for
+//!   development and debugging. Error types always implement `Debug`, `Display` and
+//!   [`core::error::Error`].
 //!
 //! # Examples
 //!
@@ -25,24 +26,39 @@
 //!
 //! # Crate Features
 //!
-//! By default, this crate is `no_std` compatible. Enable the `debug` feature for
-//! enhanced error reporting capabilities.
+//! By default, this crate is `no_std` compatible. Every error type implements
+//! [`error::Diagnostic`], which exposes a stable error code and the byte range of the
+//! input the error refers to.
 
 #![cfg_attr(not(test), no_std)]
 
+/// Parsing into caller-supplied buffers, without an allocator
+pub mod arena;
 /// Context for miniscript expressions
 pub mod context;
 /// Bitcoin descriptor parsing and validation
 pub mod descriptor;
+/// Human readable rendering of errors
+pub mod diagnostics;
+/// Error spans and stable error codes
+pub mod error;
+/// Script interpreter verifying witnesses against descriptors
+pub mod interpreter;
 /// Limits for miniscript expressions
 pub mod limits;
 /// Miniscript parser and AST representation
 pub mod parser;
+/// Spending plans computed from the available assets before signing
+#[cfg(feature = "satisfy")]
+pub mod plan;
 /// Satisfactions and dis-satisfactions of miniscript expressions
 #[cfg(feature = "satisfy")]
 pub mod satisfy;
 /// Bitcoin script generation from parsed miniscript
 pub mod script;
+/// Signature verification against the spending transaction
+#[cfg(feature = "satisfy")]
+pub mod sighash;
 /// Type checking and correctness property validation
 pub mod type_checker;
 /// Utility functions
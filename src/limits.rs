@@ -1,6 +1,10 @@
 use bitcoin::ScriptBuf;
 
-use crate::{bitcoin_definition_link, descriptor::Descriptor};
+use crate::{
+    bitcoin_definition_link,
+    descriptor::Descriptor,
+    error::{Diagnostic, Span},
+};
 
 /// Maximum allowed absolute locktime value.
 const MAX_ABSOLUTE_LOCKTIME: u32 = 0x7FFF_FFFF;
@@ -15,6 +19,12 @@ const MIN_ABSOLUTE_LOCKTIME: u32 = 1;
 /// Maximum recursion depth allowed by consensus rules.
 const MAX_RECURSION_DEPTH: u32 = 402;
 
+/// Default bound on the nesting depth of fragments while parsing.
+///
+/// The descriptor itself does not count toward the tree height, so a fragment nested
+/// one level deeper than [`MAX_RECURSION_DEPTH`] is always rejected after parsing.
+pub(crate) const MAX_PARSE_DEPTH: usize = MAX_RECURSION_DEPTH as usize + 1;
+
 /// Maximum script element size allowed by consensus rules.
 #[doc = bitcoin_definition_link!("8333aa5302902f6be929c30b3c2b4e91c6583224", "script/script.h", 28)]
 const MAX_SCRIPT_ELEMENT_SIZE: usize = 520;
@@ -37,17 +47,63 @@ pub(crate) fn check_absolute_locktime(locktime: u32) -> Result<(), u32> {
 
 // Limits for Miniscript
 
-#[cfg_attr(feature = "debug", derive(Debug))]
+#[derive(Debug)]
 pub enum LimitsError {
-    ScriptTooLarge { size: usize, max_size: usize },
-    MaxRecursiveDepthExceeded { depth: usize, max_depth: u32 },
+    ScriptTooLarge {
+        size: usize,
+        max_size: usize,
+        span: Span,
+    },
+    MaxRecursiveDepthExceeded {
+        depth: usize,
+        max_depth: u32,
+        span: Span,
+    },
+}
+
+impl core::fmt::Display for LimitsError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            LimitsError::ScriptTooLarge { size, max_size, .. } => {
+                write!(
+                    f,
+                    "script size {size} exceeds the maximum of {max_size} bytes"
+                )
+            }
+            LimitsError::MaxRecursiveDepthExceeded {
+                depth, max_depth, ..
+            } => write!(
+                f,
+                "nesting depth {depth} exceeds the maximum of {max_depth}"
+            ),
+        }
+    }
+}
+
+impl core::error::Error for LimitsError {}
+
+impl Diagnostic for LimitsError {
+    fn code(&self) -> &'static str {
+        match self {
+            LimitsError::ScriptTooLarge { .. } => "L001",
+            LimitsError::MaxRecursiveDepthExceeded { .. } => "L002",
+        }
+    }
+
+    fn span(&self) -> Option<Span> {
+        match self {
+            LimitsError::ScriptTooLarge { span, .. }
+            | LimitsError::MaxRecursiveDepthExceeded { span, .. } => Some(*span),
+        }
+    }
 }
 
-pub(crate) fn check_recursion_depth(depth: usize) -> Result<(), LimitsError> {
+pub(crate) fn check_recursion_depth(depth: usize, span: Span) -> Result<(), LimitsError> {
     if depth as u32 > MAX_RECURSION_DEPTH {
         return Err(LimitsError::MaxRecursiveDepthExceeded {
             depth,
             max_depth: MAX_RECURSION_DEPTH,
+            span,
         });
     }
     Ok(())
@@ -56,6 +112,7 @@ pub(crate) fn check_recursion_depth(depth: usize) -> Result<(), LimitsError> {
 pub(crate) fn check_script_size(
     descriptor: &Descriptor,
     script_size: usize,
+    span: Span,
 ) -> Result<(), LimitsError> {
     match descriptor {
         Descriptor::Bare => {}
@@ -65,6 +122,7 @@ pub(crate) fn check_script_size(
                 return Err(LimitsError::ScriptTooLarge {
                     size: script_size,
                     max_size: MAX_SCRIPT_ELEMENT_SIZE,
+                    span,
                 });
             }
         }
@@ -74,6 +132,7 @@ pub(crate) fn check_script_size(
                 return Err(LimitsError::ScriptTooLarge {
                     size: script_size,
                     max_size: MAX_SCRIPT_SIZE,
+                    span,
                 });
             }
 
@@ -81,11 +140,13 @@ pub(crate) fn check_script_size(
                 return Err(LimitsError::ScriptTooLarge {
                     size: script_size,
                     max_size: MAX_STANDARD_P2WSH_SCRIPT_SIZE,
+                    span,
                 });
             }
         }
-        Descriptor::Tr => {}
-        Descriptor::Pk => {}
+        Descriptor::Tr | Descriptor::RawTr => {}
+        Descriptor::Pk | Descriptor::Combo => {}
+        Descriptor::Addr | Descriptor::Raw => {}
     }
     Ok(())
 }
@@ -1,11 +1,13 @@
+use core::ops::Range;
 use core::str::FromStr;
 
 use alloc::{string::String, vec::Vec};
 use bitcoin::bip32;
-use bitcoin::hashes::Hash;
+use bitcoin::hashes::{Hash, HashEngine, sha256};
 use bitcoin::{PubkeyHash, script::Builder, secp256k1};
 
 use crate::descriptor::Descriptor;
+use crate::error::{Diagnostic, Span};
 use crate::parser::{ParseError, Position};
 
 use alloc::string::ToString;
@@ -14,6 +16,8 @@ use alloc::string::ToString;
 /// A token for a public key - enum-based approach eliminating trait objects
 pub struct KeyToken {
     inner: KeyTokenInner,
+    /// Whether the key is the NUMS point `H` or derived from it, kept through derivation
+    nums: bool,
 }
 
 #[derive(Clone)]
@@ -21,12 +25,62 @@ pub(crate) enum KeyTokenInner {
     PublicKey(bitcoin::PublicKey),
     XOnlyPublicKey(bitcoin::XOnlyPublicKey),
     ExtendedKey(ExtendedKey),
+    MuSig(MuSigKey),
 }
 
 impl KeyToken {
     #[inline]
     pub(crate) fn new(inner: KeyTokenInner) -> Self {
-        Self { inner }
+        let nums = match &inner {
+            KeyTokenInner::PublicKey(pk) => pk.inner.x_only_public_key().0.serialize() == NUMS_H,
+            KeyTokenInner::XOnlyPublicKey(pk) => pk.serialize() == NUMS_H,
+            // public derivation from `H` only adds known multiples of the generator
+            KeyTokenInner::ExtendedKey(ext) => {
+                ext.key.public_key.x_only_public_key().0.serialize() == NUMS_H
+            }
+            KeyTokenInner::MuSig(_) => false,
+        };
+        Self { inner, nums }
+    }
+
+    /// The BIP-341 NUMS point `H`, an internal key for `tr()` outputs that can only be
+    /// spent by script.
+    pub fn nums() -> Self {
+        Self::new(KeyTokenInner::PublicKey(bitcoin::PublicKey::new(
+            nums_point(),
+        )))
+    }
+
+    /// A ranged extended key with `H` as its public key and the given chain code. Its
+    /// children cannot be told apart from other keys on chain, yet whoever knows the chain
+    /// code can check that none of them has a key path.
+    pub fn nums_xpub(chain_code: [u8; 32], network: bitcoin::NetworkKind) -> Self {
+        let key = bip32::Xpub {
+            network,
+            depth: 0,
+            parent_fingerprint: Default::default(),
+            child_number: bip32::ChildNumber::Normal { index: 0 },
+            public_key: nums_point(),
+            chain_code: bip32::ChainCode::from(chain_code),
+        };
+        let raw = alloc::format!("{key}/*");
+        // not written in any descriptor, the span covers the key on its own
+        let span = Span::new(0, raw.len());
+        Self::new(KeyTokenInner::ExtendedKey(ExtendedKey {
+            raw,
+            origin: None,
+            key,
+            path: Default::default(),
+            wildcard: Wildcard::Normal,
+            x_only: true,
+            span,
+        }))
+    }
+
+    /// Check if the key is the NUMS point `H` or was derived from an extended key whose
+    /// public key is `H`, so that nobody can sign for it.
+    pub fn is_nums(&self) -> bool {
+        self.nums
     }
 
     pub fn is_compressed(&self) -> bool {
@@ -34,6 +88,7 @@ impl KeyToken {
             KeyTokenInner::PublicKey(pk) => pk.compressed,
             KeyTokenInner::XOnlyPublicKey(_) => true,
             KeyTokenInner::ExtendedKey(_) => true,
+            KeyTokenInner::MuSig(_) => true,
         }
     }
 
@@ -42,6 +97,7 @@ impl KeyToken {
             KeyTokenInner::PublicKey(pk) => pk.to_string(),
             KeyTokenInner::XOnlyPublicKey(pk) => pk.to_string(),
             KeyTokenInner::ExtendedKey(ext) => ext.identifier(),
+            KeyTokenInner::MuSig(musig) => musig.identifier(),
         }
     }
 
@@ -50,29 +106,184 @@ impl KeyToken {
             KeyTokenInner::PublicKey(pk) => Some(DefiniteKeyToken::PublicKey(*pk)),
             KeyTokenInner::XOnlyPublicKey(pk) => Some(DefiniteKeyToken::XOnlyPublicKey(*pk)),
             KeyTokenInner::ExtendedKey(_) => None,
+            KeyTokenInner::MuSig(musig) => musig.as_definite_key(),
+        }
+    }
+
+    /// Check if the key has a wildcard and thus derives a different key per index.
+    pub fn is_ranged(&self) -> bool {
+        match &self.inner {
+            KeyTokenInner::ExtendedKey(ext) => ext.wildcard != Wildcard::None,
+            KeyTokenInner::MuSig(musig) => {
+                musig.wildcard != Wildcard::None || musig.participants.iter().any(Self::is_ranged)
+            }
+            _ => false,
+        }
+    }
+
+    /// The `[fingerprint/path]` origin of an extended key, if it has one.
+    pub fn origin(&self) -> Option<(bip32::Fingerprint, &bip32::DerivationPath)> {
+        match &self.inner {
+            KeyTokenInner::ExtendedKey(ext) => ext
+                .origin
+                .as_ref()
+                .map(|(fingerprint, path)| (*fingerprint, path)),
+            _ => None,
+        }
+    }
+
+    /// The fingerprint of the master key: the origin fingerprint if there is one,
+    /// otherwise the fingerprint of the extended key itself. Single keys have none, nor
+    /// do `musig()` keys: their [`participants`](Self::participants) each have their own.
+    pub fn master_fingerprint(&self) -> Option<bip32::Fingerprint> {
+        match &self.inner {
+            KeyTokenInner::ExtendedKey(ext) => Some(
+                ext.origin
+                    .as_ref()
+                    .map_or_else(|| ext.key.fingerprint(), |(fingerprint, _)| *fingerprint),
+            ),
+            _ => None,
+        }
+    }
+
+    /// The keys aggregated by a `musig()` expression. Other keys have none.
+    pub fn participants(&self) -> &[KeyToken] {
+        match &self.inner {
+            KeyTokenInner::MuSig(musig) => &musig.participants,
+            _ => &[],
+        }
+    }
+
+    /// The extended public key, if this is an extended key.
+    pub fn xpub(&self) -> Option<&bip32::Xpub> {
+        match &self.inner {
+            KeyTokenInner::ExtendedKey(ext) => Some(&ext.key),
+            _ => None,
         }
     }
 
-    pub fn derive(&self, index: u32) -> Result<Self, String> {
+    /// The path following the extended public key, without the wildcard.
+    pub fn child_path(&self) -> Option<&bip32::DerivationPath> {
+        match &self.inner {
+            KeyTokenInner::ExtendedKey(ext) => Some(&ext.path),
+            _ => None,
+        }
+    }
+
+    /// The wildcard of an extended key, or the one following a `musig()` expression.
+    /// Single keys never have one.
+    pub fn wildcard(&self) -> Wildcard {
+        match &self.inner {
+            KeyTokenInner::ExtendedKey(ext) => ext.wildcard,
+            KeyTokenInner::MuSig(musig) => musig.wildcard,
+            _ => Wildcard::None,
+        }
+    }
+
+    /// The path from the master key to the key derived at `index`: the origin path,
+    /// the child path and `index` itself if the key is ranged. Single and `musig()` keys
+    /// have none.
+    pub fn full_derivation_path(
+        &self,
+        index: u32,
+    ) -> Result<Option<bip32::DerivationPath>, DeriveError> {
+        let KeyTokenInner::ExtendedKey(ext) = &self.inner else {
+            return Ok(None);
+        };
+
+        let mut path = match &ext.origin {
+            Some((_, origin)) => origin.extend(&ext.path),
+            None => ext.path.clone(),
+        };
+        if let Wildcard::Normal = ext.wildcard {
+            path = path.child(bip32::ChildNumber::from_normal_idx(index).map_err(|_| {
+                DeriveError::InvalidChildIndex {
+                    index,
+                    span: ext.span,
+                }
+            })?);
+        }
+        Ok(Some(path))
+    }
+
+    pub fn derive(&self, index: u32) -> Result<Self, DeriveError> {
+        self.derive_with(&secp256k1::Secp256k1::verification_only(), index)
+    }
+
+    /// Derive the key at `index` with a caller-provided context, so that deriving many
+    /// keys does not create a context every time.
+    pub fn derive_with<C: secp256k1::Verification>(
+        &self,
+        secp: &secp256k1::Secp256k1<C>,
+        index: u32,
+    ) -> Result<Self, DeriveError> {
         match &self.inner {
             KeyTokenInner::ExtendedKey(ext) => {
-                let derived = ext.derive(index)?;
+                let derived = ext.derive_with(secp, index)?;
                 Ok(KeyToken {
                     inner: KeyTokenInner::from_definite_key(derived),
+                    nums: self.nums,
                 })
             }
+            KeyTokenInner::MuSig(musig) => {
+                Ok(KeyToken::from_definite_key(musig.derive_with(secp, index)?))
+            }
             _ => Ok(self.clone()), // Non-extended keys don't need derivation
         }
     }
 
+    /// Derive the non-wildcard part of the path once, so that deriving an index
+    /// only costs a single child derivation.
+    pub(crate) fn cache_parent<C: secp256k1::Verification>(
+        &self,
+        secp: &secp256k1::Secp256k1<C>,
+    ) -> Result<Self, DeriveError> {
+        match &self.inner {
+            KeyTokenInner::ExtendedKey(ext) if !ext.path.is_empty() => {
+                let key =
+                    ext.key
+                        .derive_pub(secp, &ext.path)
+                        .map_err(|error| DeriveError::Bip32 {
+                            error,
+                            span: ext.span,
+                        })?;
+                Ok(KeyToken {
+                    inner: KeyTokenInner::ExtendedKey(ExtendedKey {
+                        key,
+                        path: Default::default(),
+                        ..ext.clone()
+                    }),
+                    nums: self.nums,
+                })
+            }
+            KeyTokenInner::MuSig(musig) if musig.aggregate.is_none() => {
+                Ok(KeyToken::new(KeyTokenInner::MuSig(MuSigKey {
+                    participants: musig
+                        .participants
+                        .iter()
+                        .map(|key| key.cache_parent(secp))
+                        .collect::<Result<_, _>>()?,
+                    ..musig.clone()
+                })))
+            }
+            _ => Ok(self.clone()),
+        }
+    }
+
+    /// Derive the key at every index of `range`. The non-wildcard part of the path is
+    /// derived once, so each index only costs a single child derivation.
+    pub fn derive_range<C: secp256k1::Verification>(
+        &self,
+        secp: &secp256k1::Secp256k1<C>,
+        range: Range<u32>,
+    ) -> Result<Vec<Self>, DeriveError> {
+        let parent = self.cache_parent(secp)?;
+        range.map(|index| parent.derive_with(secp, index)).collect()
+    }
+
     // Helper method to create from definite key
     pub fn from_definite_key(key: DefiniteKeyToken) -> Self {
-        Self {
-            inner: match key {
-                DefiniteKeyToken::PublicKey(pk) => KeyTokenInner::PublicKey(pk),
-                DefiniteKeyToken::XOnlyPublicKey(pk) => KeyTokenInner::XOnlyPublicKey(pk),
-            },
-        }
+        Self::new(KeyTokenInner::from_definite_key(key))
     }
 }
 
@@ -114,6 +325,29 @@ impl DefiniteKeyToken {
     }
 }
 
+impl PartialEq for KeyToken {
+    fn eq(&self, other: &Self) -> bool {
+        // same as comparing the identifiers, without formatting them
+        match (&self.inner, &other.inner) {
+            (KeyTokenInner::PublicKey(a), KeyTokenInner::PublicKey(b)) => a == b,
+            (KeyTokenInner::XOnlyPublicKey(a), KeyTokenInner::XOnlyPublicKey(b)) => a == b,
+            (KeyTokenInner::ExtendedKey(a), KeyTokenInner::ExtendedKey(b)) => a.raw == b.raw,
+            (KeyTokenInner::MuSig(a), KeyTokenInner::MuSig(b)) => {
+                a.participants == b.participants && a.path == b.path && a.wildcard == b.wildcard
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Eq for KeyToken {}
+
+impl core::fmt::Display for KeyToken {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.identifier())
+    }
+}
+
 #[cfg(feature = "debug")]
 impl core::fmt::Debug for KeyToken {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
@@ -131,6 +365,105 @@ impl core::fmt::Debug for DefiniteKeyToken {
     }
 }
 
+/// Errors that can occur while deriving a key at a given index.
+#[derive(Debug)]
+pub enum DeriveError {
+    /// The index is not a valid normal (non-hardened) child number
+    InvalidChildIndex { index: u32, span: Span },
+    /// The BIP-32 public derivation failed
+    Bip32 { error: bip32::Error, span: Span },
+    /// The MuSig2 participant keys add up to the point at infinity
+    InvalidAggregate { span: Span },
+}
+
+impl core::fmt::Display for DeriveError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            DeriveError::InvalidChildIndex { index, .. } => {
+                write!(f, "index {index} is not a valid non-hardened child number")
+            }
+            DeriveError::Bip32 { error, .. } => write!(f, "key derivation failed: {error}"),
+            DeriveError::InvalidAggregate { .. } => write!(f, "MuSig2 key aggregation failed"),
+        }
+    }
+}
+
+impl core::error::Error for DeriveError {}
+
+impl Diagnostic for DeriveError {
+    fn code(&self) -> &'static str {
+        match self {
+            DeriveError::InvalidChildIndex { .. } => "K001",
+            DeriveError::Bip32 { .. } => "K002",
+            DeriveError::InvalidAggregate { .. } => "K003",
+        }
+    }
+
+    fn span(&self) -> Option<Span> {
+        match self {
+            DeriveError::InvalidChildIndex { span, .. }
+            | DeriveError::Bip32 { span, .. }
+            | DeriveError::InvalidAggregate { span } => Some(*span),
+        }
+    }
+}
+
+/// Why a key expression could not be parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidKeyReason {
+    /// The key origin is missing its closing square bracket
+    MissingClosingBracket,
+    /// The key origin is too short to hold a fingerprint
+    InvalidOrigin,
+    /// The key origin fingerprint is not 8 hex characters
+    InvalidFingerprint,
+    /// The key origin derivation path is invalid
+    InvalidOriginPath,
+    /// Hardened wildcards (`/*'`) cannot be derived from an xpub
+    HardenedWildcard,
+    /// The extended public key is invalid
+    InvalidXpub,
+    /// The derivation path after the extended key is invalid
+    InvalidPath,
+    /// The public key is invalid
+    InvalidPublicKey,
+    /// The output key of `rawtr()` must be compressed or x-only
+    UncompressedOutputKey,
+    /// `musig()` is only allowed inside `tr()`
+    MuSigOutsideTaproot,
+    /// MuSig2 participants must be compressed public keys
+    UncompressedParticipant,
+    /// Participants cannot be ranged when the aggregate key is derived
+    RangedParticipant,
+    /// Only unhardened derivation can follow `musig()`
+    HardenedDerivation,
+    /// The MuSig2 participant keys cannot be aggregated
+    InvalidAggregate,
+}
+
+impl core::fmt::Display for InvalidKeyReason {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            InvalidKeyReason::MissingClosingBracket => "missing closing square bracket",
+            InvalidKeyReason::InvalidOrigin => "invalid origin format",
+            InvalidKeyReason::InvalidFingerprint => "invalid origin fingerprint",
+            InvalidKeyReason::InvalidOriginPath => "invalid origin path",
+            InvalidKeyReason::HardenedWildcard => "hardened wildcard not allowed",
+            InvalidKeyReason::InvalidXpub => "invalid extended public key",
+            InvalidKeyReason::InvalidPath => "invalid derivation path",
+            InvalidKeyReason::InvalidPublicKey => "invalid public key",
+            InvalidKeyReason::UncompressedOutputKey => "uncompressed key not allowed in rawtr()",
+            InvalidKeyReason::MuSigOutsideTaproot => "musig() is only allowed in tr()",
+            InvalidKeyReason::UncompressedParticipant => "uncompressed musig() participant",
+            InvalidKeyReason::RangedParticipant => {
+                "ranged musig() participant with derivation after musig()"
+            }
+            InvalidKeyReason::HardenedDerivation => "hardened derivation after musig()",
+            InvalidKeyReason::InvalidAggregate => "invalid musig() aggregate key",
+        })
+    }
+}
+
 #[cfg_attr(feature = "debug", derive(Debug))]
 #[derive(Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -157,6 +490,8 @@ struct ExtendedKey {
     pub path: bip32::DerivationPath,
     pub wildcard: Wildcard,
     pub x_only: bool,
+    /// Where the key is written in the descriptor
+    pub span: Span,
 }
 
 impl ExtendedKey {
@@ -165,21 +500,28 @@ impl ExtendedKey {
         self.raw.clone()
     }
 
-    pub fn derive(&self, index: u32) -> Result<DefiniteKeyToken, String> {
-        let secp = secp256k1::Secp256k1::new();
-
+    pub fn derive_with<C: secp256k1::Verification>(
+        &self,
+        secp: &secp256k1::Secp256k1<C>,
+        index: u32,
+    ) -> Result<DefiniteKeyToken, DeriveError> {
         let mut path = self.path.clone();
         if let Wildcard::Normal = self.wildcard {
-            path = path.child(
-                bip32::ChildNumber::from_normal_idx(index)
-                    .map_err(|e| alloc::format!("{:?}", e))?,
-            );
+            path = path.child(bip32::ChildNumber::from_normal_idx(index).map_err(|_| {
+                DeriveError::InvalidChildIndex {
+                    index,
+                    span: self.span,
+                }
+            })?);
         }
 
         let pubkey = self
             .key
-            .derive_pub(&secp, &path)
-            .map_err(|e| alloc::format!("{:?}", e))?;
+            .derive_pub(secp, &path)
+            .map_err(|error| DeriveError::Bip32 {
+                error,
+                span: self.span,
+            })?;
 
         if self.x_only {
             Ok(DefiniteKeyToken::XOnlyPublicKey(
@@ -219,6 +561,289 @@ impl core::fmt::Display for ExtendedKey {
     }
 }
 
+/// The x coordinate of the BIP-341 NUMS point `H`, the hash of the uncompressed generator.
+/// Nobody knows its discrete logarithm, so no signature exists for it.
+const NUMS_H: [u8; 32] = [
+    0x50, 0x92, 0x9b, 0x74, 0xc1, 0xa0, 0x49, 0x54, 0xb7, 0x8b, 0x4b, 0x60, 0x35, 0xe9, 0x7a, 0x5e,
+    0x07, 0x8a, 0x5a, 0x0f, 0x28, 0xec, 0x96, 0xd5, 0x47, 0xbf, 0xee, 0x9a, 0xce, 0x80, 0x3a, 0xc0,
+];
+
+/// `H` with an even y coordinate, as BIP-341 lifts it.
+fn nums_point() -> secp256k1::PublicKey {
+    let key = secp256k1::XOnlyPublicKey::from_slice(&NUMS_H).expect("H is on the curve");
+    secp256k1::PublicKey::from_x_only_public_key(key, secp256k1::Parity::Even)
+}
+
+/// The chain code BIP-328 gives the aggregate key to derive children from it.
+const MUSIG_CHAIN_CODE: [u8; 32] = [
+    0x86, 0x80, 0x87, 0xca, 0x02, 0xa6, 0xf9, 0x74, 0xc4, 0x59, 0x89, 0x24, 0xc3, 0x6b, 0x57, 0x76,
+    0x2d, 0x32, 0xcb, 0x45, 0x71, 0x71, 0x67, 0xe3, 0x00, 0x62, 0x2c, 0x71, 0x67, 0xe3, 0x89, 0x65,
+];
+
+/// A `musig(KEY,KEY,...)` key expression, optionally followed by an unhardened
+/// derivation of the aggregate key.
+#[cfg_attr(feature = "debug", derive(Debug))]
+#[derive(Clone)]
+pub(crate) struct MuSigKey {
+    participants: Vec<KeyToken>,
+    /// The aggregate of the participants, unless one of them is ranged
+    aggregate: Option<secp256k1::PublicKey>,
+    path: bip32::DerivationPath,
+    wildcard: Wildcard,
+    /// Where the whole expression is written in the descriptor
+    span: Span,
+}
+
+impl MuSigKey {
+    fn identifier(&self) -> String {
+        let participants = self
+            .participants
+            .iter()
+            .map(KeyToken::identifier)
+            .collect::<Vec<_>>()
+            .join(",");
+        alloc::format!(
+            "musig({participants}){}{}{}",
+            if self.path.is_empty() { "" } else { "/" },
+            self.path,
+            self.wildcard
+        )
+    }
+
+    fn as_definite_key(&self) -> Option<DefiniteKeyToken> {
+        match self.aggregate {
+            Some(aggregate) if self.path.is_empty() && self.wildcard == Wildcard::None => Some(
+                DefiniteKeyToken::XOnlyPublicKey(bitcoin::XOnlyPublicKey::from(aggregate)),
+            ),
+            _ => None,
+        }
+    }
+
+    fn derive_with<C: secp256k1::Verification>(
+        &self,
+        secp: &secp256k1::Secp256k1<C>,
+        index: u32,
+    ) -> Result<DefiniteKeyToken, DeriveError> {
+        let aggregate = match self.aggregate {
+            Some(aggregate) => aggregate,
+            None => {
+                let keys = self
+                    .participants
+                    .iter()
+                    .map(|key| participant_key(&key.derive_with(secp, index)?, secp, self.span))
+                    .collect::<Result<Vec<_>, _>>()?;
+                key_agg(secp, keys).ok_or(DeriveError::InvalidAggregate { span: self.span })?
+            }
+        };
+
+        let mut path = self.path.clone();
+        if let Wildcard::Normal = self.wildcard {
+            path = path.child(bip32::ChildNumber::from_normal_idx(index).map_err(|_| {
+                DeriveError::InvalidChildIndex {
+                    index,
+                    span: self.span,
+                }
+            })?);
+        }
+
+        let key = if path.is_empty() {
+            aggregate
+        } else {
+            let xpub = bip32::Xpub {
+                network: bitcoin::NetworkKind::Main,
+                depth: 0,
+                parent_fingerprint: Default::default(),
+                child_number: bip32::ChildNumber::Normal { index: 0 },
+                public_key: aggregate,
+                chain_code: bip32::ChainCode::from(MUSIG_CHAIN_CODE),
+            };
+            xpub.derive_pub(secp, &path)
+                .map_err(|error| DeriveError::Bip32 {
+                    error,
+                    span: self.span,
+                })?
+                .public_key
+        };
+        Ok(DefiniteKeyToken::XOnlyPublicKey(
+            bitcoin::XOnlyPublicKey::from(key),
+        ))
+    }
+}
+
+/// The full public key of a non-ranged participant of the `musig()` at `span`.
+fn participant_key<C: secp256k1::Verification>(
+    key: &KeyToken,
+    secp: &secp256k1::Secp256k1<C>,
+    span: Span,
+) -> Result<secp256k1::PublicKey, DeriveError> {
+    match &key.inner {
+        KeyTokenInner::PublicKey(pk) => Ok(pk.inner),
+        KeyTokenInner::ExtendedKey(ext) => Ok(ext
+            .key
+            .derive_pub(secp, &ext.path)
+            .map_err(|error| DeriveError::Bip32 {
+                error,
+                span: ext.span,
+            })?
+            .public_key),
+        // participants are parsed as full keys and never nest
+        KeyTokenInner::XOnlyPublicKey(_) | KeyTokenInner::MuSig(_) => {
+            Err(DeriveError::InvalidAggregate { span })
+        }
+    }
+}
+
+fn tagged_hash(tag: &str, data: &[&[u8]]) -> [u8; 32] {
+    let tag = sha256::Hash::hash(tag.as_bytes());
+    let mut engine = sha256::Hash::engine();
+    engine.input(tag.as_ref());
+    engine.input(tag.as_ref());
+    for data in data {
+        engine.input(data);
+    }
+    sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+/// Reduce a hash modulo the curve order. A hash is less than twice the order, so
+/// subtracting it once is enough.
+fn hash_to_scalar(mut hash: [u8; 32]) -> secp256k1::Scalar {
+    if let Ok(scalar) = secp256k1::Scalar::from_be_bytes(hash) {
+        return scalar;
+    }
+    let mut borrow = 0;
+    for (byte, order) in hash.iter_mut().zip(secp256k1::constants::CURVE_ORDER).rev() {
+        let difference = *byte as i16 - order as i16 - borrow;
+        *byte = difference.rem_euclid(256) as u8;
+        borrow = (difference < 0) as i16;
+    }
+    secp256k1::Scalar::from_be_bytes(hash).expect("reduced below the curve order")
+}
+
+/// BIP-327 KeyAgg of the participant keys, in the order given. `None` if the tweaked keys
+/// add up to the point at infinity.
+fn key_agg_unsorted<C: secp256k1::Verification>(
+    secp: &secp256k1::Secp256k1<C>,
+    keys: &[secp256k1::PublicKey],
+) -> Option<secp256k1::PublicKey> {
+    let serialized = keys.iter().map(|key| key.serialize()).collect::<Vec<_>>();
+    let list = tagged_hash(
+        "KeyAgg list",
+        &serialized.iter().map(|key| &key[..]).collect::<Vec<_>>(),
+    );
+    // the first key that differs from the first one gets a coefficient of one
+    let second = serialized.iter().find(|key| **key != serialized[0]);
+
+    let tweaked = serialized
+        .iter()
+        .zip(keys)
+        .map(|(serialized, key)| {
+            if Some(serialized) == second {
+                return Some(*key);
+            }
+            let coefficient = tagged_hash("KeyAgg coefficient", &[&list, serialized]);
+            key.mul_tweak(secp, &hash_to_scalar(coefficient)).ok()
+        })
+        .collect::<Option<Vec<_>>>()?;
+    secp256k1::PublicKey::combine_keys(&tweaked.iter().collect::<Vec<_>>()).ok()
+}
+
+/// BIP-327 KeyAgg of the participant keys after KeySort, so the aggregate does not
+/// depend on the order the participants are written in.
+fn key_agg<C: secp256k1::Verification>(
+    secp: &secp256k1::Secp256k1<C>,
+    mut keys: Vec<secp256k1::PublicKey>,
+) -> Option<secp256k1::PublicKey> {
+    keys.sort_by_key(|key| key.serialize());
+    key_agg_unsorted(secp, &keys)
+}
+
+/// Parse a `musig(KEY,KEY,...)` key expression from its participants and the
+/// derivation following it, if any.
+pub(crate) fn parse_musig<'a>(
+    musig: (&'a str, Position),
+    participants: &[(&'a str, Position)],
+    derivation: Option<(&'a str, Position)>,
+    descriptor: &Descriptor,
+) -> Result<KeyToken, ParseError<'a>> {
+    let invalid = |(key, position): (&'a str, Position), inner| ParseError::InvalidKey {
+        key,
+        position,
+        inner,
+    };
+    if *descriptor != Descriptor::Tr {
+        return Err(invalid(musig, InvalidKeyReason::MuSigOutsideTaproot));
+    }
+
+    // participants are aggregated as full keys, only the aggregate is x-only
+    let tokens = participants;
+    let participants = tokens
+        .iter()
+        .map(|token| {
+            let key = parse_key(*token, &Descriptor::Wsh)?;
+            if !key.is_compressed() {
+                return Err(invalid(*token, InvalidKeyReason::UncompressedParticipant));
+            }
+            Ok(key)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut wildcard = Wildcard::None;
+    let path = match derivation {
+        Some(token) => {
+            if let Some(index) = participants.iter().position(KeyToken::is_ranged) {
+                return Err(invalid(tokens[index], InvalidKeyReason::RangedParticipant));
+            }
+            let mut path_str = alloc::format!("m{}", token.0);
+            if path_str.ends_with("/*") {
+                wildcard = Wildcard::Normal;
+                path_str.truncate(path_str.len() - 2);
+            } else if path_str.ends_with("/*'") || path_str.ends_with("/*h") {
+                return Err(invalid(token, InvalidKeyReason::HardenedWildcard));
+            }
+            let path = bip32::DerivationPath::from_str(&path_str)
+                .map_err(|_| invalid(token, InvalidKeyReason::InvalidPath))?;
+            if path.into_iter().any(bip32::ChildNumber::is_hardened) {
+                return Err(invalid(token, InvalidKeyReason::HardenedDerivation));
+            }
+            path
+        }
+        None => Default::default(),
+    };
+
+    // from `musig` to the closing parenthesis or the end of the derivation
+    let end = match derivation {
+        Some((token, column)) => column + token.len() as Position,
+        None => {
+            tokens
+                .last()
+                .map_or(musig.1, |(token, column)| column + token.len() as Position)
+                + 1
+        }
+    };
+    let span = Span::from_columns(musig.1, end);
+
+    let aggregate = if participants.iter().any(KeyToken::is_ranged) {
+        None
+    } else {
+        let secp = secp256k1::Secp256k1::verification_only();
+        let keys = participants
+            .iter()
+            .map(|key| participant_key(key, &secp, span).ok())
+            .collect::<Option<Vec<_>>>()
+            .and_then(|keys| key_agg(&secp, keys))
+            .ok_or_else(|| invalid(musig, InvalidKeyReason::InvalidAggregate))?;
+        Some(keys)
+    };
+
+    Ok(KeyToken::new(KeyTokenInner::MuSig(MuSigKey {
+        participants,
+        aggregate,
+        path,
+        wildcard,
+        span,
+    })))
+}
+
 pub fn parse_key<'a>(
     token: (&'a str, Position),
     descriptor: &Descriptor,
@@ -237,7 +862,7 @@ pub fn parse_key<'a>(
                 return Err(ParseError::InvalidKey {
                     key: token.0,
                     position: token.1,
-                    inner: "Invalid format: missing closing square bracket",
+                    inner: InvalidKeyReason::MissingClosingBracket,
                 });
             }
 
@@ -247,7 +872,7 @@ pub fn parse_key<'a>(
                 return Err(ParseError::InvalidKey {
                     key: token.0,
                     position: token.1,
-                    inner: "Invalid origin format",
+                    inner: InvalidKeyReason::InvalidOrigin,
                 });
             }
 
@@ -258,7 +883,7 @@ pub fn parse_key<'a>(
                     ParseError::InvalidKey {
                         key: token.0,
                         position: token.1,
-                        inner: "Invalid origin fingerprint",
+                        inner: InvalidKeyReason::InvalidFingerprint,
                     }
                 })?);
 
@@ -270,7 +895,7 @@ pub fn parse_key<'a>(
                     |_| ParseError::InvalidKey {
                         key: token.0,
                         position: token.1,
-                        inner: "Invalid origin path",
+                        inner: InvalidKeyReason::InvalidOriginPath,
                     },
                 )?);
             }
@@ -281,7 +906,7 @@ pub fn parse_key<'a>(
         };
 
         let mut wildcard = Wildcard::None;
-        let x_only = *descriptor == Descriptor::Tr;
+        let x_only = matches!(descriptor, Descriptor::Tr | Descriptor::RawTr);
 
         let parts = remaining.splitn(2, '/').collect::<Vec<&str>>();
         let key_part = parts[0];
@@ -298,7 +923,7 @@ pub fn parse_key<'a>(
                     return Err(ParseError::InvalidKey {
                         key: token.0,
                         position: token.1,
-                        inner: "Invalid format: hardened wildcard not allowed",
+                        inner: InvalidKeyReason::HardenedWildcard,
                     });
                 }
 
@@ -310,7 +935,7 @@ pub fn parse_key<'a>(
         let key = bip32::Xpub::from_str(key_part).map_err(|_| ParseError::InvalidKey {
             key: token.0,
             position: token.1,
-            inner: "Invalid xpub",
+            inner: InvalidKeyReason::InvalidXpub,
         })?;
 
         // Parse the path
@@ -319,7 +944,7 @@ pub fn parse_key<'a>(
                 bip32::DerivationPath::from_str(&path_str).map_err(|_| ParseError::InvalidKey {
                     key: token.0,
                     position: token.1,
-                    inner: "Invalid path",
+                    inner: InvalidKeyReason::InvalidPath,
                 })?
             }
             None => Default::default(),
@@ -336,15 +961,32 @@ pub fn parse_key<'a>(
             path,
             wildcard,
             x_only,
+            span: Span::from_token(token.0, token.1),
         };
-        return Ok(KeyToken {
-            inner: KeyTokenInner::ExtendedKey(key),
-        });
+        return Ok(KeyToken::new(KeyTokenInner::ExtendedKey(key)));
     }
 
     // Get the key type based on the inner descriptor
     let key = match descriptor {
-        Descriptor::Tr => {
+        // the output key of rawtr() is usually only known in its x-only form
+        Descriptor::RawTr if token.0.len() == 64 => {
+            let key = bitcoin::XOnlyPublicKey::from_str(token.0).map_err(|_| {
+                ParseError::InvalidXOnlyKey {
+                    key: token.0,
+                    position: token.1,
+                }
+            })?;
+            KeyTokenInner::XOnlyPublicKey(key)
+        }
+        // serializing the x-only form would silently change the descriptor
+        Descriptor::RawTr if token.0.len() == 130 => {
+            return Err(ParseError::InvalidKey {
+                key: token.0,
+                position: token.1,
+                inner: InvalidKeyReason::UncompressedOutputKey,
+            });
+        }
+        Descriptor::Tr | Descriptor::RawTr => {
             // rust miniscript does not parse directly to xonly key
             // so we need to parse to pubkey and then convert to xonly key
 
@@ -362,7 +1004,7 @@ pub fn parse_key<'a>(
                 bitcoin::PublicKey::from_str(token.0).map_err(|_| ParseError::InvalidKey {
                     key: token.0,
                     position: token.1,
-                    inner: "Invalid bitcoin::PublicKey key",
+                    inner: InvalidKeyReason::InvalidPublicKey,
                 })?;
             KeyTokenInner::XOnlyPublicKey(pub_key.into())
         }
@@ -371,18 +1013,19 @@ pub fn parse_key<'a>(
                 bitcoin::PublicKey::from_str(token.0).map_err(|_| ParseError::InvalidKey {
                     key: token.0,
                     position: token.1,
-                    inner: "Invalid bitcoin::PublicKey key",
+                    inner: InvalidKeyReason::InvalidPublicKey,
                 })?;
             KeyTokenInner::PublicKey(pub_key)
         }
     };
 
-    Ok(KeyToken { inner: key })
+    Ok(KeyToken::new(key))
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::utils::fixtures::{A, B};
 
     #[test]
     fn test_parse_key() {
@@ -393,6 +1036,155 @@ mod test {
         dbg!(&derived);
     }
 
+    #[test]
+    fn test_key_origin() {
+        let key = "[aabbccdd/10'/123]tpubDAenfwNu5GyCJWv8oqRAckdKMSUoZjgVF5p8WvQwHQeXjDhAHmGrPa4a4y2Fn7HF2nfCLefJanHV3ny1UY25MRVogizB2zRUdAo7Tr9XAjm/10/*";
+        let key = parse_key((key, 0), &Descriptor::Wpkh).unwrap();
+        let fingerprint = bip32::Fingerprint::from_str("aabbccdd").unwrap();
+
+        assert_eq!(key.master_fingerprint(), Some(fingerprint));
+        assert_eq!(key.origin().unwrap().1.to_string(), "10'/123");
+        assert_eq!(key.child_path().unwrap().to_string(), "10");
+        assert_eq!(key.wildcard(), Wildcard::Normal);
+        assert_eq!(
+            key.full_derivation_path(7).unwrap().unwrap().to_string(),
+            "10'/123/10/7"
+        );
+        assert!(key.full_derivation_path(1 << 31).is_err());
+
+        let single = parse_key((A, 0), &Descriptor::Wpkh).unwrap();
+        assert!(single.master_fingerprint().is_none() && single.xpub().is_none());
+        assert_eq!(single.wildcard(), Wildcard::None);
+        assert_eq!(single.full_derivation_path(7).unwrap(), None);
+    }
+
+    #[test]
+    fn test_key_agg_vectors() {
+        // BIP-327 KeyAgg test vectors, aggregated in the given order
+        let keys = [
+            "02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+            "03DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+            "023590A94E768F8E1815C2F24B4D80A8E3149316C3518CE7B7AD338368D038CA66",
+        ]
+        .map(|key| secp256k1::PublicKey::from_str(key).unwrap());
+        let secp = secp256k1::Secp256k1::verification_only();
+        for (order, expected) in [
+            (
+                &[0, 1, 2][..],
+                "90539EEDE565F5D054F32CC0C220126889ED1E5D193BAF15AEF344FE59D4610C",
+            ),
+            (
+                &[2, 1, 0],
+                "6204DE8B083426DC6EAF9502D27024D53FC826BF7D2012148A0575435DF54B2B",
+            ),
+            (
+                &[0, 0, 0],
+                "B436E3BAD62B8CD409969A224731C193D051162D8C5AE8B109306127DA3AA935",
+            ),
+            (
+                &[0, 0, 1, 1],
+                "69BC22BFA5D106306E48A20679DE1D7389386124D07571D0D872686028C26A3E",
+            ),
+        ] {
+            let keys = order.iter().map(|i| keys[*i]).collect::<Vec<_>>();
+            let aggregate = key_agg_unsorted(&secp, &keys).unwrap();
+            assert_eq!(
+                bitcoin::XOnlyPublicKey::from(aggregate).to_string(),
+                expected.to_lowercase()
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_musig() {
+        fn parse<'a>(
+            participants: &[&'a str],
+            derivation: Option<(&'a str, Position)>,
+        ) -> Result<KeyToken, ParseError<'a>> {
+            let participants = participants.iter().map(|key| (*key, 0)).collect::<Vec<_>>();
+            parse_musig(("musig", 0), &participants, derivation, &Descriptor::Tr)
+        }
+
+        // the aggregate does not depend on the order of the participants
+        let key = parse(&[A, B], None).unwrap();
+        assert_eq!(key, parse(&[A, B], None).unwrap());
+        assert_eq!(
+            key.as_definite_key().unwrap().to_bytes(),
+            parse(&[B, A], None)
+                .unwrap()
+                .as_definite_key()
+                .unwrap()
+                .to_bytes()
+        );
+        assert_eq!(key.identifier(), alloc::format!("musig({A},{B})"));
+
+        let ranged = parse(&[A, B], Some(("/1/*", 0))).unwrap();
+        assert!(ranged.is_ranged() && ranged.as_definite_key().is_none());
+        assert_eq!(ranged.identifier(), alloc::format!("musig({A},{B})/1/*"));
+        assert_eq!(
+            ranged.derive(3).unwrap(),
+            parse(&[A, B], Some(("/1/3", 0)))
+                .unwrap()
+                .derive(0)
+                .unwrap()
+        );
+
+        let xpub = "tpubDAenfwNu5GyCJWv8oqRAckdKMSUoZjgVF5p8WvQwHQeXjDhAHmGrPa4a4y2Fn7HF2nfCLefJanHV3ny1UY25MRVogizB2zRUdAo7Tr9XAjm/0/*";
+        let participant = parse(&[A, xpub], None).unwrap();
+        assert!(participant.is_ranged());
+        let derived = parse_key((xpub, 0), &Descriptor::Wsh)
+            .unwrap()
+            .derive(5)
+            .unwrap();
+        let derived = derived.identifier();
+        assert_eq!(
+            participant.derive(5).unwrap(),
+            parse(&[A, &derived], None).unwrap().derive(0).unwrap()
+        );
+
+        let reason = |result: Result<KeyToken, ParseError>| match result {
+            Err(ParseError::InvalidKey { inner, .. }) => inner,
+            _ => panic!("expected an invalid key"),
+        };
+        assert_eq!(
+            reason(parse(&[A, xpub], Some(("/0", 0)))),
+            InvalidKeyReason::RangedParticipant
+        );
+        assert_eq!(
+            reason(parse(&[A, B], Some(("/0'", 0)))),
+            InvalidKeyReason::HardenedDerivation
+        );
+        assert_eq!(
+            reason(parse_musig(("musig", 0), &[(A, 0)], None, &Descriptor::Wsh)),
+            InvalidKeyReason::MuSigOutsideTaproot
+        );
+    }
+
+    #[test]
+    fn test_nums() {
+        let nums = KeyToken::nums();
+        assert!(nums.is_nums());
+        assert_eq!(
+            nums.identifier(),
+            "0250929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0"
+        );
+        assert!(
+            parse_key((&nums.identifier(), 0), &Descriptor::Tr)
+                .unwrap()
+                .is_nums()
+        );
+        assert!(!parse_key((A, 0), &Descriptor::Tr).unwrap().is_nums());
+
+        // derived keys are no longer `H` but are still known to be unspendable
+        let xpub = KeyToken::nums_xpub([1; 32], bitcoin::NetworkKind::Main);
+        let derived = xpub.derive(3).unwrap();
+        assert!(xpub.is_ranged() && derived.is_nums());
+        assert_ne!(derived.as_definite_key().unwrap().to_bytes(), NUMS_H);
+        let parsed = parse_key((&xpub.identifier(), 0), &Descriptor::Tr).unwrap();
+        assert!(parsed.is_nums() && parsed == xpub);
+        assert_eq!(parsed.derive(3).unwrap(), derived);
+    }
+
     #[test]
     fn test_parse_xonly_key() {
         let key = "020202020202020212131610202020202121316121618171818121715181919190";
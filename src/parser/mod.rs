@@ -2,13 +2,18 @@ pub mod keys;
 
 use core::str::FromStr;
 
+use bitcoin::address::NetworkUnchecked;
+use bitcoin::{Address, ScriptBuf};
+
 use crate::context::Context;
-use crate::parser::keys::{KeyToken, KeyTokenInner};
+use crate::descriptor::{Descriptor, ScriptContext};
+use crate::error::{Diagnostic, Span};
+use crate::parser::keys::{InvalidKeyReason, KeyToken, KeyTokenInner};
 use crate::utils::checksum;
-use crate::{Vec, descriptor::Descriptor};
+use crate::{Vec, limits};
 
 // Position
-pub type Position = u16;
+pub type Position = u32;
 
 // AST
 
@@ -16,10 +21,48 @@ pub type Position = u16;
 #[derive(Clone)]
 pub struct AST {
     pub position: Position,
+    /// The column right after the last token of the fragment
+    pub end: Position,
     pub fragment: Fragment,
 }
 
-pub type NodeIndex = u16;
+impl AST {
+    /// Get the byte range of the fragment in the original input.
+    #[inline]
+    pub fn span(&self) -> Span {
+        Span::from_columns(self.position, self.end)
+    }
+}
+
+pub type NodeIndex = u32;
+
+/// Index of a key in the key table of the [`Context`].
+pub type KeyIndex = u32;
+
+/// The sub-expressions of `thresh()`, or the keys of `multi()` and `multi_a()`, stored in
+/// the list table of the [`Context`]. See [`Context::list`].
+#[cfg_attr(feature = "debug", derive(Debug))]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct List {
+    start: u32,
+    len: u32,
+}
+
+impl List {
+    /// Get the number of sub-expressions or keys.
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Get the indices in the list table.
+    pub(crate) fn range(&self) -> core::ops::Range<usize> {
+        self.start as usize..(self.start + self.len) as usize
+    }
+}
 
 #[cfg_attr(feature = "debug", derive(Debug))]
 #[derive(Clone)]
@@ -33,11 +76,11 @@ pub enum Fragment {
     // Key Fragments
     /// pk_k(key)
     PkK {
-        key: KeyToken,
+        key: KeyIndex,
     },
     /// pk_h(key)
     PkH {
-        key: KeyToken,
+        key: KeyIndex,
     },
 
     // Time fragments
@@ -113,19 +156,19 @@ pub enum Fragment {
     /// thresh(k,X1,...,Xn)
     Thresh {
         k: i32,
-        xs: Vec<NodeIndex>,
+        xs: List,
     },
     ///  multi(k,key1,...,keyn)
     /// (P2WSH only)
     Multi {
         k: i32,
-        keys: Vec<KeyToken>,
+        keys: List,
     },
     /// multi_a(k,key1,...,keyn)
     /// (Tapscript only)
     MultiA {
         k: i32,
-        keys: Vec<KeyToken>,
+        keys: List,
     },
 
     Identity {
@@ -140,19 +183,55 @@ pub enum Fragment {
     },
 
     RawPkH {
-        key: KeyToken,
+        key: KeyIndex,
     },
 
     RawTr {
-        key: KeyToken,
+        key: KeyIndex,
         inner: Option<NodeIndex>,
     },
 
     RawPk {
-        key: KeyToken,
+        key: KeyIndex,
+    },
+
+    /// The address of `addr(ADDR)`
+    RawAddr {
+        address: Address<NetworkUnchecked>,
+    },
+    /// The script pubkey of `raw(HEX)`
+    RawScript {
+        script: ScriptBuf,
     },
 }
 
+impl Fragment {
+    /// Indices of the direct sub-expressions of the fragment, reading `thresh()` ones
+    /// from the list table `lists`.
+    ///
+    /// Sub-expressions are always added to the context before the fragment using them,
+    /// so every child index is lower than the index of its parent.
+    pub(crate) fn children<'c>(
+        &'c self,
+        lists: &'c [u32],
+    ) -> impl DoubleEndedIterator<Item = NodeIndex> + 'c {
+        let (fixed, rest): ([Option<NodeIndex>; 3], &[NodeIndex]) = match self {
+            Fragment::AndOr { x, y, z } => ([Some(*x), Some(*y), Some(*z)], &[]),
+            Fragment::AndV { x, y } | Fragment::AndB { x, y } => ([Some(*x), Some(*y), None], &[]),
+            Fragment::OrB { x, z }
+            | Fragment::OrC { x, z }
+            | Fragment::OrD { x, z }
+            | Fragment::OrI { x, z } => ([Some(*x), Some(*z), None], &[]),
+            Fragment::Thresh { xs, .. } => ([None; 3], &lists[xs.range()]),
+            Fragment::Identity { x, .. } => ([Some(*x), None, None], &[]),
+            Fragment::Descriptor { inner, .. } => ([Some(*inner), None, None], &[]),
+            Fragment::RawTr { inner, .. } => ([*inner, None, None], &[]),
+            _ => ([None; 3], &[]),
+        };
+        fixed.into_iter().flatten().chain(rest.iter().copied())
+    }
+}
+
 #[derive(PartialEq, Clone)]
 #[repr(u8)]
 pub enum IdentityType {
@@ -167,6 +246,12 @@ pub enum IdentityType {
 
 #[cfg(feature = "debug")]
 impl core::fmt::Debug for IdentityType {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Display::fmt(self, f)
+    }
+}
+
+impl core::fmt::Display for IdentityType {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             IdentityType::A => write!(f, "a"),
@@ -180,49 +265,106 @@ impl core::fmt::Debug for IdentityType {
     }
 }
 
-// Optimized tokenization using string slices instead of owned strings
-fn split_string_with_columns<'a, F>(s: &'a str, is_separator: F) -> Vec<(&'a str, Position)>
-where
-    F: Fn(char) -> bool,
-{
-    // Pre-allocate with estimated capacity to reduce reallocations
-    let estimated_tokens = s.len() / 3 + 1; // Rough estimate
-    let mut result: Vec<(&'a str, Position)> = Vec::with_capacity(estimated_tokens);
-    let mut char_indices = s.char_indices().peekable();
-    let mut start = 0;
-    let mut column = 1;
-
-    while let Some((i, c)) = char_indices.peek().copied() {
-        if is_separator(c) {
-            if start < i {
-                // Push the slice before the separator
-                let part = &s[start..i];
-                result.push((part, column));
-                column += part.chars().count() as u16;
-            }
+/// Streaming tokenizer over the input, splitting it at `(`, `)`, `,` and `:`.
+///
+/// The state is a pair of offsets, so it can be copied to look ahead or to backtrack
+/// without buffering the tokens. The input must be ASCII, so that byte offsets are columns.
+#[derive(Clone, Copy)]
+struct Lexer<'a> {
+    input: &'a str,
+    offset: usize,
+    /// The column right after the last token returned
+    end: Position,
+}
 
-            // Push the separator itself
-            result.push((&s[i..i + c.len_utf8()], column));
-            column += 1;
-            char_indices.next();
-            start = i + c.len_utf8();
-        } else {
-            char_indices.next();
+impl<'a> Lexer<'a> {
+    fn new(input: &'a str) -> Self {
+        Self {
+            input,
+            offset: 0,
+            end: 1,
         }
     }
 
-    if start < s.len() {
-        let part = &s[start..];
-        result.push((part, column));
+    #[inline]
+    fn is_separator(b: u8) -> bool {
+        matches!(b, b'(' | b')' | b',' | b':')
+    }
+
+    /// Get the state of the lexer, to come back to it with [`Lexer::seek`].
+    fn cursor(&self) -> Cursor {
+        Cursor {
+            offset: self.offset,
+            end: self.end,
+        }
     }
 
-    result
+    fn seek(&mut self, cursor: Cursor) {
+        self.offset = cursor.offset;
+        self.end = cursor.end;
+    }
 }
 
-#[cfg_attr(feature = "debug", derive(Debug))]
+/// The state of a [`Lexer`] without the input it borrows.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Cursor {
+    offset: usize,
+    end: Position,
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = (&'a str, Position);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = &self.input[self.offset..];
+        let first = *rest.as_bytes().first()?;
+        let len = if Self::is_separator(first) {
+            1
+        } else {
+            rest.bytes()
+                .position(Self::is_separator)
+                .unwrap_or(rest.len())
+        };
+
+        let column = self.offset as Position + 1;
+        self.offset += len;
+        self.end = column + len as Position;
+        Some((&rest[..len], column))
+    }
+}
+
+/// What the parser was reading when the input ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseContext {
+    /// The top level descriptor, e.g. `wsh(...)`
+    Descriptor,
+    /// A key expression
+    Key,
+    /// A miniscript expression
+    Expression,
+    /// Wrappers such as `v:`
+    Wrapper,
+    /// The arguments of a fragment, named as written in the descriptor
+    Fragment(&'static str),
+}
+
+impl core::fmt::Display for ParseContext {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ParseContext::Descriptor => write!(f, "the descriptor"),
+            ParseContext::Key => write!(f, "a key"),
+            ParseContext::Expression => write!(f, "an expression"),
+            ParseContext::Wrapper => write!(f, "wrappers"),
+            ParseContext::Fragment(name) => write!(f, "{name}()"),
+        }
+    }
+}
+
+#[derive(Debug)]
 pub enum ParseError<'a> {
     UnexpectedEof {
-        context: &'static str,
+        context: ParseContext,
+        position: Position,
     },
     UnexpectedToken {
         expected: &'static str,
@@ -231,7 +373,7 @@ pub enum ParseError<'a> {
     InvalidKey {
         key: &'a str,
         position: Position,
-        inner: &'static str,
+        inner: InvalidKeyReason,
     },
     InvalidXOnlyKey {
         key: &'a str,
@@ -252,12 +394,16 @@ pub enum ParseError<'a> {
     MultiColon {
         position: Position,
     },
-    InvalidChecksum,
+    InvalidChecksum {
+        found: (&'a str, Position),
+    },
     InvalidAbsoluteLocktime {
         locktime: u32,
         position: Position,
     },
-    NonAscii,
+    NonAscii {
+        position: Position,
+    },
     InvalidHex {
         position: Position,
     },
@@ -266,51 +412,435 @@ pub enum ParseError<'a> {
         found: usize,
         position: Position,
     },
+    TooManyFragments {
+        max_fragments: usize,
+        position: Position,
+    },
+    MaxDepthExceeded {
+        max_depth: usize,
+        position: Position,
+    },
+    InputTooLong {
+        length: usize,
+        max_length: usize,
+    },
+    InvalidAddress {
+        address: &'a str,
+        position: Position,
+    },
+    /// A table of an [`Arena`](crate::arena::Arena) is full
+    CapacityExceeded {
+        table: Table,
+        capacity: usize,
+        position: Position,
+    },
+    /// The token cannot be parsed into an [`Arena`](crate::arena::Arena), e.g. an
+    /// extended key
+    NeedsAllocator {
+        found: (&'a str, Position),
+    },
+}
+
+/// A table of the parsed descriptor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Table {
+    /// The fragments, see [`Context::get_nodes`]
+    Nodes,
+    /// The distinct keys, see [`Context::get_keys`]
+    Keys,
+    /// The sub-expressions of `thresh()` and the keys of `multi()` and `multi_a()`, see
+    /// [`Context::list`]
+    Lists,
+}
+
+impl core::fmt::Display for Table {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Table::Nodes => write!(f, "node"),
+            Table::Keys => write!(f, "key"),
+            Table::Lists => write!(f, "list"),
+        }
+    }
+}
+
+impl core::fmt::Display for ParseError<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ParseError::UnexpectedEof { context, .. } => {
+                write!(f, "unexpected end of input while parsing {context}")
+            }
+            ParseError::UnexpectedToken { expected, found } => {
+                write!(f, "expected {expected}, found '{}'", found.0)
+            }
+            ParseError::InvalidKey { key, inner, .. } => write!(f, "invalid key '{key}': {inner}"),
+            ParseError::InvalidXOnlyKey { key, .. } => write!(f, "invalid x-only key '{key}'"),
+            ParseError::InvalidXOnlyKeyLength { key, found, .. } => write!(
+                f,
+                "invalid key '{key}': expected 66 or 130 hex characters, found {found}"
+            ),
+            ParseError::UnexpectedTrailingToken { found } => {
+                write!(f, "unexpected trailing token '{}'", found.0)
+            }
+            ParseError::UnknownWrapper { found, .. } => write!(f, "unknown wrapper '{found}'"),
+            ParseError::MultiColon { .. } => {
+                write!(f, "wrappers cannot be separated by multiple colons")
+            }
+            ParseError::InvalidChecksum { found } => write!(f, "invalid checksum '{}'", found.0),
+            ParseError::InvalidAbsoluteLocktime { locktime, .. } => {
+                write!(f, "locktime {locktime} is out of range")
+            }
+            ParseError::NonAscii { .. } => write!(f, "descriptor contains non-ASCII characters"),
+            ParseError::InvalidHex { .. } => write!(f, "invalid hex string"),
+            ParseError::InvalidHexLength {
+                expected, found, ..
+            } => write!(f, "expected {expected} hex characters, found {found}"),
+            ParseError::TooManyFragments { max_fragments, .. } => {
+                write!(f, "descriptor has more than {max_fragments} fragments")
+            }
+            ParseError::MaxDepthExceeded { max_depth, .. } => {
+                write!(f, "fragments are nested more than {max_depth} levels deep")
+            }
+            ParseError::InputTooLong { length, max_length } => {
+                write!(
+                    f,
+                    "descriptor is {length} characters long, at most {max_length} allowed"
+                )
+            }
+            ParseError::InvalidAddress { address, .. } => write!(f, "invalid address '{address}'"),
+            ParseError::CapacityExceeded {
+                table, capacity, ..
+            } => write!(f, "the {table} table is full, it holds {capacity} entries"),
+            ParseError::NeedsAllocator { found } => {
+                write!(f, "'{}' cannot be parsed without an allocator", found.0)
+            }
+        }
+    }
+}
+
+impl core::error::Error for ParseError<'_> {}
+
+impl Diagnostic for ParseError<'_> {
+    fn code(&self) -> &'static str {
+        match self {
+            ParseError::UnexpectedEof { .. } => "P001",
+            ParseError::UnexpectedToken { .. } => "P002",
+            ParseError::InvalidKey { .. } => "P003",
+            ParseError::InvalidXOnlyKey { .. } => "P004",
+            ParseError::InvalidXOnlyKeyLength { .. } => "P005",
+            ParseError::UnexpectedTrailingToken { .. } => "P006",
+            ParseError::UnknownWrapper { .. } => "P007",
+            ParseError::MultiColon { .. } => "P008",
+            ParseError::InvalidChecksum { .. } => "P009",
+            ParseError::InvalidAbsoluteLocktime { .. } => "P010",
+            ParseError::NonAscii { .. } => "P011",
+            ParseError::InvalidHex { .. } => "P012",
+            ParseError::InvalidHexLength { .. } => "P013",
+            ParseError::TooManyFragments { .. } => "P014",
+            ParseError::MaxDepthExceeded { .. } => "P015",
+            ParseError::InputTooLong { .. } => "P016",
+            ParseError::InvalidAddress { .. } => "P017",
+            ParseError::CapacityExceeded { .. } => "P018",
+            ParseError::NeedsAllocator { .. } => "P019",
+        }
+    }
+
+    fn span(&self) -> Option<Span> {
+        Some(match self {
+            ParseError::UnexpectedToken { found, .. }
+            | ParseError::UnexpectedTrailingToken { found }
+            | ParseError::InvalidChecksum { found }
+            | ParseError::NeedsAllocator { found } => Span::from_token(found.0, found.1),
+            ParseError::InvalidKey { key, position, .. }
+            | ParseError::InvalidXOnlyKey { key, position }
+            | ParseError::InvalidXOnlyKeyLength { key, position, .. }
+            | ParseError::InvalidAddress {
+                address: key,
+                position,
+            } => Span::from_token(key, *position),
+            ParseError::UnexpectedEof { position, .. }
+            | ParseError::UnknownWrapper { position, .. }
+            | ParseError::MultiColon { position }
+            | ParseError::InvalidAbsoluteLocktime { position, .. }
+            | ParseError::NonAscii { position }
+            | ParseError::InvalidHex { position }
+            | ParseError::InvalidHexLength { position, .. }
+            | ParseError::TooManyFragments { position, .. }
+            | ParseError::CapacityExceeded { position, .. }
+            | ParseError::MaxDepthExceeded { position, .. } => {
+                Span::from_columns(*position, *position + 1)
+            }
+            ParseError::InputTooLong { length, max_length } => Span::new(*max_length, *length),
+        })
+    }
+}
+
+/// A table the parser appends to: a vector, or a fixed buffer of an
+/// [`Arena`](crate::arena::Arena).
+pub(crate) trait Stack<T> {
+    fn as_slice(&self) -> &[T];
+
+    /// Append the value, or fail with the capacity of the full table.
+    fn push(&mut self, value: T) -> Result<(), usize>;
+
+    fn truncate(&mut self, len: usize);
+
+    #[inline]
+    fn len(&self) -> usize {
+        self.as_slice().len()
+    }
+}
+
+impl<T> Stack<T> for Vec<T> {
+    fn as_slice(&self) -> &[T] {
+        self
+    }
+
+    fn push(&mut self, value: T) -> Result<(), usize> {
+        Vec::push(self, value);
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: usize) {
+        Vec::truncate(self, len)
+    }
+}
+
+/// Where the parser stores the tables it builds.
+pub(crate) trait Storage {
+    type Nodes: Stack<AST>;
+    type Keys: Stack<KeyToken>;
+    /// The list table, and the arguments of the fragments being parsed
+    type Indices: Stack<u32>;
+    type Calls: Stack<Call>;
+
+    /// Whether the keys and scripts that are parsed into heap allocated values, such as
+    /// extended keys, `addr()` or `raw()`, are accepted
+    const ALLOCATES: bool;
+}
+
+/// Storage in vectors, growing as needed.
+pub(crate) struct Heap;
+
+impl Storage for Heap {
+    type Nodes = Vec<AST>;
+    type Keys = Vec<KeyToken>;
+    type Indices = Vec<u32>;
+    type Calls = Vec<Call>;
+
+    const ALLOCATES: bool = true;
+}
+
+/// The sizes of the tables before a sub-expression, to drop what it added if it fails
+/// to parse.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Mark {
+    nodes: usize,
+    keys: usize,
+    lists: usize,
 }
 
-struct ParserContext<'a> {
-    tokens: Vec<(&'a str, Position)>,
-    current_token: usize,
-    nodes: Vec<AST>,
+pub(crate) struct ParserContext<'a, S: Storage = Heap> {
+    lexer: Lexer<'a>,
+    eof: Position,
+    nodes: S::Nodes,
+    /// The maximum nesting depth of fragments
+    max_depth: usize,
+    /// The distinct keys, referred to by index from the fragments
+    keys: S::Keys,
+    /// The sub-expressions of `thresh()` and the keys of `multi()` and `multi_a()`
+    lists: S::Indices,
+    /// The fragments whose arguments are being parsed, see [`parse_internal`]
+    calls: S::Calls,
+    /// The arguments parsed so far of the fragments in `calls`
+    args: S::Indices,
 
     inner_descriptor: Descriptor,
+
+    /// Whether errors are collected instead of aborting the parse
+    recover: bool,
+    errors: Vec<ParseError<'a>>,
+    /// Whether the next node added is a placeholder returned by `placeholder`
+    pending_placeholder: bool,
+    /// Indices of the placeholders standing in for the sub-expressions that failed to parse
+    placeholders: Vec<NodeIndex>,
 }
 
 impl<'a> ParserContext<'a> {
     #[inline]
     fn new(input: &'a str) -> Self {
-        let tokens =
-            split_string_with_columns(input, |c| c == '(' || c == ')' || c == ',' || c == ':');
+        Self::with_storage(
+            input,
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+        )
+    }
+
+    fn into_context(self, root: AST, top_level_descriptor: Descriptor) -> Context {
+        Context::new(
+            self.nodes,
+            self.keys,
+            self.lists,
+            root,
+            top_level_descriptor,
+            self.inner_descriptor,
+        )
+    }
+}
+
+impl<'a, S: Storage> ParserContext<'a, S> {
+    #[inline]
+    pub(crate) fn with_storage(
+        input: &'a str,
+        nodes: S::Nodes,
+        keys: S::Keys,
+        lists: S::Indices,
+        calls: S::Calls,
+        args: S::Indices,
+    ) -> Self {
         Self {
-            tokens,
-            current_token: 0,
-            nodes: Vec::new(),
+            lexer: Lexer::new(input),
+            eof: input.len() as Position + 1,
+            nodes,
+            max_depth: ParseOptions::DEFAULT.max_depth,
+            keys,
+            lists,
+            calls,
+            args,
             inner_descriptor: Descriptor::default(),
+            recover: false,
+            errors: Vec::new(),
+            pending_placeholder: false,
+            placeholders: Vec::new(),
+        }
+    }
+
+    /// Get the number of nodes, keys and list entries parsed.
+    pub(crate) fn lens(&self) -> (usize, usize, usize) {
+        (self.nodes.len(), self.keys.len(), self.lists.len())
+    }
+
+    pub(crate) fn inner_descriptor(&self) -> &Descriptor {
+        &self.inner_descriptor
+    }
+
+    // record the error when recovering, otherwise abort with it
+    fn report(&mut self, error: ParseError<'a>) -> Result<(), ParseError<'a>> {
+        if !self.recover {
+            return Err(error);
+        }
+        // an error at the same place as the previous one is a consequence of it,
+        // e.g. the missing `)` after an argument that is cut short
+        let last = self.errors.last().and_then(|last| last.span());
+        if last.is_none() || last != error.span() {
+            self.errors.push(error);
+        }
+        Ok(())
+    }
+
+    fn mark(&self) -> Mark {
+        Mark {
+            nodes: self.nodes.len(),
+            keys: self.keys.len(),
+            lists: self.lists.len(),
+        }
+    }
+
+    // parse a sub-expression, replacing it with a placeholder if it fails while recovering
+    fn parse_argument(
+        &mut self,
+        parse: fn(&mut Self) -> Result<AST, ParseError<'a>>,
+    ) -> Result<AST, ParseError<'a>> {
+        let start = self.lexer.cursor();
+        let mark = self.mark();
+        match parse(self) {
+            Ok(ast) => Ok(ast),
+            Err(e) => {
+                self.report(e)?;
+                Ok(self.placeholder(start, mark, &[",", ")"]))
+            }
         }
     }
 
+    // skip to the end of the sub-expression starting at `start`, i.e. the next
+    // `boundary` token at the same nesting level, and return a placeholder in its place.
+    // The placeholder must be the next node added.
+    fn placeholder(&mut self, start: Cursor, mark: Mark, boundary: &[&str]) -> AST {
+        // keys added by the failed sub-expression are not referenced by anything else,
+        // equal keys parsed before it reuse the earlier index
+        self.nodes.truncate(mark.nodes);
+        self.keys.truncate(mark.keys);
+        self.lists.truncate(mark.lists);
+        self.lexer.seek(start);
+
+        let position = self.peek_token().map_or(self.eof, |(_, column)| column);
+        let mut depth = 0usize;
+        while let Some((token, _column)) = self.peek_token() {
+            if depth == 0 && boundary.contains(&token) {
+                break;
+            }
+            match token {
+                "(" => depth += 1,
+                ")" => depth = depth.saturating_sub(1),
+                _ => (),
+            }
+            self.lexer.next();
+        }
+
+        self.pending_placeholder = true;
+        AST {
+            position,
+            end: self.end(),
+            fragment: Fragment::False,
+        }
+    }
+
+    // fail before parsing a fragment nested `depth` levels deep past the bound
+    fn check_depth(&self, depth: usize) -> Result<(), ParseError<'a>> {
+        if depth > self.max_depth {
+            return Err(ParseError::MaxDepthExceeded {
+                max_depth: self.max_depth,
+                position: self.peek_token().map_or(self.eof, |(_, column)| column),
+            });
+        }
+        Ok(())
+    }
+
     // return the next token
-    fn next_token(&mut self, context: &'static str) -> Result<(&'a str, Position), ParseError<'a>> {
-        if self.current_token < self.tokens.len() {
-            let token = self.tokens[self.current_token];
-            self.current_token += 1;
-            Ok(token)
-        } else {
-            Err(ParseError::UnexpectedEof { context })
+    fn next_token(&mut self, context: ParseContext) -> Result<(&'a str, Position), ParseError<'a>> {
+        match self.lexer.next() {
+            Some(token) => Ok(token),
+            None => Err(self.unexpected_eof(context)),
         }
     }
 
-    fn peek_token(&self) -> Option<(&'a str, Position)> {
-        if self.current_token < self.tokens.len() {
-            Some(self.tokens[self.current_token])
-        } else {
-            None
+    fn unexpected_eof(&self, context: ParseContext) -> ParseError<'a> {
+        ParseError::UnexpectedEof {
+            context,
+            position: self.eof,
         }
     }
 
+    // return the column right after the last consumed token
+    fn end(&self) -> Position {
+        self.lexer.end
+    }
+
+    fn peek_token(&self) -> Option<(&'a str, Position)> {
+        self.lexer.clone().next()
+    }
+
+    // return the token of `len` bytes at `column`
+    fn token_at(&self, column: Position, len: usize) -> (&'a str, Position) {
+        let start = column as usize - 1;
+        (&self.lexer.input[start..start + len], column)
+    }
+
     fn expect_token(
         &mut self,
-        context: &'static str,
+        context: ParseContext,
         expected: &'static str,
     ) -> Result<(&'a str, Position), ParseError<'a>> {
         let (token, column) = self.next_token(context)?;
@@ -324,35 +854,96 @@ impl<'a> ParserContext<'a> {
     }
 
     fn peek_next_token(&self) -> Option<(&'a str, Position)> {
-        if self.current_token + 1 < self.tokens.len() {
-            Some(self.tokens[self.current_token + 1])
-        } else {
-            None
-        }
+        self.lexer.clone().nth(1)
     }
 
     fn check_next_tokens(&self, tokens: &[&'a str]) -> bool {
-        if self.current_token + tokens.len() > self.tokens.len() {
-            return false;
+        let mut lexer = self.lexer;
+        tokens
+            .iter()
+            .all(|token| lexer.next().is_some_and(|(next, _)| next == *token))
+    }
+
+    fn add_node(&mut self, ast: AST) -> Result<NodeIndex, ParseError<'a>> {
+        // every node must be reachable by a `NodeIndex`
+        if self.nodes.len() >= NodeIndex::MAX as usize {
+            return Err(ParseError::TooManyFragments {
+                max_fragments: NodeIndex::MAX as usize,
+                position: ast.position,
+            });
         }
+        let index = self.nodes.len() as NodeIndex;
+        let position = ast.position;
+        self.nodes
+            .push(ast)
+            .map_err(|capacity| ParseError::CapacityExceeded {
+                table: Table::Nodes,
+                capacity,
+                position,
+            })?;
+        if core::mem::take(&mut self.pending_placeholder) {
+            self.placeholders.push(index);
+        }
+        Ok(index)
+    }
 
-        for (i, token) in tokens.iter().enumerate() {
-            if self.tokens[self.current_token + i].0 != *token {
-                return false;
-            }
+    /// Add the key parsed at `position` to the key table, reusing the index of an equal
+    /// key.
+    fn add_key(&mut self, key: KeyToken, position: Position) -> Result<KeyIndex, ParseError<'a>> {
+        if let Some(index) = self.keys.as_slice().iter().position(|k| *k == key) {
+            return Ok(index as KeyIndex);
         }
-        true
+        self.keys
+            .push(key)
+            .map_err(|capacity| ParseError::CapacityExceeded {
+                table: Table::Keys,
+                capacity,
+                position,
+            })?;
+        Ok((self.keys.len() - 1) as KeyIndex)
     }
 
-    fn add_node(&mut self, ast: AST) -> NodeIndex {
-        let index = self.nodes.len() as NodeIndex;
-        self.nodes.push(ast);
-        index
+    /// Append an index to the list table, the list ends with [`ParserContext::list_since`].
+    fn push_list(&mut self, index: u32, position: Position) -> Result<(), ParseError<'a>> {
+        self.lists
+            .push(index)
+            .map_err(|capacity| ParseError::CapacityExceeded {
+                table: Table::Lists,
+                capacity,
+                position,
+            })
+    }
+
+    /// Get the list of the indices appended since the list table held `start` of them.
+    fn list_since(&self, start: usize) -> List {
+        List {
+            start: start as u32,
+            len: (self.lists.len() - start) as u32,
+        }
+    }
+
+    // open a fragment whose arguments follow; a fragment is at least one node, so a
+    // stack of open fragments deeper than the node table can hold is reported as such
+    fn push_call(&mut self, call: Call) -> Result<(), ParseError<'a>> {
+        self.calls
+            .push(call)
+            .map_err(|capacity| ParseError::CapacityExceeded {
+                table: Table::Nodes,
+                capacity,
+                position: call.position,
+            })
+    }
+
+    fn pop_call(&mut self) -> Option<Call> {
+        let len = self.calls.len().checked_sub(1)?;
+        let call = self.calls.as_slice()[len];
+        self.calls.truncate(len);
+        Some(call)
     }
 
     fn parse_inner_paren(
         &mut self,
-        context: &'static str,
+        context: ParseContext,
     ) -> Result<(&'a str, Position), ParseError<'a>> {
         self.next_token(context)?; // Advance past the fragment name
 
@@ -362,30 +953,51 @@ impl<'a> ParserContext<'a> {
         Ok(inner)
     }
 
-    fn parse_call<const N: usize>(
-        &mut self,
-        context: &'static str,
-    ) -> Result<[AST; N], ParseError<'a>> {
+    /// Parse `name(KEY)`, where the key may be a `musig(...)` expression.
+    fn parse_inner_key(&mut self, context: ParseContext) -> Result<KeyToken, ParseError<'a>> {
         self.next_token(context)?; // Advance past the fragment name
 
-        let (_l_paren, _l_paren_column) = self.expect_token(context, "(")?;
-
-        // Create uninitialized array
-        let mut asts: [AST; N] = unsafe { core::mem::zeroed() };
-
-        // Fill first element
-        asts[0] = parse_internal(self)?;
+        self.expect_token(context, "(")?;
+        let key = self.parse_key_expression()?;
+        self.expect_token(context, ")")?;
+        Ok(key)
+    }
 
-        // Fill remaining elements
-        for i in 1..N {
-            let (_comma, _comma_column) = self.expect_token(context, ",")?;
-            asts[i] = parse_internal(self)?;
+    /// Parse a key expression. The lexer splits `musig(KEY,KEY,...)/path` at the
+    /// parentheses and commas, so its participants are collected here.
+    fn parse_key_expression(&mut self) -> Result<KeyToken, ParseError<'a>> {
+        let token = self.next_token(ParseContext::Key)?;
+        // hex keys are parsed in place, the others hold vectors
+        if !S::ALLOCATES && !token.0.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(ParseError::NeedsAllocator { found: token });
+        }
+        let context = ParseContext::Fragment("musig");
+        if token.0 != "musig" || self.peek_token().is_none_or(|(next, _)| next != "(") {
+            return keys::parse_key(token, &self.inner_descriptor);
         }
 
-        let (_r_paren, _r_paren_column) = self.expect_token(context, ")")?;
+        self.expect_token(context, "(")?;
+        let mut participants = Vec::new();
+        loop {
+            participants.push(self.next_token(context)?);
+            match self.next_token(context)? {
+                (",", _) => {}
+                (")", _) => break,
+                found => {
+                    return Err(ParseError::UnexpectedToken {
+                        expected: "',' or ')'",
+                        found,
+                    });
+                }
+            }
+        }
 
-        // Convert to initialized array
-        Ok(asts)
+        // the derivation of the aggregate key, e.g. `/0/*`
+        let derivation = match self.peek_token() {
+            Some(next) if next.0.starts_with('/') => Some(self.next_token(context)?),
+            _ => None,
+        };
+        keys::parse_musig(token, &participants, derivation, &self.inner_descriptor)
     }
 
     fn descriptor(&self) -> Descriptor {
@@ -394,14 +1006,156 @@ impl<'a> ParserContext<'a> {
 }
 
 pub(crate) fn parse<'a>(input: &'a str) -> Result<Context, ParseError<'a>> {
-    // check if the input is ascii
-    if !input.is_ascii() {
-        return Err(ParseError::NonAscii);
+    check_ascii(input)?;
+
+    let mut ctx = ParserContext::new(input);
+    let (root, top_level_descriptor) = parse_context(&mut ctx, input)?;
+    Ok(ctx.into_context(root, top_level_descriptor))
+}
+
+/// Bounds on the resources used to parse a descriptor.
+#[cfg_attr(feature = "debug", derive(Debug))]
+#[derive(Clone, Copy)]
+pub struct ParseOptions {
+    max_depth: usize,
+}
+
+impl ParseOptions {
+    /// Fragments nested at most one level deeper than consensus allows, so that every
+    /// valid descriptor parses.
+    pub const DEFAULT: Self = Self {
+        max_depth: limits::MAX_PARSE_DEPTH,
+    };
+
+    pub const fn new() -> Self {
+        Self::DEFAULT
+    }
+
+    /// Fail with [`ParseError::MaxDepthExceeded`] as soon as fragments are nested more
+    /// than `max_depth` levels deep, wrappers included. This bounds the stack of
+    /// fragments whose arguments are being parsed.
+    pub const fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Parse the input within the bounds of `options`.
+pub(crate) fn parse_with_options<'a>(
+    input: &'a str,
+    options: &ParseOptions,
+) -> Result<Context, ParseError<'a>> {
+    check_ascii(input)?;
+
+    let mut ctx = ParserContext::new(input);
+    ctx.max_depth = options.max_depth;
+    let (root, top_level_descriptor) = parse_context(&mut ctx, input)?;
+    Ok(ctx.into_context(root, top_level_descriptor))
+}
+
+/// Result of a parse that recovers from errors at fragment boundaries.
+pub(crate) struct Recovered<'a> {
+    /// The parsed context, unless the descriptor itself could not be parsed
+    pub(crate) ctx: Option<Context>,
+    pub(crate) errors: Vec<ParseError<'a>>,
+    /// Indices of the nodes standing in for the sub-expressions that failed to parse
+    pub(crate) placeholders: Vec<NodeIndex>,
+}
+
+/// Parse the input, replacing every sub-expression that fails to parse with a
+/// placeholder node and collecting the errors instead of stopping at the first one.
+pub(crate) fn parse_recovering<'a>(input: &'a str) -> Recovered<'a> {
+    if let Err(e) = check_ascii(input) {
+        return Recovered {
+            ctx: None,
+            errors: alloc::vec![e],
+            placeholders: Vec::new(),
+        };
+    }
+
+    let mut ctx = ParserContext::new(input);
+    ctx.recover = true;
+
+    let parsed = parse_context(&mut ctx, input);
+    let mut errors = core::mem::take(&mut ctx.errors);
+    match parsed {
+        Ok((root, top_level_descriptor)) => {
+            let placeholders = core::mem::take(&mut ctx.placeholders);
+            Recovered {
+                ctx: Some(ctx.into_context(root, top_level_descriptor)),
+                errors,
+                placeholders,
+            }
+        }
+        Err(e) => {
+            if errors.last().and_then(|last| last.span()) != e.span() {
+                errors.push(e);
+            }
+            Recovered {
+                ctx: None,
+                errors,
+                placeholders: Vec::new(),
+            }
+        }
     }
+}
+
+/// Parse a miniscript expression that is not wrapped in a descriptor, with the keys and
+/// fragments allowed in `context`.
+pub(crate) fn parse_miniscript<'a>(
+    input: &'a str,
+    context: ScriptContext,
+) -> Result<Context, ParseError<'a>> {
+    check_ascii(input)?;
 
     let mut ctx = ParserContext::new(input);
+    ctx.inner_descriptor = context.descriptor();
+    let root = parse_internal(&mut ctx)?;
+    // the root is also in the node table, where the checks of every node find it
+    ctx.add_node(root.clone())?;
+
+    // should be no more tokens, a checksum only follows a descriptor
+    if let Some(next_token) = ctx.peek_token() {
+        return Err(ParseError::UnexpectedTrailingToken { found: next_token });
+    }
+
+    Ok(ctx.into_context(root, Descriptor::Bare))
+}
 
-    let (root, top_level_descriptor) = parse_descriptor(&mut ctx)?;
+/// Longest input accepted, so that every column fits in a [`Position`].
+const MAX_INPUT_LENGTH: usize = Position::MAX as usize - 1;
+
+pub(crate) fn check_ascii(input: &str) -> Result<(), ParseError<'_>> {
+    if input.len() > MAX_INPUT_LENGTH {
+        return Err(ParseError::InputTooLong {
+            length: input.len(),
+            max_length: MAX_INPUT_LENGTH,
+        });
+    }
+    if !input.is_ascii() {
+        let position = input
+            .bytes()
+            .position(|b| !b.is_ascii())
+            .unwrap_or_default();
+        return Err(ParseError::NonAscii {
+            position: position as Position + 1,
+        });
+    }
+    Ok(())
+}
+
+/// Parse a descriptor, returning its root and its top level descriptor.
+pub(crate) fn parse_context<'a, S: Storage>(
+    ctx: &mut ParserContext<'a, S>,
+    input: &'a str,
+) -> Result<(AST, Descriptor), ParseError<'a>> {
+    let (root, top_level_descriptor) = parse_descriptor(ctx)?;
 
     // should be no more tokens
     let next_token = ctx.peek_token();
@@ -409,25 +1163,22 @@ pub(crate) fn parse<'a>(input: &'a str) -> Result<Context, ParseError<'a>> {
         let next_token = next_token.unwrap();
         if next_token.0.starts_with("#") {
             if checksum::verify_checksum(input).is_err() {
-                return Err(ParseError::InvalidChecksum);
+                ctx.report(ParseError::InvalidChecksum { found: next_token })?;
             }
         } else {
-            return Err(ParseError::UnexpectedTrailingToken { found: next_token });
+            ctx.report(ParseError::UnexpectedTrailingToken { found: next_token })?;
         }
     }
 
-    Ok(Context::new(
-        ctx.nodes,
-        root,
-        top_level_descriptor,
-        ctx.inner_descriptor,
-    ))
+    Ok((root, top_level_descriptor))
 }
 
-fn parse_descriptor<'a>(ctx: &mut ParserContext<'a>) -> Result<(AST, Descriptor), ParseError<'a>> {
-    let (token, column) = ctx.peek_token().ok_or(ParseError::UnexpectedEof {
-        context: "parse_descriptor",
-    })?;
+fn parse_descriptor<'a, S: Storage>(
+    ctx: &mut ParserContext<'a, S>,
+) -> Result<(AST, Descriptor), ParseError<'a>> {
+    let (token, column) = ctx
+        .peek_token()
+        .ok_or_else(|| ctx.unexpected_eof(ParseContext::Descriptor))?;
 
     let descriptor = Descriptor::try_from(token).map_err(|_| ParseError::UnexpectedToken {
         expected: "descriptor",
@@ -437,7 +1188,7 @@ fn parse_descriptor<'a>(ctx: &mut ParserContext<'a>) -> Result<(AST, Descriptor)
     ctx.inner_descriptor = descriptor.clone();
 
     // Advance past the descriptor
-    ctx.next_token("parse_descriptor")?;
+    ctx.next_token(ParseContext::Descriptor)?;
 
     // For sh descriptors, we need to check what's inside
     if descriptor == Descriptor::Sh
@@ -447,173 +1198,503 @@ fn parse_descriptor<'a>(ctx: &mut ParserContext<'a>) -> Result<(AST, Descriptor)
     }
 
     // Standard descriptor parsing
-    let (_l_paren, _l_paren_column) = ctx.expect_token("parse_descriptor", "(")?;
-    let inner = parse_top_internal(ctx)?;
-    let (_r_paren, _r_paren_column) = ctx.expect_token("parse_descriptor", ")")?;
+    let (_l_paren, _l_paren_column) = ctx.expect_token(ParseContext::Descriptor, "(")?;
+    // the inner part of the descriptor extends up to the closing parenthesis, the
+    // `tr()` internal key is followed by a comma
+    let start = ctx.lexer.cursor();
+    let mark = ctx.mark();
+    let inner = match parse_top_internal(ctx) {
+        Ok(inner) => inner,
+        Err(e) => {
+            ctx.report(e)?;
+            ctx.placeholder(start, mark, &[")"])
+        }
+    };
+    let (_r_paren, _r_paren_column) = ctx.expect_token(ParseContext::Descriptor, ")")?;
 
     Ok((
         AST {
             position: column,
+            end: ctx.end(),
             fragment: Fragment::Descriptor {
                 descriptor: descriptor.clone(),
-                inner: ctx.add_node(inner),
+                inner: ctx.add_node(inner)?,
             },
         },
         descriptor,
     ))
 }
 
-fn parse_sh_descriptor<'a>(
-    ctx: &mut ParserContext<'a>,
+fn parse_sh_descriptor<'a, S: Storage>(
+    ctx: &mut ParserContext<'a, S>,
     sh: (&'a str, Position),
 ) -> Result<(AST, Descriptor), ParseError<'a>> {
-    let (_l_paren, _l_paren_column) = ctx.expect_token("parse_sh_descriptor", "(")?;
+    let (_l_paren, _l_paren_column) = ctx.expect_token(ParseContext::Fragment("sh"), "(")?;
 
     // Parse the inner descriptor directly
     let (inner, inner_descriptor) = parse_descriptor(ctx)?;
 
-    let (_r_paren, _r_paren_column) = ctx.expect_token("parse_sh_descriptor", ")")?;
+    let (_r_paren, _r_paren_column) = ctx.expect_token(ParseContext::Fragment("sh"), ")")?;
 
     Ok((
         AST {
             position: sh.1,
+            end: ctx.end(),
             fragment: Fragment::Descriptor {
                 descriptor: Descriptor::Sh,
-                inner: ctx.add_node(inner),
+                inner: ctx.add_node(inner)?,
             },
         },
         Descriptor::Sh,
     ))
 }
 
-fn parse_top_internal<'a>(ctx: &mut ParserContext<'a>) -> Result<AST, ParseError<'a>> {
-    let (token, column) = ctx.peek_token().ok_or(ParseError::UnexpectedEof {
-        context: "parse_top_internal",
-    })?;
+fn parse_top_internal<'a, S: Storage>(
+    ctx: &mut ParserContext<'a, S>,
+) -> Result<AST, ParseError<'a>> {
+    let (token, column) = ctx
+        .peek_token()
+        .ok_or_else(|| ctx.unexpected_eof(ParseContext::Descriptor))?;
     match ctx.descriptor() {
         Descriptor::Pkh | Descriptor::Wpkh => {
-            ctx.next_token("parse_top_internal")?; // Advance past the key
-
-            let key = keys::parse_key((token, column), &ctx.inner_descriptor)?;
+            let key = ctx.parse_key_expression()?;
+            let key = ctx.add_key(key, column)?;
 
             Ok(AST {
                 position: column,
+                end: ctx.end(),
                 fragment: Fragment::RawPkH { key },
             })
         }
         Descriptor::Tr => {
-            ctx.next_token("parse_top_internal")?; // Advance past the key
-
-            let key = keys::parse_key((token, column), &ctx.inner_descriptor)?;
+            let key = ctx.parse_key_expression()?;
+            let key = ctx.add_key(key, column)?;
 
             if let Some((next_token, next_column)) = ctx.peek_token() {
                 if next_token == "," {
-                    ctx.next_token("parse_top_internal")?; // Advance past the comma
-                    let inner = parse_internal(ctx)?;
+                    ctx.next_token(ParseContext::Descriptor)?; // Advance past the comma
+                    let inner = ctx.parse_argument(parse_internal)?;
                     return Ok(AST {
                         position: column,
+                        end: ctx.end(),
                         fragment: Fragment::RawTr {
                             key,
-                            inner: Some(ctx.add_node(inner)),
+                            inner: Some(ctx.add_node(inner)?),
                         },
                     });
                 }
             }
             Ok(AST {
                 position: column,
+                end: ctx.end(),
                 fragment: Fragment::RawTr { key, inner: None },
             })
         }
-        Descriptor::Pk => {
-            ctx.next_token("parse_top_internal")?; // Advance past the key
+        Descriptor::RawTr => {
+            let key = ctx.parse_key_expression()?;
+            let key = ctx.add_key(key, column)?;
 
-            let key = keys::parse_key((token, column), &ctx.inner_descriptor)?;
+            Ok(AST {
+                position: column,
+                end: ctx.end(),
+                fragment: Fragment::RawTr { key, inner: None },
+            })
+        }
+        Descriptor::Pk | Descriptor::Combo => {
+            let key = ctx.parse_key_expression()?;
+            let key = ctx.add_key(key, column)?;
 
             Ok(AST {
                 position: column,
+                end: ctx.end(),
                 fragment: Fragment::RawPk { key },
             })
         }
+        Descriptor::Addr | Descriptor::Raw if !S::ALLOCATES => Err(ParseError::NeedsAllocator {
+            found: (token, column),
+        }),
+        Descriptor::Addr => {
+            ctx.next_token(ParseContext::Descriptor)?; // Advance past the address
+
+            let address = Address::from_str(token).map_err(|_| ParseError::InvalidAddress {
+                address: token,
+                position: column,
+            })?;
+
+            Ok(AST {
+                position: column,
+                end: ctx.end(),
+                fragment: Fragment::RawAddr { address },
+            })
+        }
+        Descriptor::Raw => {
+            ctx.next_token(ParseContext::Descriptor)?; // Advance past the script
+
+            use bitcoin::hex::FromHex;
+            let script = Vec::<u8>::from_hex(token)
+                .map_err(|_| ParseError::InvalidHex { position: column })?;
+
+            Ok(AST {
+                position: column,
+                end: ctx.end(),
+                fragment: Fragment::RawScript {
+                    script: ScriptBuf::from_bytes(script),
+                },
+            })
+        }
         _ => {
             return parse_internal(ctx);
         }
     }
 }
-fn parse_internal<'a>(ctx: &mut ParserContext<'a>) -> Result<AST, ParseError<'a>> {
+/// Fragments taking sub-expressions, with their number of arguments. `thresh` takes
+/// any number of them after `k`.
+const CALLS: [(&str, Option<usize>); 9] = [
+    ("andor", Some(3)),
+    ("and_v", Some(2)),
+    ("and_b", Some(2)),
+    ("and_n", Some(2)),
+    ("or_b", Some(2)),
+    ("or_c", Some(2)),
+    ("or_d", Some(2)),
+    ("or_i", Some(2)),
+    ("thresh", None),
+];
+
+/// A fragment whose arguments are being parsed.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Call {
+    name: &'static str,
+    arity: Option<usize>,
+    position: Position,
+    /// The column and length of the wrappers of the fragment, e.g. `av` in `av:and_b(X,Y)`
+    wrappers: Option<(Position, usize)>,
+    depth: usize,
+    /// Start of the argument holding the fragment, to skip it if it fails to parse
+    start: Cursor,
+    mark: Mark,
+    /// Where the arguments of the fragment start in the argument stack, they are added
+    /// to the node table as they are parsed
+    args: usize,
+    /// `thresh` only
+    k: i32,
+}
+
+impl Call {
+    fn push<'a, S: Storage>(
+        &self,
+        ctx: &mut ParserContext<'a, S>,
+        arg: AST,
+    ) -> Result<(), ParseError<'a>> {
+        let position = arg.position;
+        let index = ctx.add_node(arg)?;
+        // every argument is a node, so the argument stack is never longer than the
+        // node table
+        ctx.args
+            .push(index)
+            .map_err(|capacity| ParseError::CapacityExceeded {
+                table: Table::Nodes,
+                capacity,
+                position,
+            })
+    }
+
+    fn arg_count<S: Storage>(&self, ctx: &ParserContext<'_, S>) -> usize {
+        ctx.args.len() - self.args
+    }
+
+    // consume the separator after the arguments parsed so far, returning whether
+    // another argument follows
+    fn advance<'a, S: Storage>(
+        &self,
+        ctx: &mut ParserContext<'a, S>,
+    ) -> Result<bool, ParseError<'a>> {
+        let more = match self.arity {
+            Some(arity) => self.arg_count(ctx) < arity,
+            None => !matches!(ctx.peek_token(), Some((")", _)) | None),
+        };
+        if !more {
+            ctx.expect_token(ParseContext::Fragment(self.name), ")")?;
+        } else if self.arity.is_none() || self.arg_count(ctx) > 0 {
+            ctx.expect_token(ParseContext::Fragment(self.name), ",")?;
+        }
+        Ok(more)
+    }
+
+    fn build<'a, S: Storage>(self, ctx: &mut ParserContext<'a, S>) -> Result<AST, ParseError<'a>> {
+        let position = self.position;
+        // the arguments of the fragments taking at most three of them
+        let mut args = [0; 3];
+        let count = core::cmp::min(self.arg_count(ctx), args.len());
+        args[..count].copy_from_slice(&ctx.args.as_slice()[self.args..self.args + count]);
+
+        let fragment = match (self.name, &args[..count]) {
+            ("thresh", _) => {
+                let start = ctx.lists.len();
+                for i in self.args..ctx.args.len() {
+                    let x = ctx.args.as_slice()[i];
+                    ctx.push_list(x, position)?;
+                }
+                Fragment::Thresh {
+                    k: self.k,
+                    xs: ctx.list_since(start),
+                }
+            }
+            ("andor", &[x, y, z]) => Fragment::AndOr { x, y, z },
+            ("and_v", &[x, y]) => Fragment::AndV { x, y },
+            ("and_b", &[x, y]) => Fragment::AndB { x, y },
+            // and_n(X,Y) = andor(X,Y,0)
+            ("and_n", &[x, y]) => Fragment::AndOr {
+                x,
+                y,
+                z: ctx.add_node(AST {
+                    position,
+                    end: ctx.end(),
+                    fragment: Fragment::False,
+                })?,
+            },
+            ("or_b", &[x, z]) => Fragment::OrB { x, z },
+            ("or_c", &[x, z]) => Fragment::OrC { x, z },
+            ("or_d", &[x, z]) => Fragment::OrD { x, z },
+            ("or_i", &[x, z]) => Fragment::OrI { x, z },
+            // the number of arguments is checked by `advance`
+            _ => unreachable!(),
+        };
+        ctx.args.truncate(self.args);
+
+        let ast = AST {
+            position,
+            end: ctx.end(),
+            fragment,
+        };
+        match self.wrappers {
+            Some((column, len)) => wrap(ctx, ast, ctx.token_at(column, len)),
+            None => Ok(ast),
+        }
+    }
+}
+
+enum Operand {
+    Complete(AST),
+    /// A fragment whose arguments follow
+    Open(Call),
+}
+
+// parse a sub-expression, keeping the fragments whose arguments are being parsed on
+// an explicit stack instead of recursing into them
+fn parse_internal<'a, S: Storage>(ctx: &mut ParserContext<'a, S>) -> Result<AST, ParseError<'a>> {
+    // the calls below `base` belong to an enclosing parse
+    let base = ctx.calls.len();
+
+    'arguments: loop {
+        let start = ctx.lexer.cursor();
+        let mark = ctx.mark();
+        let depth = match ctx.calls.as_slice()[base..].last() {
+            Some(call) => call.depth,
+            None => 0,
+        };
+        let mut arg = match parse_operand(ctx, depth) {
+            Ok(Operand::Open(call)) => {
+                ctx.push_call(call)?;
+                continue;
+            }
+            Ok(Operand::Complete(ast)) => ast,
+            Err(e) if ctx.calls.len() == base => return Err(e),
+            Err(e) => {
+                ctx.report(e)?;
+                ctx.placeholder(start, mark, &[",", ")"])
+            }
+        };
+
+        // hand the argument over to the innermost call, closing the calls it completes
+        while ctx.calls.len() > base {
+            let Some(call) = ctx.pop_call() else { break };
+            let result = match call.push(ctx, arg).and_then(|()| call.advance(ctx)) {
+                Ok(true) => {
+                    ctx.push_call(call)?;
+                    continue 'arguments;
+                }
+                Ok(false) => call.build(ctx),
+                Err(e) => Err(e),
+            };
+            arg = match result {
+                Ok(ast) => ast,
+                Err(e) if ctx.calls.len() == base => return Err(e),
+                Err(e) => {
+                    ctx.report(e)?;
+                    // drop the arguments of the failed call along with its nodes
+                    ctx.args.truncate(call.args);
+                    ctx.placeholder(call.start, call.mark, &[",", ")"])
+                }
+            };
+        }
+        return Ok(arg);
+    }
+}
+
+// parse a fragment nested in `depth` others up to the arguments it takes, if any
+fn parse_operand<'a, S: Storage>(
+    ctx: &mut ParserContext<'a, S>,
+    depth: usize,
+) -> Result<Operand, ParseError<'a>> {
+    let start = ctx.lexer.cursor();
+    let mark = ctx.mark();
+    let mut depth = depth + 1;
+    ctx.check_depth(depth)?;
+
+    let (token, column) = ctx
+        .peek_token()
+        .ok_or_else(|| ctx.unexpected_eof(ParseContext::Expression))?;
+
+    // identity is a list of inner identities, eg av:X
+    let mut wrappers = None;
+    if let Some((":", _)) = ctx.peek_next_token() {
+        ctx.next_token(ParseContext::Wrapper)?; // Advance past identity type
+
+        ctx.expect_token(ParseContext::Wrapper, ":")?;
+
+        // multi colon is not allowed
+        // example: sh(uuuuuuuuuuuuuu:uuuuuu:1)
+        if let Some((":", peek_token_column)) = ctx.peek_next_token() {
+            return Err(ParseError::MultiColon {
+                position: peek_token_column,
+            });
+        }
+
+        // every wrapper is one more level of nesting
+        depth += token.len();
+        ctx.check_depth(depth)?;
+        wrappers = Some((column, token.len()));
+    }
+
     let (token, column) = ctx
         .peek_token()
-        .ok_or(ParseError::UnexpectedEof { context: "parse" })?;
+        .ok_or_else(|| ctx.unexpected_eof(ParseContext::Expression))?;
+
+    let Some(&(name, arity)) = CALLS.iter().find(|(name, _)| *name == token) else {
+        let ast = parse_leaf(ctx)?;
+        return match wrappers {
+            Some((column, len)) => wrap(ctx, ast, ctx.token_at(column, len)).map(Operand::Complete),
+            None => Ok(Operand::Complete(ast)),
+        };
+    };
+
+    ctx.next_token(ParseContext::Fragment(name))?; // Advance past the fragment name
+    let (_l_paren, _l_paren_column) = ctx.expect_token(ParseContext::Fragment(name), "(")?;
+
+    let mut k = 0;
+    if arity.is_none() {
+        let (k_token, k_column) = ctx.next_token(ParseContext::Fragment(name))?;
+
+        // Check if the number starts with a digit 1-9
+        if is_invalid_number(k_token) {
+            return Err(ParseError::UnexpectedToken {
+                expected: "Number must start with a digit 1-9",
+                found: (k_token, k_column),
+            });
+        }
+
+        k = k_token
+            .parse::<i32>()
+            .map_err(|_| ParseError::UnexpectedToken {
+                expected: "i32",
+                found: (k_token, k_column),
+            })?;
+    }
+
+    let call = Call {
+        name,
+        arity,
+        position: column,
+        wrappers,
+        depth,
+        start,
+        mark,
+        args: ctx.args.len(),
+        k,
+    };
+    // `thresh` may take no arguments at all
+    if call.advance(ctx)? {
+        Ok(Operand::Open(call))
+    } else {
+        call.build(ctx).map(Operand::Complete)
+    }
+}
+
+// parse a fragment without sub-expressions
+fn parse_leaf<'a, S: Storage>(ctx: &mut ParserContext<'a, S>) -> Result<AST, ParseError<'a>> {
+    let (token, column) = ctx
+        .peek_token()
+        .ok_or_else(|| ctx.unexpected_eof(ParseContext::Expression))?;
 
     match token {
         "pk_k" => {
-            let key_token = ctx.parse_inner_paren("pk_k")?;
-
-            // Get the key type based on the inner descriptor
-            let key = keys::parse_key(key_token, &ctx.inner_descriptor)?;
+            let key = ctx.parse_inner_key(ParseContext::Fragment("pk_k"))?;
+            let key = ctx.add_key(key, column)?;
 
             Ok(AST {
                 position: column,
+                end: ctx.end(),
                 fragment: Fragment::PkK { key },
             })
         }
         "pk_h" => {
-            let key_token = ctx.parse_inner_paren("pk_h")?;
-
-            // Get the key type based on the inner descriptor
-            let key = keys::parse_key(key_token, &ctx.inner_descriptor)?;
+            let key = ctx.parse_inner_key(ParseContext::Fragment("pk_h"))?;
+            let key = ctx.add_key(key, column)?;
 
             Ok(AST {
                 position: column,
+                end: ctx.end(),
                 fragment: Fragment::PkH { key },
             })
         }
         "pk" => {
             // pk(key) = c:pk_k(key)
-            let (key, key_column) = ctx.parse_inner_paren("pk")?;
-
-            // Get the key type based on the inner descriptor
-            let key = keys::parse_key((key, key_column), &ctx.inner_descriptor)?;
+            let key = ctx.parse_inner_key(ParseContext::Fragment("pk"))?;
+            let key = ctx.add_key(key, column)?;
 
             let mut ast = AST {
                 position: column,
+                end: ctx.end(),
                 fragment: Fragment::PkK { key },
             };
 
             // wrap in c: identity
             ast = AST {
                 position: column,
+                end: ctx.end(),
                 fragment: Fragment::Identity {
                     identity_type: IdentityType::C,
-                    x: ctx.add_node(ast),
+                    x: ctx.add_node(ast)?,
                 },
             };
             Ok(ast)
         }
         "pkh" => {
             // pkh(key) = c:pk_h(key)
-            let key_token = ctx.parse_inner_paren("pkh")?;
-
-            // Get the key type based on the inner descriptor
-            let key = keys::parse_key(key_token, &ctx.inner_descriptor)?;
+            let key = ctx.parse_inner_key(ParseContext::Fragment("pkh"))?;
+            let key = ctx.add_key(key, column)?;
 
             let mut ast = AST {
                 position: column,
+                end: ctx.end(),
                 fragment: Fragment::PkH { key },
             };
 
             // wrap in c: identity
             ast = AST {
                 position: column,
+                end: ctx.end(),
                 fragment: Fragment::Identity {
                     identity_type: IdentityType::C,
-                    x: ctx.add_node(ast),
+                    x: ctx.add_node(ast)?,
                 },
             };
             Ok(ast)
         }
 
         "older" => {
-            let (n, n_column) = ctx.parse_inner_paren("older")?;
+            let (n, n_column) = ctx.parse_inner_paren(ParseContext::Fragment("older"))?;
 
             // Check if the number starts with a digit 1-9
             if is_invalid_number(&n) {
@@ -639,12 +1720,13 @@ fn parse_internal<'a>(ctx: &mut ParserContext<'a>) -> Result<AST, ParseError<'a>
 
             Ok(AST {
                 position: column,
+                end: ctx.end(),
                 fragment: Fragment::Older { n },
             })
         }
 
         "after" => {
-            let (n, n_column) = ctx.parse_inner_paren("after")?;
+            let (n, n_column) = ctx.parse_inner_paren(ParseContext::Fragment("after"))?;
 
             // check if n is u32
 
@@ -671,392 +1753,255 @@ fn parse_internal<'a>(ctx: &mut ParserContext<'a>) -> Result<AST, ParseError<'a>
 
             Ok(AST {
                 position: column,
+                end: ctx.end(),
                 fragment: Fragment::After { n },
             })
         }
 
         "sha256" => {
-            let (h, _h_column) = ctx.parse_inner_paren("sha256")?;
+            let (h, _h_column) = ctx.parse_inner_paren(ParseContext::Fragment("sha256"))?;
 
             let h: [u8; 32] = parse_hex_to_bytes(h, _h_column)?;
 
             Ok(AST {
                 position: column,
+                end: ctx.end(),
                 fragment: Fragment::Sha256 { h },
             })
         }
 
         "hash256" => {
-            let (h, _h_column) = ctx.parse_inner_paren("hash256")?;
+            let (h, _h_column) = ctx.parse_inner_paren(ParseContext::Fragment("hash256"))?;
 
             let h: [u8; 32] = parse_hex_to_bytes(h, _h_column)?;
 
             Ok(AST {
                 position: column,
+                end: ctx.end(),
                 fragment: Fragment::Hash256 { h },
             })
         }
 
         "ripemd160" => {
-            let (h, _h_column) = ctx.parse_inner_paren("ripemd160")?;
+            let (h, _h_column) = ctx.parse_inner_paren(ParseContext::Fragment("ripemd160"))?;
 
             let h: [u8; 20] = parse_hex_to_bytes(h, _h_column)?;
 
             Ok(AST {
                 position: column,
+                end: ctx.end(),
                 fragment: Fragment::Ripemd160 { h },
             })
         }
 
         "hash160" => {
-            let (h, _h_column) = ctx.parse_inner_paren("hash160")?;
+            let (h, _h_column) = ctx.parse_inner_paren(ParseContext::Fragment("hash160"))?;
 
             let h: [u8; 20] = parse_hex_to_bytes(h, _h_column)?;
 
             Ok(AST {
                 position: column,
+                end: ctx.end(),
                 fragment: Fragment::Hash160 { h },
             })
         }
 
-        "andor" => {
-            let [x, y, z] = ctx.parse_call("andor")?;
-
-            Ok(AST {
-                position: column,
-                fragment: Fragment::AndOr {
-                    x: ctx.add_node(x),
-                    y: ctx.add_node(y),
-                    z: ctx.add_node(z),
-                },
-            })
-        }
-
-        "and_v" => {
-            let [x, y] = ctx.parse_call("and_v")?;
-
-            Ok(AST {
-                position: column,
-                fragment: Fragment::AndV {
-                    x: ctx.add_node(x),
-                    y: ctx.add_node(y),
-                },
-            })
-        }
-
-        "and_b" => {
-            let [x, y] = ctx.parse_call("and_b")?;
-
-            Ok(AST {
-                position: column,
-                fragment: Fragment::AndB {
-                    x: ctx.add_node(x),
-                    y: ctx.add_node(y),
-                },
-            })
-        }
-
-        "and_n" => {
-            // and_n(X,Y) = andor(X,Y,0)
-
-            let [x, y] = ctx.parse_call("and_n")?;
-
-            let ast = AST {
-                position: column,
-                fragment: Fragment::AndOr {
-                    x: ctx.add_node(x),
-                    y: ctx.add_node(y),
-                    z: ctx.add_node(AST {
-                        position: column,
-                        fragment: Fragment::False,
-                    }),
-                },
-            };
-            Ok(ast)
-        }
-
-        "or_b" => {
-            let [x, z] = ctx.parse_call("or_b")?;
-
-            Ok(AST {
-                position: column,
-                fragment: Fragment::OrB {
-                    x: ctx.add_node(x),
-                    z: ctx.add_node(z),
-                },
-            })
-        }
-
-        "or_c" => {
-            let [x, z] = ctx.parse_call("or_c")?;
-
-            Ok(AST {
-                position: column,
-                fragment: Fragment::OrC {
-                    x: ctx.add_node(x),
-                    z: ctx.add_node(z),
-                },
-            })
-        }
-
-        "or_d" => {
-            let [x, z] = ctx.parse_call("or_d")?;
-
-            Ok(AST {
-                position: column,
-                fragment: Fragment::OrD {
-                    x: ctx.add_node(x),
-                    z: ctx.add_node(z),
-                },
-            })
-        }
-
-        "or_i" => {
-            let [x, z] = ctx.parse_call("or_i")?;
-
-            Ok(AST {
-                position: column,
-                fragment: Fragment::OrI {
-                    x: ctx.add_node(x),
-                    z: ctx.add_node(z),
-                },
-            })
-        }
-
-        "thresh" => {
-            ctx.next_token("thresh")?; // Advance past "thresh"
-            let (_l_paren, _l_paren_column) = ctx.expect_token("thresh", "(")?;
-            let (k, k_column) = ctx.next_token("thresh")?;
-
-            // Check if the number starts with a digit 1-9
-            if is_invalid_number(&k) {
-                return Err(ParseError::UnexpectedToken {
-                    expected: "Number must start with a digit 1-9",
-                    found: (k, k_column),
-                });
-            }
-
-            let k = k.parse::<i32>().map_err(|_| ParseError::UnexpectedToken {
-                expected: "i32",
-                found: (k, k_column),
-            })?;
-
-            let estimated_tokens = core::cmp::min(k as usize, 1000);
-            let mut xs = Vec::with_capacity(estimated_tokens);
-
-            while let Some((token, _column)) = ctx.peek_token() {
-                if token == ")" {
-                    break;
-                }
-                ctx.expect_token("thresh", ",")?;
-
-                let x = parse_internal(ctx)?;
-                xs.push(ctx.add_node(x));
-            }
-
-            let (_r_paren, _r_paren_column) = ctx.expect_token("thresh", ")")?;
-
-            Ok(AST {
-                position: column,
-                fragment: Fragment::Thresh { k, xs },
-            })
-        }
-
         "multi" => {
-            ctx.next_token("multi")?; // Advance past "multi"
-            let (_l_paren, _l_paren_column) = ctx.expect_token("multi", "(")?;
-            let (k, k_column) = ctx.next_token("multi")?;
+            let context = ParseContext::Fragment("multi");
+            ctx.next_token(context)?; // Advance past "multi"
+            let (_l_paren, _l_paren_column) = ctx.expect_token(context, "(")?;
+            let (k, k_column) = ctx.next_token(context)?;
             let k = k.parse::<i32>().map_err(|_| ParseError::UnexpectedToken {
                 expected: "i32",
                 found: (k, k_column),
             })?;
 
-            // Pre-allocate with reasonable capacity
-            let mut keys = Vec::new();
+            // the keys are appended to the list table as they are parsed
+            let start = ctx.lists.len();
             while let Some((token, _column)) = ctx.peek_token() {
                 if token == ")" {
                     break;
                 } else if token == "," {
-                    ctx.next_token("multi")?;
+                    ctx.next_token(context)?;
                 }
-                let (key, key_column) = ctx.next_token("multi")?;
+                let (key, key_column) = ctx.next_token(context)?;
 
                 let key =
                     bitcoin::PublicKey::from_str(key).map_err(|e| ParseError::InvalidKey {
                         key,
                         position: key_column,
-                        inner: "Invalid bitcoin::PublicKey key",
+                        inner: InvalidKeyReason::InvalidPublicKey,
                     })?;
-                keys.push(KeyToken::new(KeyTokenInner::PublicKey(key)));
+                let key = ctx.add_key(KeyToken::new(KeyTokenInner::PublicKey(key)), key_column)?;
+                ctx.push_list(key, key_column)?;
             }
+            let keys = ctx.list_since(start);
 
-            let (_r_paren, _r_paren_column) = ctx.expect_token("multi", ")")?;
+            let (_r_paren, _r_paren_column) = ctx.expect_token(context, ")")?;
 
             Ok(AST {
                 position: column,
+                end: ctx.end(),
                 fragment: Fragment::Multi { k, keys },
             })
         }
 
         "multi_a" => {
-            ctx.next_token("multi_a")?; // Advance past "multi_a"
-            let (_l_paren, _l_paren_column) = ctx.expect_token("multi_a", "(")?;
-            let (k, k_column) = ctx.next_token("multi_a")?;
+            let context = ParseContext::Fragment("multi_a");
+            ctx.next_token(context)?; // Advance past "multi_a"
+            let (_l_paren, _l_paren_column) = ctx.expect_token(context, "(")?;
+            let (k, k_column) = ctx.next_token(context)?;
             let k = k.parse::<i32>().map_err(|_| ParseError::UnexpectedToken {
                 expected: "i32",
                 found: (k, k_column),
             })?;
 
-            // Pre-allocate with reasonable capacity
-            let mut keys = Vec::new();
+            // the keys are appended to the list table as they are parsed
+            let start = ctx.lists.len();
             while let Some((token, _column)) = ctx.peek_token() {
                 if token == ")" {
                     break;
                 } else if token == "," {
-                    ctx.next_token("multi_a")?;
+                    ctx.next_token(context)?;
                 }
-                let (key, key_column) = ctx.next_token("multi_a")?;
+                let (key, key_column) = ctx.next_token(context)?;
                 let key = bitcoin::XOnlyPublicKey::from_str(key).map_err(|e| {
                     ParseError::InvalidXOnlyKey {
                         key,
                         position: key_column,
                     }
                 })?;
-                keys.push(KeyToken::new(KeyTokenInner::XOnlyPublicKey(key)));
+                let key = ctx.add_key(
+                    KeyToken::new(KeyTokenInner::XOnlyPublicKey(key)),
+                    key_column,
+                )?;
+                ctx.push_list(key, key_column)?;
             }
+            let keys = ctx.list_since(start);
 
-            let (_r_paren, _r_paren_column) = ctx.expect_token("multi_a", ")")?;
+            let (_r_paren, _r_paren_column) = ctx.expect_token(context, ")")?;
 
             Ok(AST {
                 position: column,
+                end: ctx.end(),
                 fragment: Fragment::MultiA { k, keys },
             })
         }
 
-        _ => {
-            // the top fragment cant be an identity
-
-            if let Some((peek_token, _peek_token_column)) = ctx.peek_next_token() {
-                if peek_token == ":" {
-                    ctx.next_token("identity")?; // Advance past identity type
-
-                    ctx.expect_token("identity", ":")?;
-
-                    // multi colon is not allowed
-                    // example: sh(uuuuuuuuuuuuuu:uuuuuu:1)
-                    if let Some((peek_token, peek_token_column)) = ctx.peek_next_token() {
-                        if peek_token == ":" {
-                            return Err(ParseError::MultiColon {
-                                position: peek_token_column,
-                            });
-                        }
-                    }
+        _ => parse_bool(ctx),
+    }
+}
 
-                    // identity is a list of inner identities, eg av:X
-
-                    let mut node: AST = parse_internal(ctx)?;
-
-                    // fix critical: https://github.com/unldenis/tinyminiscript/issues/3
-                    let identities = token.chars().rev().take(500);
-
-                    for id_type in identities {
-                        if id_type == 'a'
-                            || id_type == 'v'
-                            || id_type == 'c'
-                            || id_type == 'd'
-                            || id_type == 's'
-                            || id_type == 'j'
-                            || id_type == 'n'
-                        {
-                            let identity_type = match id_type {
-                                'a' => IdentityType::A,
-                                'v' => IdentityType::V,
-                                'c' => IdentityType::C,
-                                'd' => IdentityType::D,
-                                's' => IdentityType::S,
-                                'j' => IdentityType::J,
-                                'n' => IdentityType::N,
-                                _ => continue,
-                            };
-
-                            node = AST {
-                                position: column,
-                                fragment: Fragment::Identity {
-                                    identity_type,
-                                    x: ctx.add_node(node),
-                                },
-                            }
-                        } else if id_type == 't' {
-                            // t:X = and_v(X,1)
-                            node = AST {
-                                position: column,
-                                fragment: Fragment::AndV {
-                                    x: ctx.add_node(node),
-                                    y: ctx.add_node(AST {
-                                        position: column,
-                                        fragment: Fragment::True,
-                                    }),
-                                },
-                            }
-                        } else if id_type == 'l' {
-                            // l:X = or_i(0,X)
-                            node = AST {
-                                position: column,
-                                fragment: Fragment::OrI {
-                                    x: ctx.add_node(AST {
-                                        position: column,
-                                        fragment: Fragment::False,
-                                    }),
-                                    z: ctx.add_node(node),
-                                },
-                            }
-                        } else if id_type == 'u' {
-                            // u:X = or_i(X,0)
-                            node = AST {
-                                position: column,
-                                fragment: Fragment::OrI {
-                                    x: ctx.add_node(node),
-                                    z: ctx.add_node(AST {
-                                        position: column,
-                                        fragment: Fragment::False,
-                                    }),
-                                },
-                            }
-                        } else {
-                            // invalid identity type
-                            return Err(ParseError::UnknownWrapper {
-                                found: id_type,
-                                position: column,
-                            });
-                        }
-                    }
+// apply the wrappers `(token, column)` to the fragment they precede
+fn wrap<'a, S: Storage>(
+    ctx: &mut ParserContext<'a, S>,
+    mut node: AST,
+    (token, column): (&'a str, Position),
+) -> Result<AST, ParseError<'a>> {
+    // fix critical: https://github.com/unldenis/tinyminiscript/issues/3
+    let identities = token.char_indices().rev().take(500);
+
+    for (id_index, id_type) in identities {
+        if id_type == 'a'
+            || id_type == 'v'
+            || id_type == 'c'
+            || id_type == 'd'
+            || id_type == 's'
+            || id_type == 'j'
+            || id_type == 'n'
+        {
+            let identity_type = match id_type {
+                'a' => IdentityType::A,
+                'v' => IdentityType::V,
+                'c' => IdentityType::C,
+                'd' => IdentityType::D,
+                's' => IdentityType::S,
+                'j' => IdentityType::J,
+                'n' => IdentityType::N,
+                _ => continue,
+            };
 
-                    return Ok(node);
-                }
+            node = AST {
+                position: column,
+                end: ctx.end(),
+                fragment: Fragment::Identity {
+                    identity_type,
+                    x: ctx.add_node(node)?,
+                },
             }
-
-            return parse_bool(ctx);
+        } else if id_type == 't' {
+            // t:X = and_v(X,1)
+            node = AST {
+                position: column,
+                end: ctx.end(),
+                fragment: Fragment::AndV {
+                    x: ctx.add_node(node)?,
+                    y: ctx.add_node(AST {
+                        position: column,
+                        end: ctx.end(),
+                        fragment: Fragment::True,
+                    })?,
+                },
+            }
+        } else if id_type == 'l' {
+            // l:X = or_i(0,X)
+            node = AST {
+                position: column,
+                end: ctx.end(),
+                fragment: Fragment::OrI {
+                    x: ctx.add_node(AST {
+                        position: column,
+                        end: ctx.end(),
+                        fragment: Fragment::False,
+                    })?,
+                    z: ctx.add_node(node)?,
+                },
+            }
+        } else if id_type == 'u' {
+            // u:X = or_i(X,0)
+            node = AST {
+                position: column,
+                end: ctx.end(),
+                fragment: Fragment::OrI {
+                    x: ctx.add_node(node)?,
+                    z: ctx.add_node(AST {
+                        position: column,
+                        end: ctx.end(),
+                        fragment: Fragment::False,
+                    })?,
+                },
+            }
+        } else {
+            // invalid identity type
+            return Err(ParseError::UnknownWrapper {
+                found: id_type,
+                position: column + id_index as Position,
+            });
         }
     }
+
+    Ok(node)
 }
 
-fn parse_bool<'a>(ctx: &mut ParserContext<'a>) -> Result<AST, ParseError<'a>> {
-    let (token, column) = ctx.peek_token().ok_or(ParseError::UnexpectedEof {
-        context: "parse_bool",
-    })?;
+fn parse_bool<'a, S: Storage>(ctx: &mut ParserContext<'a, S>) -> Result<AST, ParseError<'a>> {
+    let (token, column) = ctx
+        .peek_token()
+        .ok_or_else(|| ctx.unexpected_eof(ParseContext::Expression))?;
 
     match token {
         "0" => {
-            ctx.next_token("parse_bool")?;
+            ctx.next_token(ParseContext::Expression)?;
             Ok(AST {
                 position: column,
+                end: ctx.end(),
                 fragment: Fragment::False,
             })
         }
         "1" => {
-            ctx.next_token("parse_bool")?;
+            ctx.next_token(ParseContext::Expression)?;
             Ok(AST {
                 position: column,
+                end: ctx.end(),
                 fragment: Fragment::True,
             })
         }
@@ -0,0 +1,859 @@
+use bitcoin::Witness;
+use bitcoin::opcodes::all::OP_PUSHDATA1;
+
+use crate::{
+    Vec,
+    context::Context,
+    descriptor::Descriptor,
+    error::Span,
+    parser::keys::{DefiniteKeyToken, KeyToken},
+    parser::{AST, Fragment, IdentityType, KeyIndex, NodeIndex},
+    satisfy::{HashFunc, Requirements, Satisfier, SatisfyError, Strategy},
+};
+
+/// Size of the placeholder for an ECDSA signature: a 72 byte DER signature plus the sighash byte
+const MAX_ECDSA_SIGNATURE_SIZE: usize = 73;
+/// Size of the placeholder for a Schnorr signature: 64 bytes plus the sighash byte
+const MAX_SCHNORR_SIGNATURE_SIZE: usize = 65;
+/// Preimages of all the supported hash fragments are 32 bytes long
+const PREIMAGE_SIZE: usize = 32;
+/// Size of the P2WPKH program `sh(wpkh())` pushes as its redeem script
+const P2WPKH_SCRIPT_SIZE: usize = 22;
+/// Size of the P2WSH program `sh(wsh())` pushes as its redeem script
+const P2WSH_SCRIPT_SIZE: usize = 34;
+
+/// Absolute locktimes below this value are block heights, the others are timestamps
+const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+/// Relative locktimes with this flag are in units of 512 seconds, the others are blocks
+const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000ffff;
+
+/// What is available to spend a descriptor before any signature exists.
+///
+/// # Examples
+///
+/// ```rust
+/// use tinyminiscript::{Context, plan::Assets};
+///
+/// let ctx = Context::try_from("wsh(or_d(pk(022f8bde4d1a07209355b4a7250a5c5128e88b84bddc619ab7cba8d569b240efe4),and_v(v:pk(025cbdf0646e5db4eaa398f365f2ea7a0e3d419b7e0330e39ce92bddedcac4f9bc),older(144))))").unwrap();
+///
+/// let mut assets = Assets::new().older_blocks(200);
+/// ctx.iterate_keys(|key| {
+///     if key.identifier().starts_with("025c") {
+///         assets = assets.clone().add_key(key.clone());
+///     }
+/// });
+///
+/// let plan = ctx.plan(&assets).unwrap();
+/// assert_eq!(plan.keys.len(), 1);
+/// assert_eq!(plan.relative_timelock, Some(144));
+/// ```
+#[derive(Clone, Default)]
+#[cfg_attr(feature = "debug", derive(Debug))]
+pub struct Assets {
+    keys: Vec<KeyToken>,
+    preimages: Vec<(HashFunc, Vec<u8>)>,
+    height: Option<u32>,
+    time: Option<u32>,
+    age_blocks: Option<u16>,
+    age_time: Option<u16>,
+}
+
+impl Assets {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A key we can request a signature from.
+    pub fn add_key(mut self, key: KeyToken) -> Self {
+        self.keys.push(key);
+        self
+    }
+
+    /// A hash we know the preimage of.
+    pub fn add_preimage(mut self, hash_func: HashFunc, hash: &[u8]) -> Self {
+        self.preimages.push((hash_func, hash.to_vec()));
+        self
+    }
+
+    /// The current block height, satisfying height based `after()`.
+    pub fn after_height(mut self, height: u32) -> Self {
+        self.height = Some(height);
+        self
+    }
+
+    /// The current median time past, satisfying time based `after()`.
+    pub fn after_time(mut self, time: u32) -> Self {
+        self.time = Some(time);
+        self
+    }
+
+    /// The number of blocks since the coin was confirmed, satisfying block based `older()`.
+    pub fn older_blocks(mut self, blocks: u16) -> Self {
+        self.age_blocks = Some(blocks);
+        self
+    }
+
+    /// The time since the coin was confirmed in units of 512 seconds, satisfying time
+    /// based `older()`.
+    pub fn older_time(mut self, units: u16) -> Self {
+        self.age_time = Some(units);
+        self
+    }
+}
+
+/// The planning satisfier answers with placeholders of the maximum size, so the chosen
+/// path and its witness size are known before any signature exists.
+impl Satisfier for Assets {
+    fn check_older(&self, locktime: u32) -> Option<bool> {
+        let value = locktime & SEQUENCE_LOCKTIME_MASK;
+        let age = if locktime & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+            self.age_time
+        } else {
+            self.age_blocks
+        };
+        Some(age.is_some_and(|age| value <= age as u32))
+    }
+
+    fn check_after(&self, locktime: u32) -> Option<bool> {
+        let now = if locktime < LOCKTIME_THRESHOLD {
+            self.height
+        } else {
+            self.time
+        };
+        Some(now.is_some_and(|now| locktime <= now))
+    }
+
+    fn sign(&self, pubkey: &KeyToken) -> Option<(Vec<u8>, bool)> {
+        if !self.keys.contains(pubkey) {
+            return Some((Vec::new(), false));
+        }
+        let size = match pubkey.as_definite_key() {
+            Some(DefiniteKeyToken::XOnlyPublicKey(_)) => MAX_SCHNORR_SIGNATURE_SIZE,
+            _ => MAX_ECDSA_SIGNATURE_SIZE,
+        };
+        Some((alloc::vec![0; size], true))
+    }
+
+    fn preimage(&self, hash_func: HashFunc, hash: &[u8]) -> Option<(Vec<u8>, bool)> {
+        let known = self
+            .preimages
+            .iter()
+            .any(|(f, h)| *f == hash_func && h.as_slice() == hash);
+        if known {
+            Some((alloc::vec![0; PREIMAGE_SIZE], true))
+        } else {
+            Some((Vec::new(), false))
+        }
+    }
+}
+
+/// How a descriptor will be spent, computed from [`Assets`] before any signature exists.
+#[cfg_attr(feature = "debug", derive(Debug))]
+#[derive(Clone)]
+pub struct Plan {
+    /// The keys to request signatures from
+    pub keys: Vec<KeyToken>,
+    /// The preimages needed, as the hash function and the hash
+    pub preimages: Vec<(HashFunc, Vec<u8>)>,
+    /// The `nSequence` to set, if the path has `older()` constraints
+    pub relative_timelock: Option<u32>,
+    /// The `nLockTime` to set, if the path has `after()` constraints
+    pub absolute_timelock: Option<u32>,
+    /// The size of the witness, with signatures counted at their maximum size (73 bytes
+    /// for ECDSA, 65 for Schnorr). It includes the witness script of `wsh()`, and the leaf
+    /// script and control block of a `tr()` script path. Legacy descriptors have no witness.
+    pub witness_size: usize,
+    /// The size of the script sig, without its length prefix. For legacy descriptors it is
+    /// made of minimal pushes of the satisfaction, ending with the redeem script of `sh()`.
+    /// `sh(wpkh())` and `sh(wsh())` only push their redeem script, 23 and 35 bytes.
+    pub script_sig_size: usize,
+    /// Whether a third party could change the witness without invalidating it
+    pub malleable: bool,
+}
+
+impl Plan {
+    pub(crate) fn new(ctx: &Context, assets: &Assets) -> Result<Self, SatisfyError> {
+        let sat = ctx.satisfy(assets, &Strategy::DEFAULT)?.sat;
+        if !sat.available {
+            return Err(SatisfyError::Unsatisfiable {
+                span: ctx.get_root().span(),
+            });
+        }
+
+        let requirements = sat.requirements;
+        let (witness_size, script_sig_size) = spend_size(ctx, &push_script(ctx, sat.witness)?);
+        Ok(Self {
+            keys: requirements.keys,
+            preimages: requirements.preimages,
+            relative_timelock: requirements.older,
+            absolute_timelock: requirements.after,
+            witness_size,
+            script_sig_size,
+            malleable: sat.malleable,
+        })
+    }
+
+    /// Satisfy the plan once the signatures and preimages are available, yielding the
+    /// planned witness. The witness script of `wsh()`, or the redeem script of `sh()`, is
+    /// pushed last.
+    ///
+    /// Only the keys, preimages and locktimes of the plan are used, whatever else the
+    /// satisfier could provide.
+    pub fn satisfy(
+        &self,
+        ctx: &Context,
+        satisfier: &dyn Satisfier,
+    ) -> Result<Witness, SatisfyError> {
+        let sat = ctx
+            .satisfy(
+                &PlanSatisfier {
+                    plan: self,
+                    inner: satisfier,
+                },
+                &Strategy::DEFAULT,
+            )?
+            .sat;
+        if !sat.available {
+            return Err(SatisfyError::Unsatisfiable {
+                span: ctx.get_root().span(),
+            });
+        }
+        push_script(ctx, sat.witness)
+    }
+}
+
+/// Push the witness script of `wsh()` or the redeem script of `sh()` after the satisfaction.
+/// The leaf script of `tr()` is part of the satisfaction already.
+fn push_script(ctx: &Context, mut witness: Witness) -> Result<Witness, SatisfyError> {
+    if matches!(ctx.descriptor(), Descriptor::Wsh | Descriptor::Sh) {
+        witness.push(crate::satisfy::build_script(ctx, ctx.get_root().span())?);
+    }
+    Ok(witness)
+}
+
+/// The sizes of the witness and of the script sig, which pushes the elements of `witness`
+/// for descriptors that are not spent from the witness.
+fn spend_size(ctx: &Context, witness: &Witness) -> (usize, usize) {
+    if !ctx.descriptor().spends_from_witness() {
+        return (0, witness.iter().map(push_size).sum());
+    }
+    let script_sig_size = match ctx.descriptor() {
+        _ if !ctx.is_wrapped() => 0,
+        Descriptor::Wpkh => 1 + P2WPKH_SCRIPT_SIZE,
+        _ => 1 + P2WSH_SCRIPT_SIZE,
+    };
+    (witness.size(), script_sig_size)
+}
+
+/// The size of the minimal push of `data`: `OP_0`, `OP_1NEGATE` and `OP_1` to `OP_16`
+/// for the values they stand for, otherwise the shortest push opcode followed by `data`.
+fn push_size(data: &[u8]) -> usize {
+    match data {
+        [] | [0x81] | [1..=16] => 1,
+        _ if data.len() < OP_PUSHDATA1.to_u8() as usize => 1 + data.len(),
+        _ if data.len() <= 0xff => 2 + data.len(),
+        _ if data.len() <= 0xffff => 3 + data.len(),
+        _ => 5 + data.len(),
+    }
+}
+
+/// Enumerate the minimal sets of requirements satisfying the root of `ctx`, then plan
+/// each of them to get its witness size and malleability.
+pub(crate) fn spending_paths(ctx: &Context, max_paths: usize) -> Result<Vec<Plan>, SatisfyError> {
+    let enumerator = PathEnumerator { ctx, max_paths };
+    let (sats, _) = enumerator.enumerate(ctx.get_root())?;
+
+    sats.into_iter()
+        .map(|requirements| Plan::new(ctx, &Assets::from_requirements(requirements)))
+        .collect()
+}
+
+impl Assets {
+    /// Exactly the assets needed to fulfill `requirements`.
+    fn from_requirements(requirements: Requirements) -> Self {
+        let mut assets = Self {
+            keys: requirements.keys,
+            preimages: requirements.preimages,
+            ..Self::default()
+        };
+        if let Some(n) = requirements.older {
+            let value = (n & SEQUENCE_LOCKTIME_MASK) as u16;
+            assets = if n & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+                assets.older_time(value)
+            } else {
+                assets.older_blocks(value)
+            };
+        }
+        if let Some(n) = requirements.after {
+            assets = if n < LOCKTIME_THRESHOLD {
+                assets.after_height(n)
+            } else {
+                assets.after_time(n)
+            };
+        }
+        assets
+    }
+}
+
+/// Computes, for every fragment, the minimal sets of requirements of its satisfactions
+/// and dissatisfactions, following the same combinations as the satisfier.
+struct PathEnumerator<'a> {
+    ctx: &'a Context,
+    max_paths: usize,
+}
+
+/// Alternative sets of requirements, an empty list means unavailable.
+type Paths = Vec<Requirements>;
+
+impl PathEnumerator<'_> {
+    /// Returns the (satisfaction, dissatisfaction) paths of the node.
+    fn enumerate(&self, node: &AST) -> Result<(Paths, Paths), SatisfyError> {
+        // the sub-expressions are enumerated bottom-up, before the fragments using them
+        let mut done: Vec<Option<(Paths, Paths)>> =
+            (0..self.ctx.get_nodes().len()).map(|_| None).collect();
+        for index in self.ctx.post_order(node) {
+            let node = self.ctx.get_node(index);
+            done[index as usize] = Some(self.paths(node, &mut done)?);
+        }
+        self.paths(node, &mut done)
+    }
+
+    /// Returns the paths of a fragment from the paths of its sub-expressions in `done`.
+    fn paths(
+        &self,
+        node: &AST,
+        done: &mut [Option<(Paths, Paths)>],
+    ) -> Result<(Paths, Paths), SatisfyError> {
+        let mut child = |index: NodeIndex| {
+            done[index as usize]
+                .take()
+                .expect("sub-expressions are enumerated first")
+        };
+        let none = || alloc::vec![Requirements::default()];
+        let span = node.span();
+
+        Ok(match &node.fragment {
+            Fragment::False => (Vec::new(), none()),
+            Fragment::True => (none(), Vec::new()),
+            Fragment::PkK { key }
+            | Fragment::PkH { key }
+            | Fragment::RawPkH { key }
+            | Fragment::RawPk { key } => (alloc::vec![self.key(*key)], none()),
+            Fragment::Older { n } => (
+                alloc::vec![Requirements {
+                    older: Some(*n),
+                    ..Requirements::default()
+                }],
+                Vec::new(),
+            ),
+            Fragment::After { n } => (
+                alloc::vec![Requirements {
+                    after: Some(*n),
+                    ..Requirements::default()
+                }],
+                Vec::new(),
+            ),
+            Fragment::Sha256 { h } => (Self::preimage(HashFunc::Sha256, h), none()),
+            Fragment::Hash256 { h } => (Self::preimage(HashFunc::Hash256, h), none()),
+            Fragment::Ripemd160 { h } => (Self::preimage(HashFunc::Ripemd160, h), none()),
+            Fragment::Hash160 { h } => (Self::preimage(HashFunc::Hash160, h), none()),
+            Fragment::AndOr { x, y, z } => {
+                let (x_sat, x_dsat) = child(*x);
+                let (y_sat, y_dsat) = child(*y);
+                let (z_sat, z_dsat) = child(*z);
+                (
+                    self.union(
+                        self.product(&y_sat, &x_sat, span)?,
+                        self.product(&z_sat, &x_dsat, span)?,
+                        span,
+                    )?,
+                    self.union(
+                        self.product(&z_dsat, &x_dsat, span)?,
+                        self.product(&y_dsat, &x_sat, span)?,
+                        span,
+                    )?,
+                )
+            }
+            Fragment::AndV { x, y } => {
+                let (x_sat, _) = child(*x);
+                let (y_sat, y_dsat) = child(*y);
+                (
+                    self.product(&y_sat, &x_sat, span)?,
+                    self.product(&y_dsat, &x_sat, span)?,
+                )
+            }
+            Fragment::AndB { x, y } => {
+                let (x_sat, x_dsat) = child(*x);
+                let (y_sat, y_dsat) = child(*y);
+                let dsat = self.union(
+                    self.product(&y_dsat, &x_dsat, span)?,
+                    self.product(&y_sat, &x_dsat, span)?,
+                    span,
+                )?;
+                (
+                    self.product(&y_sat, &x_sat, span)?,
+                    self.union(dsat, self.product(&y_dsat, &x_sat, span)?, span)?,
+                )
+            }
+            Fragment::OrB { x, z } => {
+                let (x_sat, x_dsat) = child(*x);
+                let (z_sat, z_dsat) = child(*z);
+                let sat = self.union(
+                    self.product(&z_dsat, &x_sat, span)?,
+                    self.product(&z_sat, &x_dsat, span)?,
+                    span,
+                )?;
+                (
+                    self.union(sat, self.product(&z_sat, &x_sat, span)?, span)?,
+                    self.product(&z_dsat, &x_dsat, span)?,
+                )
+            }
+            Fragment::OrC { x, z } => {
+                let (x_sat, x_dsat) = child(*x);
+                let (z_sat, _) = child(*z);
+                (
+                    self.union(x_sat, self.product(&z_sat, &x_dsat, span)?, span)?,
+                    Vec::new(),
+                )
+            }
+            Fragment::OrD { x, z } => {
+                let (x_sat, x_dsat) = child(*x);
+                let (z_sat, z_dsat) = child(*z);
+                (
+                    self.union(x_sat, self.product(&z_sat, &x_dsat, span)?, span)?,
+                    self.product(&z_dsat, &x_dsat, span)?,
+                )
+            }
+            Fragment::OrI { x, z } => {
+                let (x_sat, x_dsat) = child(*x);
+                let (z_sat, z_dsat) = child(*z);
+                (
+                    self.union(x_sat, z_sat, span)?,
+                    self.union(x_dsat, z_dsat, span)?,
+                )
+            }
+            Fragment::Thresh { k, xs } => {
+                // sats[j] holds the paths satisfying exactly j of the sub-expressions seen so far
+                let mut sats: Vec<Paths> = alloc::vec![none()];
+                for x in self.ctx.list(*xs) {
+                    let (x_sat, x_dsat) = child(*x);
+                    let mut next_sats = Vec::with_capacity(sats.len() + 1);
+                    next_sats.push(self.product(&sats[0], &x_dsat, span)?);
+                    for j in 1..sats.len() {
+                        next_sats.push(self.union(
+                            self.product(&sats[j], &x_dsat, span)?,
+                            self.product(&sats[j - 1], &x_sat, span)?,
+                            span,
+                        )?);
+                    }
+                    next_sats.push(self.product(&sats[sats.len() - 1], &x_sat, span)?);
+                    sats = next_sats;
+                }
+
+                let k = *k as usize;
+                let mut dsat = Vec::new();
+                for (j, paths) in sats.iter().enumerate() {
+                    if j != k {
+                        dsat = self.union(dsat, paths.clone(), span)?;
+                    }
+                }
+                (sats.get(k).cloned().unwrap_or_default(), dsat)
+            }
+            Fragment::Multi { k, keys } | Fragment::MultiA { k, keys } => (
+                self.combinations(self.ctx.list(*keys), *k as usize, span)?,
+                none(),
+            ),
+            Fragment::Identity { identity_type, x } => {
+                let (x_sat, x_dsat) = child(*x);
+                match identity_type {
+                    IdentityType::D | IdentityType::J => (x_sat, none()),
+                    IdentityType::V => (x_sat, Vec::new()),
+                    _ => (x_sat, x_dsat),
+                }
+            }
+            Fragment::Descriptor { inner, .. } => child(*inner),
+            Fragment::RawTr { key, inner } => {
+                // nobody can sign for a NUMS internal key
+                let key_path = if self.ctx.get_key(*key).is_nums() {
+                    Vec::new()
+                } else {
+                    alloc::vec![self.key(*key)]
+                };
+                match inner {
+                    Some(inner) => {
+                        let (script_paths, _) = child(*inner);
+                        (self.union(key_path, script_paths, span)?, Vec::new())
+                    }
+                    None => (key_path, Vec::new()),
+                }
+            }
+            Fragment::RawAddr { .. } | Fragment::RawScript { .. } => {
+                return Err(SatisfyError::NotSolvable { span });
+            }
+        })
+    }
+
+    fn key(&self, key: KeyIndex) -> Requirements {
+        Requirements {
+            keys: alloc::vec![self.ctx.get_key(key).clone()],
+            ..Requirements::default()
+        }
+    }
+
+    fn preimage(hash_func: HashFunc, hash: &[u8]) -> Paths {
+        alloc::vec![Requirements {
+            preimages: alloc::vec![(hash_func, hash.to_vec())],
+            ..Requirements::default()
+        }]
+    }
+
+    /// Every way of fulfilling one path of `a` and one path of `b`.
+    fn product(
+        &self,
+        a: &[Requirements],
+        b: &[Requirements],
+        span: Span,
+    ) -> Result<Paths, SatisfyError> {
+        let mut paths = Vec::with_capacity(a.len() * b.len());
+        for a in a {
+            for b in b {
+                paths.push(a.and(b));
+            }
+        }
+        self.minimize(paths, span)
+    }
+
+    /// Every way of fulfilling either a path of `a` or a path of `b`.
+    fn union(&self, mut a: Paths, b: Paths, span: Span) -> Result<Paths, SatisfyError> {
+        a.extend(b);
+        self.minimize(a, span)
+    }
+
+    /// Every way of picking `k` of the keys.
+    fn combinations(&self, keys: &[KeyIndex], k: usize, span: Span) -> Result<Paths, SatisfyError> {
+        let mut paths = Vec::new();
+        if k > keys.len() {
+            return Ok(paths);
+        }
+
+        // indices of the picked keys, in lexicographic order
+        let mut picked: Vec<usize> = (0..k).collect();
+        loop {
+            paths.push(Requirements {
+                keys: picked
+                    .iter()
+                    .map(|i| self.ctx.get_key(keys[*i]).clone())
+                    .collect(),
+                ..Requirements::default()
+            });
+            if paths.len() > self.max_paths {
+                return Err(SatisfyError::TooManyPaths {
+                    max: self.max_paths,
+                    span,
+                });
+            }
+
+            // advance the rightmost index that can still move
+            let Some(i) = (0..k).rev().find(|i| picked[*i] < keys.len() - k + i) else {
+                return Ok(paths);
+            };
+            picked[i] += 1;
+            for j in i + 1..k {
+                picked[j] = picked[j - 1] + 1;
+            }
+        }
+    }
+
+    /// Drop the duplicated paths and the ones requiring more than another path.
+    fn minimize(&self, paths: Paths, span: Span) -> Result<Paths, SatisfyError> {
+        let mut minimal: Paths = Vec::with_capacity(paths.len());
+        for path in paths {
+            if minimal.iter().any(|other| other.is_subset_of(&path)) {
+                continue;
+            }
+            minimal.retain(|other| !path.is_subset_of(other));
+            minimal.push(path);
+        }
+
+        if minimal.len() > self.max_paths {
+            return Err(SatisfyError::TooManyPaths {
+                max: self.max_paths,
+                span,
+            });
+        }
+        Ok(minimal)
+    }
+}
+
+/// Restricts a satisfier to what a plan relies on.
+struct PlanSatisfier<'a> {
+    plan: &'a Plan,
+    inner: &'a dyn Satisfier,
+}
+
+impl Satisfier for PlanSatisfier<'_> {
+    fn check_older(&self, locktime: u32) -> Option<bool> {
+        // the path constraints are only satisfied by the planned nSequence
+        Some(self.plan.relative_timelock.is_some_and(|sequence| {
+            (sequence & SEQUENCE_LOCKTIME_TYPE_FLAG) == (locktime & SEQUENCE_LOCKTIME_TYPE_FLAG)
+                && (locktime & SEQUENCE_LOCKTIME_MASK) <= (sequence & SEQUENCE_LOCKTIME_MASK)
+        }))
+    }
+
+    fn check_after(&self, locktime: u32) -> Option<bool> {
+        Some(self.plan.absolute_timelock.is_some_and(|lock_time| {
+            (lock_time < LOCKTIME_THRESHOLD) == (locktime < LOCKTIME_THRESHOLD)
+                && locktime <= lock_time
+        }))
+    }
+
+    fn sign(&self, pubkey: &KeyToken) -> Option<(Vec<u8>, bool)> {
+        if self.plan.keys.contains(pubkey) {
+            self.inner.sign(pubkey)
+        } else {
+            Some((Vec::new(), false))
+        }
+    }
+
+    fn check_signature(&self, pubkey: &KeyToken, sig: &[u8]) -> bool {
+        self.inner.check_signature(pubkey, sig)
+    }
+
+    fn preimage(&self, hash_func: HashFunc, hash: &[u8]) -> Option<(Vec<u8>, bool)> {
+        if self
+            .plan
+            .preimages
+            .iter()
+            .any(|(f, h)| *f == hash_func && h.as_slice() == hash)
+        {
+            self.inner.preimage(hash_func, hash)
+        } else {
+            Some((Vec::new(), false))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::fixtures::{A, B, C, Signer};
+
+    #[test]
+    fn test_plan_then_satisfy() {
+        let descriptor = alloc::format!(
+            "wsh(or_d(pk({A}),and_v(v:pk({B}),and_v(v:sha256({}),after(800000)))))",
+            "11".repeat(32)
+        );
+        let ctx = Context::try_from(descriptor.as_str()).unwrap();
+
+        let mut b_key = None;
+        ctx.iterate_keys(|key| {
+            if key.identifier() == B {
+                b_key = Some(key.clone());
+            }
+        });
+        let b_key = b_key.unwrap();
+
+        // without the preimage or before the locktime the recovery path is not available
+        let assets = Assets::new().add_key(b_key.clone()).after_height(900_000);
+        assert!(matches!(
+            ctx.plan(&assets),
+            Err(SatisfyError::Unsatisfiable { .. })
+        ));
+        let assets = assets.add_preimage(HashFunc::Sha256, &[0x11; 32]);
+        assert!(ctx.plan(&assets.clone().after_height(700_000)).is_err());
+
+        let plan = ctx.plan(&assets).unwrap();
+        assert_eq!(plan.keys, [b_key]);
+        assert_eq!(plan.preimages, [(HashFunc::Sha256, alloc::vec![0x11; 32])]);
+        assert_eq!(plan.absolute_timelock, Some(800_000));
+        assert_eq!(plan.relative_timelock, None);
+
+        // the signer could sign with both keys, only the planned one is used
+        let witness = plan.satisfy(&ctx, &Signer).unwrap();
+        assert_eq!(witness.len(), 4);
+        // the signatures are 34 bytes long, planned at 73
+        assert_eq!(witness.size(), plan.witness_size - (73 - 34));
+    }
+
+    #[test]
+    fn test_script_sig_size() {
+        use bitcoin::script::{Builder, PushBytes};
+
+        let descriptor =
+            alloc::format!("sh(or_i(pk({A}),and_v(v:pkh({B}),and_v(v:pk({C}),older(144)))))");
+        let ctx = Context::try_from(descriptor.as_str()).unwrap();
+        // the redeem script is pushed with OP_PUSHDATA1
+        assert!(ctx.build_script().unwrap().len() > 75);
+
+        let key = |id: &str| {
+            ctx.get_keys()
+                .iter()
+                .find(|k| k.identifier() == id)
+                .cloned()
+        };
+        for (planned, signatures) in [(A, 1), (B, 2)] {
+            let assets = Assets::new()
+                .add_key(key(planned).unwrap())
+                .add_key(key(C).unwrap())
+                .older_blocks(144);
+            let plan = ctx.plan(&assets).unwrap();
+            let witness = plan.satisfy(&ctx, &Signer).unwrap();
+
+            let mut script_sig = Builder::new();
+            for element in witness.iter() {
+                script_sig = match element {
+                    [] => script_sig.push_int(0),
+                    [n @ 1..=16] => script_sig.push_int(*n as i64),
+                    _ => script_sig.push_slice(<&PushBytes>::try_from(element).unwrap()),
+                };
+            }
+            let script_sig = script_sig.into_script();
+            ctx.inspect_spend(&Witness::new(), &script_sig, &Signer)
+                .unwrap();
+            // the signatures are 34 bytes long, planned at 73
+            assert_eq!(
+                script_sig.len(),
+                plan.script_sig_size - signatures * (73 - 34)
+            );
+            assert_eq!(plan.witness_size, 0);
+        }
+
+        let ctx = Context::try_from(alloc::format!("pkh({A})").as_str()).unwrap();
+        let plan = ctx
+            .plan(&Assets::new().add_key(ctx.get_key(0).clone()))
+            .unwrap();
+        assert_eq!(plan.script_sig_size, (1 + 73) + (1 + 33));
+
+        // nested segwit pushes the native program as its redeem script
+        let ctx = Context::try_from(alloc::format!("sh(wpkh({A}))").as_str()).unwrap();
+        let plan = ctx
+            .plan(&Assets::new().add_key(ctx.get_key(0).clone()))
+            .unwrap();
+        assert_eq!(plan.witness_size, 1 + (1 + 73) + (1 + 33));
+        assert_eq!(plan.script_sig_size, 23);
+
+        let ctx = Context::try_from(alloc::format!("sh(wsh(pk({A})))").as_str()).unwrap();
+        let plan = ctx
+            .plan(&Assets::new().add_key(ctx.get_key(0).clone()))
+            .unwrap();
+        let native = Context::try_from(alloc::format!("wsh(pk({A}))").as_str()).unwrap();
+        assert_eq!(
+            plan.script_sig_size,
+            1 + native.build_script_pubkey().unwrap().len()
+        );
+    }
+
+    #[test]
+    fn test_plan_prefers_the_key_path() {
+        let descriptor = alloc::format!("wsh(or_d(pk({A}),and_v(v:pkh({B}),older(144))))");
+        let ctx = Context::try_from(descriptor.as_str()).unwrap();
+        let key = |id: &str| {
+            ctx.get_keys()
+                .iter()
+                .find(|k| k.identifier() == id)
+                .cloned()
+        };
+
+        // the recovery path needs a signature as well, it is not picked over the key path
+        let assets = Assets::new()
+            .add_key(key(A).unwrap())
+            .add_key(key(B).unwrap())
+            .older_blocks(1000);
+        let plan = ctx.plan(&assets).unwrap();
+        assert_eq!(plan.keys, [key(A).unwrap()]);
+        assert_eq!(plan.relative_timelock, None);
+        let script = ctx.build_script().unwrap();
+        assert_eq!(plan.witness_size, 1 + (1 + 73) + (1 + script.len()));
+        assert_eq!(plan.witness_size, 143);
+    }
+
+    #[test]
+    fn test_spending_paths() {
+        let descriptor = alloc::format!("wsh(or_d(pk({A}),and_v(v:pk({B}),older(144))))");
+        let ctx = Context::try_from(descriptor.as_str()).unwrap();
+        let paths = ctx.spending_paths(10).unwrap();
+        assert_eq!(paths.len(), 2);
+
+        assert_eq!(paths[0].keys.len(), 1);
+        assert_eq!(paths[0].keys[0].identifier(), A);
+        assert_eq!(paths[0].relative_timelock, None);
+        assert!(!paths[0].malleable);
+
+        assert_eq!(paths[1].keys.len(), 1);
+        assert_eq!(paths[1].keys[0].identifier(), B);
+        assert_eq!(paths[1].relative_timelock, Some(144));
+        assert!(paths[1].witness_size > paths[0].witness_size);
+
+        let descriptor = alloc::format!("wsh(multi(2,{A},{B},{C}))");
+        let ctx = Context::try_from(descriptor.as_str()).unwrap();
+        let paths = ctx.spending_paths(3).unwrap();
+        assert_eq!(paths.len(), 3);
+        assert!(paths.iter().all(|path| path.keys.len() == 2));
+        assert!(matches!(
+            ctx.spending_paths(2),
+            Err(SatisfyError::TooManyPaths { max: 2, .. })
+        ));
+
+        // the key path of tr() and its leaf
+        let descriptor = alloc::format!("tr({A},pk({B}))");
+        let ctx = Context::try_from(descriptor.as_str()).unwrap();
+        let paths = ctx.spending_paths(10).unwrap();
+        assert_eq!(paths.len(), 2);
+        assert_eq!(paths[0].keys[0].identifier(), A[2..]);
+        assert_eq!(paths[0].witness_size, 1 + 1 + 65);
+        assert_eq!(paths[1].keys[0].identifier(), B[2..]);
+        // the signature, the leaf script and the control block
+        assert_eq!(paths[1].witness_size, 1 + (1 + 65) + (1 + 34) + (1 + 33));
+
+        let descriptor = alloc::format!("tr(musig({C},{A}),pk({A}))");
+        let ctx = Context::try_from(descriptor.as_str()).unwrap();
+        let paths = ctx.spending_paths(10).unwrap();
+        assert_eq!(paths.len(), 2);
+        assert!(paths[0].keys[0].identifier().starts_with("musig("));
+    }
+
+    #[test]
+    fn test_nums_internal_key() {
+        let nums = KeyToken::nums();
+
+        let descriptor = alloc::format!("tr({nums},or_d(pk({A}),and_v(v:pk({B}),older(144))))");
+        let ctx = Context::try_from(descriptor.as_str()).unwrap();
+        assert!(!ctx.has_key_path());
+        let tr = alloc::format!("tr({A},pk({B}))");
+        assert!(Context::try_from(tr.as_str()).unwrap().has_key_path());
+        let wsh = alloc::format!("wsh(pk({A}))");
+        assert!(!Context::try_from(wsh.as_str()).unwrap().has_key_path());
+
+        // only the script paths are left, and the witness is the one of the script
+        let paths = ctx.spending_paths(10).unwrap();
+        assert_eq!(paths.len(), 2);
+        assert!(
+            paths
+                .iter()
+                .all(|path| path.keys.iter().all(|key| !key.is_nums()))
+        );
+        assert_eq!(paths[0].keys[0].identifier(), A[2..]);
+        assert_eq!(paths[1].relative_timelock, Some(144));
+        let witness = paths[0].satisfy(&ctx, &Signer).unwrap();
+        ctx.verify_witness(&witness, &Signer).unwrap();
+
+        // an unrecognisable NUMS key per index
+        let xpub = KeyToken::nums_xpub([7; 32], bitcoin::NetworkKind::Test);
+        let descriptor = alloc::format!("tr({xpub},pk({A}))");
+        let ctx = Context::try_from(descriptor.as_str()).unwrap();
+        let derived = ctx.derive(5).unwrap();
+        assert!(!derived.has_key_path());
+        assert_eq!(derived.spending_paths(10).unwrap().len(), 1);
+
+        let descriptor = alloc::format!("tr({nums})");
+        let ctx = Context::try_from(descriptor.as_str()).unwrap();
+        assert!(ctx.spending_paths(10).unwrap().is_empty());
+        assert!(matches!(
+            ctx.plan(&Assets::new().add_key(nums)),
+            Err(SatisfyError::Unsatisfiable { .. })
+        ));
+    }
+}
@@ -1,11 +1,21 @@
 use core::ops::Deref;
 
-use bitcoin::Witness;
+use alloc::rc::Rc;
+
+use bitcoin::key::TapTweak;
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::taproot::{ControlBlock, LeafVersion, TapNodeHash, TaprootMerkleBranch};
+use bitcoin::{ScriptBuf, Witness, consensus::encode::VarInt};
 
 use crate::{
     Vec, bitcoin_definition_link,
     context::Context,
-    parser::{AST, Fragment, keys::KeyToken},
+    error::{Diagnostic, Span},
+    parser::{
+        AST, Fragment, NodeIndex,
+        keys::{DefiniteKeyToken, KeyToken},
+    },
+    script::ScriptBuilderError,
 };
 
 use alloc::string::String;
@@ -22,12 +32,18 @@ pub trait Satisfier {
     /// Sign generates a signature for the given public key.
     fn sign(&self, pubkey: &KeyToken) -> Option<(Vec<u8>, bool)>;
 
+    /// CheckSignature checks a signature returned by [`Satisfier::sign`] before it is used
+    /// in a satisfaction. Every signature is accepted by default.
+    fn check_signature(&self, _pubkey: &KeyToken, _sig: &[u8]) -> bool {
+        true
+    }
+
     /// Preimage returns the preimage of the hash value. hashFunc is one of "sha256", "ripemd160",
     /// "hash256", "hash160".
     fn preimage(&self, hash_func: HashFunc, hash: &[u8]) -> Option<(Vec<u8>, bool)>;
 }
 
-#[cfg_attr(feature = "debug", derive(Debug))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum HashFunc {
     Sha256,
@@ -36,6 +52,17 @@ pub enum HashFunc {
     Hash160,
 }
 
+impl core::fmt::Display for HashFunc {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            HashFunc::Sha256 => "sha256",
+            HashFunc::Ripemd160 => "ripemd160",
+            HashFunc::Hash256 => "hash256",
+            HashFunc::Hash160 => "hash160",
+        })
+    }
+}
+
 impl HashFunc {
     pub const fn expected_length(&self) -> usize {
         // match self {
@@ -55,6 +82,59 @@ pub struct Satisfaction {
     pub available: bool,
     pub malleable: bool,
     pub has_sig: bool,
+    /// Keys, preimages and locktimes the witness relies on
+    pub(crate) requirements: Requirements,
+}
+
+/// Keys, preimages and locktimes a satisfaction relies on.
+#[derive(Clone, Default)]
+#[cfg_attr(feature = "debug", derive(Debug))]
+pub(crate) struct Requirements {
+    pub(crate) keys: Vec<KeyToken>,
+    pub(crate) preimages: Vec<(HashFunc, Vec<u8>)>,
+    /// The highest `older()` value, i.e. the `nSequence` to set
+    pub(crate) older: Option<u32>,
+    /// The highest `after()` value, i.e. the `nLockTime` to set
+    pub(crate) after: Option<u32>,
+}
+
+impl Requirements {
+    const NONE: Requirements = Requirements {
+        keys: Vec::new(),
+        preimages: Vec::new(),
+        older: None,
+        after: None,
+    };
+
+    pub(crate) fn and(&self, other: &Self) -> Self {
+        let mut keys = self.keys.clone();
+        for key in &other.keys {
+            if !keys.contains(key) {
+                keys.push(key.clone());
+            }
+        }
+        let mut preimages = self.preimages.clone();
+        for preimage in &other.preimages {
+            if !preimages.contains(preimage) {
+                preimages.push(preimage.clone());
+            }
+        }
+        Self {
+            keys,
+            preimages,
+            older: self.older.max(other.older),
+            after: self.after.max(other.after),
+        }
+    }
+
+    /// Whether everything required by `self` is also required by `other`.
+    pub(crate) fn is_subset_of(&self, other: &Self) -> bool {
+        let within = |n: Option<u32>, m: Option<u32>| n.is_none_or(|n| m.is_some_and(|m| n <= m));
+        self.keys.iter().all(|key| other.keys.contains(key))
+            && self.preimages.iter().all(|p| other.preimages.contains(p))
+            && within(self.older, other.older)
+            && within(self.after, other.after)
+    }
 }
 
 impl Satisfaction {
@@ -66,6 +146,7 @@ impl Satisfaction {
             available,
             malleable,
             has_sig,
+            requirements: Requirements::NONE,
         }
     }
 
@@ -85,21 +166,345 @@ impl Satisfaction {
     }
 
     pub fn and(&self, other: &Self) -> Self {
+        Candidate::from(self).and(&Candidate::from(other)).build()
+    }
+
+    pub fn or(&self, other: &Self) -> Self {
+        self.or_with(other, &Strategy::DEFAULT)
+    }
+
+    /// Pick the best of two satisfactions according to `strategy`.
+    pub fn or_with(&self, other: &Self, strategy: &Strategy) -> Self {
+        Candidate::from_satisfaction(self, strategy)
+            .or_with(&Candidate::from_satisfaction(other, strategy), strategy)
+            .build()
+    }
+}
+
+/// How [`Context::satisfy`] picks between the satisfactions of a disjunction or
+/// threshold.
+///
+/// By default only non-malleable satisfactions are preferred, then the smallest witness.
+///
+/// # Examples
+///
+/// ```rust
+/// use tinyminiscript::satisfy::Strategy;
+///
+/// // our own transaction, relayed directly to a miner
+/// let strategy = Strategy::new().allow_malleable(true).avoid_timelocks(true);
+/// ```
+#[derive(Clone, Default)]
+#[cfg_attr(feature = "debug", derive(Debug))]
+pub struct Strategy {
+    allow_malleable: bool,
+    avoid_timelocks: bool,
+    avoid_preimages: bool,
+    preferred_keys: Vec<KeyToken>,
+}
+
+impl Strategy {
+    /// Non-malleable satisfactions with the smallest witness.
+    pub const DEFAULT: Strategy = Strategy {
+        allow_malleable: false,
+        avoid_timelocks: false,
+        avoid_preimages: false,
+        preferred_keys: Vec::new(),
+    };
+
+    #[inline]
+    pub fn new() -> Self {
+        Self::DEFAULT
+    }
+
+    /// Pick a malleable satisfaction if it is smaller, e.g. for transactions that are not
+    /// relayed through the public network.
+    pub fn allow_malleable(mut self, allow: bool) -> Self {
+        self.allow_malleable = allow;
+        self
+    }
+
+    /// Never use `older()` and `after()`, paths relying on them are unavailable.
+    pub fn avoid_timelocks(mut self, avoid: bool) -> Self {
+        self.avoid_timelocks = avoid;
+        self
+    }
+
+    /// Prefer paths that do not reveal hash preimages, even if their witness is larger.
+    pub fn avoid_preimages(mut self, avoid: bool) -> Self {
+        self.avoid_preimages = avoid;
+        self
+    }
+
+    /// Prefer paths signed only by `keys`, even if their witness is larger.
+    pub fn prefer_keys(mut self, keys: &[KeyToken]) -> Self {
+        self.preferred_keys = keys.to_vec();
+        self
+    }
+}
+
+#[cfg_attr(feature = "debug", derive(Debug))]
+
+pub struct Satisfactions {
+    pub dsat: Satisfaction,
+    pub sat: Satisfaction,
+}
+
+impl Satisfactions {
+    #[inline]
+    pub const fn new(dsat: Satisfaction, sat: Satisfaction) -> Self {
+        Self { dsat, sat }
+    }
+}
+
+#[derive(Debug)]
+pub enum SatisfyError {
+    MissingSignature { key: String, span: Span },
+    MissingLockTime { locktime: u32, span: Span },
+    MissingPreimage { hash_func: HashFunc, span: Span },
+    InvalidPreimage { hash_func: HashFunc, span: Span },
+    NonDefiniteKey { key: String, span: Span },
+    Unsatisfiable { span: Span },
+    TooManyPaths { max: usize, span: Span },
+    InvalidSignature { key: String, span: Span },
+    Sighash { span: Span },
+    NotSolvable { span: Span },
+    InvalidThreshold { k: i32, span: Span },
+}
+
+impl core::fmt::Display for SatisfyError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            SatisfyError::MissingSignature { key, .. } => {
+                write!(
+                    f,
+                    "satisfier has no answer for the signature of key '{key}'"
+                )
+            }
+            SatisfyError::MissingLockTime { locktime, .. } => {
+                write!(f, "satisfier has no answer for locktime {locktime}")
+            }
+            SatisfyError::MissingPreimage { hash_func, .. } => {
+                write!(f, "satisfier has no answer for the {hash_func} preimage")
+            }
+            SatisfyError::InvalidPreimage { hash_func, .. } => {
+                write!(f, "{hash_func} preimage must be 32 bytes long")
+            }
+            SatisfyError::NonDefiniteKey { key, .. } => {
+                write!(f, "key '{key}' must be derived before satisfying")
+            }
+            SatisfyError::Unsatisfiable { .. } => {
+                write!(f, "no spending path is available")
+            }
+            SatisfyError::TooManyPaths { max, .. } => {
+                write!(f, "fragment has more than {max} spending paths")
+            }
+            SatisfyError::InvalidSignature { key, .. } => {
+                write!(
+                    f,
+                    "signature of key '{key}' is invalid for the spending transaction"
+                )
+            }
+            SatisfyError::Sighash { .. } => {
+                write!(f, "sighash cannot be computed for the spending transaction")
+            }
+            SatisfyError::NotSolvable { .. } => {
+                write!(f, "script is not miniscript, how to spend it is unknown")
+            }
+            SatisfyError::InvalidThreshold { k, .. } => {
+                write!(
+                    f,
+                    "threshold {k} must be between 1 and the number of sub-expressions"
+                )
+            }
+        }
+    }
+}
+
+impl core::error::Error for SatisfyError {}
+
+impl Diagnostic for SatisfyError {
+    fn code(&self) -> &'static str {
+        match self {
+            SatisfyError::MissingSignature { .. } => "F001",
+            SatisfyError::MissingLockTime { .. } => "F002",
+            SatisfyError::MissingPreimage { .. } => "F003",
+            SatisfyError::InvalidPreimage { .. } => "F004",
+            SatisfyError::NonDefiniteKey { .. } => "F005",
+            // F006 was taproot satisfaction not being supported, it is not reused
+            SatisfyError::Unsatisfiable { .. } => "F007",
+            SatisfyError::TooManyPaths { .. } => "F008",
+            SatisfyError::InvalidSignature { .. } => "F009",
+            SatisfyError::Sighash { .. } => "F010",
+            SatisfyError::NotSolvable { .. } => "F011",
+            SatisfyError::InvalidThreshold { .. } => "F012",
+        }
+    }
+
+    fn span(&self) -> Option<Span> {
+        match self {
+            SatisfyError::MissingSignature { span, .. }
+            | SatisfyError::MissingLockTime { span, .. }
+            | SatisfyError::MissingPreimage { span, .. }
+            | SatisfyError::InvalidPreimage { span, .. }
+            | SatisfyError::NonDefiniteKey { span, .. }
+            | SatisfyError::Unsatisfiable { span }
+            | SatisfyError::TooManyPaths { span, .. }
+            | SatisfyError::InvalidSignature { span, .. }
+            | SatisfyError::Sighash { span }
+            | SatisfyError::NotSolvable { span }
+            | SatisfyError::InvalidThreshold { span, .. } => Some(*span),
+        }
+    }
+}
+
+/// A satisfaction whose witness is not materialized yet.
+///
+/// The witness is a tree of concatenations shared with the candidates it was built from,
+/// so `and` does not copy stacks and `or` only compares sizes and metadata. The witness
+/// of the chosen candidate is built once by [`Candidate::build`].
+#[derive(Clone)]
+pub(crate) struct Candidate {
+    stack: Stack,
+    /// Number of witness elements
+    elements: usize,
+    /// Serialized size of the witness elements, without the element count
+    bytes: usize,
+    available: bool,
+    malleable: bool,
+    has_sig: bool,
+    has_preimage: bool,
+    /// Whether every key relied on is preferred by the strategy the candidate is built for
+    uses_preferred_keys: bool,
+    older: Option<u32>,
+    after: Option<u32>,
+}
+
+#[derive(Clone)]
+enum Stack {
+    Empty,
+    Element(Rc<[u8]>),
+    /// Requirements do not add elements to the witness
+    Key(Rc<KeyToken>),
+    Preimage(Rc<(HashFunc, Vec<u8>)>),
+    Concat(Rc<(Stack, Stack)>),
+}
+
+impl Stack {
+    fn concat(a: &Stack, b: &Stack) -> Stack {
+        match (a, b) {
+            (Stack::Empty, stack) | (stack, Stack::Empty) => stack.clone(),
+            _ => Stack::Concat(Rc::new((a.clone(), b.clone()))),
+        }
+    }
+
+    /// Visit the leaves from the bottom of the stack, without recursion as the tree of
+    /// large thresholds is deep.
+    fn for_each(&self, mut f: impl FnMut(&Stack)) {
+        let mut pending = alloc::vec![self];
+        while let Some(stack) = pending.pop() {
+            match stack {
+                Stack::Empty => (),
+                Stack::Concat(pair) => {
+                    pending.push(&pair.1);
+                    pending.push(&pair.0);
+                }
+                leaf => f(leaf),
+            }
+        }
+    }
+}
+
+const EMPTY: Candidate = Candidate {
+    stack: Stack::Empty,
+    elements: 0,
+    bytes: 0,
+    available: true,
+    malleable: false,
+    has_sig: false,
+    has_preimage: false,
+    uses_preferred_keys: true,
+    older: None,
+    after: None,
+};
+
+const UNAVAILABLE: Candidate = Candidate {
+    available: false,
+    ..EMPTY
+};
+
+impl Candidate {
+    /// A witness with a single element.
+    fn push(data: &[u8]) -> Self {
+        Self {
+            stack: Stack::Element(Rc::from(data)),
+            elements: 1,
+            bytes: VarInt::from(data.len()).size() + data.len(),
+            ..EMPTY
+        }
+    }
+
+    fn set_available(mut self, available: bool) -> Self {
+        self.available = available;
+        self
+    }
+
+    fn with_sig(mut self) -> Self {
+        self.has_sig = true;
+        self
+    }
+
+    fn set_malleable(mut self, malleable: bool) -> Self {
+        self.malleable = malleable;
+        self
+    }
+
+    fn require_key(mut self, key: &KeyToken, strategy: &Strategy) -> Self {
+        self.stack = Stack::concat(&self.stack, &Stack::Key(Rc::new(key.clone())));
+        self.uses_preferred_keys &=
+            strategy.preferred_keys.is_empty() || strategy.preferred_keys.contains(key);
+        self
+    }
+
+    fn require_preimage(mut self, hash_func: HashFunc, hash: &[u8]) -> Self {
+        let preimage = Stack::Preimage(Rc::new((hash_func, hash.to_vec())));
+        self.stack = Stack::concat(&self.stack, &preimage);
+        self.has_preimage = true;
+        self
+    }
+
+    fn require_older(mut self, n: u32) -> Self {
+        self.older = self.older.max(Some(n));
+        self
+    }
+
+    fn require_after(mut self, n: u32) -> Self {
+        self.after = self.after.max(Some(n));
+        self
+    }
+
+    /// The size of the witness, as [`Witness::size`].
+    fn size(&self) -> usize {
+        VarInt::from(self.elements).size() + self.bytes
+    }
+
+    fn and(&self, other: &Self) -> Self {
         Self {
-            witness: Witness::from_slice(
-                self.witness
-                    .into_iter()
-                    .chain(other.witness.into_iter())
-                    .collect::<Vec<_>>()
-                    .as_slice(),
-            ),
+            stack: Stack::concat(&self.stack, &other.stack),
+            elements: self.elements + other.elements,
+            bytes: self.bytes + other.bytes,
             available: self.available && other.available,
             malleable: self.malleable || other.malleable,
             has_sig: self.has_sig || other.has_sig,
+            has_preimage: self.has_preimage || other.has_preimage,
+            uses_preferred_keys: self.uses_preferred_keys && other.uses_preferred_keys,
+            older: self.older.max(other.older),
+            after: self.after.max(other.after),
         }
     }
 
-    pub fn or(&self, other: &Self) -> Self {
+    /// Pick the best of two candidates according to `strategy`.
+    fn or_with(&self, other: &Self, strategy: &Strategy) -> Self {
         let mut _self = self.clone();
         let mut _other = other.clone();
 
@@ -110,255 +515,447 @@ impl Satisfaction {
         if !_other.available {
             return _self;
         }
-        // If only one of the solutions has a signature, we must pick the other one.
-        if !_self.has_sig && _other.has_sig {
-            return _self;
-        }
-        if _self.has_sig && !_other.has_sig {
-            return _other;
-        }
-        // If neither solution requires a signature, the result is inevitably malleable.
-        if !_self.has_sig && !_other.has_sig {
-            _self.malleable = true;
-            _other.malleable = true;
+        if strategy.allow_malleable {
+            // A third party can always replace a signature-bearing solution with one
+            // that does not need a signature.
+            if !_self.has_sig || !_other.has_sig {
+                _self.malleable |= _self.has_sig || !_other.has_sig;
+                _other.malleable |= _other.has_sig || !_self.has_sig;
+            }
         } else {
-            // If both options require a signature, prefer the non-malleable one.
-            if _other.malleable && !_self.malleable {
+            // If only one of the solutions has a signature, we must pick the other one.
+            if !_self.has_sig && _other.has_sig {
                 return _self;
             }
-            if _self.malleable && !_other.malleable {
+            if _self.has_sig && !_other.has_sig {
                 return _other;
             }
-        }
-        // Both avaiable, pick smaller one.
-        if _self.available && _other.available {
-            if _self.witness.size() <= _other.witness.size() {
-                return _self;
+            // If neither solution requires a signature, the result is inevitably malleable.
+            if !_self.has_sig && !_other.has_sig {
+                _self.malleable = true;
+                _other.malleable = true;
+            } else {
+                // If both options require a signature, prefer the non-malleable one.
+                if _other.malleable && !_self.malleable {
+                    return _self;
+                }
+                if _self.malleable && !_other.malleable {
+                    return _other;
+                }
             }
-            return _other;
         }
-        // If only one available, return that one. If both unavailable, the result is unavailable.
-        if _self.available {
+        // Prefer the solution using only the preferred keys, then the one without preimages.
+        if _self.uses_preferred_keys != _other.uses_preferred_keys {
+            return if _self.uses_preferred_keys {
+                _self
+            } else {
+                _other
+            };
+        }
+        if strategy.avoid_preimages && _self.has_preimage != _other.has_preimage {
+            return if _self.has_preimage { _other } else { _self };
+        }
+        // Both avaiable, pick smaller one.
+        if _self.size() <= _other.size() {
             return _self;
         }
-        return _other;
+        _other
+    }
+
+    /// Materialize the witness and the requirements of the candidate.
+    pub(crate) fn build(&self) -> Satisfaction {
+        let mut witness = Witness::new();
+        let mut requirements = Requirements {
+            older: self.older,
+            after: self.after,
+            ..Requirements::NONE
+        };
+        self.stack.for_each(|leaf| match leaf {
+            Stack::Element(element) => witness.push(element),
+            Stack::Key(key) => {
+                if !requirements.keys.contains(key) {
+                    requirements.keys.push(KeyToken::clone(key));
+                }
+            }
+            Stack::Preimage(preimage) => {
+                if !requirements.preimages.contains(preimage) {
+                    requirements.preimages.push(preimage.as_ref().clone());
+                }
+            }
+            Stack::Empty | Stack::Concat(_) => (),
+        });
+        Satisfaction {
+            witness,
+            available: self.available,
+            malleable: self.malleable,
+            has_sig: self.has_sig,
+            requirements,
+        }
     }
 }
 
-#[cfg_attr(feature = "debug", derive(Debug))]
+impl From<&Satisfaction> for Candidate {
+    fn from(sat: &Satisfaction) -> Self {
+        Self::from_satisfaction(sat, &Strategy::DEFAULT)
+    }
+}
 
-pub struct Satisfactions {
-    pub dsat: Satisfaction,
-    pub sat: Satisfaction,
+impl Candidate {
+    /// The candidate of a materialized satisfaction, its keys checked against `strategy`.
+    fn from_satisfaction(sat: &Satisfaction, strategy: &Strategy) -> Self {
+        let mut candidate = EMPTY;
+        for element in sat.witness.iter() {
+            candidate = candidate.and(&Candidate::push(element));
+        }
+        for key in &sat.requirements.keys {
+            candidate = candidate.require_key(key, strategy);
+        }
+        for (hash_func, hash) in &sat.requirements.preimages {
+            candidate = candidate.require_preimage(*hash_func, hash);
+        }
+        if let Some(n) = sat.requirements.older {
+            candidate = candidate.require_older(n);
+        }
+        if let Some(n) = sat.requirements.after {
+            candidate = candidate.require_after(n);
+        }
+        Candidate {
+            available: sat.available,
+            malleable: sat.malleable,
+            has_sig: sat.has_sig,
+            ..candidate
+        }
+    }
 }
 
-impl Satisfactions {
+/// The dissatisfaction and satisfaction candidates of a fragment.
+pub(crate) struct Candidates {
+    dsat: Candidate,
+    sat: Candidate,
+}
+
+impl Candidates {
     #[inline]
-    pub const fn new(dsat: Satisfaction, sat: Satisfaction) -> Self {
+    const fn new(dsat: Candidate, sat: Candidate) -> Self {
         Self { dsat, sat }
     }
+
+    pub(crate) fn build(self) -> Satisfactions {
+        Satisfactions::new(self.dsat.build(), self.sat.build())
+    }
 }
 
-#[cfg_attr(feature = "debug", derive(Debug))]
-pub enum SatisfyError {
-    MissingSignature(String),
-    MissingLockTime(u32),
-    MissingPreimage(HashFunc),
-    InvalidPreimage(HashFunc),
-    NonDefiniteKey(String),
-    TaprootNotSupported,
+/// Ask the satisfier for the signature of `key` and check it, unavailable signatures
+/// are not checked.
+fn signature(
+    satisfier: &dyn Satisfier,
+    key: &KeyToken,
+    span: Span,
+) -> Result<(Vec<u8>, bool), SatisfyError> {
+    let (sig, avail) = satisfier
+        .sign(key)
+        .ok_or_else(|| SatisfyError::MissingSignature {
+            key: key.identifier(),
+            span,
+        })?;
+    if avail && !satisfier.check_signature(key, &sig) {
+        return Err(SatisfyError::InvalidSignature {
+            key: key.identifier(),
+            span,
+        });
+    }
+    Ok((sig, avail))
 }
 
-const EMPTY: Satisfaction = Satisfaction {
-    witness: Witness::new(),
-    available: true,
-    malleable: false,
-    has_sig: false,
-};
+/// The script of `ctx`, keys must be derived.
+pub(crate) fn build_script(ctx: &Context, span: Span) -> Result<ScriptBuf, SatisfyError> {
+    ctx.build_script().map_err(|e| match e {
+        ScriptBuilderError::NonDefiniteKey { key, span } => {
+            SatisfyError::NonDefiniteKey { key, span }
+        }
+        e => SatisfyError::NotSolvable {
+            span: e.span().unwrap_or(span),
+        },
+    })
+}
 
-const UNAVAILABLE: Satisfaction = Satisfaction {
-    witness: Witness::new(),
-    available: false,
-    malleable: false,
-    has_sig: false,
-};
+/// The leaf script of `tr()` and its control block. The tree holds a single leaf, so the
+/// merkle branch is empty.
+fn tap_leaf(
+    ctx: &Context,
+    key: &KeyToken,
+    span: Span,
+) -> Result<(ScriptBuf, Vec<u8>), SatisfyError> {
+    let internal_key = match key.as_definite_key() {
+        Some(DefiniteKeyToken::XOnlyPublicKey(pk)) => pk,
+        Some(DefiniteKeyToken::PublicKey(pk)) => pk.into(),
+        None => {
+            return Err(SatisfyError::NonDefiniteKey {
+                key: key.identifier(),
+                span,
+            });
+        }
+    };
+    let script = build_script(ctx, span)?;
+
+    let merkle_root = TapNodeHash::from_script(&script, LeafVersion::TapScript);
+    let secp = Secp256k1::verification_only();
+    let (_, output_key_parity) = internal_key.tap_tweak(&secp, Some(merkle_root));
+    let control_block = ControlBlock {
+        leaf_version: LeafVersion::TapScript,
+        output_key_parity,
+        internal_key,
+        merkle_branch: TaprootMerkleBranch::default(),
+    };
+    Ok((script, control_block.serialize()))
+}
 
 /// Satisfy is a function that satisfies a miniscript expression.
 #[doc = bitcoin_definition_link!("8333aa5302902f6be929c30b3c2b4e91c6583224", "script/miniscript.h", 1186)]
 pub(crate) fn satisfy(
     ctx: &Context,
     satisfier: &dyn Satisfier,
+    strategy: &Strategy,
+    node: &AST,
+) -> Result<Candidates, SatisfyError> {
+    // the sub-expressions are satisfied bottom-up, before the fragments using them
+    let mut done: Vec<Option<Candidates>> = (0..ctx.get_nodes().len()).map(|_| None).collect();
+    for index in ctx.post_order(node) {
+        let node = ctx.get_node(index);
+        done[index as usize] = Some(satisfy_fragment(ctx, satisfier, strategy, node, &mut done)?);
+    }
+    satisfy_fragment(ctx, satisfier, strategy, node, &mut done)
+}
+
+/// Satisfy a fragment from the candidates of its sub-expressions in `done`.
+fn satisfy_fragment(
+    ctx: &Context,
+    satisfier: &dyn Satisfier,
+    strategy: &Strategy,
     node: &AST,
-) -> Result<Satisfactions, SatisfyError> {
-    let zero = || Satisfaction::new(&[], true, false, false);
-    let one = || Satisfaction::new(&[1], true, false, false);
-    let witness = |w: &[u8]| Satisfaction::new(w, true, false, false);
+    done: &mut [Option<Candidates>],
+) -> Result<Candidates, SatisfyError> {
+    let mut sub = |index: NodeIndex| {
+        done[index as usize]
+            .take()
+            .expect("sub-expressions are satisfied first")
+    };
+    let zero = || Candidate::push(&[]);
+    let one = || Candidate::push(&[1]);
+    let witness = |w: &[u8]| Candidate::push(w);
 
     match &node.fragment {
-        Fragment::False => Ok(Satisfactions::new(EMPTY, UNAVAILABLE)),
-        Fragment::True => Ok(Satisfactions::new(UNAVAILABLE, EMPTY)),
+        Fragment::False => Ok(Candidates::new(EMPTY, UNAVAILABLE)),
+        Fragment::True => Ok(Candidates::new(UNAVAILABLE, EMPTY)),
         Fragment::PkK { key } => {
-            let (sig, avail) = satisfier
-                .sign(key)
-                .ok_or(SatisfyError::MissingSignature(key.identifier()))?;
-            Ok(Satisfactions::new(
+            let key = ctx.get_key(*key);
+            let (sig, avail) = signature(satisfier, key, node.span())?;
+            Ok(Candidates::new(
                 zero(),
-                witness(sig.as_slice()).with_sig().set_available(avail),
+                witness(sig.as_slice())
+                    .with_sig()
+                    .set_available(avail)
+                    .require_key(key, strategy),
             ))
         }
         Fragment::PkH { key } => {
-            let (sig, avail) = satisfier
-                .sign(key)
-                .ok_or(SatisfyError::MissingSignature(key.identifier()))?;
+            let key = ctx.get_key(*key);
+            let (sig, avail) = signature(satisfier, key, node.span())?;
 
-            let key = match key.as_definite_key() {
+            let definite_key = match key.as_definite_key() {
                 Some(k) => k,
-                None => return Err(SatisfyError::NonDefiniteKey(key.identifier())),
+                None => {
+                    return Err(SatisfyError::NonDefiniteKey {
+                        key: key.identifier(),
+                        span: node.span(),
+                    });
+                }
             };
 
-            Ok(Satisfactions::new(
-                zero().and(&witness(&key.to_bytes())),
+            Ok(Candidates::new(
+                zero().and(&witness(&definite_key.to_bytes())),
                 witness(sig.as_slice())
+                    .with_sig()
                     .set_available(avail)
-                    .and(&witness(&key.to_bytes())),
+                    .require_key(key, strategy)
+                    .and(&witness(&definite_key.to_bytes())),
             ))
         }
         Fragment::Older { n } => {
             let avail = satisfier
                 .check_older(*n)
-                .ok_or(SatisfyError::MissingLockTime(*n))?;
+                .ok_or(SatisfyError::MissingLockTime {
+                    locktime: *n,
+                    span: node.span(),
+                })?;
 
-            if avail {
-                Ok(Satisfactions::new(UNAVAILABLE, EMPTY))
+            if avail && !strategy.avoid_timelocks {
+                Ok(Candidates::new(UNAVAILABLE, EMPTY.require_older(*n)))
             } else {
-                Ok(Satisfactions::new(UNAVAILABLE, UNAVAILABLE))
+                Ok(Candidates::new(UNAVAILABLE, UNAVAILABLE))
             }
         }
         Fragment::After { n } => {
             let avail = satisfier
                 .check_after(*n)
-                .ok_or(SatisfyError::MissingLockTime(*n))?;
+                .ok_or(SatisfyError::MissingLockTime {
+                    locktime: *n,
+                    span: node.span(),
+                })?;
 
-            if avail {
-                Ok(Satisfactions::new(UNAVAILABLE, EMPTY))
+            if avail && !strategy.avoid_timelocks {
+                Ok(Candidates::new(UNAVAILABLE, EMPTY.require_after(*n)))
             } else {
-                Ok(Satisfactions::new(UNAVAILABLE, UNAVAILABLE))
+                Ok(Candidates::new(UNAVAILABLE, UNAVAILABLE))
             }
         }
         Fragment::Sha256 { h } => {
-            let (preimage, avail) = satisfier
-                .preimage(HashFunc::Sha256, h.as_slice())
-                .ok_or(SatisfyError::MissingPreimage(HashFunc::Sha256))?;
+            let (preimage, avail) = satisfier.preimage(HashFunc::Sha256, h.as_slice()).ok_or(
+                SatisfyError::MissingPreimage {
+                    hash_func: HashFunc::Sha256,
+                    span: node.span(),
+                },
+            )?;
 
             if avail && preimage.len() != HashFunc::Sha256.expected_length() {
-                return Err(SatisfyError::InvalidPreimage(HashFunc::Sha256));
+                return Err(SatisfyError::InvalidPreimage {
+                    hash_func: HashFunc::Sha256,
+                    span: node.span(),
+                });
             }
-            Ok(Satisfactions::new(
+            Ok(Candidates::new(
                 witness(&[0; HashFunc::Sha256.expected_length()]).set_malleable(true),
-                witness(preimage.as_slice()).set_available(avail),
+                witness(preimage.as_slice())
+                    .set_available(avail)
+                    .require_preimage(HashFunc::Sha256, h),
             ))
         }
         Fragment::Hash256 { h } => {
-            let (preimage, avail) = satisfier
-                .preimage(HashFunc::Hash256, h.as_slice())
-                .ok_or(SatisfyError::MissingPreimage(HashFunc::Hash256))?;
+            let (preimage, avail) = satisfier.preimage(HashFunc::Hash256, h.as_slice()).ok_or(
+                SatisfyError::MissingPreimage {
+                    hash_func: HashFunc::Hash256,
+                    span: node.span(),
+                },
+            )?;
             if avail && preimage.len() != HashFunc::Hash256.expected_length() {
-                return Err(SatisfyError::InvalidPreimage(HashFunc::Hash256));
+                return Err(SatisfyError::InvalidPreimage {
+                    hash_func: HashFunc::Hash256,
+                    span: node.span(),
+                });
             }
-            Ok(Satisfactions::new(
+            Ok(Candidates::new(
                 witness(&[0; HashFunc::Hash256.expected_length()]).set_malleable(true),
-                witness(preimage.as_slice()).set_available(avail),
+                witness(preimage.as_slice())
+                    .set_available(avail)
+                    .require_preimage(HashFunc::Hash256, h),
             ))
         }
         Fragment::Ripemd160 { h } => {
             let (preimage, avail) = satisfier
                 .preimage(HashFunc::Ripemd160, h.as_slice())
-                .ok_or(SatisfyError::MissingPreimage(HashFunc::Ripemd160))?;
+                .ok_or(SatisfyError::MissingPreimage {
+                    hash_func: HashFunc::Ripemd160,
+                    span: node.span(),
+                })?;
             if avail && preimage.len() != HashFunc::Ripemd160.expected_length() {
-                return Err(SatisfyError::InvalidPreimage(HashFunc::Ripemd160));
+                return Err(SatisfyError::InvalidPreimage {
+                    hash_func: HashFunc::Ripemd160,
+                    span: node.span(),
+                });
             }
-            Ok(Satisfactions::new(
+            Ok(Candidates::new(
                 witness(&[0; HashFunc::Ripemd160.expected_length()]).set_malleable(true),
-                witness(preimage.as_slice()).set_available(avail),
+                witness(preimage.as_slice())
+                    .set_available(avail)
+                    .require_preimage(HashFunc::Ripemd160, h),
             ))
         }
         Fragment::Hash160 { h } => {
-            let (preimage, avail) = satisfier
-                .preimage(HashFunc::Hash160, h.as_slice())
-                .ok_or(SatisfyError::MissingPreimage(HashFunc::Hash160))?;
+            let (preimage, avail) = satisfier.preimage(HashFunc::Hash160, h.as_slice()).ok_or(
+                SatisfyError::MissingPreimage {
+                    hash_func: HashFunc::Hash160,
+                    span: node.span(),
+                },
+            )?;
             if avail && preimage.len() != HashFunc::Hash160.expected_length() {
-                return Err(SatisfyError::InvalidPreimage(HashFunc::Hash160));
+                return Err(SatisfyError::InvalidPreimage {
+                    hash_func: HashFunc::Hash160,
+                    span: node.span(),
+                });
             }
-            Ok(Satisfactions::new(
+            Ok(Candidates::new(
                 witness(&[0; HashFunc::Hash160.expected_length()]).set_malleable(true),
-                witness(preimage.as_slice()).set_available(avail),
+                witness(preimage.as_slice())
+                    .set_available(avail)
+                    .require_preimage(HashFunc::Hash160, h),
             ))
         }
         Fragment::AndOr { x, y, z } => {
-            let x = satisfy(ctx, satisfier, &ctx.get_node(*x))?;
-            let y = satisfy(ctx, satisfier, &ctx.get_node(*y))?;
-            let z = satisfy(ctx, satisfier, &ctx.get_node(*z))?;
-            Ok(Satisfactions::new(
-                z.dsat.and(&x.dsat).or(&y.dsat.and(&x.sat)),
-                y.sat.and(&x.sat).or(&z.sat.and(&x.dsat)),
+            let x = sub(*x);
+            let y = sub(*y);
+            let z = sub(*z);
+            Ok(Candidates::new(
+                z.dsat.and(&x.dsat).or_with(&y.dsat.and(&x.sat), strategy),
+                y.sat.and(&x.sat).or_with(&z.sat.and(&x.dsat), strategy),
             ))
         }
         Fragment::AndV { x, y } => {
-            let x = satisfy(ctx, satisfier, &ctx.get_node(*x))?;
-            let y = satisfy(ctx, satisfier, &ctx.get_node(*y))?;
-            Ok(Satisfactions::new(y.dsat.and(&x.sat), y.sat.and(&x.sat)))
+            let x = sub(*x);
+            let y = sub(*y);
+            Ok(Candidates::new(y.dsat.and(&x.sat), y.sat.and(&x.sat)))
         }
         Fragment::AndB { x, y } => {
-            let x = satisfy(ctx, satisfier, &ctx.get_node(*x))?;
-            let y = satisfy(ctx, satisfier, &ctx.get_node(*y))?;
-            Ok(Satisfactions::new(
+            let x = sub(*x);
+            let y = sub(*y);
+            Ok(Candidates::new(
                 y.dsat
                     .and(&x.dsat)
-                    .or(&y.sat.and(&x.dsat).set_malleable(true))
-                    .or(&y.dsat.and(&x.sat).set_malleable(true)),
+                    .or_with(&y.sat.and(&x.dsat).set_malleable(true), strategy)
+                    .or_with(&y.dsat.and(&x.sat).set_malleable(true), strategy),
                 y.sat.and(&x.sat),
             ))
         }
         Fragment::OrB { x, z } => {
-            let x = satisfy(ctx, satisfier, &ctx.get_node(*x))?;
-            let z = satisfy(ctx, satisfier, &ctx.get_node(*z))?;
-            Ok(Satisfactions::new(
+            let x = sub(*x);
+            let z = sub(*z);
+            Ok(Candidates::new(
                 z.dsat.and(&x.dsat),
                 z.dsat
                     .and(&x.sat)
-                    .or(&z.sat.and(&x.dsat))
-                    .or(&z.sat.and(&x.sat).set_malleable(true)),
+                    .or_with(&z.sat.and(&x.dsat), strategy)
+                    .or_with(&z.sat.and(&x.sat).set_malleable(true), strategy),
             ))
         }
         Fragment::OrC { x, z } => {
-            let x = satisfy(ctx, satisfier, &ctx.get_node(*x))?;
-            let z = satisfy(ctx, satisfier, &ctx.get_node(*z))?;
-            Ok(Satisfactions::new(
+            let x = sub(*x);
+            let z = sub(*z);
+            Ok(Candidates::new(
                 UNAVAILABLE,
-                x.sat.or(&z.sat.and(&x.dsat)),
+                x.sat.or_with(&z.sat.and(&x.dsat), strategy),
             ))
         }
         Fragment::OrD { x, z } => {
-            let x = satisfy(ctx, satisfier, &ctx.get_node(*x))?;
-            let z = satisfy(ctx, satisfier, &ctx.get_node(*z))?;
-            Ok(Satisfactions::new(
+            let x = sub(*x);
+            let z = sub(*z);
+            Ok(Candidates::new(
                 z.dsat.and(&x.dsat),
-                x.sat.or(&z.sat.and(&x.dsat)),
+                x.sat.or_with(&z.sat.and(&x.dsat), strategy),
             ))
         }
         Fragment::OrI { x, z } => {
-            let x = satisfy(ctx, satisfier, &ctx.get_node(*x))?;
-            let z = satisfy(ctx, satisfier, &ctx.get_node(*z))?;
-            Ok(Satisfactions::new(
-                x.dsat.and(&one()).or(&z.dsat.and(&zero())),
-                x.sat.and(&one()).or(&z.sat.and(&zero())),
+            let x = sub(*x);
+            let z = sub(*z);
+            Ok(Candidates::new(
+                x.dsat.and(&one()).or_with(&z.dsat.and(&zero()), strategy),
+                x.sat.and(&one()).or_with(&z.sat.and(&zero()), strategy),
             ))
         }
         Fragment::Thresh { k, xs } => {
+            let xs = ctx.list(*xs);
             let n = xs.len();
             let mut sub_sats = Vec::new();
             for arg in xs {
-                let sat = satisfy(ctx, satisfier, &ctx.get_node(*arg))?;
+                let sat = sub(*arg);
                 sub_sats.push(sat);
             }
 
@@ -379,7 +976,9 @@ pub(crate) fn satisfy(
                 next_sats.push(sats[0].and(&res.dsat));
 
                 for j in 1..sats.len() {
-                    next_sats.push((sats[j].and(&res.dsat)).or(&sats[j - 1].and(&res.sat)));
+                    next_sats.push(
+                        (sats[j].and(&res.dsat)).or_with(&sats[j - 1].and(&res.sat), strategy),
+                    );
                 }
                 next_sats.push(sats[sats.len() - 1].and(&res.sat));
 
@@ -402,18 +1001,22 @@ pub(crate) fn satisfy(
                 }
                 // Include all dissatisfactions (even these non-canonical ones) in nsat.
                 if i != *k as usize {
-                    nsat = nsat.or(&sats[i]);
+                    nsat = nsat.or_with(&sats[i], strategy);
                 }
             }
 
             // Safety check: k should be valid
-            if *k as usize >= sats.len() {
-                return Err(SatisfyError::MissingLockTime(*k as u32));
+            if *k <= 0 || *k as usize >= sats.len() {
+                return Err(SatisfyError::InvalidThreshold {
+                    k: *k,
+                    span: node.span(),
+                });
             }
 
-            Ok(Satisfactions::new(nsat, sats[*k as usize].clone()))
+            Ok(Candidates::new(nsat, sats[*k as usize].clone()))
         }
         Fragment::Multi { k, keys } => {
+            let keys = ctx.list(*keys);
             // sats[j] represents the best stack containing j valid signatures (out of the first i keys).
             // In the loop below, these stacks are built up using a dynamic programming approach.
             // sats[0] starts off being {0}, due to the CHECKMULTISIG bug that pops off one element too many.
@@ -421,12 +1024,14 @@ pub(crate) fn satisfy(
             sats.push(zero());
 
             for i in 0..keys.len() {
-                let (sig, avail) = satisfier
-                    .sign(&keys[i])
-                    .ok_or(SatisfyError::MissingSignature(keys[i].identifier()))?;
+                let key = ctx.get_key(keys[i]);
+                let (sig, avail) = signature(satisfier, key, node.span())?;
 
                 // Compute signature stack for just the i'th key.
-                let sat = witness(&sig).with_sig().set_available(avail);
+                let sat = witness(&sig)
+                    .with_sig()
+                    .set_available(avail)
+                    .require_key(key, strategy);
 
                 // Compute the next sats vector: next_sats[0] is a copy of sats[0] (no signatures). All further
                 // next_sats[j] are equal to either the existing sats[j], or sats[j-1] plus a signature for the
@@ -435,7 +1040,7 @@ pub(crate) fn satisfy(
                 next_sats.push(sats[0].clone());
 
                 for j in 1..sats.len() {
-                    next_sats.push(sats[j].or(&sats[j - 1].and(&sat)));
+                    next_sats.push(sats[j].or_with(&sats[j - 1].and(&sat), strategy));
                 }
                 next_sats.push(sats[sats.len() - 1].and(&sat));
 
@@ -450,27 +1055,31 @@ pub(crate) fn satisfy(
             }
 
             // Safety check: k should be valid
-            if *k as usize >= sats.len() {
-                return Err(SatisfyError::MissingLockTime(*k as u32));
+            if *k <= 0 || *k as usize >= sats.len() {
+                return Err(SatisfyError::InvalidThreshold {
+                    k: *k,
+                    span: node.span(),
+                });
             }
 
-            Ok(Satisfactions::new(nsat, sats[*k as usize].clone()))
+            Ok(Candidates::new(nsat, sats[*k as usize].clone()))
         }
         Fragment::Identity { identity_type, x } => {
-            let x_pair = satisfy(ctx, satisfier, &ctx.get_node(*x))?;
+            let x_pair = sub(*x);
             match identity_type {
                 crate::parser::IdentityType::D => {
-                    Ok(Satisfactions::new(zero(), x_pair.sat.and(&one())))
+                    Ok(Candidates::new(zero(), x_pair.sat.and(&one())))
                 }
-                crate::parser::IdentityType::V => Ok(Satisfactions::new(UNAVAILABLE, x_pair.sat)),
-                crate::parser::IdentityType::J => Ok(Satisfactions::new(
+                crate::parser::IdentityType::V => Ok(Candidates::new(UNAVAILABLE, x_pair.sat)),
+                crate::parser::IdentityType::J => Ok(Candidates::new(
                     zero().set_malleable(x_pair.dsat.available && !x_pair.dsat.has_sig),
                     x_pair.sat,
                 )),
-                _ => return satisfy(ctx, satisfier, &ctx.get_node(*x)),
+                _ => Ok(x_pair),
             }
         }
         Fragment::MultiA { k, keys } => {
+            let keys = ctx.list(*keys);
             let n = keys.len();
             // sats[j] represents the best stack containing j valid signatures (out of the first i keys).
             // In the loop below, these stacks are built up using a dynamic programming approach.
@@ -481,13 +1090,14 @@ pub(crate) fn satisfy(
                 // Get the signature for the i'th key in reverse order (the signature for the first key needs to
                 // be at the top of the stack, contrary to CHECKMULTISIG's satisfaction).
                 let key_idx = n - 1 - i;
-                let key_type = &keys[key_idx];
-                let (sig, avail) = satisfier
-                    .sign(&key_type)
-                    .ok_or(SatisfyError::MissingSignature(key_type.identifier()))?;
+                let key_type = ctx.get_key(keys[key_idx]);
+                let (sig, avail) = signature(satisfier, key_type, node.span())?;
 
                 // Compute signature stack for just this key.
-                let sat = witness(&sig).with_sig().set_available(avail);
+                let sat = witness(&sig)
+                    .with_sig()
+                    .set_available(avail)
+                    .require_key(key_type, strategy);
 
                 // Compute the next sats vector: next_sats[0] is a copy of sats[0] (no signatures). All further
                 // next_sats[j] are equal to either the existing sats[j] + ZERO, or sats[j-1] plus a signature
@@ -496,7 +1106,8 @@ pub(crate) fn satisfy(
                 next_sats.push(sats[0].and(&zero()));
 
                 for j in 1..sats.len() {
-                    next_sats.push((sats[j].and(&zero())).or(&sats[j - 1].and(&sat)));
+                    next_sats
+                        .push((sats[j].and(&zero())).or_with(&sats[j - 1].and(&sat), strategy));
                 }
                 next_sats.push(sats[sats.len() - 1].and(&sat));
 
@@ -510,42 +1121,206 @@ pub(crate) fn satisfy(
 
             // Safety check: k should be valid
             if *k <= 0 || *k as usize >= sats.len() {
-                return Err(SatisfyError::MissingSignature(keys[0].identifier()));
+                return Err(SatisfyError::InvalidThreshold {
+                    k: *k,
+                    span: node.span(),
+                });
             }
 
-            Ok(Satisfactions::new(nsat, sats[*k as usize].clone()))
-        }
-        Fragment::Descriptor { descriptor, inner } => {
-            satisfy(ctx, satisfier, &ctx.get_node(*inner))
+            Ok(Candidates::new(nsat, sats[*k as usize].clone()))
         }
+        Fragment::Descriptor { descriptor, inner } => Ok(sub(*inner)),
         Fragment::RawPkH { key } => {
-            let (sig, avail) = satisfier
-                .sign(key)
-                .ok_or(SatisfyError::MissingSignature(key.identifier()))?;
+            let key = ctx.get_key(*key);
+            let (sig, avail) = signature(satisfier, key, node.span())?;
 
-            let key = match key.as_definite_key() {
+            let definite_key = match key.as_definite_key() {
                 Some(k) => k,
-                None => return Err(SatisfyError::NonDefiniteKey(key.identifier())),
+                None => {
+                    return Err(SatisfyError::NonDefiniteKey {
+                        key: key.identifier(),
+                        span: node.span(),
+                    });
+                }
             };
 
-            Ok(Satisfactions::new(
-                zero().and(&witness(&key.to_bytes())),
+            Ok(Candidates::new(
+                zero().and(&witness(&definite_key.to_bytes())),
                 witness(sig.as_slice())
+                    .with_sig()
                     .set_available(avail)
-                    .and(&witness(&key.to_bytes())),
+                    .require_key(key, strategy)
+                    .and(&witness(&definite_key.to_bytes())),
             ))
         }
+        // the key path competes with the leaf, whose satisfaction is followed by the leaf
+        // script and its control block
         Fragment::RawTr { key, inner } => {
-            return Err(SatisfyError::TaprootNotSupported);
+            let key = ctx.get_key(*key);
+            let key_path = if key.is_nums() {
+                // nobody can sign for a NUMS internal key
+                UNAVAILABLE
+            } else {
+                let (sig, avail) = signature(satisfier, key, node.span())?;
+                witness(sig.as_slice())
+                    .with_sig()
+                    .set_available(avail)
+                    .require_key(key, strategy)
+            };
+            let sat = match inner {
+                Some(inner) => {
+                    let (script, control_block) = tap_leaf(ctx, key, node.span())?;
+                    let script_path = sub(*inner)
+                        .sat
+                        .and(&witness(script.as_bytes()))
+                        .and(&witness(&control_block));
+                    // the key path is picked when the strategy finds both equally good
+                    key_path.or_with(&script_path, strategy)
+                }
+                None => key_path,
+            };
+            Ok(Candidates::new(UNAVAILABLE, sat))
         }
         Fragment::RawPk { key } => {
-            let (sig, avail) = satisfier
-                .sign(key)
-                .ok_or(SatisfyError::MissingSignature(key.identifier()))?;
-            Ok(Satisfactions::new(
+            let key = ctx.get_key(*key);
+            let (sig, avail) = signature(satisfier, key, node.span())?;
+            Ok(Candidates::new(
                 zero(),
-                witness(sig.as_slice()).with_sig().set_available(avail),
+                witness(sig.as_slice())
+                    .with_sig()
+                    .set_available(avail)
+                    .require_key(key, strategy),
             ))
         }
+        Fragment::RawAddr { .. } | Fragment::RawScript { .. } => {
+            Err(SatisfyError::NotSolvable { span: node.span() })
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::plan::Assets;
+    use crate::utils::fixtures::{A, B, C};
+
+    /// Satisfy with every key, preimage and locktime available.
+    fn satisfy_all(descriptor: &str, strategy: &Strategy) -> Satisfaction {
+        let ctx = Context::try_from(descriptor).unwrap();
+        let mut assets = Assets::new()
+            .older_blocks(u16::MAX)
+            .after_height(u32::MAX >> 1);
+        ctx.iterate_keys(|key| assets = assets.clone().add_key(key.clone()));
+        for node in ctx.get_nodes() {
+            if let Fragment::Sha256 { h } = node.fragment {
+                assets = assets.add_preimage(HashFunc::Sha256, &h);
+            }
+        }
+        ctx.satisfy(&assets, strategy).unwrap().sat
+    }
+
+    fn signers(sat: &Satisfaction) -> Vec<String> {
+        sat.requirements
+            .keys
+            .iter()
+            .map(|k| k.identifier())
+            .collect()
+    }
+
+    fn key(ctx: &Context, identifier: &str) -> KeyToken {
+        let mut found = None;
+        ctx.iterate_keys(|key| {
+            if key.identifier() == identifier {
+                found = Some(key.clone());
+            }
+        });
+        found.unwrap()
+    }
+
+    #[test]
+    fn test_strategies() {
+        let h1 = "11".repeat(32);
+        let h2 = "22".repeat(32);
+        let h3 = "33".repeat(32);
+
+        let descriptor = alloc::format!("wsh(or_d(pk({A}),and_v(v:pk({B}),older(144))))");
+        let ctx = Context::try_from(descriptor.as_str()).unwrap();
+        let assets = Assets::new().add_key(key(&ctx, B)).older_blocks(144);
+        let strategy = Strategy::new().avoid_timelocks(true);
+        assert!(
+            ctx.satisfy(&assets, &Strategy::DEFAULT)
+                .unwrap()
+                .sat
+                .available
+        );
+        assert!(!ctx.satisfy(&assets, &strategy).unwrap().sat.available);
+
+        // pkh() needs a signature too, so the recovery path is not free
+        let descriptor = alloc::format!("wsh(or_d(pk({A}),and_v(v:pkh({B}),older(144))))");
+        let ctx = Context::try_from(descriptor.as_str()).unwrap();
+        let assets = Assets::new()
+            .add_key(key(&ctx, A))
+            .add_key(key(&ctx, B))
+            .older_blocks(144);
+        let sat = ctx.satisfy(&assets, &Strategy::DEFAULT).unwrap().sat;
+        assert_eq!(signers(&sat), [A]);
+        assert_eq!(sat.witness.len(), 1);
+
+        // the right branch of or_i is one byte smaller
+        let descriptor = alloc::format!("wsh(or_i(pk({A}),pk({B})))");
+        let ctx = Context::try_from(descriptor.as_str()).unwrap();
+        assert_eq!(signers(&satisfy_all(&descriptor, &Strategy::DEFAULT)), [B]);
+        let strategy = Strategy::new().prefer_keys(&[key(&ctx, A)]);
+        assert_eq!(signers(&satisfy_all(&descriptor, &strategy)), [A]);
+
+        let descriptor =
+            alloc::format!("wsh(or_i(and_v(v:pk({A}),sha256({h1})),and_v(v:pk({B}),pk({C}))))");
+        let sat = satisfy_all(&descriptor, &Strategy::DEFAULT);
+        assert_eq!(sat.requirements.preimages.len(), 1);
+        let sat = satisfy_all(&descriptor, &Strategy::new().avoid_preimages(true));
+        assert!(sat.requirements.preimages.is_empty());
+        assert_eq!(signers(&sat), [C, B]);
+
+        // the preimages can be replaced by a third party, the signature cannot
+        let descriptor = alloc::format!(
+            "wsh(or_i(and_v(v:sha256({h1}),and_v(v:sha256({h2}),sha256({h3}))),pk({A})))"
+        );
+        let sat = satisfy_all(&descriptor, &Strategy::DEFAULT);
+        assert_eq!(sat.requirements.preimages.len(), 3);
+        let sat = satisfy_all(&descriptor, &Strategy::new().allow_malleable(true));
+        assert_eq!(signers(&sat), [A]);
+        assert!(sat.malleable);
+
+        // the key path of tr() is smaller, the strategy can still prefer the leaf
+        let descriptor = alloc::format!("tr({A},pk({B}))");
+        let ctx = Context::try_from(descriptor.as_str()).unwrap();
+        let sat = satisfy_all(&descriptor, &Strategy::DEFAULT);
+        assert_eq!(signers(&sat), [&A[2..]]);
+        assert_eq!(sat.witness.len(), 1);
+        let strategy = Strategy::new().prefer_keys(&[key(&ctx, &B[2..])]);
+        let sat = satisfy_all(&descriptor, &strategy);
+        assert_eq!(signers(&sat), [&B[2..]]);
+        assert_eq!(sat.witness.len(), 3);
+    }
+
+    #[test]
+    fn test_large_threshold() {
+        // 19 of 20 signatures, every dissatisfaction is a single empty element
+        let mut descriptor = alloc::format!("wsh(thresh(19,pk({A})");
+        for key in [B, C].iter().cycle().take(19) {
+            descriptor.push_str(&alloc::format!(",s:pk({key})"));
+        }
+        descriptor.push_str("))");
+
+        let sat = satisfy_all(&descriptor, &Strategy::DEFAULT);
+        assert!(sat.available);
+        assert!(!sat.malleable);
+        assert_eq!(sat.witness.len(), 20);
+        assert_eq!(sat.witness.iter().filter(|e| e.is_empty()).count(), 1);
+
+        // the candidate size matches the materialized witness
+        let candidate = Candidate::from(&sat);
+        assert_eq!(candidate.size(), sat.witness.size());
+        assert_eq!(candidate.build().witness, sat.witness);
     }
 }
@@ -1,17 +1,27 @@
 use core::marker::PhantomData;
+use core::ops::Range;
 
 use bitcoin::{
-    key::ParsePublicKeyError, opcodes::{self, OP_0}, script::Builder, Address, Network, PubkeyHash, ScriptBuf
+    Address, Network, PubkeyHash, Script, ScriptBuf,
+    key::ParsePublicKeyError,
+    opcodes::{OP_0, OP_FALSE, OP_TRUE, Opcode, all::*},
+    script::{Builder, PushBytes, write_scriptint},
+    secp256k1, taproot,
 };
 
 use crate::{
-    context::Context,
+    Vec,
+    context::{Context, Tree},
     descriptor::Descriptor,
-    parser::{AST, Fragment, Position},
+    error::{Diagnostic, Help, Span},
+    parser::{
+        AST, Fragment, IdentityType, KeyIndex, NodeIndex, Position,
+        keys::{DefiniteKeyToken, DeriveError},
+    },
 };
 use alloc::string::String;
 
-#[cfg_attr(feature = "debug", derive(Debug))]
+#[derive(Debug)]
 pub enum ScriptBuilderError<'a> {
     InvalidKeyForDescriptor {
         position: Position,
@@ -22,40 +32,180 @@ pub enum ScriptBuilderError<'a> {
         position: Position,
         key: &'a str,
     },
-    NonDefiniteKey(String),
+    NonDefiniteKey {
+        key: String,
+        span: Span,
+    },
 
     NoAddressForm,
-    TaprootScriptWithoutInner,
+    TaprootScriptWithoutInner {
+        span: Span,
+    },
+    /// The script is `length` bytes long, more than the buffer it is written to holds
+    CapacityExceeded {
+        length: usize,
+        capacity: usize,
+    },
+}
+
+impl core::fmt::Display for ScriptBuilderError<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ScriptBuilderError::InvalidKeyForDescriptor { key, inner, .. } => {
+                write!(f, "invalid key '{key}' for descriptor: {inner}")
+            }
+            ScriptBuilderError::InvalidXOnlyKeyForDescriptor { key, .. } => {
+                write!(f, "invalid x-only key '{key}' for descriptor")
+            }
+            ScriptBuilderError::NonDefiniteKey { key, .. } => {
+                write!(f, "key '{key}' must be derived before building the script")
+            }
+            ScriptBuilderError::NoAddressForm => write!(f, "descriptor has no address form"),
+            ScriptBuilderError::TaprootScriptWithoutInner { .. } => {
+                write!(f, "taproot descriptor has no script path")
+            }
+            ScriptBuilderError::CapacityExceeded { length, capacity } => write!(
+                f,
+                "script is {length} bytes long, the buffer holds {capacity} bytes"
+            ),
+        }
+    }
+}
+
+impl core::error::Error for ScriptBuilderError<'_> {}
+
+impl Diagnostic for ScriptBuilderError<'_> {
+    fn code(&self) -> &'static str {
+        match self {
+            ScriptBuilderError::InvalidKeyForDescriptor { .. } => "S001",
+            ScriptBuilderError::InvalidXOnlyKeyForDescriptor { .. } => "S002",
+            ScriptBuilderError::NonDefiniteKey { .. } => "S003",
+            ScriptBuilderError::NoAddressForm => "S004",
+            ScriptBuilderError::TaprootScriptWithoutInner { .. } => "S005",
+            ScriptBuilderError::CapacityExceeded { .. } => "S006",
+        }
+    }
+
+    fn span(&self) -> Option<Span> {
+        match self {
+            ScriptBuilderError::InvalidKeyForDescriptor { position, key, .. }
+            | ScriptBuilderError::InvalidXOnlyKeyForDescriptor { position, key } => {
+                Some(Span::from_token(key, *position))
+            }
+            ScriptBuilderError::NonDefiniteKey { span, .. }
+            | ScriptBuilderError::TaprootScriptWithoutInner { span } => Some(*span),
+            ScriptBuilderError::NoAddressForm | ScriptBuilderError::CapacityExceeded { .. } => None,
+        }
+    }
 }
 
-#[cfg_attr(feature = "debug", derive(Debug))]
+#[derive(Debug)]
 pub enum AddressBuilderError<'a> {
     ScriptBuilder(ScriptBuilderError<'a>),
-    NoAddressForm,
-    NonDefiniteKey(String),
+    NoAddressForm { span: Span },
+    NonDefiniteKey { key: String, span: Span },
+    NotRanged { span: Span },
+    Derive(DeriveError),
+    WrongNetwork { network: Network, span: Span },
+    MultipleOutputs { span: Span },
 }
 
-pub(crate) fn build_script<'a>(ctx: &Context) -> Result<ScriptBuf, ScriptBuilderError<'a>> {
-    let mut script_builder = ScriptBuilder::new();
+impl core::fmt::Display for AddressBuilderError<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            AddressBuilderError::ScriptBuilder(e) => core::fmt::Display::fmt(e, f),
+            AddressBuilderError::NoAddressForm { .. } => {
+                write!(f, "descriptor has no address form")
+            }
+            AddressBuilderError::NonDefiniteKey { key, .. } => {
+                write!(f, "key '{key}' must be derived before building the address")
+            }
+            AddressBuilderError::NotRanged { .. } => write!(f, "descriptor has no wildcard keys"),
+            AddressBuilderError::Derive(e) => core::fmt::Display::fmt(e, f),
+            AddressBuilderError::WrongNetwork { network, .. } => {
+                write!(f, "address is not valid on {network}")
+            }
+            AddressBuilderError::MultipleOutputs { .. } => write!(
+                f,
+                "combo() stands for several outputs, use script_pubkeys() or build_addresses()"
+            ),
+        }
+    }
+}
+
+impl core::error::Error for AddressBuilderError<'_> {}
+
+impl Diagnostic for AddressBuilderError<'_> {
+    fn code(&self) -> &'static str {
+        match self {
+            AddressBuilderError::ScriptBuilder(e) => e.code(),
+            AddressBuilderError::NoAddressForm { .. } => "A001",
+            AddressBuilderError::NonDefiniteKey { .. } => "A002",
+            AddressBuilderError::NotRanged { .. } => "A003",
+            AddressBuilderError::Derive(e) => e.code(),
+            AddressBuilderError::WrongNetwork { .. } => "A004",
+            AddressBuilderError::MultipleOutputs { .. } => "A005",
+        }
+    }
+
+    fn span(&self) -> Option<Span> {
+        match self {
+            AddressBuilderError::ScriptBuilder(e) => e.span(),
+            AddressBuilderError::NoAddressForm { span }
+            | AddressBuilderError::NonDefiniteKey { span, .. }
+            | AddressBuilderError::NotRanged { span }
+            | AddressBuilderError::WrongNetwork { span, .. }
+            | AddressBuilderError::MultipleOutputs { span } => Some(*span),
+            AddressBuilderError::Derive(e) => e.span(),
+        }
+    }
+
+    fn help(&self) -> Option<Help> {
+        match self {
+            AddressBuilderError::ScriptBuilder(e) => e.help(),
+            _ => None,
+        }
+    }
+}
 
+pub(crate) fn build_script<'a>(ctx: &Context) -> Result<ScriptBuf, ScriptBuilderError<'a>> {
+    let mut links = alloc::vec![Link::EMPTY; ctx.get_nodes().len()];
     let mut builder = Builder::new();
-    builder = script_builder.build_fragment(ctx, ctx.get_root(), builder)?;
+    ScriptBuilder::new().build_fragment(ctx, ctx.get_root(), &mut links, &mut builder)?;
     Ok(builder.into_script())
 }
 
 pub(crate) fn build_address<'a>(
     ctx: &Context,
     network: Network,
+) -> Result<Address, AddressBuilderError<'a>> {
+    build_address_with(ctx, network, &secp256k1::Secp256k1::verification_only())
+}
+
+/// Build the address with a caller-provided context, used to tweak the `tr()` output key.
+pub(crate) fn build_address_with<'a, C: secp256k1::Verification>(
+    ctx: &Context,
+    network: Network,
+    secp: &secp256k1::Secp256k1<C>,
 ) -> Result<Address, AddressBuilderError<'a>> {
     match ctx.descriptor() {
-        Descriptor::Bare => Err(AddressBuilderError::NoAddressForm),
-        Descriptor::Pkh | Descriptor::Pk => {
+        // p2pk outputs have no address
+        Descriptor::Bare | Descriptor::Pk => Err(AddressBuilderError::NoAddressForm {
+            span: ctx.get_root().span(),
+        }),
+        Descriptor::Combo => Err(AddressBuilderError::MultipleOutputs {
+            span: ctx.get_root().span(),
+        }),
+        Descriptor::Pkh => {
             let mut key = None;
             ctx.iterate_keys(|k| key = Some(k.clone()));
             let key = key.expect("One key is always present");
             let key = key
                 .as_definite_key()
-                .ok_or_else(|| AddressBuilderError::NonDefiniteKey(key.identifier()))?;
+                .ok_or_else(|| AddressBuilderError::NonDefiniteKey {
+                    key: key.identifier(),
+                    span: ctx.get_root().span(),
+                })?;
 
             let key = bitcoin::PublicKey::from_slice(&key.to_bytes()).expect("Valid key");
             Ok(Address::p2pkh(key, network))
@@ -66,7 +216,10 @@ pub(crate) fn build_address<'a>(
             let key = key.expect("One key is always present");
             let key = key
                 .as_definite_key()
-                .ok_or_else(|| AddressBuilderError::NonDefiniteKey(key.identifier()))?;
+                .ok_or_else(|| AddressBuilderError::NonDefiniteKey {
+                    key: key.identifier(),
+                    span: ctx.get_root().span(),
+                })?;
 
             let key = bitcoin::CompressedPublicKey::from_slice(&key.to_bytes()).expect("Valid key");
 
@@ -88,293 +241,672 @@ pub(crate) fn build_address<'a>(
                 Ok(Address::p2wsh(script.as_script(), network))
             }
         }
-        Descriptor::Tr => Err(AddressBuilderError::NoAddressForm),
+        Descriptor::Tr => {
+            let (internal_key, merkle_root) = taproot_output(ctx)?;
+            Ok(Address::p2tr(secp, internal_key, merkle_root, network))
+        }
+        Descriptor::RawTr => {
+            let (output_key, _) = taproot_output(ctx)?;
+            let output_key = bitcoin::key::TweakedPublicKey::dangerous_assume_tweaked(output_key);
+            Ok(Address::p2tr_tweaked(output_key, network))
+        }
+        Descriptor::Addr | Descriptor::Raw => {
+            let span = ctx.get_root().span();
+            let Fragment::Descriptor { inner, .. } = &ctx.get_root().fragment else {
+                return Err(AddressBuilderError::NoAddressForm { span });
+            };
+            match &ctx.get_node(*inner).fragment {
+                Fragment::RawAddr { address } => address
+                    .clone()
+                    .require_network(network)
+                    .map_err(|_| AddressBuilderError::WrongNetwork { network, span }),
+                Fragment::RawScript { script } => Address::from_script(script, network)
+                    .map_err(|_| AddressBuilderError::NoAddressForm { span }),
+                _ => Err(AddressBuilderError::NoAddressForm { span }),
+            }
+        }
     }
 }
 
-struct ScriptBuilder<'a> {
+pub(crate) fn build_script_pubkey<'a>(ctx: &Context) -> Result<ScriptBuf, AddressBuilderError<'a>> {
+    match ctx.descriptor() {
+        Descriptor::Bare | Descriptor::Pk | Descriptor::Addr | Descriptor::Raw => {
+            build_script(ctx).map_err(AddressBuilderError::ScriptBuilder)
+        }
+        _ => build_address(ctx, Network::Bitcoin).map(|address| address.script_pubkey()),
+    }
+}
+
+/// Get the internal key and the merkle root of a taproot descriptor, the output key for
+/// `rawtr()`.
+pub(crate) fn taproot_output<'a>(
+    ctx: &Context,
+) -> Result<(bitcoin::XOnlyPublicKey, Option<taproot::TapNodeHash>), AddressBuilderError<'a>> {
+    let span = ctx.get_root().span();
+    let Fragment::Descriptor { inner, .. } = &ctx.get_root().fragment else {
+        return Err(AddressBuilderError::NoAddressForm { span });
+    };
+    let tr = ctx.get_node(*inner);
+    let Fragment::RawTr { key, inner } = &tr.fragment else {
+        return Err(AddressBuilderError::NoAddressForm { span });
+    };
+    let key = ctx.get_key(*key);
+
+    let internal_key = match key.as_definite_key() {
+        Some(DefiniteKeyToken::XOnlyPublicKey(pk)) => pk,
+        Some(DefiniteKeyToken::PublicKey(pk)) => pk.into(),
+        None => {
+            return Err(AddressBuilderError::NonDefiniteKey {
+                key: key.identifier(),
+                span: tr.span(),
+            });
+        }
+    };
+
+    let merkle_root = match inner {
+        Some(_) => {
+            let script = build_script(ctx).map_err(AddressBuilderError::ScriptBuilder)?;
+            Some(taproot::TapNodeHash::from_script(
+                script.as_script(),
+                taproot::LeafVersion::TapScript,
+            ))
+        }
+        None => None,
+    };
+    Ok((internal_key, merkle_root))
+}
+
+/// Iterator over the addresses of a ranged descriptor, see [`Context::addresses`].
+///
+/// `combo()` yields the address of every output it stands for at each index, except the
+/// p2pk one which has no address.
+pub struct AddressIter {
+    ctx: Context,
+    secp: secp256k1::Secp256k1<secp256k1::VerifyOnly>,
+    network: Network,
+    range: Range<u32>,
+    index: u32,
+    pending: alloc::vec::IntoIter<(Address, ScriptBuf)>,
+}
+
+impl AddressIter {
+    pub(crate) fn new(
+        ctx: &Context,
+        network: Network,
+        range: Range<u32>,
+    ) -> Result<Self, AddressBuilderError<'static>> {
+        if matches!(ctx.descriptor(), Descriptor::Bare | Descriptor::Pk) {
+            return Err(AddressBuilderError::NoAddressForm {
+                span: ctx.get_root().span(),
+            });
+        }
+        if !ctx.is_ranged() {
+            return Err(AddressBuilderError::NotRanged {
+                span: ctx.get_root().span(),
+            });
+        }
+
+        let secp = secp256k1::Secp256k1::verification_only();
+        let ctx = ctx
+            .try_map_keys(|key| key.cache_parent(&secp))
+            .map_err(AddressBuilderError::Derive)?;
+
+        Ok(Self {
+            ctx,
+            secp,
+            network,
+            index: range.start,
+            range,
+            pending: Vec::new().into_iter(),
+        })
+    }
+
+    fn build(&self, index: u32) -> Result<Vec<(Address, ScriptBuf)>, AddressBuilderError<'static>> {
+        let derived = self
+            .ctx
+            .derive_with(&self.secp, index)
+            .map_err(AddressBuilderError::Derive)?;
+        let outputs = match derived.descriptor() {
+            Descriptor::Combo => derived
+                .expand_combo()
+                .into_iter()
+                .filter(|ctx| ctx.descriptor() != Descriptor::Pk)
+                .collect(),
+            _ => alloc::vec![derived],
+        };
+        outputs
+            .iter()
+            .map(|ctx| {
+                let address = build_address_with(ctx, self.network, &self.secp)?;
+                let script_pubkey = address.script_pubkey();
+                Ok((address, script_pubkey))
+            })
+            .collect()
+    }
+}
+
+impl Iterator for AddressIter {
+    type Item = Result<(u32, Address, ScriptBuf), AddressBuilderError<'static>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((address, script_pubkey)) = self.pending.next() {
+                return Some(Ok((self.index, address, script_pubkey)));
+            }
+            let index = self.range.next()?;
+            match self.build(index) {
+                Ok(outputs) => {
+                    self.index = index;
+                    self.pending = outputs.into_iter();
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        // combo() has up to three addresses per index
+        let per_index = match self.ctx.descriptor() {
+            Descriptor::Combo => 3,
+            _ => 1,
+        };
+        let (lower, upper) = self.range.size_hint();
+        let pending = self.pending.len();
+        (
+            pending + lower,
+            upper.map(|upper| pending + upper * per_index),
+        )
+    }
+}
+
+/// The byte range of the script emitted by every fragment, innermost fragments first.
+/// The root descriptor is not included.
+pub(crate) type FragmentMap = Vec<(Range<usize>, NodeIndex)>;
+
+pub(crate) fn build_script_with_map<'a>(
+    ctx: &Context,
+) -> Result<(ScriptBuf, FragmentMap), ScriptBuilderError<'a>> {
+    let mut script_builder = ScriptBuilder::new();
+    script_builder.fragments = Some(Vec::new());
+
+    let mut links = alloc::vec![Link::EMPTY; ctx.get_nodes().len()];
+    let mut builder = Builder::new();
+    script_builder.build_fragment(ctx, ctx.get_root(), &mut links, &mut builder)?;
+    Ok((
+        builder.into_script(),
+        script_builder.fragments.unwrap_or_default(),
+    ))
+}
+
+/// Where a script is written: a growable [`Builder`], or a fixed buffer with a
+/// [`ScriptWriter`].
+pub(crate) trait ScriptSink {
+    fn len(&self) -> usize;
+
+    fn push_opcode(&mut self, opcode: Opcode);
+
+    fn push_int(&mut self, n: i64);
+
+    fn push_slice(&mut self, data: &[u8]);
+
+    /// Turn the last opcode into its `VERIFY` form, or push `OP_VERIFY`.
+    fn push_verify(&mut self);
+
+    /// Append the bytes of a complete script, e.g. the script pubkey of `addr()`.
+    fn push_script(&mut self, script: &Script);
+
+    fn push_key(&mut self, key: &DefiniteKeyToken) {
+        match key {
+            DefiniteKeyToken::PublicKey(pk) if pk.compressed => {
+                self.push_slice(&pk.inner.serialize())
+            }
+            DefiniteKeyToken::PublicKey(pk) => self.push_slice(&pk.inner.serialize_uncompressed()),
+            DefiniteKeyToken::XOnlyPublicKey(pk) => self.push_slice(&pk.serialize()),
+        }
+    }
+}
+
+impl ScriptSink for Builder {
+    fn len(&self) -> usize {
+        Builder::len(self)
+    }
+
+    fn push_opcode(&mut self, opcode: Opcode) {
+        *self = core::mem::take(self).push_opcode(opcode);
+    }
+
+    fn push_int(&mut self, n: i64) {
+        *self = core::mem::take(self).push_int(n);
+    }
+
+    fn push_slice(&mut self, data: &[u8]) {
+        let data = <&PushBytes>::try_from(data).expect("pushes are shorter than 4GB");
+        *self = core::mem::take(self).push_slice(data);
+    }
+
+    fn push_verify(&mut self) {
+        *self = core::mem::take(self).push_verify();
+    }
+
+    fn push_script(&mut self, script: &Script) {
+        let mut bytes = core::mem::take(self).into_bytes();
+        bytes.extend_from_slice(script.as_bytes());
+        *self = Builder::from(bytes);
+    }
+}
+
+/// Writes a script into a fixed buffer, encoding it like [`Builder`] does.
+///
+/// Writing goes on past the end of the buffer without storing anything, so that
+/// [`ScriptWriter::finish`] can report the length the script needs.
+pub(crate) struct ScriptWriter<'buf> {
+    buf: &'buf mut [u8],
+    len: usize,
+    /// The last opcode pushed, `None` after a data push
+    last_opcode: Option<Opcode>,
+}
+
+impl<'buf> ScriptWriter<'buf> {
+    pub(crate) fn new(buf: &'buf mut [u8]) -> Self {
+        Self {
+            buf,
+            len: 0,
+            last_opcode: None,
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        if let Some(dst) = self.buf.get_mut(self.len..self.len + bytes.len()) {
+            dst.copy_from_slice(bytes);
+        }
+        self.len += bytes.len();
+    }
+
+    /// Get the script written, or the length it needs if the buffer is too small.
+    pub(crate) fn finish<'a>(self) -> Result<&'buf [u8], ScriptBuilderError<'a>> {
+        if self.len > self.buf.len() {
+            return Err(ScriptBuilderError::CapacityExceeded {
+                length: self.len,
+                capacity: self.buf.len(),
+            });
+        }
+        Ok(&self.buf[..self.len])
+    }
+}
+
+impl ScriptSink for ScriptWriter<'_> {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn push_opcode(&mut self, opcode: Opcode) {
+        self.write(&[opcode.to_u8()]);
+        self.last_opcode = Some(opcode);
+    }
+
+    fn push_int(&mut self, n: i64) {
+        match n {
+            -1 | 1..=16 => self.push_opcode(Opcode::from((n - 1 + OP_TRUE.to_u8() as i64) as u8)),
+            0 => self.push_opcode(OP_0),
+            _ => {
+                let mut buf = [0u8; 8];
+                let len = write_scriptint(&mut buf, n);
+                self.push_slice(&buf[..len]);
+            }
+        }
+    }
+
+    fn push_slice(&mut self, data: &[u8]) {
+        let len = data.len();
+        match len {
+            len if len < OP_PUSHDATA1.to_u8() as usize => self.write(&[len as u8]),
+            len if len <= 0xff => self.write(&[OP_PUSHDATA1.to_u8(), len as u8]),
+            len if len <= 0xffff => {
+                self.write(&[OP_PUSHDATA2.to_u8()]);
+                self.write(&(len as u16).to_le_bytes());
+            }
+            len => {
+                self.write(&[OP_PUSHDATA4.to_u8()]);
+                self.write(&(len as u32).to_le_bytes());
+            }
+        }
+        self.write(data);
+        self.last_opcode = None;
+    }
+
+    fn push_verify(&mut self) {
+        let verify = match self.last_opcode {
+            Some(OP_EQUAL) => Some(OP_EQUALVERIFY),
+            Some(OP_NUMEQUAL) => Some(OP_NUMEQUALVERIFY),
+            Some(OP_CHECKSIG) => Some(OP_CHECKSIGVERIFY),
+            Some(OP_CHECKMULTISIG) => Some(OP_CHECKMULTISIGVERIFY),
+            _ => None,
+        };
+        match verify {
+            Some(verify) => {
+                self.len -= 1;
+                self.push_opcode(verify);
+            }
+            None => self.push_opcode(OP_VERIFY),
+        }
+    }
+
+    fn push_script(&mut self, script: &Script) {
+        self.write(script.as_bytes());
+        self.last_opcode = None;
+    }
+}
+
+/// Where the emission resumes once the script of a fragment is complete.
+#[derive(Clone, Copy)]
+pub(crate) struct Link {
+    /// The fragment containing it, `None` for the fragment the emission started from
+    parent: Option<NodeIndex>,
+    /// The phase of the parent that follows it, see [`ScriptBuilder::emit`]
+    phase: usize,
+    /// The length of the script when the fragment started
+    start: usize,
+}
+
+impl Link {
+    pub(crate) const EMPTY: Self = Self {
+        parent: None,
+        phase: 0,
+        start: 0,
+    };
+}
+
+pub(crate) struct ScriptBuilder<'a> {
     phantom: PhantomData<&'a ()>,
     descriptor: Descriptor,
+    /// Only recorded when a fragment map is requested
+    fragments: Option<FragmentMap>,
 }
 
 impl<'a> ScriptBuilder<'a> {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         Self {
             phantom: PhantomData,
             descriptor: Descriptor::default(),
+            fragments: None,
         }
     }
 
-    fn build_fragment(
+    /// Emit the script of `ast` into `sink` without recursing into the sub-expressions.
+    ///
+    /// The emission walks down into a sub-expression and back up to the fragment
+    /// containing it, which `links` records with one slot per node, so no stack grows
+    /// with the nesting depth.
+    pub(crate) fn build_fragment<C: Tree, S: ScriptSink>(
         &mut self,
-        ctx: &Context,
+        ctx: &C,
         ast: &AST,
-        mut builder: Builder,
-    ) -> Result<Builder, ScriptBuilderError<'a>> {
-        match &ast.fragment {
-            Fragment::False => {
-                builder = builder.push_opcode(opcodes::OP_FALSE);
-                Ok(builder)
-            }
-            Fragment::True => {
-                builder = builder.push_opcode(opcodes::OP_TRUE);
-                Ok(builder)
-            }
-            Fragment::PkK { key } => {
-                let key = match key.as_definite_key() {
-                    Some(k) => k,
-                    None => return Err(ScriptBuilderError::NonDefiniteKey(key.identifier())),
-                };
-                builder = key.push_to_script(builder);
-                Ok(builder)
-            }
-            Fragment::PkH { key } => {
-                let key = match key.as_definite_key() {
-                    Some(k) => k,
-                    None => return Err(ScriptBuilderError::NonDefiniteKey(key.identifier())),
-                };
-                let hash: PubkeyHash = key.pubkey_hash();
-
-                builder = builder
-                    .push_opcode(opcodes::all::OP_DUP)
-                    .push_opcode(opcodes::all::OP_HASH160)
-                    .push_slice(&hash)
-                    .push_opcode(opcodes::all::OP_EQUALVERIFY);
-                Ok(builder)
-            }
-            Fragment::Older { n } => {
-                builder = builder
-                    .push_int(*n as i64)
-                    .push_opcode(opcodes::all::OP_CSV);
-                Ok(builder)
-            }
-            Fragment::After { n } => {
-                builder = builder
-                    .push_int(*n as i64)
-                    .push_opcode(opcodes::all::OP_CLTV);
-                Ok(builder)
-            }
-            Fragment::Sha256 { h } => {
-                builder = builder
-                    .push_opcode(opcodes::all::OP_SIZE)
-                    .push_int(32)
-                    .push_opcode(opcodes::all::OP_EQUALVERIFY)
-                    .push_opcode(opcodes::all::OP_SHA256)
-                    .push_slice(h)
-                    .push_opcode(opcodes::all::OP_EQUAL);
-                Ok(builder)
-            }
-            Fragment::Hash256 { h } => {
-                builder = builder
-                    .push_opcode(opcodes::all::OP_SIZE)
-                    .push_int(32)
-                    .push_opcode(opcodes::all::OP_EQUALVERIFY)
-                    .push_opcode(opcodes::all::OP_HASH256)
-                    .push_slice(h)
-                    .push_opcode(opcodes::all::OP_EQUAL);
-                Ok(builder)
-            }
-            Fragment::Ripemd160 { h } => {
-                builder = builder
-                    .push_opcode(opcodes::all::OP_SIZE)
-                    .push_int(32)
-                    .push_opcode(opcodes::all::OP_EQUALVERIFY)
-                    .push_opcode(opcodes::all::OP_RIPEMD160)
-                    .push_slice(h)
-                    .push_opcode(opcodes::all::OP_EQUAL);
-                Ok(builder)
-            }
-            Fragment::Hash160 { h } => {
-                builder = builder
-                    .push_opcode(opcodes::all::OP_SIZE)
-                    .push_int(32)
-                    .push_opcode(opcodes::all::OP_EQUALVERIFY)
-                    .push_opcode(opcodes::all::OP_HASH160)
-                    .push_slice(h)
-                    .push_opcode(opcodes::all::OP_EQUAL);
-                Ok(builder)
-            }
-            Fragment::AndOr { x, y, z } => {
-                let builder = self.build_fragment(ctx, ctx.get_node(*x), builder)?;
-                let builder = builder.push_opcode(opcodes::all::OP_NOTIF);
-                let builder = self.build_fragment(ctx, ctx.get_node(*z), builder)?;
-                let builder = builder.push_opcode(opcodes::all::OP_ELSE);
-                let builder = self.build_fragment(ctx, ctx.get_node(*y), builder)?;
-                let builder = builder.push_opcode(opcodes::all::OP_ENDIF);
-                Ok(builder)
-            }
-            Fragment::AndV { x, y } => {
-                let builder = self.build_fragment(ctx, ctx.get_node(*x), builder)?;
-                let builder = self.build_fragment(ctx, ctx.get_node(*y), builder)?;
-                Ok(builder)
-            }
-            Fragment::AndB { x, y } => {
-                let builder = self.build_fragment(ctx, ctx.get_node(*x), builder)?;
-                let builder = self.build_fragment(ctx, ctx.get_node(*y), builder)?;
-                let builder = builder.push_opcode(opcodes::all::OP_BOOLAND);
-                Ok(builder)
-            }
-            Fragment::OrB { x, z } => {
-                let builder = self.build_fragment(ctx, ctx.get_node(*x), builder)?;
-                let builder = self.build_fragment(ctx, ctx.get_node(*z), builder)?;
-                let builder = builder.push_opcode(opcodes::all::OP_BOOLOR);
-                Ok(builder)
-            }
-            Fragment::OrC { x, z } => {
-                let builder = self.build_fragment(ctx, ctx.get_node(*x), builder)?;
-                let builder = builder.push_opcode(opcodes::all::OP_NOTIF);
-                let builder = self.build_fragment(ctx, ctx.get_node(*z), builder)?;
-                let builder = builder.push_opcode(opcodes::all::OP_ENDIF);
-                Ok(builder)
-            }
-            Fragment::OrD { x, z } => {
-                let builder = self.build_fragment(ctx, ctx.get_node(*x), builder)?;
-                let builder = builder.push_opcode(opcodes::all::OP_IFDUP);
-                let builder = builder.push_opcode(opcodes::all::OP_NOTIF);
-                let builder = self.build_fragment(ctx, ctx.get_node(*z), builder)?;
-                let builder = builder.push_opcode(opcodes::all::OP_ENDIF);
-                Ok(builder)
-            }
-            Fragment::OrI { x, z } => {
-                let builder = builder.push_opcode(opcodes::all::OP_IF);
-                let builder = self.build_fragment(ctx, ctx.get_node(*x), builder)?;
-                let builder = builder.push_opcode(opcodes::all::OP_ELSE);
-                let builder = self.build_fragment(ctx, ctx.get_node(*z), builder)?;
-                let builder = builder.push_opcode(opcodes::all::OP_ENDIF);
-                Ok(builder)
-            }
-            Fragment::Thresh { k, xs } => {
-                // must be at least one key
-                builder = self.build_fragment(ctx, ctx.get_node(xs[0]), builder)?;
-
-                let mut builder = builder;
-                for x in xs.iter().skip(1) {
-                    builder = self.build_fragment(ctx, ctx.get_node(*x), builder)?;
-                    builder = builder.push_opcode(opcodes::all::OP_ADD);
+        links: &mut [Link],
+        sink: &mut S,
+    ) -> Result<(), ScriptBuilderError<'a>> {
+        let (mut current, mut phase) = (None, 0);
+        loop {
+            let node = current.map_or(ast, |index| ctx.get_node(index));
+            match self.emit(ctx, node, phase, sink)? {
+                Some(child) => {
+                    links[child as usize] = Link {
+                        parent: current,
+                        phase: phase + 1,
+                        start: sink.len(),
+                    };
+                    (current, phase) = (Some(child), 0);
                 }
-                builder = builder.push_int(*k as i64);
-                builder = builder.push_opcode(opcodes::all::OP_EQUAL);
-                Ok(builder)
-            }
-            Fragment::Multi { k, keys } => {
-                let mut builder = builder.push_int(*k as i64);
-                for key in keys {
-                    // Multi only supports public keys
-                    let key = match key.as_definite_key() {
-                        Some(k) => k,
-                        None => return Err(ScriptBuilderError::NonDefiniteKey(key.identifier())),
+                None => {
+                    let Some(index) = current else {
+                        return Ok(());
                     };
-                    builder = key.push_to_script(builder);
+                    let link = links[index as usize];
+                    if let Some(fragments) = &mut self.fragments {
+                        fragments.push((link.start..sink.len(), index));
+                    }
+                    (current, phase) = (link.parent, link.phase);
                 }
-                builder = builder.push_int(keys.len() as i64);
-                builder = builder.push_opcode(opcodes::all::OP_CHECKMULTISIG);
-                Ok(builder)
             }
-            Fragment::MultiA { k, keys } => {
-                let mut builder = builder;
-                for key in keys {
-                    let key = match key.as_definite_key() {
-                        Some(k) => k,
-                        None => return Err(ScriptBuilderError::NonDefiniteKey(key.identifier())),
-                    };
-                    builder = key.push_to_script(builder);
+        }
+    }
 
-                    builder = builder.push_opcode(opcodes::all::OP_CHECKSIG);
-                    builder = builder.push_opcode(opcodes::all::OP_ADD);
-                }
-                builder = builder.push_int(*k as i64);
-                builder = builder.push_opcode(opcodes::all::OP_NUMEQUAL);
-                Ok(builder)
-            }
-            Fragment::Identity { identity_type, x } => match identity_type {
-                crate::parser::IdentityType::A => {
-                    let builder = builder.push_opcode(opcodes::all::OP_TOALTSTACK);
-                    let builder = self.build_fragment(ctx, ctx.get_node(*x), builder)?;
-                    let builder = builder.push_opcode(opcodes::all::OP_FROMALTSTACK);
-                    Ok(builder)
-                }
-                crate::parser::IdentityType::S => {
-                    let builder = builder.push_opcode(opcodes::all::OP_SWAP);
-                    let builder = self.build_fragment(ctx, ctx.get_node(*x), builder)?;
-                    Ok(builder)
+    /// Emit the script of a fragment up to its `phase`-th sub-expression, counted in the
+    /// order they appear in the script, and return that sub-expression. Past the last one,
+    /// emit the end of the script and return `None`.
+    fn emit<C: Tree, S: ScriptSink>(
+        &mut self,
+        ctx: &C,
+        ast: &AST,
+        phase: usize,
+        sink: &mut S,
+    ) -> Result<Option<NodeIndex>, ScriptBuilderError<'a>> {
+        let definite_key = |key: KeyIndex| {
+            let key = ctx.get_key(key);
+            key.as_definite_key()
+                .ok_or_else(|| ScriptBuilderError::NonDefiniteKey {
+                    key: key.identifier(),
+                    span: ast.span(),
+                })
+        };
+
+        let next = match (&ast.fragment, phase) {
+            (Fragment::False, _) => {
+                sink.push_opcode(OP_FALSE);
+                None
+            }
+            (Fragment::True, _) => {
+                sink.push_opcode(OP_TRUE);
+                None
+            }
+            (Fragment::PkK { key }, _) => {
+                sink.push_key(&definite_key(*key)?);
+                None
+            }
+            (Fragment::PkH { key }, _) => {
+                let hash: PubkeyHash = definite_key(*key)?.pubkey_hash();
+                sink.push_opcode(OP_DUP);
+                sink.push_opcode(OP_HASH160);
+                sink.push_slice(hash.as_ref());
+                sink.push_opcode(OP_EQUALVERIFY);
+                None
+            }
+            (Fragment::Older { n }, _) => {
+                sink.push_int(*n as i64);
+                sink.push_opcode(OP_CSV);
+                None
+            }
+            (Fragment::After { n }, _) => {
+                sink.push_int(*n as i64);
+                sink.push_opcode(OP_CLTV);
+                None
+            }
+            (Fragment::Sha256 { h }, _) => {
+                push_hash_check(sink, OP_SHA256, h);
+                None
+            }
+            (Fragment::Hash256 { h }, _) => {
+                push_hash_check(sink, OP_HASH256, h);
+                None
+            }
+            (Fragment::Ripemd160 { h }, _) => {
+                push_hash_check(sink, OP_RIPEMD160, h);
+                None
+            }
+            (Fragment::Hash160 { h }, _) => {
+                push_hash_check(sink, OP_HASH160, h);
+                None
+            }
+            // X NOTIF Z ELSE Y ENDIF
+            (Fragment::AndOr { x, .. }, 0) => Some(*x),
+            (Fragment::AndOr { z, .. }, 1) => {
+                sink.push_opcode(OP_NOTIF);
+                Some(*z)
+            }
+            (Fragment::AndOr { y, .. }, 2) => {
+                sink.push_opcode(OP_ELSE);
+                Some(*y)
+            }
+            (Fragment::AndOr { .. }, _) => {
+                sink.push_opcode(OP_ENDIF);
+                None
+            }
+            // X Y
+            (Fragment::AndV { x, .. } | Fragment::AndB { x, .. }, 0) => Some(*x),
+            (Fragment::AndV { y, .. } | Fragment::AndB { y, .. }, 1) => Some(*y),
+            (Fragment::AndV { .. }, _) => None,
+            (Fragment::AndB { .. }, _) => {
+                sink.push_opcode(OP_BOOLAND);
+                None
+            }
+            // X Z BOOLOR
+            (Fragment::OrB { x, .. }, 0) => Some(*x),
+            (Fragment::OrB { z, .. }, 1) => Some(*z),
+            (Fragment::OrB { .. }, _) => {
+                sink.push_opcode(OP_BOOLOR);
+                None
+            }
+            // X NOTIF Z ENDIF
+            (Fragment::OrC { x, .. }, 0) => Some(*x),
+            (Fragment::OrC { z, .. }, 1) => {
+                sink.push_opcode(OP_NOTIF);
+                Some(*z)
+            }
+            // X IFDUP NOTIF Z ENDIF
+            (Fragment::OrD { x, .. }, 0) => Some(*x),
+            (Fragment::OrD { z, .. }, 1) => {
+                sink.push_opcode(OP_IFDUP);
+                sink.push_opcode(OP_NOTIF);
+                Some(*z)
+            }
+            // IF X ELSE Z ENDIF
+            (Fragment::OrI { x, .. }, 0) => {
+                sink.push_opcode(OP_IF);
+                Some(*x)
+            }
+            (Fragment::OrI { z, .. }, 1) => {
+                sink.push_opcode(OP_ELSE);
+                Some(*z)
+            }
+            (Fragment::OrC { .. } | Fragment::OrD { .. } | Fragment::OrI { .. }, _) => {
+                sink.push_opcode(OP_ENDIF);
+                None
+            }
+            // X1 X2 ADD ... Xn ADD k EQUAL
+            (Fragment::Thresh { k, xs }, _) => {
+                if phase >= 2 {
+                    sink.push_opcode(OP_ADD);
                 }
-                crate::parser::IdentityType::C => {
-                    let builder = self.build_fragment(ctx, ctx.get_node(*x), builder)?;
-                    let builder = builder.push_opcode(opcodes::all::OP_CHECKSIG);
-                    Ok(builder)
+                match ctx.list(*xs).get(phase) {
+                    Some(x) => Some(*x),
+                    None => {
+                        sink.push_int(*k as i64);
+                        sink.push_opcode(OP_EQUAL);
+                        None
+                    }
                 }
-                crate::parser::IdentityType::D => {
-                    let builder = builder.push_opcode(opcodes::all::OP_DUP);
-                    let builder = builder.push_opcode(opcodes::all::OP_IF);
-                    let builder = self.build_fragment(ctx, ctx.get_node(*x), builder)?;
-                    let builder = builder.push_opcode(opcodes::all::OP_ENDIF);
-                    Ok(builder)
+            }
+            (Fragment::Multi { k, keys }, _) => {
+                let keys = ctx.list(*keys);
+                sink.push_int(*k as i64);
+                for key in keys {
+                    // Multi only supports public keys
+                    sink.push_key(&definite_key(*key)?);
                 }
-                crate::parser::IdentityType::V => {
-                    let builder = self.build_fragment(ctx, ctx.get_node(*x), builder)?;
-                    let builder = builder.push_verify();
-                    Ok(builder)
+                sink.push_int(keys.len() as i64);
+                sink.push_opcode(OP_CHECKMULTISIG);
+                None
+            }
+            (Fragment::MultiA { k, keys }, _) => {
+                for key in ctx.list(*keys) {
+                    sink.push_key(&definite_key(*key)?);
+                    sink.push_opcode(OP_CHECKSIG);
+                    sink.push_opcode(OP_ADD);
                 }
-                crate::parser::IdentityType::J => {
-                    let builder = builder.push_opcode(opcodes::all::OP_SIZE);
-                    let builder = builder.push_opcode(opcodes::all::OP_0NOTEQUAL);
-                    let builder = builder.push_opcode(opcodes::all::OP_IF);
-                    let builder = self.build_fragment(ctx, ctx.get_node(*x), builder)?;
-                    let builder = builder.push_opcode(opcodes::all::OP_ENDIF);
-                    Ok(builder)
+                sink.push_int(*k as i64);
+                sink.push_opcode(OP_NUMEQUAL);
+                None
+            }
+            (Fragment::Identity { identity_type, x }, 0) => {
+                match identity_type {
+                    IdentityType::A => sink.push_opcode(OP_TOALTSTACK),
+                    IdentityType::S => sink.push_opcode(OP_SWAP),
+                    IdentityType::D => {
+                        sink.push_opcode(OP_DUP);
+                        sink.push_opcode(OP_IF);
+                    }
+                    IdentityType::J => {
+                        sink.push_opcode(OP_SIZE);
+                        sink.push_opcode(OP_0NOTEQUAL);
+                        sink.push_opcode(OP_IF);
+                    }
+                    IdentityType::C | IdentityType::V | IdentityType::N => (),
                 }
-                crate::parser::IdentityType::N => {
-                    let builder = self.build_fragment(ctx, ctx.get_node(*x), builder)?;
-                    let builder = builder.push_opcode(opcodes::all::OP_0NOTEQUAL);
-                    Ok(builder)
+                Some(*x)
+            }
+            (Fragment::Identity { identity_type, .. }, _) => {
+                match identity_type {
+                    IdentityType::A => sink.push_opcode(OP_FROMALTSTACK),
+                    IdentityType::C => sink.push_opcode(OP_CHECKSIG),
+                    IdentityType::D | IdentityType::J => sink.push_opcode(OP_ENDIF),
+                    IdentityType::V => sink.push_verify(),
+                    IdentityType::N => sink.push_opcode(OP_0NOTEQUAL),
+                    IdentityType::S => (),
                 }
-            },
-            Fragment::Descriptor { descriptor, inner } => {
+                None
+            }
+            (Fragment::Descriptor { descriptor, inner }, 0) => {
                 // set descriptor
                 self.descriptor = descriptor.clone();
-
-                let builder = self.build_fragment(ctx, ctx.get_node(*inner), builder)?;
-                Ok(builder)
+                Some(*inner)
             }
-            Fragment::RawPkH { key } => {
-                let key = match key.as_definite_key() {
-                    Some(k) => k,
-                    None => return Err(ScriptBuilderError::NonDefiniteKey(key.identifier())),
-                };
-                let hash: PubkeyHash = key.pubkey_hash();
-
-                builder = if ctx.descriptor().is_witness() {
-                    builder
-                    .push_opcode(OP_0)
-                    .push_slice(&hash)
-                } else {
-                    builder
-                    .push_opcode(opcodes::all::OP_DUP)
-                    .push_opcode(opcodes::all::OP_HASH160)
-                    .push_slice(&hash)
-                    .push_opcode(opcodes::all::OP_EQUALVERIFY)
-                    .push_opcode(opcodes::all::OP_CHECKSIG)
-                };
-
-                Ok(builder)
-            }
-            Fragment::RawTr { key, inner } => {
-                if let Some(inner) = inner {
-                    let builder = self.build_fragment(ctx, ctx.get_node(*inner), builder)?;
-                    Ok(builder)
+            (
+                Fragment::RawTr {
+                    inner: Some(inner), ..
+                },
+                0,
+            ) => Some(*inner),
+            (Fragment::RawTr { inner: None, .. }, _) => {
+                return Err(ScriptBuilderError::TaprootScriptWithoutInner { span: ast.span() });
+            }
+            (Fragment::Descriptor { .. } | Fragment::RawTr { .. }, _) => None,
+            (Fragment::RawPkH { key }, _) => {
+                let hash: PubkeyHash = definite_key(*key)?.pubkey_hash();
+                if ctx.descriptor().is_witness() {
+                    sink.push_opcode(OP_0);
+                    sink.push_slice(hash.as_ref());
                 } else {
-                    return Err(ScriptBuilderError::TaprootScriptWithoutInner);
+                    sink.push_opcode(OP_DUP);
+                    sink.push_opcode(OP_HASH160);
+                    sink.push_slice(hash.as_ref());
+                    sink.push_opcode(OP_EQUALVERIFY);
+                    sink.push_opcode(OP_CHECKSIG);
                 }
+                None
             }
-            Fragment::RawPk { key } => {
-                let key = match key.as_definite_key() {
-                    Some(k) => k,
-                    None => return Err(ScriptBuilderError::NonDefiniteKey(key.identifier())),
-                };
-                builder = key.push_to_script(builder);
-                builder = builder.push_opcode(opcodes::all::OP_CHECKSIG);
-                Ok(builder)
+            (Fragment::RawPk { key }, _) => {
+                sink.push_key(&definite_key(*key)?);
+                sink.push_opcode(OP_CHECKSIG);
+                None
             }
-        }
+            (Fragment::RawAddr { address }, _) => {
+                sink.push_script(&address.assume_checked_ref().script_pubkey());
+                None
+            }
+            (Fragment::RawScript { script }, _) => {
+                sink.push_script(script);
+                None
+            }
+        };
+        Ok(next)
     }
 }
+
+/// Emit `SIZE 32 EQUALVERIFY <hash opcode> <h> EQUAL`.
+fn push_hash_check<S: ScriptSink>(sink: &mut S, opcode: Opcode, h: &[u8]) {
+    sink.push_opcode(OP_SIZE);
+    sink.push_int(32);
+    sink.push_opcode(OP_EQUALVERIFY);
+    sink.push_opcode(opcode);
+    sink.push_slice(h);
+    sink.push_opcode(OP_EQUAL);
+}
@@ -0,0 +1,336 @@
+use bitcoin::key::TapTweak;
+use bitcoin::secp256k1::{Message, Secp256k1, VerifyOnly};
+use bitcoin::sighash::{EcdsaSighashType, Prevouts, SighashCache, TapSighashType};
+use bitcoin::taproot::{LeafVersion, TapLeafHash};
+use bitcoin::{Transaction, TxOut, XOnlyPublicKey, ecdsa, taproot};
+
+use crate::{
+    Vec,
+    context::Context,
+    descriptor::Descriptor,
+    error::Diagnostic,
+    parser::keys::{DefiniteKeyToken, KeyToken},
+    satisfy::{HashFunc, Satisfier, SatisfyError},
+};
+
+/// A [`Satisfier`] checking every signature of the wrapped satisfier against the
+/// transaction spending the descriptor, so that invalid signatures are reported by
+/// [`Context::satisfy`] with [`SatisfyError::InvalidSignature`] instead of at broadcast.
+///
+/// The sighash is computed once for the input: legacy for `sh()`, `pkh()` and bare
+/// descriptors, segwit v0 for `wsh()` and `wpkh()`, the tapscript leaf sighash for
+/// `tr()` and the key path sighash for `rawtr()`. The internal key of `tr()` may also sign
+/// the key path sighash, checked against the output key. Signatures must carry
+/// `sighash_type` as their sighash flag.
+pub struct VerifyingSatisfier<'a> {
+    inner: &'a dyn Satisfier,
+    secp: Secp256k1<VerifyOnly>,
+    message: Message,
+    /// The internal key of `tr()`, its output key and the key path sighash
+    key_path: Option<(XOnlyPublicKey, XOnlyPublicKey, Message)>,
+    taproot: bool,
+    sighash_type: u32,
+}
+
+impl<'a> VerifyingSatisfier<'a> {
+    /// Compute the sighash of input `input_index` of `tx`. `prevouts` are the outputs
+    /// spent by every input of the transaction, in order.
+    pub fn new(
+        ctx: &Context,
+        inner: &'a dyn Satisfier,
+        tx: &Transaction,
+        input_index: usize,
+        prevouts: &[TxOut],
+        sighash_type: u32,
+    ) -> Result<Self, SatisfyError> {
+        let span = ctx.get_root().span();
+        let script = || {
+            ctx.build_script().map_err(|e| SatisfyError::Sighash {
+                span: e.span().unwrap_or(span),
+            })
+        };
+        let value = prevouts
+            .get(input_index)
+            .map(|prevout| prevout.value)
+            .ok_or(SatisfyError::Sighash { span })?;
+
+        let mut cache = SighashCache::new(tx);
+        let tap_sighash_type = || {
+            u8::try_from(sighash_type)
+                .ok()
+                .and_then(|t| TapSighashType::from_consensus_u8(t).ok())
+                .ok_or(SatisfyError::Sighash { span })
+        };
+        let secp = Secp256k1::verification_only();
+        let tr_output = match ctx.descriptor() {
+            Descriptor::Tr => {
+                Some(
+                    crate::script::taproot_output(ctx).map_err(|e| SatisfyError::Sighash {
+                        span: e.span().unwrap_or(span),
+                    })?,
+                )
+            }
+            _ => None,
+        };
+        let mut key_spend_message = || -> Result<Message, SatisfyError> {
+            let sighash = cache
+                .taproot_key_spend_signature_hash(
+                    input_index,
+                    &Prevouts::All(prevouts),
+                    tap_sighash_type()?,
+                )
+                .map_err(|_| SatisfyError::Sighash { span })?;
+            Ok(Message::from(sighash))
+        };
+        let key_path = match tr_output {
+            Some((internal_key, merkle_root)) => {
+                let (output_key, _) = internal_key.tap_tweak(&secp, merkle_root);
+                Some((
+                    internal_key,
+                    output_key.to_x_only_public_key(),
+                    key_spend_message()?,
+                ))
+            }
+            None => None,
+        };
+
+        let message = match ctx.descriptor() {
+            Descriptor::Tr if tr_output.is_some_and(|(_, merkle_root)| merkle_root.is_some()) => {
+                let sighash_type = tap_sighash_type()?;
+                let leaf_hash = TapLeafHash::from_script(&script()?, LeafVersion::TapScript);
+                let sighash = cache
+                    .taproot_script_spend_signature_hash(
+                        input_index,
+                        &Prevouts::All(prevouts),
+                        leaf_hash,
+                        sighash_type,
+                    )
+                    .map_err(|_| SatisfyError::Sighash { span })?;
+                Message::from(sighash)
+            }
+            // without leaves, only the key path sighash is signed
+            Descriptor::Tr | Descriptor::RawTr => key_spend_message()?,
+            Descriptor::Wpkh => {
+                let sighash_type = EcdsaSighashType::from_standard(sighash_type)
+                    .map_err(|_| SatisfyError::Sighash { span })?;
+                let sighash = cache
+                    .p2wpkh_signature_hash(input_index, &script()?, value, sighash_type)
+                    .map_err(|_| SatisfyError::Sighash { span })?;
+                Message::from(sighash)
+            }
+            Descriptor::Wsh => {
+                let sighash_type = EcdsaSighashType::from_standard(sighash_type)
+                    .map_err(|_| SatisfyError::Sighash { span })?;
+                let sighash = cache
+                    .p2wsh_signature_hash(input_index, &script()?, value, sighash_type)
+                    .map_err(|_| SatisfyError::Sighash { span })?;
+                Message::from(sighash)
+            }
+            _ => {
+                let sighash = cache
+                    .legacy_signature_hash(input_index, &script()?, sighash_type)
+                    .map_err(|_| SatisfyError::Sighash { span })?;
+                Message::from(sighash)
+            }
+        };
+
+        Ok(Self {
+            inner,
+            secp,
+            message,
+            key_path,
+            taproot: matches!(ctx.descriptor(), Descriptor::Tr | Descriptor::RawTr),
+            sighash_type,
+        })
+    }
+
+    fn verify(&self, key: DefiniteKeyToken, sig: &[u8]) -> bool {
+        if self.taproot {
+            let Ok(sig) = taproot::Signature::from_slice(sig) else {
+                return false;
+            };
+            let key = match key {
+                DefiniteKeyToken::XOnlyPublicKey(pk) => pk,
+                DefiniteKeyToken::PublicKey(pk) => pk.into(),
+            };
+            let valid = |message: &Message, key: &XOnlyPublicKey| {
+                self.secp
+                    .verify_schnorr(&sig.signature, message, key)
+                    .is_ok()
+            };
+            let valid = match &self.key_path {
+                // without leaves `message` is the key path sighash, only signed for the
+                // output key
+                Some((internal, output, message)) if key == *internal => {
+                    valid(message, output)
+                        || (*message != self.message && valid(&self.message, &key))
+                }
+                _ => valid(&self.message, &key),
+            };
+            sig.sighash_type as u32 == self.sighash_type && valid
+        } else {
+            let (Ok(sig), DefiniteKeyToken::PublicKey(key)) =
+                (ecdsa::Signature::from_slice(sig), key)
+            else {
+                return false;
+            };
+            sig.sighash_type.to_u32() == self.sighash_type
+                && self
+                    .secp
+                    .verify_ecdsa(&self.message, &sig.signature, &key.inner)
+                    .is_ok()
+        }
+    }
+}
+
+impl Satisfier for VerifyingSatisfier<'_> {
+    fn check_older(&self, locktime: u32) -> Option<bool> {
+        self.inner.check_older(locktime)
+    }
+
+    fn check_after(&self, locktime: u32) -> Option<bool> {
+        self.inner.check_after(locktime)
+    }
+
+    fn sign(&self, pubkey: &KeyToken) -> Option<(Vec<u8>, bool)> {
+        self.inner.sign(pubkey)
+    }
+
+    fn check_signature(&self, pubkey: &KeyToken, sig: &[u8]) -> bool {
+        pubkey
+            .as_definite_key()
+            .is_some_and(|key| self.verify(key, sig))
+            && self.inner.check_signature(pubkey, sig)
+    }
+
+    fn preimage(&self, hash_func: HashFunc, hash: &[u8]) -> Option<(Vec<u8>, bool)> {
+        self.inner.preimage(hash_func, hash)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::satisfy::Strategy;
+    use crate::utils::fixtures::Wallet;
+    use bitcoin::{Amount, OutPoint, Sequence, TxIn, Witness, absolute, transaction};
+
+    /// A transaction spending the output of `ctx`, with the outputs it spends.
+    fn spend(ctx: &Context) -> (Transaction, [TxOut; 1]) {
+        let prevout = TxOut {
+            value: Amount::from_sat(50_000),
+            script_pubkey: ctx.build_script_pubkey().unwrap(),
+        };
+        let tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: alloc::vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: bitcoin::ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: alloc::vec![TxOut {
+                value: Amount::from_sat(49_000),
+                script_pubkey: bitcoin::ScriptBuf::new(),
+            }],
+        };
+        (tx, [prevout])
+    }
+
+    #[test]
+    fn test_verify_signatures() {
+        let input = "wsh(pk(0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798))";
+        let ctx = Context::try_from(input).unwrap();
+        let (tx, prevouts) = spend(&ctx);
+
+        let wallet = Wallet {
+            message: Message::from_digest([0; 32]),
+            schnorr: false,
+            tweak: false,
+        };
+        let satisfier = VerifyingSatisfier::new(&ctx, &wallet, &tx, 0, &prevouts, 0x01).unwrap();
+        let err = ctx.satisfy(&satisfier, &Strategy::DEFAULT).err().unwrap();
+        assert!(matches!(err, SatisfyError::InvalidSignature { .. }));
+
+        let wallet = Wallet {
+            message: satisfier.message,
+            schnorr: false,
+            tweak: false,
+        };
+        let satisfier = VerifyingSatisfier::new(&ctx, &wallet, &tx, 0, &prevouts, 0x01).unwrap();
+        assert!(
+            ctx.satisfy(&satisfier, &Strategy::DEFAULT)
+                .unwrap()
+                .sat
+                .available
+        );
+
+        // the signatures commit to SIGHASH_ALL
+        let satisfier = VerifyingSatisfier::new(&ctx, &wallet, &tx, 0, &prevouts, 0x02).unwrap();
+        assert!(ctx.satisfy(&satisfier, &Strategy::DEFAULT).is_err());
+    }
+
+    #[test]
+    fn test_verify_rawtr_key_path() {
+        let input = "rawtr(79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798)";
+        let ctx = Context::try_from(input).unwrap();
+        let (tx, prevouts) = spend(&ctx);
+
+        let wallet = Wallet {
+            message: Message::from_digest([0; 32]),
+            schnorr: true,
+            tweak: false,
+        };
+        let satisfier = VerifyingSatisfier::new(&ctx, &wallet, &tx, 0, &prevouts, 0x00).unwrap();
+        let err = ctx.satisfy(&satisfier, &Strategy::DEFAULT).err().unwrap();
+        assert!(matches!(err, SatisfyError::InvalidSignature { .. }));
+
+        // the signature is checked against the output key, without tweaking it
+        let wallet = Wallet {
+            message: satisfier.message,
+            schnorr: true,
+            tweak: false,
+        };
+        let satisfier = VerifyingSatisfier::new(&ctx, &wallet, &tx, 0, &prevouts, 0x00).unwrap();
+        let sat = ctx.satisfy(&satisfier, &Strategy::DEFAULT).unwrap().sat;
+        assert!(sat.available);
+        assert_eq!(sat.witness.len(), 1);
+        assert_eq!(sat.witness.nth(0).unwrap().len(), 64);
+    }
+
+    #[test]
+    fn test_verify_tr_key_path() {
+        let input = "tr(0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798)";
+        let ctx = Context::try_from(input).unwrap();
+        let (tx, prevouts) = spend(&ctx);
+
+        let wallet = Wallet {
+            message: Message::from_digest([0; 32]),
+            schnorr: true,
+            tweak: true,
+        };
+        let satisfier = VerifyingSatisfier::new(&ctx, &wallet, &tx, 0, &prevouts, 0x00).unwrap();
+        let (_, _, message) = satisfier.key_path.unwrap();
+
+        // the internal key signs the key path sighash for the output key
+        let wallet = Wallet {
+            message,
+            schnorr: true,
+            tweak: false,
+        };
+        let satisfier = VerifyingSatisfier::new(&ctx, &wallet, &tx, 0, &prevouts, 0x00).unwrap();
+        let err = ctx.satisfy(&satisfier, &Strategy::DEFAULT).err().unwrap();
+        assert!(matches!(err, SatisfyError::InvalidSignature { .. }));
+
+        let wallet = Wallet {
+            message,
+            schnorr: true,
+            tweak: true,
+        };
+        let satisfier = VerifyingSatisfier::new(&ctx, &wallet, &tx, 0, &prevouts, 0x00).unwrap();
+        let sat = ctx.satisfy(&satisfier, &Strategy::DEFAULT).unwrap().sat;
+        assert!(sat.available);
+        assert_eq!(sat.witness.len(), 1);
+    }
+}
@@ -1,8 +1,9 @@
 use crate::{
-    context::ASTVisitor,
-    context::Context,
+    Vec,
+    context::{ASTVisitor, Tree},
     descriptor::Descriptor,
-    parser::{AST, Fragment, IdentityType, Position},
+    error::{Diagnostic, Help, Span},
+    parser::{AST, Fragment, IdentityType, NodeIndex},
 };
 use core::cmp;
 
@@ -21,26 +22,106 @@ fn script_num_size(n: usize) -> usize {
 // Miniscript Types as bit flags
 
 /// Base
-const MINISCRIPT_TYPE_B: u8 = 1 << 0;
+pub const MINISCRIPT_TYPE_B: u8 = 1 << 0;
 /// Verify
-const MINISCRIPT_TYPE_V: u8 = 1 << 1;
+pub const MINISCRIPT_TYPE_V: u8 = 1 << 1;
 /// Key
-const MINISCRIPT_TYPE_K: u8 = 1 << 2;
+pub const MINISCRIPT_TYPE_K: u8 = 1 << 2;
 /// Wrapped
-const MINISCRIPT_TYPE_W: u8 = 1 << 3;
+pub const MINISCRIPT_TYPE_W: u8 = 1 << 3;
 
 // Properties as bit flags
 
-const PROPERTY_Z: u8 = 1 << 0;
-const PROPERTY_O: u8 = 1 << 1;
-const PROPERTY_N: u8 = 1 << 2;
-const PROPERTY_D: u8 = 1 << 3;
-const PROPERTY_U: u8 = 1 << 4;
+pub const PROPERTY_Z: u8 = 1 << 0;
+pub const PROPERTY_O: u8 = 1 << 1;
+pub const PROPERTY_N: u8 = 1 << 2;
+pub const PROPERTY_D: u8 = 1 << 3;
+pub const PROPERTY_U: u8 = 1 << 4;
+
+// Malleability properties as bit flags
+
+/// Signed: every satisfaction requires a signature
+pub const MALLEABILITY_S: u8 = 1 << 0;
+/// Forced: every dissatisfaction, if any, requires a signature
+pub const MALLEABILITY_F: u8 = 1 << 1;
+/// Expressive: a unique dissatisfaction exists without a signature
+pub const MALLEABILITY_E: u8 = 1 << 2;
+/// Non-malleable: a non-malleable satisfaction always exists
+pub const MALLEABILITY_M: u8 = 1 << 3;
+
+/// Display adapter for a base type bitmask, e.g. `B` or `B/K/V` when several are set.
+pub struct DisplayType(pub u8);
+
+impl core::fmt::Display for DisplayType {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let mut first = true;
+        for (flag, name) in [
+            (MINISCRIPT_TYPE_B, "B"),
+            (MINISCRIPT_TYPE_V, "V"),
+            (MINISCRIPT_TYPE_K, "K"),
+            (MINISCRIPT_TYPE_W, "W"),
+        ] {
+            if self.0 & flag != 0 {
+                if !first {
+                    f.write_str("/")?;
+                }
+                f.write_str(name)?;
+                first = false;
+            }
+        }
+        if first {
+            f.write_str("none")?;
+        }
+        Ok(())
+    }
+}
+
+/// Display adapter for a correctness properties bitmask, e.g. `ndu`.
+pub struct DisplayProperties(pub u8);
+
+impl core::fmt::Display for DisplayProperties {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for (flag, name) in [
+            (PROPERTY_Z, 'z'),
+            (PROPERTY_O, 'o'),
+            (PROPERTY_N, 'n'),
+            (PROPERTY_D, 'd'),
+            (PROPERTY_U, 'u'),
+        ] {
+            if self.0 & flag != 0 {
+                core::fmt::Write::write_char(f, name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Display adapter for a malleability properties bitmask, e.g. `esm`.
+pub struct DisplayMalleability(pub u8);
+
+impl core::fmt::Display for DisplayMalleability {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for (flag, name) in [
+            (MALLEABILITY_E, 'e'),
+            (MALLEABILITY_S, 's'),
+            (MALLEABILITY_F, 'f'),
+            (MALLEABILITY_M, 'm'),
+        ] {
+            if self.0 & flag != 0 {
+                core::fmt::Write::write_char(f, name)?;
+            }
+        }
+        Ok(())
+    }
+}
 
+/// Type of a fragment: its base type, correctness properties and malleability properties.
 #[cfg_attr(feature = "debug", derive(Debug))]
-pub(crate) struct TypeInfo {
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct TypeInfo {
     base_type: u8,
     properties: u8,
+    malleability: u8,
 
     /// The number of bytes needed to encode its scriptpubkey
     pub(crate) pk_cost: usize,
@@ -53,7 +134,7 @@ pub(crate) struct TypeInfo {
 
 impl TypeInfo {
     #[inline]
-    pub const fn new(
+    pub(crate) const fn new(
         base_type: u8,
         properties: u8,
         pk_cost: usize,
@@ -63,6 +144,7 @@ impl TypeInfo {
         Self {
             base_type,
             properties,
+            malleability: 0,
             pk_cost,
             has_free_verify,
             tree_height,
@@ -79,6 +161,11 @@ impl TypeInfo {
         self.properties
     }
 
+    #[inline]
+    pub const fn malleability(&self) -> u8 {
+        self.malleability
+    }
+
     #[inline]
     pub const fn has_property(&self, property: u8) -> bool {
         (self.properties & property) != 0
@@ -90,42 +177,247 @@ impl TypeInfo {
     }
 }
 
+/// Formats the type like `Bonduesm`: the base type, then the correctness and malleability
+/// properties.
+impl core::fmt::Display for TypeInfo {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{}{}{}",
+            DisplayType(self.base_type),
+            DisplayProperties(self.properties),
+            DisplayMalleability(self.malleability)
+        )
+    }
+}
+
 // Type Checker
 
-pub struct CorrectnessPropertiesVisitor {}
+/// Where the type checker records the type of every node.
+pub trait TypeTable: AsRef<[Option<TypeInfo>]> + AsMut<[Option<TypeInfo>]> {
+    /// Get one empty slot per node, for a context of `len` nodes.
+    fn reset(&mut self, len: usize) -> &mut [Option<TypeInfo>];
+}
+
+impl TypeTable for Vec<Option<TypeInfo>> {
+    fn reset(&mut self, len: usize) -> &mut [Option<TypeInfo>] {
+        self.clear();
+        self.resize(len, None);
+        self
+    }
+}
+
+/// The types table of an arena, which holds a slot for as many nodes as it can store.
+impl TypeTable for &mut [Option<TypeInfo>] {
+    fn reset(&mut self, len: usize) -> &mut [Option<TypeInfo>] {
+        let types = &mut self[..len];
+        types.fill(None);
+        types
+    }
+}
+
+pub struct CorrectnessPropertiesVisitor<T = Vec<Option<TypeInfo>>> {
+    /// Types of the already checked nodes, `None` for the nodes that failed to type check
+    /// or were not checked yet.
+    types: T,
+}
 
 impl CorrectnessPropertiesVisitor {
     #[inline]
     pub const fn new() -> Self {
-        Self {}
+        Self { types: Vec::new() }
     }
 }
 
-#[cfg_attr(feature = "debug", derive(Debug))]
+impl<T: TypeTable> CorrectnessPropertiesVisitor<T> {
+    /// Record the types in `types`, e.g. the types table of an arena.
+    pub(crate) const fn with_types(types: T) -> Self {
+        Self { types }
+    }
+
+    /// Type check every node of the context bottom up, collecting all the errors.
+    ///
+    /// A node is only checked if all its sub-expressions type checked, so an error is
+    /// reported once at the innermost failing fragment. Nodes in `placeholders` stand in
+    /// for sub-expressions that failed to parse and are skipped the same way.
+    ///
+    /// Returns the type of the root if the whole tree type checked.
+    pub(crate) fn check_recovering<C: Tree>(
+        &mut self,
+        ctx: &C,
+        placeholders: &[NodeIndex],
+    ) -> (Option<TypeInfo>, Vec<CorrectnessPropertiesVisitorError>) {
+        self.types.reset(ctx.get_nodes().len());
+        let mut errors = Vec::new();
+
+        for (index, node) in ctx.get_nodes().iter().enumerate() {
+            let type_info = if placeholders.contains(&(index as NodeIndex)) {
+                None
+            } else {
+                self.check_node(ctx, node, &mut errors)
+            };
+            self.types.as_mut()[index] = type_info;
+        }
+
+        let root = self.check_node(ctx, ctx.get_root(), &mut errors);
+        (root, errors)
+    }
+
+    /// The types of the checked nodes, indexed by [`NodeIndex`].
+    pub(crate) fn into_types(self) -> T {
+        self.types
+    }
+
+    fn check_node<C: Tree>(
+        &mut self,
+        ctx: &C,
+        node: &AST,
+        errors: &mut Vec<CorrectnessPropertiesVisitorError>,
+    ) -> Option<TypeInfo> {
+        let types = self.types.as_ref();
+        if node
+            .fragment
+            .children(ctx.lists())
+            .any(|child| !matches!(types.get(child as usize), Some(Some(_))))
+        {
+            return None;
+        }
+
+        match self.visit_ast(ctx, node) {
+            Ok(type_info) => Some(type_info),
+            Err(e) => {
+                errors.push(e);
+                None
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
 pub enum CorrectnessPropertiesVisitorError {
+    /// The fragment has the wrong base type (`B`, `V`, `K` or `W` bit flags)
     UnexpectedType {
-        position: Position,
+        span: Span,
         reason: &'static str,
+        expected: u8,
         found: u8,
     },
     InvalidThreshold {
-        position: Position,
+        span: Span,
         k: i32,
     },
     EmptyThreshold {
-        position: Position,
+        span: Span,
     },
+    /// The sub-expression of `j:` lacks the `n` property, `found` holds the ones it has
     NonZeroZero {
-        position: Position,
+        span: Span,
+        found: u8,
     },
+    /// The sub-expression of `s:` lacks the `o` property, `found` holds the ones it has
     SwapNonOne {
-        position: Position,
+        span: Span,
+        found: u8,
     },
     NonTopLevel {
-        position: Position,
+        span: Span,
+    },
+    /// The fragment lacks some of the required correctness properties (`zondu` bit flags)
+    MissingProperties {
+        span: Span,
+        reason: &'static str,
+        expected: u8,
+        found: u8,
     },
 }
 
+impl core::fmt::Display for CorrectnessPropertiesVisitorError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            CorrectnessPropertiesVisitorError::UnexpectedType { reason, found, .. } => {
+                write!(f, "{reason} (found {})", DisplayType(*found))
+            }
+            CorrectnessPropertiesVisitorError::MissingProperties { reason, found, .. } => {
+                write!(f, "{reason} (found '{}')", DisplayProperties(*found))
+            }
+            CorrectnessPropertiesVisitorError::InvalidThreshold { k, .. } => {
+                write!(
+                    f,
+                    "threshold {k} must be between 1 and the number of sub-expressions"
+                )
+            }
+            CorrectnessPropertiesVisitorError::EmptyThreshold { .. } => {
+                write!(f, "threshold has no sub-expressions")
+            }
+            CorrectnessPropertiesVisitorError::NonZeroZero { .. } => {
+                write!(f, "j:X: X must have property n (non-zero)")
+            }
+            CorrectnessPropertiesVisitorError::SwapNonOne { .. } => {
+                write!(f, "s:X: X must have property o (one-arg)")
+            }
+            CorrectnessPropertiesVisitorError::NonTopLevel { .. } => {
+                write!(f, "top level fragment must be type B (Base)")
+            }
+        }
+    }
+}
+
+impl core::error::Error for CorrectnessPropertiesVisitorError {}
+
+impl Diagnostic for CorrectnessPropertiesVisitorError {
+    fn code(&self) -> &'static str {
+        match self {
+            CorrectnessPropertiesVisitorError::UnexpectedType { .. } => "T001",
+            CorrectnessPropertiesVisitorError::InvalidThreshold { .. } => "T002",
+            CorrectnessPropertiesVisitorError::EmptyThreshold { .. } => "T003",
+            CorrectnessPropertiesVisitorError::NonZeroZero { .. } => "T004",
+            CorrectnessPropertiesVisitorError::SwapNonOne { .. } => "T005",
+            CorrectnessPropertiesVisitorError::NonTopLevel { .. } => "T006",
+            CorrectnessPropertiesVisitorError::MissingProperties { .. } => "T007",
+        }
+    }
+
+    fn span(&self) -> Option<Span> {
+        match self {
+            CorrectnessPropertiesVisitorError::UnexpectedType { span, .. }
+            | CorrectnessPropertiesVisitorError::InvalidThreshold { span, .. }
+            | CorrectnessPropertiesVisitorError::EmptyThreshold { span }
+            | CorrectnessPropertiesVisitorError::NonZeroZero { span, .. }
+            | CorrectnessPropertiesVisitorError::SwapNonOne { span, .. }
+            | CorrectnessPropertiesVisitorError::NonTopLevel { span }
+            | CorrectnessPropertiesVisitorError::MissingProperties { span, .. } => Some(*span),
+        }
+    }
+
+    fn help(&self) -> Option<Help> {
+        match self {
+            CorrectnessPropertiesVisitorError::UnexpectedType {
+                expected, found, ..
+            } => Some(Help::Type {
+                expected: *expected,
+                found: *found,
+            }),
+            CorrectnessPropertiesVisitorError::MissingProperties {
+                expected, found, ..
+            } => Some(Help::Properties {
+                expected: *expected,
+                found: *found,
+            }),
+            CorrectnessPropertiesVisitorError::NonZeroZero { found, .. } => {
+                Some(Help::Properties {
+                    expected: PROPERTY_N,
+                    found: *found,
+                })
+            }
+            CorrectnessPropertiesVisitorError::SwapNonOne { found, .. } => Some(Help::Properties {
+                expected: PROPERTY_O,
+                found: *found,
+            }),
+            _ => None,
+        }
+    }
+}
+
 const TYPE_FALSE: TypeInfo = TypeInfo::new(
     MINISCRIPT_TYPE_B,
     PROPERTY_Z | PROPERTY_U | PROPERTY_D,
@@ -184,11 +476,35 @@ const TYPE_HASH160: TypeInfo = TypeInfo::new(
     0,
 );
 
-impl ASTVisitor<TypeInfo> for CorrectnessPropertiesVisitor {
+impl<T: TypeTable> ASTVisitor<TypeInfo> for CorrectnessPropertiesVisitor<T> {
     type Error = CorrectnessPropertiesVisitorError;
 
-    fn visit_ast(&mut self, ctx: &Context, node: &AST) -> Result<TypeInfo, Self::Error> {
-        match &node.fragment {
+    /// Type check the nodes in the order they are stored. Sub-expressions are always
+    /// stored before the fragment using them, so the type of every sub-expression is known
+    /// before checking the fragment and checking never recurses.
+    fn visit<C: Tree>(&mut self, ctx: &C) -> Result<TypeInfo, Self::Error> {
+        self.types.reset(ctx.get_nodes().len());
+        for (index, node) in ctx.get_nodes().iter().enumerate() {
+            let type_info = self.visit_ast(ctx, node)?;
+            self.types.as_mut()[index] = Some(type_info);
+        }
+        self.visit_ast(ctx, ctx.get_root())
+    }
+
+    #[inline]
+    fn visit_ast_by_index<C: Tree>(
+        &mut self,
+        ctx: &C,
+        index: NodeIndex,
+    ) -> Result<TypeInfo, Self::Error> {
+        match self.types.as_ref().get(index as usize) {
+            Some(Some(type_info)) => Ok(*type_info),
+            _ => self.visit_ast(ctx, ctx.get_node(index)),
+        }
+    }
+
+    fn visit_ast<C: Tree>(&mut self, ctx: &C, node: &AST) -> Result<TypeInfo, Self::Error> {
+        let mut type_info = match &node.fragment {
             Fragment::False => Ok(TYPE_FALSE),
             Fragment::True => Ok(TYPE_TRUE),
             Fragment::PkK { key } => Ok(TYPE_PKK),
@@ -220,24 +536,27 @@ impl ASTVisitor<TypeInfo> for CorrectnessPropertiesVisitor {
                 if x_type.base_type() != MINISCRIPT_TYPE_B {
                     return Err(CorrectnessPropertiesVisitorError::UnexpectedType {
                         reason: "andor(X,Y,Z): X must be type B (Base)",
+                        expected: MINISCRIPT_TYPE_B,
                         found: x_type.base_type(),
-                        position: node.position,
+                        span: ctx.get_node(*x).span(),
                     });
                 }
 
                 if !x_type.has_properties(PROPERTY_D | PROPERTY_U) {
-                    return Err(CorrectnessPropertiesVisitorError::UnexpectedType {
+                    return Err(CorrectnessPropertiesVisitorError::MissingProperties {
                         reason: "andor(X,Y,Z): X must have property 'du'",
+                        expected: PROPERTY_D | PROPERTY_U,
                         found: x_type.properties(),
-                        position: node.position,
+                        span: ctx.get_node(*x).span(),
                     });
                 }
 
                 if y_type.base_type() != z_type.base_type() {
                     return Err(CorrectnessPropertiesVisitorError::UnexpectedType {
                         reason: "andor(X,Y,Z): Y and Z must have the same type, but Y is",
+                        expected: z_type.base_type(),
                         found: y_type.base_type(),
-                        position: node.position,
+                        span: ctx.get_node(*y).span(),
                     });
                 }
 
@@ -247,8 +566,9 @@ impl ASTVisitor<TypeInfo> for CorrectnessPropertiesVisitor {
                 {
                     return Err(CorrectnessPropertiesVisitorError::UnexpectedType {
                         reason: "andor(X,Y,Z): Y must be type B (Base), K (Key), or V (Verify)",
+                        expected: MINISCRIPT_TYPE_B | MINISCRIPT_TYPE_K | MINISCRIPT_TYPE_V,
                         found: y_type.base_type(),
-                        position: node.position,
+                        span: ctx.get_node(*y).span(),
                     });
                 }
 
@@ -296,8 +616,9 @@ impl ASTVisitor<TypeInfo> for CorrectnessPropertiesVisitor {
                 if x_type.base_type() != MINISCRIPT_TYPE_V {
                     return Err(CorrectnessPropertiesVisitorError::UnexpectedType {
                         reason: "and_v(X,Y): X must be type V (Verify)",
+                        expected: MINISCRIPT_TYPE_V,
                         found: x_type.base_type(),
-                        position: node.position,
+                        span: ctx.get_node(*x).span(),
                     });
                 }
 
@@ -307,8 +628,9 @@ impl ASTVisitor<TypeInfo> for CorrectnessPropertiesVisitor {
                 {
                     return Err(CorrectnessPropertiesVisitorError::UnexpectedType {
                         reason: "and_v(X,Y): Y must be type B (Base), K (Key), or V (Verify)",
+                        expected: MINISCRIPT_TYPE_B | MINISCRIPT_TYPE_K | MINISCRIPT_TYPE_V,
                         found: y_type.base_type(),
-                        position: node.position,
+                        span: ctx.get_node(*y).span(),
                     });
                 }
 
@@ -348,16 +670,18 @@ impl ASTVisitor<TypeInfo> for CorrectnessPropertiesVisitor {
                 if x_type.base_type() != MINISCRIPT_TYPE_B {
                     return Err(CorrectnessPropertiesVisitorError::UnexpectedType {
                         reason: "and_b(X,Y): X must be type B (Base)",
+                        expected: MINISCRIPT_TYPE_B,
                         found: x_type.base_type(),
-                        position: node.position,
+                        span: ctx.get_node(*x).span(),
                     });
                 }
 
                 if y_type.base_type() != MINISCRIPT_TYPE_W {
                     return Err(CorrectnessPropertiesVisitorError::UnexpectedType {
                         reason: "and_b(X,Y): Y must be type W (Wrapped)",
+                        expected: MINISCRIPT_TYPE_W,
                         found: y_type.base_type(),
-                        position: node.position,
+                        span: ctx.get_node(*y).span(),
                     });
                 }
 
@@ -400,32 +724,36 @@ impl ASTVisitor<TypeInfo> for CorrectnessPropertiesVisitor {
                 if x_type.base_type() != MINISCRIPT_TYPE_B {
                     return Err(CorrectnessPropertiesVisitorError::UnexpectedType {
                         reason: "or_b(X,Z): X must be type B (Base)",
+                        expected: MINISCRIPT_TYPE_B,
                         found: x_type.base_type(),
-                        position: node.position,
+                        span: ctx.get_node(*x).span(),
                     });
                 }
 
                 if !x_type.has_property(PROPERTY_D) {
-                    return Err(CorrectnessPropertiesVisitorError::UnexpectedType {
+                    return Err(CorrectnessPropertiesVisitorError::MissingProperties {
                         reason: "or_b(X,Z): X must have property D (Data)",
+                        expected: PROPERTY_D,
                         found: x_type.properties(),
-                        position: node.position,
+                        span: ctx.get_node(*x).span(),
                     });
                 }
 
                 if z_type.base_type() != MINISCRIPT_TYPE_W {
                     return Err(CorrectnessPropertiesVisitorError::UnexpectedType {
                         reason: "or_b(X,Z): Z must be type W (Wrapped)",
+                        expected: MINISCRIPT_TYPE_W,
                         found: z_type.base_type(),
-                        position: node.position,
+                        span: ctx.get_node(*z).span(),
                     });
                 }
 
                 if !z_type.has_property(PROPERTY_D) {
-                    return Err(CorrectnessPropertiesVisitorError::UnexpectedType {
+                    return Err(CorrectnessPropertiesVisitorError::MissingProperties {
                         reason: "or_b(X,Z): Z must have property D (Data)",
+                        expected: PROPERTY_D,
                         found: z_type.properties(),
-                        position: node.position,
+                        span: ctx.get_node(*z).span(),
                     });
                 }
 
@@ -460,24 +788,27 @@ impl ASTVisitor<TypeInfo> for CorrectnessPropertiesVisitor {
                 if x_type.base_type() != MINISCRIPT_TYPE_B {
                     return Err(CorrectnessPropertiesVisitorError::UnexpectedType {
                         reason: "or_c(X,Z): X must be type B (Base)",
+                        expected: MINISCRIPT_TYPE_B,
                         found: x_type.base_type(),
-                        position: node.position,
+                        span: ctx.get_node(*x).span(),
                     });
                 }
 
                 if !x_type.has_properties(PROPERTY_D | PROPERTY_U) {
-                    return Err(CorrectnessPropertiesVisitorError::UnexpectedType {
+                    return Err(CorrectnessPropertiesVisitorError::MissingProperties {
                         reason: "or_c(X,Z): X must have properties D (Data) and U (Unknown)",
+                        expected: PROPERTY_D | PROPERTY_U,
                         found: x_type.properties(),
-                        position: node.position,
+                        span: ctx.get_node(*x).span(),
                     });
                 }
 
                 if z_type.base_type() != MINISCRIPT_TYPE_V {
                     return Err(CorrectnessPropertiesVisitorError::UnexpectedType {
                         reason: "or_c(X,Z): Z must be type V (Verify)",
+                        expected: MINISCRIPT_TYPE_V,
                         found: z_type.base_type(),
-                        position: node.position,
+                        span: ctx.get_node(*z).span(),
                     });
                 }
 
@@ -507,24 +838,27 @@ impl ASTVisitor<TypeInfo> for CorrectnessPropertiesVisitor {
                 if x_type.base_type() != MINISCRIPT_TYPE_B {
                     return Err(CorrectnessPropertiesVisitorError::UnexpectedType {
                         reason: "or_d(X,Z): X must be type B (Base)",
+                        expected: MINISCRIPT_TYPE_B,
                         found: x_type.base_type(),
-                        position: node.position,
+                        span: ctx.get_node(*x).span(),
                     });
                 }
 
                 if !x_type.has_properties(PROPERTY_D | PROPERTY_U) {
-                    return Err(CorrectnessPropertiesVisitorError::UnexpectedType {
+                    return Err(CorrectnessPropertiesVisitorError::MissingProperties {
                         reason: "or_d(X,Z): X must have properties D (Data) and U (Unknown)",
+                        expected: PROPERTY_D | PROPERTY_U,
                         found: x_type.properties(),
-                        position: node.position,
+                        span: ctx.get_node(*x).span(),
                     });
                 }
 
                 if z_type.base_type() != MINISCRIPT_TYPE_B {
                     return Err(CorrectnessPropertiesVisitorError::UnexpectedType {
                         reason: "or_d(X,Z): Z must be type B (Base)",
+                        expected: MINISCRIPT_TYPE_B,
                         found: z_type.base_type(),
-                        position: node.position,
+                        span: ctx.get_node(*z).span(),
                     });
                 }
 
@@ -562,8 +896,9 @@ impl ASTVisitor<TypeInfo> for CorrectnessPropertiesVisitor {
                 if x_type.base_type() != z_type.base_type() {
                     return Err(CorrectnessPropertiesVisitorError::UnexpectedType {
                         reason: "or_i(X,Z): X and Z must have the same type, but X is",
+                        expected: z_type.base_type(),
                         found: x_type.base_type(),
-                        position: node.position,
+                        span: ctx.get_node(*x).span(),
                     });
                 }
 
@@ -573,8 +908,9 @@ impl ASTVisitor<TypeInfo> for CorrectnessPropertiesVisitor {
                 {
                     return Err(CorrectnessPropertiesVisitorError::UnexpectedType {
                         reason: "or_i(X,Z): X must be type B (Base), K (Key), or V (Verify)",
+                        expected: MINISCRIPT_TYPE_B | MINISCRIPT_TYPE_K | MINISCRIPT_TYPE_V,
                         found: x_type.base_type(),
-                        position: node.position,
+                        span: ctx.get_node(*x).span(),
                     });
                 }
 
@@ -602,24 +938,25 @@ impl ASTVisitor<TypeInfo> for CorrectnessPropertiesVisitor {
             }
             Fragment::Thresh { k, xs } => {
                 // 1 ≤ k ≤ n; X1 is Bdu; others are Wdu
+                let xs = ctx.list(*xs);
                 let k = *k;
                 if k < 1 {
                     return Err(CorrectnessPropertiesVisitorError::InvalidThreshold {
-                        position: node.position,
+                        span: node.span(),
                         k,
                     });
                 }
 
                 if xs.len() < k as usize {
                     return Err(CorrectnessPropertiesVisitorError::InvalidThreshold {
-                        position: node.position,
+                        span: node.span(),
                         k,
                     });
                 }
 
                 if xs.is_empty() {
                     return Err(CorrectnessPropertiesVisitorError::EmptyThreshold {
-                        position: node.position,
+                        span: node.span(),
                     });
                 }
 
@@ -632,32 +969,36 @@ impl ASTVisitor<TypeInfo> for CorrectnessPropertiesVisitor {
                         if x_type.base_type() != MINISCRIPT_TYPE_B {
                             return Err(CorrectnessPropertiesVisitorError::UnexpectedType {
                                 reason: "thresh(k,X1,...,Xn): X1 must be type B (Base)",
+                                expected: MINISCRIPT_TYPE_B,
                                 found: x_type.base_type(),
-                                position: node.position,
+                                span: ctx.get_node(*x).span(),
                             });
                         }
 
                         if !x_type.has_properties(PROPERTY_D | PROPERTY_U) {
-                            return Err(CorrectnessPropertiesVisitorError::UnexpectedType {
+                            return Err(CorrectnessPropertiesVisitorError::MissingProperties {
                                 reason: "thresh(k,X1,...,Xn): X{} must have properties D (Data) and U (Unknown)",
+                                expected: PROPERTY_D | PROPERTY_U,
                                 found: x_type.properties(),
-                                position: node.position,
+                                span: ctx.get_node(*x).span(),
                             });
                         }
                     } else {
                         if x_type.base_type() != MINISCRIPT_TYPE_W {
                             return Err(CorrectnessPropertiesVisitorError::UnexpectedType {
                                 reason: "thresh(k,X1,...,Xn): X{} must be type W (Wrapped)",
+                                expected: MINISCRIPT_TYPE_W,
                                 found: x_type.base_type(),
-                                position: node.position,
+                                span: ctx.get_node(*x).span(),
                             });
                         }
 
                         if !x_type.has_properties(PROPERTY_D | PROPERTY_U) {
-                            return Err(CorrectnessPropertiesVisitorError::UnexpectedType {
+                            return Err(CorrectnessPropertiesVisitorError::MissingProperties {
                                 reason: "thresh(k,X1,...,Xn): X{} must have properties D (Data) and U (Unknown)",
+                                expected: PROPERTY_D | PROPERTY_U,
                                 found: x_type.properties(),
-                                position: node.position,
+                                span: ctx.get_node(*x).span(),
                             });
                         }
                     }
@@ -704,21 +1045,21 @@ impl ASTVisitor<TypeInfo> for CorrectnessPropertiesVisitor {
                 let k = *k;
                 if k < 1 {
                     return Err(CorrectnessPropertiesVisitorError::InvalidThreshold {
-                        position: node.position,
+                        span: node.span(),
                         k,
                     });
                 }
 
                 if n < k as usize {
                     return Err(CorrectnessPropertiesVisitorError::InvalidThreshold {
-                        position: node.position,
+                        span: node.span(),
                         k,
                     });
                 }
 
                 if keys.is_empty() {
                     return Err(CorrectnessPropertiesVisitorError::EmptyThreshold {
-                        position: node.position,
+                        span: node.span(),
                     });
                 }
 
@@ -743,21 +1084,21 @@ impl ASTVisitor<TypeInfo> for CorrectnessPropertiesVisitor {
                 let k = *k;
                 if k < 1 {
                     return Err(CorrectnessPropertiesVisitorError::InvalidThreshold {
-                        position: node.position,
+                        span: node.span(),
                         k,
                     });
                 }
 
                 if n < k as usize {
                     return Err(CorrectnessPropertiesVisitorError::InvalidThreshold {
-                        position: node.position,
+                        span: node.span(),
                         k,
                     });
                 }
 
                 if keys.is_empty() {
                     return Err(CorrectnessPropertiesVisitorError::EmptyThreshold {
-                        position: node.position,
+                        span: node.span(),
                     });
                 }
 
@@ -784,8 +1125,9 @@ impl ASTVisitor<TypeInfo> for CorrectnessPropertiesVisitor {
                         if x_type.base_type() != MINISCRIPT_TYPE_B {
                             return Err(CorrectnessPropertiesVisitorError::UnexpectedType {
                                 reason: "a:X: X must be type B (Base)",
+                                expected: MINISCRIPT_TYPE_B,
                                 found: x_type.base_type(),
-                                position: node.position,
+                                span: ctx.get_node(*x).span(),
                             });
                         }
 
@@ -810,14 +1152,16 @@ impl ASTVisitor<TypeInfo> for CorrectnessPropertiesVisitor {
                         if x_type.base_type() != MINISCRIPT_TYPE_B {
                             return Err(CorrectnessPropertiesVisitorError::UnexpectedType {
                                 reason: "s:X: X must be type B (Base)",
+                                expected: MINISCRIPT_TYPE_B,
                                 found: x_type.base_type(),
-                                position: node.position,
+                                span: ctx.get_node(*x).span(),
                             });
                         }
 
                         if !x_type.has_property(PROPERTY_O) {
                             return Err(CorrectnessPropertiesVisitorError::SwapNonOne {
-                                position: node.position,
+                                span: ctx.get_node(*x).span(),
+                                found: x_type.properties(),
                             });
                         }
 
@@ -839,7 +1183,7 @@ impl ASTVisitor<TypeInfo> for CorrectnessPropertiesVisitor {
                     }
                     IdentityType::C => {
                         // X is K
-                        return type_info_for_identity_c(node.position, &x_type);
+                        type_info_for_identity_c(ctx.get_node(*x).span(), &x_type)
                     }
 
                     IdentityType::D => {
@@ -847,16 +1191,18 @@ impl ASTVisitor<TypeInfo> for CorrectnessPropertiesVisitor {
                         if x_type.base_type() != MINISCRIPT_TYPE_V {
                             return Err(CorrectnessPropertiesVisitorError::UnexpectedType {
                                 reason: "d:X: X must be type V (Verify)",
+                                expected: MINISCRIPT_TYPE_V,
                                 found: x_type.base_type(),
-                                position: node.position,
+                                span: ctx.get_node(*x).span(),
                             });
                         }
 
                         if !x_type.has_property(PROPERTY_Z) {
-                            return Err(CorrectnessPropertiesVisitorError::UnexpectedType {
+                            return Err(CorrectnessPropertiesVisitorError::MissingProperties {
                                 reason: "d:X: X must have property Z (Zero)",
+                                expected: PROPERTY_Z,
                                 found: x_type.properties(),
-                                position: node.position,
+                                span: ctx.get_node(*x).span(),
                             });
                         }
 
@@ -883,8 +1229,9 @@ impl ASTVisitor<TypeInfo> for CorrectnessPropertiesVisitor {
                         if x_type.base_type() != MINISCRIPT_TYPE_B {
                             return Err(CorrectnessPropertiesVisitorError::UnexpectedType {
                                 reason: "v:X: X must be type B (Base)",
+                                expected: MINISCRIPT_TYPE_B,
                                 found: x_type.base_type(),
-                                position: node.position,
+                                span: ctx.get_node(*x).span(),
                             });
                         }
 
@@ -915,14 +1262,16 @@ impl ASTVisitor<TypeInfo> for CorrectnessPropertiesVisitor {
                         if x_type.base_type() != MINISCRIPT_TYPE_B {
                             return Err(CorrectnessPropertiesVisitorError::UnexpectedType {
                                 reason: "j:X: X must be type B (Base)",
+                                expected: MINISCRIPT_TYPE_B,
                                 found: x_type.base_type(),
-                                position: node.position,
+                                span: ctx.get_node(*x).span(),
                             });
                         }
 
                         if !x_type.has_property(PROPERTY_N) {
                             return Err(CorrectnessPropertiesVisitorError::NonZeroZero {
-                                position: node.position,
+                                span: ctx.get_node(*x).span(),
+                                found: x_type.properties(),
                             });
                         }
 
@@ -951,8 +1300,9 @@ impl ASTVisitor<TypeInfo> for CorrectnessPropertiesVisitor {
                         if x_type.base_type() != MINISCRIPT_TYPE_B {
                             return Err(CorrectnessPropertiesVisitorError::UnexpectedType {
                                 reason: "n:X: X must be type B (Base)",
+                                expected: MINISCRIPT_TYPE_B,
                                 found: x_type.base_type(),
-                                position: node.position,
+                                span: ctx.get_node(*x).span(),
                             });
                         }
 
@@ -992,35 +1342,158 @@ impl ASTVisitor<TypeInfo> for CorrectnessPropertiesVisitor {
                 // Check whether the top-level is type B
                 if inner_type.base_type() != MINISCRIPT_TYPE_B {
                     return Err(CorrectnessPropertiesVisitorError::NonTopLevel {
-                        position: node.position,
+                        span: ctx.get_node(*inner).span(),
                     });
                 }
                 Ok(inner_type)
             }
-            Fragment::RawPkH { key } => Ok(TypeInfo::new(MINISCRIPT_TYPE_B, 0, 0, false, 0)),
+            Fragment::RawPkH { .. } => type_info_for_identity_c(node.span(), &TYPE_PKH),
             Fragment::RawTr { key, inner } => {
                 if let Some(inner) = inner {
                     let inner_type = self.visit_ast_by_index(ctx, *inner)?;
                     Ok(inner_type)
                 } else {
-                    Ok(TypeInfo::new(MINISCRIPT_TYPE_B, 0, 0, false, 0))
+                    // a key path spend only, like pk(key)
+                    type_info_for_identity_c(node.span(), &TYPE_PKK)
                 }
             }
-            Fragment::RawPk { key } => type_info_for_identity_c(node.position, &TYPE_PKK),
-        }
+            Fragment::RawPk { .. } => type_info_for_identity_c(node.span(), &TYPE_PKK),
+            // the script pubkey of an address or a raw script is not miniscript, nothing is
+            // known about how to spend it
+            Fragment::RawAddr { address } => {
+                let script_size = address.assume_checked_ref().script_pubkey().len();
+                Ok(TypeInfo::new(MINISCRIPT_TYPE_B, 0, script_size, false, 0))
+            }
+            Fragment::RawScript { script } => {
+                Ok(TypeInfo::new(MINISCRIPT_TYPE_B, 0, script.len(), false, 0))
+            }
+        }?;
+
+        type_info.malleability = self.malleability(ctx, node)?;
+        Ok(type_info)
+    }
+}
+
+impl<T: TypeTable> CorrectnessPropertiesVisitor<T> {
+    /// Compute the malleability properties of a fragment whose sub-expressions type checked.
+    fn malleability<C: Tree>(
+        &mut self,
+        ctx: &C,
+        node: &AST,
+    ) -> Result<u8, CorrectnessPropertiesVisitorError> {
+        const E: u8 = MALLEABILITY_E;
+        const S: u8 = MALLEABILITY_S;
+        const F: u8 = MALLEABILITY_F;
+        const M: u8 = MALLEABILITY_M;
+        let when = |condition: bool, flags: u8| if condition { flags } else { 0 };
+        let mut of = |index: NodeIndex| {
+            self.visit_ast_by_index(ctx, index)
+                .map(|type_info| type_info.malleability)
+        };
+
+        Ok(match &node.fragment {
+            Fragment::False
+            | Fragment::PkK { .. }
+            | Fragment::PkH { .. }
+            | Fragment::Multi { .. }
+            | Fragment::MultiA { .. }
+            | Fragment::RawPkH { .. }
+            | Fragment::RawPk { .. }
+            | Fragment::RawTr { inner: None, .. } => E | S | M,
+            Fragment::True | Fragment::Older { .. } | Fragment::After { .. } => F | M,
+            Fragment::Sha256 { .. }
+            | Fragment::Hash256 { .. }
+            | Fragment::Ripemd160 { .. }
+            | Fragment::Hash160 { .. } => M,
+            Fragment::RawAddr { .. } | Fragment::RawScript { .. } => 0,
+            Fragment::AndOr { x, y, z } => {
+                let (x, y, z) = (of(*x)?, of(*y)?, of(*z)?);
+                // f=fZ and e=eZ if sX or fY; m=mXmYmZ if eX and some is s; s=sZ(sX or sY)
+                when(x & S != 0 || y & F != 0, z & (F | E))
+                    | when(x & E != 0 && (x | y | z) & S != 0, x & y & z & M)
+                    | (z & (x | y) & S)
+            }
+            Fragment::AndV { x, y } => {
+                let (x, y) = (of(*x)?, of(*y)?);
+                // s=sX or sY; f=fY or sX; m=mXmY
+                ((x | y) & S) | when(y & F != 0 || x & S != 0, F) | (x & y & M)
+            }
+            Fragment::AndB { x, y } => {
+                let (x, y) = (of(*x)?, of(*y)?);
+                // s=sX or sY; e=eXeY if sXsY; f=fXfY or sXfX or sYfY; m=mXmY
+                let forced = x & y & F != 0 || x & (S | F) == S | F || y & (S | F) == S | F;
+                ((x | y) & S) | when(x & y & S != 0, x & y & E) | when(forced, F) | (x & y & M)
+            }
+            Fragment::OrB { x, z } => {
+                let (x, z) = (of(*x)?, of(*z)?);
+                // s=sXsZ; e=eXeZ; m=mXmZ if eXeZ and some is s
+                (x & z & (S | E)) | when(x & z & E != 0 && (x | z) & S != 0, x & z & M)
+            }
+            Fragment::OrC { x, z } => {
+                let (x, z) = (of(*x)?, of(*z)?);
+                // s=sXsZ; f; m=mXmZ if eX and some is s
+                (x & z & S) | F | when(x & E != 0 && (x | z) & S != 0, x & z & M)
+            }
+            Fragment::OrD { x, z } => {
+                let (x, z) = (of(*x)?, of(*z)?);
+                // s=sXsZ; e=eXeZ; f=fZ; m=mXmZ if eX and some is s
+                (x & z & (S | E)) | (z & F) | when(x & E != 0 && (x | z) & S != 0, x & z & M)
+            }
+            Fragment::OrI { x, z } => {
+                let (x, z) = (of(*x)?, of(*z)?);
+                // s=sXsZ; f=fXfZ; e=eXfZ or fXeZ; m=mXmZ if some is s
+                (x & z & (S | F))
+                    | when((x | z) & F != 0, (x | z) & E)
+                    | when((x | z) & S != 0, x & z & M)
+            }
+            Fragment::Thresh { k, xs } => {
+                // e if all are e and s; m if all are e and m, and at least n-k are s;
+                // s if at least n-k+1 are s
+                let (mut all_e, mut all_m, mut signed) = (true, true, 0);
+                for x in ctx.list(*xs) {
+                    let x = of(*x)?;
+                    all_e &= x & E != 0;
+                    all_m &= x & M != 0;
+                    signed += usize::from(x & S != 0);
+                }
+                let n = xs.len();
+                let k = *k as usize;
+                when(all_e && signed == n, E)
+                    | when(all_e && all_m && signed + k >= n, M)
+                    | when(signed + k > n, S)
+            }
+            Fragment::Identity { identity_type, x } => {
+                let x = of(*x)?;
+                match identity_type {
+                    // s, f, e and m are kept
+                    IdentityType::A | IdentityType::S | IdentityType::N => x & (S | F | E | M),
+                    // f=fX; e=eX; m=mX; s
+                    IdentityType::C => (x & (F | E | M)) | S,
+                    // e=fX; m=mX; s=sX
+                    IdentityType::D | IdentityType::J => when(x & F != 0, E) | (x & (M | S)),
+                    // m=mX; s=sX; f
+                    IdentityType::V => (x & (M | S)) | F,
+                }
+            }
+            Fragment::Descriptor { inner, .. }
+            | Fragment::RawTr {
+                inner: Some(inner), ..
+            } => of(*inner)?,
+        })
     }
 }
 
 fn type_info_for_identity_c(
-    position: Position,
+    span: Span,
     x_type: &TypeInfo,
 ) -> Result<TypeInfo, CorrectnessPropertiesVisitorError> {
     // X is K
     if x_type.base_type() != MINISCRIPT_TYPE_K {
         return Err(CorrectnessPropertiesVisitorError::UnexpectedType {
             reason: "c:X: X must be type K (Key)",
+            expected: MINISCRIPT_TYPE_K,
             found: x_type.base_type(),
-            position: position,
+            span,
         });
     }
 
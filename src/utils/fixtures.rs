@@ -0,0 +1,127 @@
+//! Keys and satisfiers shared by the unit tests.
+
+pub const A: &str = "022f8bde4d1a07209355b4a7250a5c5128e88b84bddc619ab7cba8d569b240efe4";
+pub const B: &str = "025cbdf0646e5db4eaa398f365f2ea7a0e3d419b7e0330e39ce92bddedcac4f9bc";
+/// The generator point, i.e. the public key of the secret key 1
+pub const C: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+pub const XPUB: &str = "tpubDAenfwNu5GyCJWv8oqRAckdKMSUoZjgVF5p8WvQwHQeXjDhAHmGrPa4a4y2Fn7HF2nfCLefJanHV3ny1UY25MRVogizB2zRUdAo7Tr9XAjm";
+
+#[cfg(feature = "satisfy")]
+pub use satisfiers::*;
+
+#[cfg(feature = "satisfy")]
+mod satisfiers {
+    use bitcoin::key::TapTweak;
+    use bitcoin::secp256k1::{self, Message, Secp256k1};
+
+    use crate::{
+        Vec,
+        interpreter::Verifier,
+        parser::keys::KeyToken,
+        satisfy::{HashFunc, Satisfier},
+    };
+
+    /// Signs with `0x30 || pubkey`, so the verifier can check which key signed. Every
+    /// preimage is 32 bytes of `0x42`, the verifier accepts `after()` up to height 800,000.
+    pub struct Signer;
+
+    impl Satisfier for Signer {
+        fn check_older(&self, _locktime: u32) -> Option<bool> {
+            Some(true)
+        }
+
+        fn check_after(&self, _locktime: u32) -> Option<bool> {
+            Some(true)
+        }
+
+        fn sign(&self, pubkey: &KeyToken) -> Option<(Vec<u8>, bool)> {
+            let mut sig = alloc::vec![0x30];
+            sig.extend(pubkey.as_definite_key()?.to_bytes());
+            Some((sig, true))
+        }
+
+        fn preimage(&self, _hash_func: HashFunc, _hash: &[u8]) -> Option<(Vec<u8>, bool)> {
+            Some((alloc::vec![0x42; 32], true))
+        }
+    }
+
+    impl Verifier for Signer {
+        fn check_sig(&self, sig: &[u8], pubkey: &[u8]) -> bool {
+            sig[1..] == *pubkey
+        }
+
+        fn check_older(&self, _locktime: u32) -> bool {
+            true
+        }
+
+        fn check_after(&self, locktime: u32) -> bool {
+            locktime <= 800_000
+        }
+    }
+
+    /// A [`Signer`] that cannot sign for one key.
+    pub struct Offline<'a>(pub &'a str);
+
+    impl Satisfier for Offline<'_> {
+        fn check_older(&self, locktime: u32) -> Option<bool> {
+            Satisfier::check_older(&Signer, locktime)
+        }
+
+        fn check_after(&self, locktime: u32) -> Option<bool> {
+            Satisfier::check_after(&Signer, locktime)
+        }
+
+        fn sign(&self, pubkey: &KeyToken) -> Option<(Vec<u8>, bool)> {
+            if pubkey.identifier() == self.0 {
+                return Some((Vec::new(), false));
+            }
+            Signer.sign(pubkey)
+        }
+
+        fn preimage(&self, hash_func: HashFunc, hash: &[u8]) -> Option<(Vec<u8>, bool)> {
+            Signer.preimage(hash_func, hash)
+        }
+    }
+
+    /// Signs `message` for every key with the secret key 1, i.e. the generator point [`C`].
+    ///
+    /// [`C`]: super::C
+    pub struct Wallet {
+        pub message: Message,
+        pub schnorr: bool,
+        /// Sign with the key tweaked for a `tr()` output without leaves
+        pub tweak: bool,
+    }
+
+    impl Satisfier for Wallet {
+        fn check_older(&self, _locktime: u32) -> Option<bool> {
+            Some(true)
+        }
+
+        fn check_after(&self, _locktime: u32) -> Option<bool> {
+            Some(true)
+        }
+
+        fn sign(&self, _pubkey: &KeyToken) -> Option<(Vec<u8>, bool)> {
+            let secp = Secp256k1::new();
+            let mut secret = [0; 32];
+            secret[31] = 1;
+            let secret = secp256k1::SecretKey::from_slice(&secret).unwrap();
+            if self.schnorr {
+                let mut keypair = secp256k1::Keypair::from_secret_key(&secp, &secret);
+                if self.tweak {
+                    keypair = keypair.tap_tweak(&secp, None).to_keypair();
+                }
+                let sig = secp.sign_schnorr_no_aux_rand(&self.message, &keypair);
+                return Some((sig.as_ref().to_vec(), true));
+            }
+            let sig = secp.sign_ecdsa(&self.message, &secret);
+            let sig = bitcoin::ecdsa::Signature::sighash_all(sig);
+            Some((sig.to_vec(), true))
+        }
+
+        fn preimage(&self, _hash_func: HashFunc, _hash: &[u8]) -> Option<(Vec<u8>, bool)> {
+            None
+        }
+    }
+}
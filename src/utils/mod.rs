@@ -1,3 +1,12 @@
 pub mod checksum;
+#[cfg(test)]
+pub mod fixtures;
 pub mod macros;
 pub mod serialize;
+
+use crate::Vec;
+
+/// Push `next` so that its steps run in order, before the steps already pushed.
+pub(crate) fn schedule<T, const N: usize>(steps: &mut Vec<T>, next: [T; N]) {
+    steps.extend(next.into_iter().rev());
+}
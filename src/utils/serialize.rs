@@ -1,10 +1,18 @@
 use alloc::{format, string::String};
 
 use crate::{
+    Vec,
     context::Context,
-    parser::{AST, Fragment},
+    parser::{AST, Fragment, NodeIndex},
+    utils::schedule,
 };
 
+/// A step of the serialization, replacing the recursion into the sub-expressions.
+enum Step<'c> {
+    Node(&'c AST),
+    Text(&'static str),
+}
+
 /// Serializer for Miniscript descriptors.
 pub struct Serializer {
     output: String,
@@ -23,7 +31,25 @@ impl Serializer {
         self.output.clone()
     }
 
-    fn serialize_node(&mut self, ctx: &Context, ast: &AST) {
+    fn serialize_node<'c>(&mut self, ctx: &'c Context, ast: &'c AST) {
+        let mut steps = alloc::vec![Step::Node(ast)];
+        while let Some(step) = steps.pop() {
+            match step {
+                Step::Node(ast) => self.serialize_fragment(ctx, ast, &mut steps),
+                Step::Text(text) => self.output.push_str(text),
+            }
+        }
+    }
+
+    /// Write a fragment up to its first sub-expression, and push the steps writing the
+    /// rest of it in reverse order.
+    fn serialize_fragment<'c>(
+        &mut self,
+        ctx: &'c Context,
+        ast: &'c AST,
+        steps: &mut Vec<Step<'c>>,
+    ) {
+        let node = |index: &NodeIndex| Step::Node(ctx.get_node(*index));
         match &ast.fragment {
             Fragment::False => {
                 self.output.push_str("0");
@@ -32,10 +58,12 @@ impl Serializer {
                 self.output.push_str("1");
             }
             Fragment::PkK { key } => {
-                self.output.push_str(&format!("pk_k({:?})", key));
+                self.output
+                    .push_str(&format!("pk_k({})", ctx.get_key(*key)));
             }
             Fragment::PkH { key } => {
-                self.output.push_str(&format!("pk_h({:?})", key));
+                self.output
+                    .push_str(&format!("pk_h({})", ctx.get_key(*key)));
             }
             Fragment::Older { n } => {
                 self.output.push_str(&format!("older({})", n));
@@ -57,80 +85,66 @@ impl Serializer {
             }
             Fragment::AndOr { x, y, z } => {
                 self.output.push_str("andor(");
-                self.serialize_node(ctx, ctx.get_node(*x));
-                self.output.push_str(",");
-                self.serialize_node(ctx, ctx.get_node(*y));
-                self.output.push_str(",");
-                self.serialize_node(ctx, ctx.get_node(*z));
-                self.output.push_str(")");
+                schedule(
+                    steps,
+                    [
+                        node(x),
+                        Step::Text(","),
+                        node(y),
+                        Step::Text(","),
+                        node(z),
+                        Step::Text(")"),
+                    ],
+                );
             }
             Fragment::AndV { x, y } => {
                 self.output.push_str("and_v(");
-                self.serialize_node(ctx, ctx.get_node(*x));
-                self.output.push_str(",");
-                self.serialize_node(ctx, ctx.get_node(*y));
-                self.output.push_str(")");
+                schedule(steps, [node(x), Step::Text(","), node(y), Step::Text(")")]);
             }
             Fragment::AndB { x, y } => {
                 self.output.push_str("and_b(");
-                self.serialize_node(ctx, ctx.get_node(*x));
-                self.output.push_str(",");
-                self.serialize_node(ctx, ctx.get_node(*y));
-                self.output.push_str(")");
+                schedule(steps, [node(x), Step::Text(","), node(y), Step::Text(")")]);
             }
             Fragment::OrB { x, z } => {
                 self.output.push_str("or_b(");
-                self.serialize_node(ctx, ctx.get_node(*x));
-                self.output.push_str(",");
-                self.serialize_node(ctx, ctx.get_node(*z));
-                self.output.push_str(")");
+                schedule(steps, [node(x), Step::Text(","), node(z), Step::Text(")")]);
             }
             Fragment::OrC { x, z } => {
                 self.output.push_str("or_c(");
-                self.serialize_node(ctx, ctx.get_node(*x));
-                self.output.push_str(",");
-                self.serialize_node(ctx, ctx.get_node(*z));
-                self.output.push_str(")");
+                schedule(steps, [node(x), Step::Text(","), node(z), Step::Text(")")]);
             }
             Fragment::OrD { x, z } => {
                 self.output.push_str("or_d(");
-                self.serialize_node(ctx, ctx.get_node(*x));
-                self.output.push_str(",");
-                self.serialize_node(ctx, ctx.get_node(*z));
-                self.output.push_str(")");
+                schedule(steps, [node(x), Step::Text(","), node(z), Step::Text(")")]);
             }
             Fragment::OrI { x, z } => {
                 self.output.push_str("or_i(");
-                self.serialize_node(ctx, ctx.get_node(*x));
-                self.output.push_str(",");
-                self.serialize_node(ctx, ctx.get_node(*z));
-                self.output.push_str(")");
+                schedule(steps, [node(x), Step::Text(","), node(z), Step::Text(")")]);
             }
             Fragment::Thresh { k, xs } => {
                 self.output.push_str(&format!("thresh({}", k));
-                for x in xs {
-                    self.output.push_str(",");
-                    self.serialize_node(ctx, ctx.get_node(*x));
+                steps.push(Step::Text(")"));
+                for x in ctx.list(*xs).iter().rev() {
+                    schedule(steps, [Step::Text(","), node(x)]);
                 }
-                self.output.push_str(")");
             }
             Fragment::Multi { k, keys } => {
                 self.output.push_str(&format!("multi({}", k));
-                for key in keys {
-                    self.output.push_str(&format!(",{:?}", key));
+                for key in ctx.list(*keys) {
+                    self.output.push_str(&format!(",{}", ctx.get_key(*key)));
                 }
                 self.output.push_str(")");
             }
             Fragment::MultiA { k, keys } => {
                 // keys joined by comma
                 self.output.push_str(&format!("multi_a({}", k));
-                for key in keys {
-                    self.output.push_str(&format!(",{:?}", key));
+                for key in ctx.list(*keys) {
+                    self.output.push_str(&format!(",{}", ctx.get_key(*key)));
                 }
                 self.output.push_str(")");
             }
             Fragment::Identity { identity_type, x } => {
-                self.output.push_str(&format!("{:?}", identity_type));
+                self.output.push_str(&format!("{}", identity_type));
 
                 // if the inner node is an identity, do not add a colon
                 match &ctx.get_node(*x).fragment {
@@ -139,25 +153,31 @@ impl Serializer {
                         self.output.push_str(":");
                     }
                 }
-                self.serialize_node(ctx, ctx.get_node(*x));
+                steps.push(node(x));
             }
             Fragment::Descriptor { descriptor, inner } => {
-                self.output.push_str(&format!("{:?}(", descriptor));
-                self.serialize_node(ctx, ctx.get_node(*inner));
-                self.output.push_str(")");
+                self.output.push_str(&format!("{}(", descriptor));
+                schedule(steps, [node(inner), Step::Text(")")]);
             }
             Fragment::RawPkH { key } => {
-                self.output.push_str(&format!("{:?}", key));
+                self.output.push_str(&format!("{}", ctx.get_key(*key)));
             }
             Fragment::RawTr { key, inner } => {
-                self.output.push_str(&format!("{:?}", key));
+                self.output.push_str(&format!("{}", ctx.get_key(*key)));
                 if let Some(inner) = inner {
                     self.output.push_str(",");
-                    self.serialize_node(ctx, ctx.get_node(*inner));
+                    steps.push(node(inner));
                 }
             }
             Fragment::RawPk { key } => {
-                self.output.push_str(&format!("{:?}", key));
+                self.output.push_str(&format!("{}", ctx.get_key(*key)));
+            }
+            Fragment::RawAddr { address } => {
+                self.output
+                    .push_str(&format!("{}", address.assume_checked_ref()));
+            }
+            Fragment::RawScript { script } => {
+                self.output.push_str(&script.to_hex_string());
             }
         }
     }
@@ -91,17 +91,20 @@ fn test_key_derivation() {
     println!("📋 Original script: {}", script);
 
     match Context::try_from(script.as_str()) {
-        Ok(mut ctx) => {
+        Ok(ctx) => {
             println!("📦 Serialized before derivation: {}", ctx.serialize());
 
-            if let Err(e) = ctx.derive(22) {
-                println!("❌ Error during derivation: {:?}", e);
-                return;
-            }
+            let mut derived = match ctx.derive(22) {
+                Ok(derived) => derived,
+                Err(e) => {
+                    println!("❌ Error during derivation: {:?}", e);
+                    return;
+                }
+            };
 
-            println!("📦 Serialized after derivation: {}", ctx.serialize());
+            println!("📦 Serialized after derivation: {}", derived.serialize());
 
-            ctx.iterate_keys_mut(|key| {
+            derived.iterate_keys_mut(|key| {
                 println!("🔧 Before derivation: {:?}", key.identifier());
 
                 match key.derive(22) {
@@ -187,7 +190,7 @@ fn test_diffs() {
         "tr(020202020202020212131610202020202121316121618171818121715181919190)",
         "tr(3202020020202020202020202020202121316121618171818121715181919190)",
         "wpkh(022222200000000000000000000000000000000000000008413722338318148499)",
-        "pkh(022222200000000000000000000000000000000000000008413722338318148499)"
+        "pkh(022222200000000000000000000000000000000000000008413722338318148499)",
     ];
 
     for (i, script) in scripts.iter().enumerate() {